@@ -1,3 +1,4 @@
+use crate::builtins::BitOp;
 use std::fmt;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -6,7 +7,14 @@ pub enum BinaryOp {
     Subtract,
     Multiply,
     Divide,
+    IntDivide,
     Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    Xor,
+    ShiftLeft,
+    ShiftRight,
     Equals,
     NotEquals,
     Greater,
@@ -15,6 +23,15 @@ pub enum BinaryOp {
     LessOrEquals,
     And,
     Or,
+    /// `X contains Y`: whether string Y occurs in string X
+    Contains,
+    /// `index of Y in X`: left is X, right is Y
+    IndexOf,
+    /// `join X with Y`: the elements of array X as text, separated by Y
+    Join,
+    /// `X otherwise Y`: X unless it is null, in which case Y. Y is only
+    /// evaluated when it is needed.
+    Otherwise,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -23,11 +40,23 @@ pub enum UnaryOp {
     Negate,
     Length,
     Uppercase,
+    Lowercase,
+    Trim,
+    /// `type of X`: the name of X's type as a string
+    TypeOf,
+    /// `reverse X`: array X back to front
+    Reverse,
+    /// `sort X`: array X in ascending order
+    Sort,
+    /// `X is null`; `X is not null` is `Not` applied to this
+    IsNull,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Number(f64),
+    /// A whole-number literal, kept exact
+    Integer(i64),
     String(String),
     Boolean(bool),
     Identifier(String),
@@ -45,6 +74,8 @@ pub enum Expr {
         args: Vec<Expr>,
     },
     Array(Vec<Expr>),
+    /// Key/value pairs of a `map` literal, in source order
+    Map(Vec<(Expr, Expr)>),
     Index {
         array: Box<Expr>,
         index: Box<Expr>,
@@ -54,10 +85,51 @@ pub enum Expr {
         from: Box<Expr>,
         to: Box<Expr>,
     },
+    /// `slice array from I to J`: elements I up to but excluding J
+    Slice {
+        array: Box<Expr>,
+        from: Box<Expr>,
+        to: Box<Expr>,
+    },
+    /// `replace pattern with replacement in string`
+    Replace {
+        string: Box<Expr>,
+        pattern: Box<Expr>,
+        replacement: Box<Expr>,
+    },
+    /// `pop from name`: removes and yields the last element of the array
+    /// variable `name`
+    Pop(String),
+    /// `if condition then A else B`: only the chosen branch is evaluated
+    Ternary {
+        condition: Box<Expr>,
+        then_value: Box<Expr>,
+        else_value: Box<Expr>,
+    },
+}
+
+/// Where a statement starts in the source, both counted from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A statement and where it starts
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Stmt { kind, span }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Stmt {
+pub enum StmtKind {
     VarDecl {
         name: String,
         value: Expr,
@@ -72,30 +144,72 @@ pub enum Stmt {
         index: Box<Expr>,
         value: Expr,
     },
+    /// `push value onto name`: appends to the array variable `name`
+    Push {
+        array: String,
+        value: Expr,
+    },
     Print(Expr),
+    /// `write value`: print without a newline
+    Write(Expr),
+    /// `ask name prompt`, or `ask text name prompt` to keep the line as a
+    /// string even when it looks like a number
     Ask {
         name: String,
         prompt: Option<Expr>,
+        text: bool,
+    },
+    /// `assert condition message "text"`: stops the program, reporting
+    /// its line, when the condition is false
+    Assert {
+        condition: Expr,
+        message: Option<String>,
     },
     If {
         condition: Expr,
         then_block: Vec<Stmt>,
         else_block: Option<Vec<Stmt>>,
     },
+    /// `label` names the loop for `break` and `continue` in nested loops
     While {
         condition: Expr,
         body: Vec<Stmt>,
+        label: Option<String>,
+    },
+    /// `repeat count times { body }`; `count` is evaluated once
+    Repeat {
+        count: Expr,
+        body: Vec<Stmt>,
+    },
+    /// `do { body } while condition`; the body runs before the first check
+    DoWhile {
+        body: Vec<Stmt>,
+        condition: Expr,
+    },
+    /// `for each var in iterable { body }` over the elements of an array
+    ForEach {
+        var: String,
+        iterable: Expr,
+        body: Vec<Stmt>,
+        label: Option<String>,
     },
     For {
         init: Box<Stmt>,
         condition: Expr,
         increment: Box<Stmt>,
         body: Vec<Stmt>,
+        label: Option<String>,
     },
     Return(Option<Expr>),
-    Break,
-    Continue,
+    /// Leave the loop with the given label, or the innermost loop
+    Break(Option<String>),
+    /// Start the next iteration of the loop with the given label, or the
+    /// innermost loop
+    Continue(Option<String>),
     Expression(Expr),
+    /// Statements run in their own scope; produced by the optimizer when it
+    /// removes an `if` whose condition is constant
+    Block(Vec<Stmt>),
 }
 
 #[derive(Debug, Clone)]
@@ -105,11 +219,53 @@ pub struct Function {
     pub body: Vec<Stmt>,
 }
 
+impl Function {
+    /// The expression a body ending in an expression statement returns, as
+    /// in `function two() { 1 plus 1 }`
+    pub fn result(&self) -> Option<&Expr> {
+        match self.body.last() {
+            Some(Stmt {
+                kind: StmtKind::Expression(expr),
+                ..
+            }) => Some(expr),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Program {
+    /// Files named by `import` statements, relative to the importing file
+    pub imports: Vec<String>,
     pub functions: Vec<Function>,
 }
 
+impl BinaryOp {
+    /// The operation on bits this operator performs, if it is bitwise
+    pub fn bit_op(&self) -> Option<BitOp> {
+        match self {
+            BinaryOp::BitAnd => Some(BitOp::And),
+            BinaryOp::BitOr => Some(BitOp::Or),
+            BinaryOp::Xor => Some(BitOp::Xor),
+            BinaryOp::ShiftLeft => Some(BitOp::ShiftLeft),
+            BinaryOp::ShiftRight => Some(BitOp::ShiftRight),
+            _ => None,
+        }
+    }
+
+    /// The kind of value this operator takes, as named in type mismatch
+    /// errors
+    pub fn operand_type(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "number or string",
+            BinaryOp::And | BinaryOp::Or => "boolean",
+            BinaryOp::Contains | BinaryOp::IndexOf => "string",
+            BinaryOp::Join => "array",
+            _ => "number",
+        }
+    }
+}
+
 impl fmt::Display for BinaryOp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -117,7 +273,9 @@ impl fmt::Display for BinaryOp {
             BinaryOp::Subtract => write!(f, "-"),
             BinaryOp::Multiply => write!(f, "*"),
             BinaryOp::Divide => write!(f, "/"),
+            BinaryOp::IntDivide => write!(f, "//"),
             BinaryOp::Modulo => write!(f, "%"),
+            BinaryOp::Power => write!(f, "**"),
             BinaryOp::Equals => write!(f, "=="),
             BinaryOp::NotEquals => write!(f, "!="),
             BinaryOp::Greater => write!(f, ">"),
@@ -126,6 +284,15 @@ impl fmt::Display for BinaryOp {
             BinaryOp::LessOrEquals => write!(f, "<="),
             BinaryOp::And => write!(f, "&&"),
             BinaryOp::Or => write!(f, "||"),
+            BinaryOp::Contains => write!(f, "contains"),
+            BinaryOp::IndexOf => write!(f, "index of"),
+            BinaryOp::Join => write!(f, "join"),
+            BinaryOp::Otherwise => write!(f, "??"),
+            BinaryOp::BitAnd => write!(f, "&"),
+            BinaryOp::BitOr => write!(f, "|"),
+            BinaryOp::Xor => write!(f, "^"),
+            BinaryOp::ShiftLeft => write!(f, "<<"),
+            BinaryOp::ShiftRight => write!(f, ">>"),
         }
     }
 }
@@ -137,6 +304,12 @@ impl fmt::Display for UnaryOp {
             UnaryOp::Negate => write!(f, "-"),
             UnaryOp::Length => write!(f, "length"),
             UnaryOp::Uppercase => write!(f, "uppercase"),
+            UnaryOp::Lowercase => write!(f, "lowercase"),
+            UnaryOp::Trim => write!(f, "trim"),
+            UnaryOp::TypeOf => write!(f, "type of"),
+            UnaryOp::Reverse => write!(f, "reverse"),
+            UnaryOp::Sort => write!(f, "sort"),
+            UnaryOp::IsNull => write!(f, "is null"),
         }
     }
 }
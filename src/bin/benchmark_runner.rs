@@ -62,6 +62,8 @@ fn main() {
         ("Interpreter", vec![]),
         ("Bytecode VM", vec!["--bytecode"]),
         ("NaN Boxing", vec!["--bytecode", "--nanbox"]),
+        ("Register VM", vec!["--bytecode", "--registers"]),
+        ("Threaded VM", vec!["--bytecode", "--threaded"]),
     ];
 
     let mut all_results = Vec::new();
@@ -144,9 +146,15 @@ fn print_summary_table(results: &[(String, String, u128)], benchmarks: &[Benchma
     println!("║              Summary Results (ms)                 ║");
     println!("╚═══════════════════════════════════════════════════╝");
     println!();
-    println!("┌─────────────────┬────────────┬────────────┬────────────┐");
-    println!("│ Benchmark       │ Interpreter│ Bytecode   │ NaN Boxing │");
-    println!("├─────────────────┼────────────┼────────────┼────────────┤");
+    println!(
+        "┌─────────────────┬────────────┬────────────┬────────────┬────────────┬────────────┐"
+    );
+    println!(
+        "│ Benchmark       │ Interpreter│ Bytecode   │ NaN Boxing │ Registers  │ Threaded   │"
+    );
+    println!(
+        "├─────────────────┼────────────┼────────────┼────────────┼────────────┼────────────┤"
+    );
 
     for bench in benchmarks {
         let interp = results
@@ -161,15 +169,27 @@ fn print_summary_table(results: &[(String, String, u128)], benchmarks: &[Benchma
             .iter()
             .find(|(n, v, _)| n == &bench.name && v == "NaN Boxing")
             .map(|(_, _, d)| *d);
+        let registers = results
+            .iter()
+            .find(|(n, v, _)| n == &bench.name && v == "Register VM")
+            .map(|(_, _, d)| *d);
+        let threaded = results
+            .iter()
+            .find(|(n, v, _)| n == &bench.name && v == "Threaded VM")
+            .map(|(_, _, d)| *d);
 
         print!("│ {:15} │", bench.name);
         print!(" {:9} │", format_opt_ms(interp));
         print!(" {:9} │", format_opt_ms(bytecode));
         print!(" {:9} │", format_opt_ms(nanbox));
+        print!(" {:9} │", format_opt_ms(registers));
+        print!(" {:9} │", format_opt_ms(threaded));
         println!();
     }
 
-    println!("└─────────────────┴────────────┴────────────┴────────────┘");
+    println!(
+        "└─────────────────┴────────────┴────────────┴────────────┴────────────┴────────────┘"
+    );
     println!();
 }
 
@@ -178,9 +198,9 @@ fn print_speedup_analysis(results: &[(String, String, u128)], benchmarks: &[Benc
     println!("║            Speedup Analysis                       ║");
     println!("╚═══════════════════════════════════════════════════╝");
     println!();
-    println!("┌─────────────────┬──────────────┬──────────────┐");
-    println!("│ Benchmark       │ Bytecode/Int │ NanBox/Byte  │");
-    println!("├─────────────────┼──────────────┼──────────────┤");
+    println!("┌─────────────────┬──────────────┬──────────────┬──────────────┬──────────────┐");
+    println!("│ Benchmark       │ Bytecode/Int │ NanBox/Byte  │ Regs/Byte    │ Thread/Byte  │");
+    println!("├─────────────────┼──────────────┼──────────────┼──────────────┼──────────────┤");
 
     for bench in benchmarks {
         let interp = results
@@ -195,6 +215,14 @@ fn print_speedup_analysis(results: &[(String, String, u128)], benchmarks: &[Benc
             .iter()
             .find(|(n, v, _)| n == &bench.name && v == "NaN Boxing")
             .map(|(_, _, d)| *d);
+        let registers = results
+            .iter()
+            .find(|(n, v, _)| n == &bench.name && v == "Register VM")
+            .map(|(_, _, d)| *d);
+        let threaded = results
+            .iter()
+            .find(|(n, v, _)| n == &bench.name && v == "Threaded VM")
+            .map(|(_, _, d)| *d);
 
         print!("│ {:15} │", bench.name);
 
@@ -210,12 +238,26 @@ fn print_speedup_analysis(results: &[(String, String, u128)], benchmarks: &[Benc
             "N/A".to_string()
         };
 
+        let speedup3 = if let (Some(b), Some(r)) = (bytecode, registers) {
+            format!("{:.2}x", b as f64 / r as f64)
+        } else {
+            "N/A".to_string()
+        };
+
         print!(" {:12} │", speedup1);
         print!(" {:12} │", speedup2);
+        let speedup4 = if let (Some(b), Some(t)) = (bytecode, threaded) {
+            format!("{:.2}x", b as f64 / t as f64)
+        } else {
+            "N/A".to_string()
+        };
+
+        print!(" {:12} │", speedup3);
+        print!(" {:12} │", speedup4);
         println!();
     }
 
-    println!("└─────────────────┴──────────────┴──────────────┘");
+    println!("└─────────────────┴──────────────┴──────────────┴──────────────┴──────────────┘");
     println!();
 
     // Calculate averages
@@ -224,6 +266,10 @@ fn print_speedup_analysis(results: &[(String, String, u128)], benchmarks: &[Benc
     let avg_nanbox_speedup =
         calculate_avg_speedup(results, benchmarks, "Bytecode VM", "NaN Boxing");
     let total_speedup = calculate_avg_speedup(results, benchmarks, "Interpreter", "NaN Boxing");
+    let avg_register_speedup =
+        calculate_avg_speedup(results, benchmarks, "Bytecode VM", "Register VM");
+    let avg_threaded_speedup =
+        calculate_avg_speedup(results, benchmarks, "Bytecode VM", "Threaded VM");
 
     println!("📈 Average Speedups:");
     println!(
@@ -235,6 +281,14 @@ fn print_speedup_analysis(results: &[(String, String, u128)], benchmarks: &[Benc
         "   NaN Boxing vs Interpreter:  {:.2}x (total)",
         total_speedup
     );
+    println!(
+        "   Register VM vs Bytecode:    {:.2}x",
+        avg_register_speedup
+    );
+    println!(
+        "   Threaded VM vs Bytecode:    {:.2}x",
+        avg_threaded_speedup
+    );
     println!();
 }
 
@@ -0,0 +1,654 @@
+//! Built-in functions
+//!
+//! Built-ins are called with the same syntax as user functions
+//! (`sqrt(x)`). A user function with the same name takes precedence.
+
+use crate::error::RuntimeError;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Floor,
+    Ceil,
+    Round,
+    Abs,
+    Sqrt,
+    Min,
+    Max,
+    Clamp,
+    Random,
+    RandomInt,
+    Range,
+    ToNumber,
+    ToString,
+    ToBoolean,
+    /// Stops the whole program; compiled to `Instruction::Exit` rather
+    /// than called
+    Exit,
+}
+
+impl Builtin {
+    /// Every built-in, in a fixed order (the index is the bytecode encoding)
+    pub const ALL: [Builtin; 15] = [
+        Builtin::Floor,
+        Builtin::Ceil,
+        Builtin::Round,
+        Builtin::Abs,
+        Builtin::Sqrt,
+        Builtin::Random,
+        Builtin::RandomInt,
+        Builtin::Range,
+        Builtin::ToNumber,
+        Builtin::ToString,
+        Builtin::ToBoolean,
+        Builtin::Min,
+        Builtin::Max,
+        Builtin::Clamp,
+        Builtin::Exit,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        Self::ALL.into_iter().find(|b| b.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Floor => "floor",
+            Builtin::Ceil => "ceil",
+            Builtin::Round => "round",
+            Builtin::Abs => "abs",
+            Builtin::Sqrt => "sqrt",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Clamp => "clamp",
+            Builtin::Random => "random",
+            Builtin::RandomInt => "random_int",
+            Builtin::Range => "range",
+            Builtin::ToNumber => "to_number",
+            Builtin::ToString => "to_string",
+            Builtin::ToBoolean => "to_boolean",
+            Builtin::Exit => "exit",
+        }
+    }
+
+    /// Fewest and most arguments the built-in takes
+    pub fn arity(self) -> (usize, usize) {
+        match self {
+            Builtin::Random => (0, 0),
+            Builtin::Floor
+            | Builtin::Ceil
+            | Builtin::Round
+            | Builtin::Abs
+            | Builtin::Sqrt
+            | Builtin::ToNumber
+            | Builtin::ToString
+            | Builtin::ToBoolean
+            | Builtin::Exit => (1, 1),
+            Builtin::RandomInt | Builtin::Min | Builtin::Max => (2, 2),
+            Builtin::Clamp => (3, 3),
+            Builtin::Range => (2, 3),
+        }
+    }
+
+    /// Error unless `count` arguments is acceptable
+    pub fn check_arity(self, count: usize) -> Result<()> {
+        let (min, max) = self.arity();
+        if count < min || count > max {
+            let expected = if min == max {
+                min.to_string()
+            } else {
+                format!("{} to {}", min, max)
+            };
+            return Err(RuntimeError::WrongArgumentCount {
+                function: self.name().to_string(),
+                expected,
+                got: count,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Apply a built-in that returns a number to its (numeric) arguments.
+    /// `range` returns an array and goes through [`range_values`] instead;
+    /// the conversions take any value and are handled by each backend, and
+    /// `exit` never returns.
+    pub fn apply(self, args: &[f64], rng: &mut Rng) -> Result<f64> {
+        match (self, args) {
+            (Builtin::Floor, &[x]) => Ok(x.floor()),
+            (Builtin::Ceil, &[x]) => Ok(x.ceil()),
+            (Builtin::Round, &[x]) => Ok(x.round()),
+            (Builtin::Abs, &[x]) => Ok(x.abs()),
+            (Builtin::Sqrt, &[x]) => Ok(x.sqrt()),
+            (Builtin::Min, &[a, b]) => Ok(a.min(b)),
+            (Builtin::Max, &[a, b]) => Ok(a.max(b)),
+            (Builtin::Clamp, &[x, lo, hi]) => clamp(x, lo, hi),
+            (Builtin::Random, []) => Ok(rng.next_f64()),
+            (Builtin::RandomInt, &[lo, hi]) => rng.int_between(lo, hi),
+            (Builtin::Range, _) => Err(anyhow!("range returns an array, not a number")),
+            (Builtin::ToNumber | Builtin::ToString | Builtin::ToBoolean, [_]) => {
+                Err(anyhow!("{} converts values, not numbers", self.name()))
+            }
+            (Builtin::Exit, [_]) => Err(anyhow!("exit stops the program, it returns nothing")),
+            _ => {
+                self.check_arity(args.len())?;
+                unreachable!("every built-in is matched at its arity")
+            }
+        }
+    }
+}
+
+/// `x` limited to `[lo, hi]`
+fn clamp(x: f64, lo: f64, hi: f64) -> Result<f64> {
+    if lo.is_nan() || hi.is_nan() || lo > hi {
+        return Err(anyhow!("clamp expects lo <= hi, got {} and {}", lo, hi));
+    }
+    Ok(x.max(lo).min(hi))
+}
+
+/// How every backend shows a string inside an array or map: quoted, so
+/// `list "1", 1` prints as `["1", 1]`
+pub fn quote_string(s: &str) -> String {
+    format!("{:?}", s)
+}
+
+/// How every backend shows a number: whole numbers without a fraction,
+/// and `infinity`, `-infinity` and `nan` spelled the same on every platform
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "infinity" } else { "-infinity" }.to_string()
+    } else if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        (n as i64).to_string()
+    } else {
+        // Outside the i64 range `{}` still prints every digit, without an exponent
+        n.to_string()
+    }
+}
+
+/// Parse the text given to `to_number`, ignoring surrounding whitespace
+pub fn parse_number(text: &str) -> Result<f64> {
+    text.trim()
+        .parse()
+        .map_err(|_| anyhow!("Cannot convert \"{}\" to a number", text))
+}
+
+/// Parse the text given to `to_number`, keeping whole numbers exact
+pub fn parse_numeric(text: &str) -> Result<Numeric> {
+    match text.trim().parse() {
+        Ok(n) => Ok(Numeric::Integer(n)),
+        Err(_) => parse_number(text).map(Numeric::Float),
+    }
+}
+
+/// A number as arithmetic sees it. Whole numbers stay exact until an
+/// operation needs a fraction or would overflow, and then become floats.
+#[derive(Debug, Clone, Copy)]
+pub enum Numeric {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Numeric {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Numeric::Integer(n) => n as f64,
+            Numeric::Float(x) => x,
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.as_f64() == 0.0
+    }
+
+    /// `int` on two integers, or `float` when either is a float or the
+    /// integer result doesn't fit
+    fn combine(
+        self,
+        other: Numeric,
+        int: impl Fn(i64, i64) -> Option<i64>,
+        float: impl Fn(f64, f64) -> f64,
+    ) -> Numeric {
+        if let (Numeric::Integer(a), Numeric::Integer(b)) = (self, other) {
+            if let Some(n) = int(a, b) {
+                return Numeric::Integer(n);
+            }
+        }
+        Numeric::Float(float(self.as_f64(), other.as_f64()))
+    }
+
+    pub fn plus(self, other: Numeric) -> Numeric {
+        self.combine(other, i64::checked_add, |a, b| a + b)
+    }
+
+    pub fn minus(self, other: Numeric) -> Numeric {
+        self.combine(other, i64::checked_sub, |a, b| a - b)
+    }
+
+    pub fn times(self, other: Numeric) -> Numeric {
+        self.combine(other, i64::checked_mul, |a, b| a * b)
+    }
+
+    /// `divided by`: stays whole only when the division is exact
+    pub fn divide(self, other: Numeric) -> Numeric {
+        self.combine(
+            other,
+            |a, b| (a.checked_rem(b)? == 0).then(|| a / b),
+            |a, b| a / b,
+        )
+    }
+
+    /// `divided by ... rounded down`
+    pub fn floor_divide(self, other: Numeric) -> Numeric {
+        self.combine(
+            other,
+            |a, b| {
+                let quotient = a.checked_div(b)?;
+                if a % b != 0 && (a < 0) != (b < 0) {
+                    Some(quotient - 1)
+                } else {
+                    Some(quotient)
+                }
+            },
+            |a, b| (a / b).floor(),
+        )
+    }
+
+    pub fn modulo(self, other: Numeric) -> Numeric {
+        self.combine(other, i64::checked_rem, |a, b| a % b)
+    }
+
+    /// `to the power of`: whole for a whole base and a non-negative whole
+    /// exponent
+    pub fn power(self, other: Numeric) -> Numeric {
+        self.combine(
+            other,
+            |a, b| a.checked_pow(u32::try_from(b).ok()?),
+            f64::powf,
+        )
+    }
+
+    pub fn negate(self) -> Numeric {
+        match self {
+            Numeric::Integer(n) => n
+                .checked_neg()
+                .map_or(Numeric::Float(-(n as f64)), Numeric::Integer),
+            Numeric::Float(x) => Numeric::Float(-x),
+        }
+    }
+
+    /// How `equals` compares numbers: integers exactly, anything involving
+    /// a float to within `f64::EPSILON`
+    pub fn equals(self, other: Numeric) -> bool {
+        match (self, other) {
+            (Numeric::Integer(a), Numeric::Integer(b)) => a == b,
+            _ => (self.as_f64() - other.as_f64()).abs() < f64::EPSILON,
+        }
+    }
+
+    /// The order `sort` puts numbers in, with NaN placed as `f64::total_cmp`
+    /// places it
+    pub fn total_cmp(self, other: Numeric) -> Ordering {
+        match (self, other) {
+            (Numeric::Integer(a), Numeric::Integer(b)) => a.cmp(&b),
+            _ => self.as_f64().total_cmp(&other.as_f64()),
+        }
+    }
+}
+
+impl PartialEq for Numeric {
+    fn eq(&self, other: &Numeric) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Numeric {
+    fn partial_cmp(&self, other: &Numeric) -> Option<Ordering> {
+        match (self, other) {
+            (Numeric::Integer(a), Numeric::Integer(b)) => Some(a.cmp(b)),
+            _ => self.as_f64().partial_cmp(&other.as_f64()),
+        }
+    }
+}
+
+impl std::fmt::Display for Numeric {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Numeric::Integer(n) => write!(f, "{}", n),
+            Numeric::Float(x) => write!(f, "{}", format_number(*x)),
+        }
+    }
+}
+
+/// Elements of `range(start, end)` or `range(start, end, step)`: from
+/// `start` up to but excluding `end`, counting down when `step` is negative
+pub fn range_values(args: &[f64]) -> Result<Vec<f64>> {
+    Builtin::Range.check_arity(args.len())?;
+    let (start, end) = (args[0], args[1]);
+    let step = args.get(2).copied().unwrap_or(1.0);
+    if step == 0.0 || !step.is_finite() {
+        return Err(anyhow!(
+            "range step must be a non-zero number, got {}",
+            step
+        ));
+    }
+
+    let mut values = Vec::new();
+    let mut i = 0.0;
+    loop {
+        let value = start + i * step;
+        if (step > 0.0 && value >= end) || (step < 0.0 && value <= end) {
+            break;
+        }
+        values.push(value);
+        i += 1.0;
+    }
+    Ok(values)
+}
+
+/// Characters `from` up to but excluding `to` of `s`, for `substring`
+pub fn substring(s: &str, from: f64, to: f64) -> Result<String> {
+    let length = s.chars().count();
+    let (start, end) = (from as usize, to as usize);
+    if from < 0.0 || to < 0.0 || start > end || end > length {
+        return Err(RuntimeError::InvalidSubstring { from, to, length }.into());
+    }
+    Ok(s.chars().skip(start).take(end - start).collect())
+}
+
+/// Positions `from` up to but excluding `to` of an array of `length`
+/// elements, for `slice`
+pub fn slice_range(from: f64, to: f64, length: usize) -> Result<Range<usize>> {
+    let (start, end) = (from as usize, to as usize);
+    if from < 0.0 || to < 0.0 || start > end || end > length {
+        return Err(RuntimeError::InvalidSlice { from, to, length }.into());
+    }
+    Ok(start..end)
+}
+
+/// What `sort` compares an element by
+pub enum SortKey {
+    Number(Numeric),
+    String(String),
+    /// An element that can't be sorted, with the name of its type
+    Other(&'static str),
+}
+
+/// `items` in the order `sort` puts them: numbers ascending, or strings in
+/// lexicographic order. An array mixing the two has no order and is an error.
+pub fn sort_array<T>(items: Vec<T>, key: impl Fn(&T) -> SortKey) -> Result<Vec<T>> {
+    let mut keyed: Vec<(SortKey, T)> = items.into_iter().map(|item| (key(&item), item)).collect();
+    let mut kinds = keyed.iter().map(|(key, _)| match key {
+        SortKey::Number(_) => Ok("number"),
+        SortKey::String(_) => Ok("string"),
+        SortKey::Other(name) => Err(anyhow!("Cannot sort an array containing a {}", name)),
+    });
+    if let Some(first) = kinds.next() {
+        let first = first?;
+        for kind in kinds {
+            if kind? != first {
+                return Err(anyhow!("Cannot sort an array of both numbers and strings"));
+            }
+        }
+    }
+
+    keyed.sort_by(|(a, _), (b, _)| match (a, b) {
+        (SortKey::Number(a), SortKey::Number(b)) => a.total_cmp(*b),
+        (SortKey::String(a), SortKey::String(b)) => a.cmp(b),
+        _ => Ordering::Equal,
+    });
+    Ok(keyed.into_iter().map(|(_, item)| item).collect())
+}
+
+/// Position of element `index` in an array of `length` elements
+pub fn array_index(index: f64, length: usize) -> Result<usize> {
+    let position = index as usize;
+    if index < 0.0 || position >= length {
+        return Err(RuntimeError::IndexOutOfBounds { index, length }.into());
+    }
+    Ok(position)
+}
+
+/// An operator on the bits of whole numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+    ShiftLeft,
+    ShiftRight,
+}
+
+impl BitOp {
+    /// Every operator, in a fixed order (the index is the bytecode encoding)
+    pub const ALL: [BitOp; 5] = [
+        BitOp::And,
+        BitOp::Or,
+        BitOp::Xor,
+        BitOp::ShiftLeft,
+        BitOp::ShiftRight,
+    ];
+
+    /// The words the operator is written with
+    pub fn name(self) -> &'static str {
+        match self {
+            BitOp::And => "bitand",
+            BitOp::Or => "bitor",
+            BitOp::Xor => "xor",
+            BitOp::ShiftLeft => "shift left",
+            BitOp::ShiftRight => "shift right",
+        }
+    }
+
+    /// `a op b` on the two's complement bits of `a` and `b`. Both must be
+    /// whole numbers, and a shift must be by 0 to 63 places; shifting
+    /// right keeps the sign.
+    pub fn apply(self, a: Numeric, b: Numeric) -> Result<Numeric> {
+        let whole = |n: Numeric| -> Result<i64> {
+            match n {
+                Numeric::Integer(n) => Ok(n),
+                // The round trip fails for fractions, NaN, infinities and
+                // anything else out of `i64` range
+                Numeric::Float(x) if x as i64 as f64 == x => Ok(x as i64),
+                Numeric::Float(x) => Err(RuntimeError::NotAWholeNumber {
+                    operator: self.name(),
+                    value: x,
+                }
+                .into()),
+            }
+        };
+        let a = whole(a)?;
+        let shift = || -> Result<u32> {
+            let b = b.as_f64();
+            if !(0.0..64.0).contains(&b) || b.fract() != 0.0 {
+                return Err(RuntimeError::InvalidShift(b).into());
+            }
+            Ok(b as u32)
+        };
+        let result = match self {
+            BitOp::And => a & whole(b)?,
+            BitOp::Or => a | whole(b)?,
+            BitOp::Xor => a ^ whole(b)?,
+            BitOp::ShiftLeft => a << shift()?,
+            BitOp::ShiftRight => a >> shift()?,
+        };
+        Ok(Numeric::Integer(result))
+    }
+}
+
+/// Deterministic pseudo-random number generator (SplitMix64) behind
+/// `random` and `random_int`
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// Seed from the system clock and the process's hash randomization
+    pub fn from_entropy() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        Rng::new(hasher.finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A float in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// An integer in `[lo, hi]`, both ends inclusive (bounds are floored)
+    pub fn int_between(&mut self, lo: f64, hi: f64) -> Result<f64> {
+        let (lo, hi) = (lo.floor(), hi.floor());
+        if !lo.is_finite() || !hi.is_finite() || lo > hi {
+            return Err(anyhow!(
+                "random_int expects lo <= hi, got {} and {}",
+                lo,
+                hi
+            ));
+        }
+        let span = (hi - lo) as u64 + 1;
+        Ok(lo + (self.next_u64() % span) as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_and_math() {
+        for builtin in Builtin::ALL {
+            assert_eq!(Builtin::from_name(builtin.name()), Some(builtin));
+        }
+        assert_eq!(Builtin::from_name("main"), None);
+
+        let rng = &mut Rng::new(0);
+        assert_eq!(Builtin::Floor.apply(&[-1.5], rng).unwrap(), -2.0);
+        assert_eq!(Builtin::Ceil.apply(&[1.2], rng).unwrap(), 2.0);
+        assert_eq!(Builtin::Round.apply(&[2.5], rng).unwrap(), 3.0);
+        assert_eq!(Builtin::Abs.apply(&[-3.0], rng).unwrap(), 3.0);
+        assert_eq!(Builtin::Sqrt.apply(&[16.0], rng).unwrap(), 4.0);
+        assert!(Builtin::Sqrt.apply(&[1.0, 2.0], rng).is_err());
+        assert_eq!(Builtin::Min.apply(&[2.0, -3.0], rng).unwrap(), -3.0);
+        assert_eq!(Builtin::Max.apply(&[2.0, -3.0], rng).unwrap(), 2.0);
+        assert_eq!(Builtin::Clamp.apply(&[5.0, 0.0, 1.0], rng).unwrap(), 1.0);
+        assert!(Builtin::Clamp.apply(&[5.0, 1.0, 0.0], rng).is_err());
+    }
+
+    #[test]
+    fn test_seeded_sequence() {
+        let mut rng = Rng::new(42);
+        let ints: Vec<f64> = (0..5).map(|_| rng.int_between(1.0, 6.0).unwrap()).collect();
+        assert_eq!(ints, vec![2.0, 2.0, 1.0, 1.0, 5.0]);
+
+        let mut rng = Rng::new(42);
+        let x = rng.next_f64();
+        assert!((0.0..1.0).contains(&x));
+        assert!(rng.int_between(3.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_range_values() {
+        assert_eq!(
+            range_values(&[0.0, 5.0]).unwrap(),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0]
+        );
+        assert_eq!(range_values(&[3.0, 3.0]).unwrap(), Vec::<f64>::new());
+        assert_eq!(
+            range_values(&[5.0, 0.0, -2.0]).unwrap(),
+            vec![5.0, 3.0, 1.0]
+        );
+        assert!(range_values(&[0.0, 5.0, 0.0]).is_err());
+        assert!(Builtin::Range.check_arity(1).is_err());
+    }
+
+    #[test]
+    fn test_sort_array() {
+        let key = |n: &f64| SortKey::Number(Numeric::Float(*n));
+        assert_eq!(
+            sort_array(vec![3.0, 1.0, 2.0], key).unwrap(),
+            vec![1.0, 2.0, 3.0]
+        );
+        assert_eq!(sort_array(Vec::new(), key).unwrap(), Vec::<f64>::new());
+
+        let key = |s: &&str| SortKey::String(s.to_string());
+        assert_eq!(sort_array(vec!["b", "a"], key).unwrap(), vec!["a", "b"]);
+
+        let mixed = sort_array(vec![1, 2], |n| match n {
+            1 => SortKey::Number(Numeric::Integer(1)),
+            _ => SortKey::String("2".to_string()),
+        });
+        assert!(mixed.is_err());
+    }
+
+    #[test]
+    fn test_array_index_bounds() {
+        assert_eq!(array_index(2.0, 3).unwrap(), 2);
+        assert_eq!(
+            array_index(7.0, 3).unwrap_err().to_string(),
+            "index 7 out of bounds for array of length 3"
+        );
+        assert_eq!(
+            array_index(-1.0, 3).unwrap_err().to_string(),
+            "index -1 out of bounds for array of length 3: indices can't be negative"
+        );
+    }
+
+    #[test]
+    fn test_substring_bounds() {
+        assert_eq!(substring("héllo", 1.0, 3.0).unwrap(), "él");
+        assert_eq!(substring("abcd", 4.0, 4.0).unwrap(), "");
+        assert_eq!(
+            substring("abcd", 5.0, 2.0).unwrap_err().to_string(),
+            "substring 5..2 of string length 4 is invalid"
+        );
+        assert_eq!(
+            substring("abcd", 1.0, 9.0).unwrap_err().to_string(),
+            "substring 1..9 of string length 4 is invalid"
+        );
+        assert_eq!(
+            substring("abcd", -1.0, 2.0).unwrap_err().to_string(),
+            "substring -1..2 of string length 4 is invalid: indices can't be negative"
+        );
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(42.0), "42");
+        assert_eq!(format_number(-0.5), "-0.5");
+        assert_eq!(format_number(2f64.powi(70)), "1180591620717411300000");
+        assert_eq!(format_number(f64::INFINITY), "infinity");
+        assert_eq!(format_number(f64::NEG_INFINITY), "-infinity");
+        assert_eq!(format_number(f64::NAN), "nan");
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number(" 42 ").unwrap(), 42.0);
+        assert_eq!(parse_number("-1.5e2").unwrap(), -150.0);
+        let err = parse_number("abc").unwrap_err();
+        assert_eq!(err.to_string(), "Cannot convert \"abc\" to a number");
+    }
+}
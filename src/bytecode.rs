@@ -2,6 +2,11 @@
 ///
 /// This defines a stack-based bytecode format that is much faster to execute
 /// than walking the AST tree. Each instruction operates on a value stack.
+use crate::builtins::{BitOp, Builtin};
+use crate::error::{CompileError, RuntimeError};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write as IoWrite};
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)] // Some instructions are defined but not yet emitted by compiler
@@ -35,9 +40,18 @@ pub enum Instruction {
     /// Pop two values, divide them, push result
     Divide,
 
+    /// Pop two values, divide them and round down, push result
+    IntDivide,
+
     /// Pop two values, modulo them, push result
     Modulo,
 
+    /// Pop two values, raise the first to the power of the second, push result
+    Power,
+
+    /// Pop two whole numbers, combine their bits, push result
+    Bitwise(BitOp),
+
     /// Negate top of stack
     Negate,
 
@@ -99,12 +113,28 @@ pub enum Instruction {
     /// Call function with N arguments (pops N values from stack)
     Call(String, usize),
 
+    /// Call a built-in function with N arguments (pops N values from stack)
+    CallBuiltin(Builtin, usize),
+
+    /// Call function with N arguments in place of the current frame, returning its result
+    TailCall(String, usize),
+
+    /// `Call` to the function with the given index in `Chunk::function_names`,
+    /// so calling it needs no lookup by name
+    CallIndex(usize, usize),
+
+    /// `TailCall` to the function with the given index in `Chunk::function_names`
+    TailCallIndex(usize, usize),
+
     /// Return from function (optionally with value on stack)
     Return,
 
     /// Return null value
     ReturnNull,
 
+    /// Pop the exit code and stop the whole program, from however deep a call
+    Exit,
+
     // Stack Operations
     /// Pop and discard top of stack
     Pop,
@@ -122,6 +152,22 @@ pub enum Instruction {
     /// Pop value, index, and array, set array\[index\] = value
     SetIndex,
 
+    // Map Operations
+    /// Pop N key/value pairs and create a map
+    MakeMap(usize),
+
+    /// Pop key and map, push map\[key\] (null if absent)
+    MapGet,
+
+    /// Pop value, key, and map, set map\[key\] = value, push map
+    MapSet,
+
+    /// Pop value and array, push the array with value appended
+    ArrayPush,
+
+    /// Pop array, push its last element, then the array without it
+    ArrayPop,
+
     // String Operations
     /// Pop string, push length
     Length,
@@ -129,16 +175,61 @@ pub enum Instruction {
     /// Pop string, push uppercase
     Uppercase,
 
+    /// Pop string, push lowercase
+    Lowercase,
+
     /// Pop to, from, string, push substring
     Substring,
 
+    /// Pop needle, string, push whether the string contains the needle
+    Contains,
+
+    /// Pop needle, string, push the needle's char position (or -1)
+    IndexOf,
+
+    /// Pop replacement, pattern, string, push the string with every
+    /// occurrence of pattern replaced
+    Replace,
+
+    /// Pop string, push it without leading/trailing whitespace
+    Trim,
+
+    /// Pop to, from, array, push the elements between the two positions
+    Slice,
+
+    /// Pop array, push it back to front
+    Reverse,
+
+    /// Pop array, push it sorted in ascending order
+    Sort,
+
+    /// Pop separator and array, push the elements as text joined by the
+    /// separator
+    Join,
+
+    /// Pop value, push the name of its type ("number", "string", ...)
+    TypeOf,
+
+    /// Stop with an error unless the top of the stack is an array, which
+    /// stays there; checks what `for each` iterates
+    ExpectArray,
+
     // I/O Operations
     /// Pop value and print it
     Print,
 
+    /// Pop value and print it without a newline
+    Write,
+
     /// Read input with optional prompt (push string onto stack)
     Input(Option<String>),
 
+    /// Read a line like `Input`, but always push it as a string
+    InputText(Option<String>),
+
+    /// Pop value, stop with an error (and the message, if any) when it's falsy
+    Assert(Option<String>),
+
     // Special
     /// Halt execution
     Halt,
@@ -147,6 +238,176 @@ pub enum Instruction {
     Nop,
 }
 
+impl Instruction {
+    /// Number of instruction kinds; opcodes run from 0 to `COUNT - 1`
+    pub const COUNT: usize = 70;
+
+    /// Name of each instruction kind, indexed by opcode
+    pub const NAMES: [&'static str; Instruction::COUNT] = [
+        "LoadConst",
+        "LoadVar",
+        "StoreVar",
+        "LoadGlobal",
+        "StoreGlobal",
+        "Add",
+        "Subtract",
+        "Multiply",
+        "Divide",
+        "IntDivide",
+        "Modulo",
+        "Power",
+        "Negate",
+        "AddInt",
+        "SubInt",
+        "MulInt",
+        "LessInt",
+        "IncrementInt",
+        "Equal",
+        "NotEqual",
+        "Greater",
+        "GreaterEqual",
+        "Less",
+        "LessEqual",
+        "And",
+        "Or",
+        "Not",
+        "Jump",
+        "JumpIfFalse",
+        "JumpIfTrue",
+        "Call",
+        "Return",
+        "ReturnNull",
+        "Pop",
+        "Dup",
+        "MakeArray",
+        "GetIndex",
+        "SetIndex",
+        "MakeMap",
+        "MapGet",
+        "MapSet",
+        "Length",
+        "Uppercase",
+        "Lowercase",
+        "Substring",
+        "Print",
+        "Input",
+        "Halt",
+        "Nop",
+        "CallBuiltin",
+        "Contains",
+        "IndexOf",
+        "Replace",
+        "Trim",
+        "ArrayPush",
+        "ArrayPop",
+        "TypeOf",
+        "TailCall",
+        "Assert",
+        "Write",
+        "Slice",
+        "Reverse",
+        "Sort",
+        "Join",
+        "CallIndex",
+        "TailCallIndex",
+        "Exit",
+        "Bitwise",
+        "InputText",
+        "ExpectArray",
+    ];
+
+    /// Number identifying the kind of instruction, used in `.topc` files and
+    /// to index per-instruction tables
+    pub fn opcode(&self) -> u8 {
+        use Instruction::*;
+
+        match self {
+            LoadConst(_) => 0,
+            LoadVar(_) => 1,
+            StoreVar(_) => 2,
+            LoadGlobal(_) => 3,
+            StoreGlobal(_) => 4,
+            Add => 5,
+            Subtract => 6,
+            Multiply => 7,
+            Divide => 8,
+            IntDivide => 9,
+            Modulo => 10,
+            Power => 11,
+            Negate => 12,
+            AddInt => 13,
+            SubInt => 14,
+            MulInt => 15,
+            LessInt => 16,
+            IncrementInt => 17,
+            Equal => 18,
+            NotEqual => 19,
+            Greater => 20,
+            GreaterEqual => 21,
+            Less => 22,
+            LessEqual => 23,
+            And => 24,
+            Or => 25,
+            Not => 26,
+            Jump(_) => 27,
+            JumpIfFalse(_) => 28,
+            JumpIfTrue(_) => 29,
+            Call(_, _) => 30,
+            Return => 31,
+            ReturnNull => 32,
+            Pop => 33,
+            Dup => 34,
+            MakeArray(_) => 35,
+            GetIndex => 36,
+            SetIndex => 37,
+            MakeMap(_) => 38,
+            MapGet => 39,
+            MapSet => 40,
+            Length => 41,
+            Uppercase => 42,
+            Lowercase => 43,
+            Substring => 44,
+            Print => 45,
+            Input(_) => 46,
+            Halt => 47,
+            Nop => 48,
+            CallBuiltin(_, _) => 49,
+            Contains => 50,
+            IndexOf => 51,
+            Replace => 52,
+            Trim => 53,
+            ArrayPush => 54,
+            ArrayPop => 55,
+            TypeOf => 56,
+            TailCall(_, _) => 57,
+            Assert(_) => 58,
+            Write => 59,
+            Slice => 60,
+            Reverse => 61,
+            Sort => 62,
+            Join => 63,
+            CallIndex(_, _) => 64,
+            TailCallIndex(_, _) => 65,
+            Exit => 66,
+            Bitwise(_) => 67,
+            InputText(_) => 68,
+            ExpectArray => 69,
+        }
+    }
+
+    /// Name of the function a call instruction calls, looking the index of
+    /// the `CallIndex` forms up in `names`, the program's `Chunk::function_names`
+    pub fn callee<'a>(&'a self, names: &'a [String]) -> Option<&'a str> {
+        use Instruction::*;
+
+        match self {
+            Call(name, _) | TailCall(name, _) => Some(name),
+            CallIndex(id, _) | TailCallIndex(id, _) => Some(&names[*id]),
+            _ => None,
+        }
+    }
+}
+
 /// A chunk of bytecode with associated constant pool
 #[derive(Debug, Clone)]
 pub struct Chunk {
@@ -159,19 +420,34 @@ pub struct Chunk {
     /// Function chunks (name -> chunk)
     pub functions: std::collections::HashMap<String, Chunk>,
 
-    /// Line number information for debugging
+    /// Line number information for debugging, `NO_LINE` for instructions
+    /// not written in the source
     pub lines: Vec<usize>,
+
+    /// Number of local slots a call frame needs (parameters included)
+    pub local_count: usize,
+
+    /// Number of parameters; they occupy the first `arity` local slots
+    pub arity: usize,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 #[allow(dead_code)] // Null variant reserved for future use
 pub enum Constant {
     Number(f64),
-    String(String),
+    Integer(i64),
+    /// Shared with every value loaded from it, so loads don't copy the text
+    String(Rc<str>),
     Boolean(bool),
     Null,
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
@@ -179,9 +455,78 @@ impl Chunk {
             constants: Vec::new(),
             functions: std::collections::HashMap::new(),
             lines: Vec::new(),
+            local_count: 0,
+            arity: 0,
         }
     }
 
+    /// Names of `functions` in the order `CallIndex` numbers them
+    pub fn function_names(&self) -> Vec<&String> {
+        let mut names: Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        names
+    }
+
+    /// Every global variable the chunk or its functions read or write,
+    /// sorted, for backends that give each one fixed storage
+    pub fn global_names(&self) -> Vec<String> {
+        let mut names = BTreeSet::new();
+        let chunks = std::iter::once(self).chain(self.functions.values());
+        for instruction in chunks.flat_map(|chunk| &chunk.code) {
+            if let Instruction::LoadGlobal(name) | Instruction::StoreGlobal(name) = instruction {
+                names.insert(name.clone());
+            }
+        }
+        names.into_iter().collect()
+    }
+
+    /// Replace each call to one of `functions` with the `CallIndex` form, so
+    /// the VMs find the callee by position instead of hashing its name. Calls
+    /// to other names, and calls passing the wrong number of arguments, keep
+    /// their name for the error they raise when run.
+    pub fn resolve_calls(&mut self) {
+        let ids: HashMap<String, (usize, usize)> = self
+            .function_names()
+            .into_iter()
+            .enumerate()
+            .map(|(id, name)| (name.clone(), (id, self.functions[name].arity)))
+            .collect();
+        let resolve = |code: &mut Vec<Instruction>| {
+            for instruction in code {
+                let resolved = match instruction {
+                    Instruction::Call(name, arity) => match ids.get(name) {
+                        Some(&(id, expected)) if expected == *arity => {
+                            Instruction::CallIndex(id, *arity)
+                        }
+                        _ => continue,
+                    },
+                    Instruction::TailCall(name, arity) => match ids.get(name) {
+                        Some(&(id, expected)) if expected == *arity => {
+                            Instruction::TailCallIndex(id, *arity)
+                        }
+                        _ => continue,
+                    },
+                    _ => continue,
+                };
+                *instruction = resolved;
+            }
+        };
+        resolve(&mut self.code);
+        for function in self.functions.values_mut() {
+            resolve(&mut function.code);
+        }
+    }
+
+    /// The same program with an entry point that calls `name` instead of
+    /// `main`, as `--run-tests` does for each test
+    pub fn calling(&self, name: &str) -> Chunk {
+        let mut chunk = self.clone();
+        chunk.code = vec![Instruction::Call(name.to_string(), 0), Instruction::Halt];
+        chunk.lines = vec![NO_LINE; chunk.code.len()];
+        chunk.resolve_calls();
+        chunk
+    }
+
     /// Add a constant to the pool and return its index
     pub fn add_constant(&mut self, constant: Constant) -> usize {
         // Check if constant already exists to save space
@@ -201,6 +546,14 @@ impl Chunk {
         self.lines.push(line);
     }
 
+    /// Source line of the instruction at `offset`, if it has one
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.lines
+            .get(offset)
+            .copied()
+            .filter(|&line| line != NO_LINE)
+    }
+
     /// Get the current instruction pointer (for jump targets)
     pub fn current_position(&self) -> usize {
         self.code.len()
@@ -220,71 +573,1111 @@ impl Chunk {
 
     /// Disassemble the chunk for debugging
     pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+        print!("{}", self.disassembly(name));
+    }
+
+    /// The listing `disassemble` prints. Each jump target gets an `L0012:`
+    /// label line, and jumps name the label, the target's source line and
+    /// whether they go back (loops) or forward.
+    pub fn disassembly(&self, name: &str) -> String {
+        let targets: BTreeSet<usize> = self
+            .code
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Jump(target)
+                | Instruction::JumpIfFalse(target)
+                | Instruction::JumpIfTrue(target) => Some(*target),
+                _ => None,
+            })
+            .collect();
+
+        let mut out = format!("== {} ==\n", name);
         for (i, instruction) in self.code.iter().enumerate() {
-            print!("{:04} ", i);
+            if targets.contains(&i) {
+                out.push_str(&format!("L{:04}:\n", i));
+            }
+            out.push_str(&format!("{:04} ", i));
             if i > 0 && self.lines[i] == self.lines[i - 1] {
-                print!("   | ");
+                out.push_str("   | ");
             } else {
-                print!("{:4} ", self.lines[i]);
+                out.push_str(&format!("{:4} ", self.lines[i]));
             }
-            self.disassemble_instruction(instruction, i);
+            out.push_str(&self.instruction_text(instruction, i));
+            out.push('\n');
+        }
+        // A jump just past the last instruction ends the chunk
+        if targets.contains(&self.code.len()) {
+            out.push_str(&format!("L{:04}:\n", self.code.len()));
+        }
+        out
+    }
+
+    pub fn disassemble_instruction(&self, instruction: &Instruction, offset: usize) {
+        println!("{}", self.instruction_text(instruction, offset));
+    }
+
+    /// `L0012 (back, line 3)` for a jump at `offset` to `target`
+    fn describe_jump(&self, offset: usize, target: usize) -> String {
+        let direction = if target <= offset { "back" } else { "fwd" };
+        match self.lines.get(target) {
+            Some(line) => format!("L{:04} ({}, line {})", target, direction, line),
+            None => format!("L{:04} ({})", target, direction),
         }
     }
 
-    pub fn disassemble_instruction(&self, instruction: &Instruction, _offset: usize) {
+    fn instruction_text(&self, instruction: &Instruction, offset: usize) -> String {
         match instruction {
             Instruction::LoadConst(idx) => {
-                println!("LoadConst {:4} '{:?}'", idx, self.constants[*idx]);
+                format!("LoadConst {:4} '{:?}'", idx, self.constants[*idx])
+            }
+            Instruction::LoadVar(idx) => format!("LoadVar {}", idx),
+            Instruction::StoreVar(idx) => format!("StoreVar {}", idx),
+            Instruction::LoadGlobal(name) => format!("LoadGlobal '{}'", name),
+            Instruction::StoreGlobal(name) => format!("StoreGlobal '{}'", name),
+            Instruction::Add => "Add".to_string(),
+            Instruction::Subtract => "Subtract".to_string(),
+            Instruction::Multiply => "Multiply".to_string(),
+            Instruction::Divide => "Divide".to_string(),
+            Instruction::IntDivide => "IntDivide".to_string(),
+            Instruction::Modulo => "Modulo".to_string(),
+            Instruction::Power => "Power".to_string(),
+            Instruction::Bitwise(op) => format!("Bitwise '{}'", op.name()),
+            Instruction::Negate => "Negate".to_string(),
+            Instruction::AddInt => "AddInt".to_string(),
+            Instruction::SubInt => "SubInt".to_string(),
+            Instruction::MulInt => "MulInt".to_string(),
+            Instruction::LessInt => "LessInt".to_string(),
+            Instruction::IncrementInt => "IncrementInt".to_string(),
+            Instruction::Equal => "Equal".to_string(),
+            Instruction::NotEqual => "NotEqual".to_string(),
+            Instruction::Greater => "Greater".to_string(),
+            Instruction::GreaterEqual => "GreaterEqual".to_string(),
+            Instruction::Less => "Less".to_string(),
+            Instruction::LessEqual => "LessEqual".to_string(),
+            Instruction::And => "And".to_string(),
+            Instruction::Or => "Or".to_string(),
+            Instruction::Not => "Not".to_string(),
+            Instruction::Jump(target) => {
+                format!("Jump -> {}", self.describe_jump(offset, *target))
             }
-            Instruction::LoadVar(idx) => println!("LoadVar {}", idx),
-            Instruction::StoreVar(idx) => println!("StoreVar {}", idx),
-            Instruction::LoadGlobal(name) => println!("LoadGlobal '{}'", name),
-            Instruction::StoreGlobal(name) => println!("StoreGlobal '{}'", name),
-            Instruction::Add => println!("Add"),
-            Instruction::Subtract => println!("Subtract"),
-            Instruction::Multiply => println!("Multiply"),
-            Instruction::Divide => println!("Divide"),
-            Instruction::Modulo => println!("Modulo"),
-            Instruction::Negate => println!("Negate"),
-            Instruction::AddInt => println!("AddInt"),
-            Instruction::SubInt => println!("SubInt"),
-            Instruction::MulInt => println!("MulInt"),
-            Instruction::LessInt => println!("LessInt"),
-            Instruction::IncrementInt => println!("IncrementInt"),
-            Instruction::Equal => println!("Equal"),
-            Instruction::NotEqual => println!("NotEqual"),
-            Instruction::Greater => println!("Greater"),
-            Instruction::GreaterEqual => println!("GreaterEqual"),
-            Instruction::Less => println!("Less"),
-            Instruction::LessEqual => println!("LessEqual"),
-            Instruction::And => println!("And"),
-            Instruction::Or => println!("Or"),
-            Instruction::Not => println!("Not"),
-            Instruction::Jump(target) => println!("Jump -> {:04}", target),
-            Instruction::JumpIfFalse(target) => println!("JumpIfFalse -> {:04}", target),
-            Instruction::JumpIfTrue(target) => println!("JumpIfTrue -> {:04}", target),
-            Instruction::Call(name, arity) => println!("Call '{}' ({})", name, arity),
-            Instruction::Return => println!("Return"),
-            Instruction::ReturnNull => println!("ReturnNull"),
-            Instruction::Pop => println!("Pop"),
-            Instruction::Dup => println!("Dup"),
-            Instruction::MakeArray(size) => println!("MakeArray {}", size),
-            Instruction::GetIndex => println!("GetIndex"),
-            Instruction::SetIndex => println!("SetIndex"),
-            Instruction::Length => println!("Length"),
-            Instruction::Uppercase => println!("Uppercase"),
-            Instruction::Substring => println!("Substring"),
-            Instruction::Print => println!("Print"),
+            Instruction::JumpIfFalse(target) => {
+                format!("JumpIfFalse -> {}", self.describe_jump(offset, *target))
+            }
+            Instruction::JumpIfTrue(target) => {
+                format!("JumpIfTrue -> {}", self.describe_jump(offset, *target))
+            }
+            Instruction::Call(name, arity) => format!("Call '{}' ({})", name, arity),
+            Instruction::CallBuiltin(builtin, arity) => {
+                format!("CallBuiltin '{}' ({})", builtin.name(), arity)
+            }
+            Instruction::TailCall(name, arity) => format!("TailCall '{}' ({})", name, arity),
+            Instruction::CallIndex(id, arity) => format!("CallIndex #{} ({})", id, arity),
+            Instruction::TailCallIndex(id, arity) => format!("TailCallIndex #{} ({})", id, arity),
+            Instruction::Return => "Return".to_string(),
+            Instruction::ReturnNull => "ReturnNull".to_string(),
+            Instruction::Exit => "Exit".to_string(),
+            Instruction::Pop => "Pop".to_string(),
+            Instruction::Dup => "Dup".to_string(),
+            Instruction::MakeArray(size) => format!("MakeArray {}", size),
+            Instruction::GetIndex => "GetIndex".to_string(),
+            Instruction::SetIndex => "SetIndex".to_string(),
+            Instruction::MakeMap(size) => format!("MakeMap {}", size),
+            Instruction::MapGet => "MapGet".to_string(),
+            Instruction::MapSet => "MapSet".to_string(),
+            Instruction::ArrayPush => "ArrayPush".to_string(),
+            Instruction::ArrayPop => "ArrayPop".to_string(),
+            Instruction::Length => "Length".to_string(),
+            Instruction::Uppercase => "Uppercase".to_string(),
+            Instruction::Lowercase => "Lowercase".to_string(),
+            Instruction::Substring => "Substring".to_string(),
+            Instruction::Contains => "Contains".to_string(),
+            Instruction::IndexOf => "IndexOf".to_string(),
+            Instruction::Replace => "Replace".to_string(),
+            Instruction::Trim => "Trim".to_string(),
+            Instruction::Slice => "Slice".to_string(),
+            Instruction::Reverse => "Reverse".to_string(),
+            Instruction::Sort => "Sort".to_string(),
+            Instruction::Join => "Join".to_string(),
+            Instruction::TypeOf => "TypeOf".to_string(),
+            Instruction::Print => "Print".to_string(),
+            Instruction::Write => "Write".to_string(),
             Instruction::Input(prompt) => {
                 if let Some(p) = prompt {
-                    println!("Input '{}'", p);
+                    format!("Input '{}'", p)
                 } else {
-                    println!("Input");
+                    "Input".to_string()
                 }
             }
-            Instruction::Halt => println!("Halt"),
-            Instruction::Nop => println!("Nop"),
+            Instruction::InputText(prompt) => match prompt {
+                Some(p) => format!("InputText '{}'", p),
+                None => "InputText".to_string(),
+            },
+            Instruction::Assert(message) => match message {
+                Some(m) => format!("Assert '{}'", m),
+                None => "Assert".to_string(),
+            },
+            Instruction::ExpectArray => "ExpectArray".to_string(),
+            Instruction::Halt => "Halt".to_string(),
+            Instruction::Nop => "Nop".to_string(),
         }
     }
 }
+
+/// Magic bytes at the start of a serialized `.topc` file
+const BYTECODE_MAGIC: &[u8; 4] = b"TOPC";
+
+/// Bumped whenever the serialized layout or instruction numbering changes
+const BYTECODE_VERSION: u16 = 1;
+
+impl Chunk {
+    /// Serialize the chunk (and its functions) to the `.topc` binary format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(BYTECODE_MAGIC);
+        out.extend_from_slice(&BYTECODE_VERSION.to_le_bytes());
+        self.write_to(&mut out);
+        out
+    }
+
+    /// Load a chunk written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Chunk> {
+        if bytes.len() < 6 || &bytes[..4] != BYTECODE_MAGIC {
+            return Err(anyhow::anyhow!("Not a TopLang bytecode file"));
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != BYTECODE_VERSION {
+            return Err(anyhow::anyhow!(
+                "Unsupported bytecode version {} (expected {}); recompile the source",
+                version,
+                BYTECODE_VERSION
+            ));
+        }
+
+        let invalid = |reason: String| CompileError::InvalidBytecode(reason);
+        let mut reader = ByteReader { bytes, pos: 6 };
+        let chunk = reader.chunk().map_err(|e| invalid(e.to_string()))?;
+        if reader.pos != bytes.len() {
+            return Err(invalid("trailing data after bytecode".to_string()).into());
+        }
+        chunk.validate()?;
+        Ok(chunk)
+    }
+
+    /// Check every operand refers to something that exists, so a corrupted
+    /// or hand-written chunk is rejected before a VM trusts it
+    pub fn validate(&self) -> Result<(), CompileError> {
+        let arities: Vec<usize> = self
+            .function_names()
+            .into_iter()
+            .map(|name| self.functions[name].arity)
+            .collect();
+
+        self.validate_code("the program", &arities)?;
+        for (name, function) in &self.functions {
+            function.validate_code(&format!("function '{}'", name), &arities)?;
+        }
+        Ok(())
+    }
+
+    fn validate_code(&self, owner: &str, arities: &[usize]) -> Result<(), CompileError> {
+        let invalid =
+            |reason: String| CompileError::InvalidBytecode(format!("{} in {}", reason, owner));
+
+        if self.code.is_empty() {
+            return Err(invalid("no instructions".to_string()));
+        }
+        if self.lines.len() != self.code.len() {
+            return Err(invalid(format!(
+                "{} line numbers for {} instructions",
+                self.lines.len(),
+                self.code.len()
+            )));
+        }
+        if self.arity > self.local_count {
+            return Err(invalid(format!(
+                "{} parameters but only {} local slots",
+                self.arity, self.local_count
+            )));
+        }
+
+        for (ip, instruction) in self.code.iter().enumerate() {
+            let problem = match *instruction {
+                Instruction::LoadConst(index) if index >= self.constants.len() => {
+                    Some(format!("constant {} out of range", index))
+                }
+                Instruction::LoadVar(slot) | Instruction::StoreVar(slot)
+                    if slot >= self.local_count =>
+                {
+                    Some(format!("local slot {} out of range", slot))
+                }
+                Instruction::Jump(target)
+                | Instruction::JumpIfFalse(target)
+                | Instruction::JumpIfTrue(target)
+                    if target >= self.code.len() =>
+                {
+                    Some(format!("jump target {} out of range", target))
+                }
+                Instruction::CallIndex(id, arity) | Instruction::TailCallIndex(id, arity) => {
+                    match arities.get(id) {
+                        None => Some(format!("function index {} out of range", id)),
+                        Some(&expected) if expected != arity => Some(format!(
+                            "call passes {} arguments to a function taking {}",
+                            arity, expected
+                        )),
+                        Some(_) => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(problem) = problem {
+                return Err(invalid(format!("{} at instruction {}", problem, ip)));
+            }
+        }
+
+        crate::registers::max_stack_depth(self).map_err(|e| invalid(e.to_string()))?;
+        Ok(())
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        write_len(out, self.code.len());
+        for instruction in &self.code {
+            write_instruction(out, instruction);
+        }
+
+        write_len(out, self.constants.len());
+        for constant in &self.constants {
+            match constant {
+                Constant::Number(n) => {
+                    out.push(0);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+                Constant::String(s) => {
+                    out.push(1);
+                    write_str(out, s);
+                }
+                Constant::Boolean(b) => {
+                    out.push(2);
+                    out.push(*b as u8);
+                }
+                Constant::Null => out.push(3),
+                Constant::Integer(n) => {
+                    out.push(4);
+                    out.extend_from_slice(&n.to_le_bytes());
+                }
+            }
+        }
+
+        // Sorted so the same program always serializes to the same bytes
+        let names = self.function_names();
+        write_len(out, names.len());
+        for name in names {
+            write_str(out, name);
+            self.functions[name].write_to(out);
+        }
+
+        write_len(out, self.lines.len());
+        for line in &self.lines {
+            write_len(out, *line);
+        }
+        write_len(out, self.local_count);
+        write_len(out, self.arity);
+    }
+}
+
+/// Register number, relative to the base of the current call frame
+pub type Reg = usize;
+
+/// Register-based instruction set, translated from stack bytecode by
+/// `registers::translate`
+///
+/// A call frame's registers hold its locals first, then the temporaries its
+/// expressions need. Operands are read before the destination is written, so
+/// `AddR(a, a, b)` adds `b` to `a` in place.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegInstruction {
+    /// dst = constant
+    LoadConstR(Reg, usize),
+
+    /// dst = src
+    MoveR(Reg, Reg),
+
+    /// dst = global variable
+    LoadGlobalR(Reg, String),
+
+    /// global variable = src
+    StoreGlobalR(String, Reg),
+
+    /// dst = a + b (numbers add, strings concatenate)
+    AddR(Reg, Reg, Reg),
+
+    /// dst = a - b
+    SubR(Reg, Reg, Reg),
+
+    /// dst = a * b
+    MulR(Reg, Reg, Reg),
+
+    /// dst = a / b
+    DivR(Reg, Reg, Reg),
+
+    /// dst = floor(a / b)
+    IntDivR(Reg, Reg, Reg),
+
+    /// dst = a % b
+    ModR(Reg, Reg, Reg),
+
+    /// dst = a ^ b
+    PowR(Reg, Reg, Reg),
+
+    /// dst = a op b, on the bits of two whole numbers
+    BitwiseR(BitOp, Reg, Reg, Reg),
+
+    /// dst = -a
+    NegR(Reg, Reg),
+
+    /// dst = a + 1
+    IncR(Reg, Reg),
+
+    /// dst = a == b
+    EqR(Reg, Reg, Reg),
+
+    /// dst = a != b
+    NeR(Reg, Reg, Reg),
+
+    /// dst = a > b
+    GtR(Reg, Reg, Reg),
+
+    /// dst = a >= b
+    GeR(Reg, Reg, Reg),
+
+    /// dst = a < b
+    LtR(Reg, Reg, Reg),
+
+    /// dst = a <= b
+    LeR(Reg, Reg, Reg),
+
+    /// dst = a and b
+    AndR(Reg, Reg, Reg),
+
+    /// dst = a or b
+    OrR(Reg, Reg, Reg),
+
+    /// dst = not a
+    NotR(Reg, Reg),
+
+    /// Unconditional jump
+    JumpR(usize),
+
+    /// Jump if the register is falsy
+    JumpIfFalseR(Reg, usize),
+
+    /// Jump if the register is truthy
+    JumpIfTrueR(Reg, usize),
+
+    /// Call a function with arguments in `base..base + arity`; the callee's
+    /// frame starts at `base` and its result lands there
+    CallR(String, Reg, usize),
+
+    /// dst = built-in applied to the arguments in `first..first + arity`
+    CallBuiltinR(Builtin, Reg, Reg, usize),
+
+    /// Call a function with arguments in `base..base + arity` in place of the
+    /// current frame
+    TailCallR(String, Reg, usize),
+
+    /// `CallR` to the function with the given index in `Chunk::function_names`
+    CallIndexR(usize, Reg, usize),
+
+    /// `TailCallR` to the function with the given index in `Chunk::function_names`
+    TailCallIndexR(usize, Reg, usize),
+
+    /// Return the register's value to the caller
+    ReturnR(Reg),
+
+    /// Return null to the caller
+    ReturnNullR,
+
+    /// Stop the program with the register's value as the exit code
+    ExitR(Reg),
+
+    /// dst = array of the `size` registers starting at `first`
+    MakeArrayR(Reg, Reg, usize),
+
+    /// dst = map of the `size` key/value register pairs starting at `first`
+    MakeMapR(Reg, Reg, usize),
+
+    /// dst = target at index (arrays and maps)
+    GetIndexR(Reg, Reg, Reg),
+
+    /// dst = target with index set to value (arrays and maps)
+    SetIndexR(Reg, Reg, Reg, Reg),
+
+    /// dst = map at key
+    MapGetR(Reg, Reg, Reg),
+
+    /// dst = map with key set to value
+    MapSetR(Reg, Reg, Reg, Reg),
+
+    /// dst = array with value appended
+    ArrayPushR(Reg, Reg, Reg),
+
+    /// dst = last element of src, dst + 1 = src without it
+    ArrayPopR(Reg, Reg),
+
+    /// dst = length of a string, array or map
+    LengthR(Reg, Reg),
+
+    /// dst = uppercase string
+    UppercaseR(Reg, Reg),
+
+    /// dst = lowercase string
+    LowercaseR(Reg, Reg),
+
+    /// dst = string between two character indices
+    SubstringR(Reg, Reg, Reg, Reg),
+
+    /// dst = whether the string contains the needle
+    ContainsR(Reg, Reg, Reg),
+
+    /// dst = character index of the needle in the string, or -1
+    IndexOfR(Reg, Reg, Reg),
+
+    /// dst = string with every pattern replaced
+    ReplaceR(Reg, Reg, Reg, Reg),
+
+    /// dst = string without surrounding whitespace
+    TrimR(Reg, Reg),
+
+    /// dst = array elements between two positions
+    SliceR(Reg, Reg, Reg, Reg),
+
+    /// dst = array back to front
+    ReverseR(Reg, Reg),
+
+    /// dst = array sorted in ascending order
+    SortR(Reg, Reg),
+
+    /// dst = array elements as text, joined by the separator
+    JoinR(Reg, Reg, Reg),
+
+    /// dst = name of the value's type
+    TypeOfR(Reg, Reg),
+
+    /// Print the register's value
+    PrintR(Reg),
+
+    /// Print the register's value without a newline
+    WriteR(Reg),
+
+    /// dst = line read from stdin, after printing the optional prompt
+    InputR(Reg, Option<String>),
+
+    /// dst = line read from stdin as a string, after printing the optional prompt
+    InputTextR(Reg, Option<String>),
+
+    /// Stop with an error (and the message, if any) when the register is falsy
+    AssertR(Reg, Option<String>),
+
+    /// Stop with an error unless the register holds an array
+    ExpectArrayR(Reg),
+
+    /// Halt execution, using the register (if any) as the exit code
+    HaltR(Option<Reg>),
+}
+
+/// A function's register bytecode with associated constant pool
+#[derive(Debug, Clone)]
+pub struct RegisterChunk {
+    /// The register instructions
+    pub code: Vec<RegInstruction>,
+
+    /// Constant pool, shared with the stack chunk it was translated from
+    pub constants: Vec<Constant>,
+
+    /// Function chunks (name -> chunk)
+    pub functions: std::collections::HashMap<String, RegisterChunk>,
+
+    /// Line number information for debugging
+    pub lines: Vec<usize>,
+
+    /// Number of registers a call frame needs (locals and temporaries)
+    pub register_count: usize,
+
+    /// Number of parameters; they occupy the first `arity` registers
+    pub arity: usize,
+}
+
+impl RegisterChunk {
+    /// Source line of the instruction at `offset`, if it has one
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.lines
+            .get(offset)
+            .copied()
+            .filter(|&line| line != NO_LINE)
+    }
+
+    /// Disassemble the chunk for debugging
+    pub fn disassemble(&self, name: &str) {
+        println!("== {} ==", name);
+        for (i, instruction) in self.code.iter().enumerate() {
+            print!("{:04} ", i);
+            if i > 0 && self.lines[i] == self.lines[i - 1] {
+                print!("   | ");
+            } else {
+                print!("{:4} ", self.lines[i]);
+            }
+            println!("{:?}", instruction);
+        }
+    }
+}
+
+/// Share each function chunk between a table by name, for `Call`, and one by
+/// index, for `CallIndex`. The frames running a function share it too
+/// rather than copying it per call.
+pub fn function_tables<T>(functions: HashMap<String, T>) -> (HashMap<String, Rc<T>>, Vec<Rc<T>>) {
+    let mut functions: Vec<(String, Rc<T>)> = functions
+        .into_iter()
+        .map(|(name, function)| (name, Rc::new(function)))
+        .collect();
+    // The order of `Chunk::function_names`
+    functions.sort_by(|a, b| a.0.cmp(&b.0));
+    let by_index = functions
+        .iter()
+        .map(|(_, function)| function.clone())
+        .collect();
+    (functions.into_iter().collect(), by_index)
+}
+
+/// Name a function from `function_tables` is called by, for error messages
+pub fn function_name<'a, T>(functions: &'a HashMap<String, Rc<T>>, function: &Rc<T>) -> &'a str {
+    functions
+        .iter()
+        .find(|(_, candidate)| Rc::ptr_eq(candidate, function))
+        .map_or("<unknown>", |(name, _)| name)
+}
+
+/// Line recorded for instructions with no source line, like the entry
+/// point that calls `main`
+pub const NO_LINE: usize = 0;
+
+/// How many calls deep a program may go before the VMs stop it
+pub const DEFAULT_MAX_DEPTH: usize = 10_000;
+
+/// How many values the stack VMs may hold at once before they stop a program
+pub const DEFAULT_MAX_STACK: usize = 1 << 20;
+
+/// Slots a value stack starts with, or `max_stack` if that's fewer
+pub fn initial_stack_size(max_stack: usize) -> usize {
+    max_stack.min(256)
+}
+
+/// What to grow a full value stack of `len` slots to: double, but no more
+/// than `max_stack`, erroring when it's already that big
+pub fn grown_stack_size(len: usize, max_stack: usize) -> anyhow::Result<usize> {
+    if len >= max_stack {
+        return Err(RuntimeError::StackOverflow(max_stack).into());
+    }
+    Ok((len * 2).clamp(1, max_stack))
+}
+
+/// Error if entering `function` would make `depth` frames more than
+/// `max_depth`, naming it after its entry in `functions`
+pub fn check_depth<T>(
+    depth: usize,
+    max_depth: usize,
+    function: &Rc<T>,
+    functions: &HashMap<String, Rc<T>>,
+) -> anyhow::Result<()> {
+    if depth <= max_depth {
+        return Ok(());
+    }
+    let name = functions
+        .iter()
+        .find(|(_, f)| Rc::ptr_eq(f, function))
+        .map_or("?", |(name, _)| name.as_str());
+    Err(RuntimeError::RecursionLimit(name.to_string()).into())
+}
+
+/// Print `prompt`, if any, and read a line from stdin without its line ending
+pub fn read_line(prompt: Option<&str>) -> anyhow::Result<String> {
+    if let Some(p) = prompt {
+        print!("{}", p);
+        io::stdout().flush()?;
+    }
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    let end = line.trim_end_matches(['\n', '\r']).len();
+    line.truncate(end);
+    Ok(line)
+}
+
+/// Check that a call to `name` passes as many arguments as it has parameters
+pub fn check_arity(name: &str, expected: usize, given: usize) -> anyhow::Result<()> {
+    if expected != given {
+        return Err(RuntimeError::WrongArgumentCount {
+            function: name.to_string(),
+            expected: expected.to_string(),
+            got: given,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn write_len(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u64).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_instruction(out: &mut Vec<u8>, instruction: &Instruction) {
+    use Instruction::*;
+
+    out.push(instruction.opcode());
+
+    match instruction {
+        LoadConst(n) | LoadVar(n) | StoreVar(n) | Jump(n) | JumpIfFalse(n) | JumpIfTrue(n)
+        | MakeArray(n) | MakeMap(n) => write_len(out, *n),
+        CallIndex(id, arity) | TailCallIndex(id, arity) => {
+            write_len(out, *id);
+            write_len(out, *arity);
+        }
+        LoadGlobal(name) | StoreGlobal(name) => write_str(out, name),
+        Call(name, arity) | TailCall(name, arity) => {
+            write_str(out, name);
+            write_len(out, *arity);
+        }
+        CallBuiltin(builtin, arity) => {
+            let index = Builtin::ALL.iter().position(|b| b == builtin).unwrap();
+            out.push(index as u8);
+            write_len(out, *arity);
+        }
+        Bitwise(op) => {
+            let index = BitOp::ALL.iter().position(|o| o == op).unwrap();
+            out.push(index as u8);
+        }
+        Input(prompt) | InputText(prompt) | Assert(prompt) => match prompt {
+            Some(p) => {
+                out.push(1);
+                write_str(out, p);
+            }
+            None => out.push(0),
+        },
+        _ => {}
+    }
+}
+
+/// Cursor over serialized bytecode
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl ByteReader<'_> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(anyhow::anyhow!("Unexpected end of bytecode"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn len(&mut self) -> anyhow::Result<usize> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| anyhow::anyhow!("Invalid UTF-8 string in bytecode"))
+    }
+
+    fn chunk(&mut self) -> anyhow::Result<Chunk> {
+        let mut chunk = Chunk::new();
+
+        for _ in 0..self.len()? {
+            let instruction = self.instruction()?;
+            chunk.code.push(instruction);
+        }
+
+        for _ in 0..self.len()? {
+            let constant = match self.byte()? {
+                0 => Constant::Number(f64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                1 => Constant::String(self.string()?.into()),
+                2 => Constant::Boolean(self.byte()? != 0),
+                3 => Constant::Null,
+                4 => Constant::Integer(i64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+                tag => return Err(anyhow::anyhow!("Unknown constant tag {}", tag)),
+            };
+            chunk.constants.push(constant);
+        }
+
+        for _ in 0..self.len()? {
+            let name = self.string()?;
+            let function = self.chunk()?;
+            chunk.functions.insert(name, function);
+        }
+
+        for _ in 0..self.len()? {
+            let line = self.len()?;
+            chunk.lines.push(line);
+        }
+        chunk.local_count = self.len()?;
+        chunk.arity = self.len()?;
+
+        Ok(chunk)
+    }
+
+    fn instruction(&mut self) -> anyhow::Result<Instruction> {
+        use Instruction::*;
+
+        Ok(match self.byte()? {
+            0 => LoadConst(self.len()?),
+            1 => LoadVar(self.len()?),
+            2 => StoreVar(self.len()?),
+            3 => LoadGlobal(self.string()?),
+            4 => StoreGlobal(self.string()?),
+            5 => Add,
+            6 => Subtract,
+            7 => Multiply,
+            8 => Divide,
+            9 => IntDivide,
+            10 => Modulo,
+            11 => Power,
+            12 => Negate,
+            13 => AddInt,
+            14 => SubInt,
+            15 => MulInt,
+            16 => LessInt,
+            17 => IncrementInt,
+            18 => Equal,
+            19 => NotEqual,
+            20 => Greater,
+            21 => GreaterEqual,
+            22 => Less,
+            23 => LessEqual,
+            24 => And,
+            25 => Or,
+            26 => Not,
+            27 => Jump(self.len()?),
+            28 => JumpIfFalse(self.len()?),
+            29 => JumpIfTrue(self.len()?),
+            30 => Call(self.string()?, self.len()?),
+            31 => Return,
+            32 => ReturnNull,
+            33 => Pop,
+            34 => Dup,
+            35 => MakeArray(self.len()?),
+            36 => GetIndex,
+            37 => SetIndex,
+            38 => MakeMap(self.len()?),
+            39 => MapGet,
+            40 => MapSet,
+            41 => Length,
+            42 => Uppercase,
+            43 => Lowercase,
+            44 => Substring,
+            45 => Print,
+            46 => Input(match self.byte()? {
+                0 => None,
+                _ => Some(self.string()?),
+            }),
+            47 => Halt,
+            48 => Nop,
+            49 => {
+                let index = self.byte()? as usize;
+                let builtin = *Builtin::ALL
+                    .get(index)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown built-in {}", index))?;
+                CallBuiltin(builtin, self.len()?)
+            }
+            50 => Contains,
+            51 => IndexOf,
+            52 => Replace,
+            53 => Trim,
+            54 => ArrayPush,
+            55 => ArrayPop,
+            56 => TypeOf,
+            57 => TailCall(self.string()?, self.len()?),
+            58 => Assert(match self.byte()? {
+                0 => None,
+                _ => Some(self.string()?),
+            }),
+            59 => Write,
+            60 => Slice,
+            61 => Reverse,
+            62 => Sort,
+            63 => Join,
+            64 => CallIndex(self.len()?, self.len()?),
+            65 => TailCallIndex(self.len()?, self.len()?),
+            66 => Exit,
+            67 => {
+                let index = self.byte()? as usize;
+                Bitwise(
+                    *BitOp::ALL
+                        .get(index)
+                        .ok_or_else(|| anyhow::anyhow!("Unknown bitwise operator {}", index))?,
+                )
+            }
+            68 => InputText(match self.byte()? {
+                0 => None,
+                _ => Some(self.string()?),
+            }),
+            69 => ExpectArray,
+            opcode => return Err(anyhow::anyhow!("Unknown opcode {}", opcode)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm_optimized::OptimizedVM;
+
+    fn compile(source: &str) -> Chunk {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().expect("Failed to parse");
+        Compiler::new().compile(program).expect("Failed to compile")
+    }
+
+    #[test]
+    fn test_round_trip_executes_identically() {
+        let chunk = compile(
+            r#"
+function greet(name) {
+    var words is list "hello", name
+    var lookup is map "x" to 1.5
+    return length of words plus lookup at "x"
+}
+
+function main() {
+    var total is 0
+    var i is 0
+    while i less than 4 {
+        total is total plus greet("you")
+        i is i plus 1
+    }
+    print uppercase "done"
+    return total
+}
+"#,
+        );
+
+        let bytes = chunk.to_bytes();
+        let loaded = Chunk::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.code, chunk.code);
+        assert_eq!(loaded.constants, chunk.constants);
+        assert_eq!(loaded.to_bytes(), bytes);
+
+        let expected = OptimizedVM::new().execute(chunk.clone()).unwrap();
+        assert_eq!(OptimizedVM::new().execute(loaded).unwrap(), expected);
+        assert_eq!(expected, 14);
+    }
+
+    #[test]
+    fn test_calls_resolve_to_indices() {
+        let chunk = compile(
+            r#"
+function fib(n) {
+    if n less than 2 {
+        return n
+    }
+    return fib(n minus 1) plus fib(n minus 2)
+}
+
+function wrong() {
+    return fib(1, 2)
+}
+
+function main() {
+    return fib(10)
+}
+"#,
+        );
+
+        let names = chunk.function_names();
+        let fib = names.iter().position(|name| *name == "fib").unwrap();
+        let calls: Vec<&Instruction> = chunk.functions["fib"]
+            .code
+            .iter()
+            .filter(|i| matches!(i, Instruction::Call(..) | Instruction::CallIndex(..)))
+            .collect();
+        assert_eq!(calls, [&Instruction::CallIndex(fib, 1); 2]);
+        // The wrong argument count is still reported by name when it runs
+        assert!(chunk.functions["wrong"]
+            .code
+            .contains(&Instruction::Call("fib".to_string(), 2)));
+        assert_eq!(OptimizedVM::new().execute(chunk).unwrap(), 55);
+    }
+
+    #[test]
+    fn test_indexed_calls_are_checked_without_validation() {
+        let chunk = compile(
+            r#"
+function add(a, b) {
+    return a plus b
+}
+
+function main() {
+    return add(1, 2)
+}
+"#,
+        );
+        let add = chunk
+            .function_names()
+            .iter()
+            .position(|name| *name == "add")
+            .unwrap();
+        let run_all = |call: Instruction| -> Vec<String> {
+            let mut bad = chunk.clone();
+            let main = bad.functions.get_mut("main").unwrap();
+            let at = main
+                .code
+                .iter()
+                .position(|i| matches!(i, Instruction::CallIndex(..)))
+                .unwrap();
+            main.code[at] = call;
+            let registers = crate::registers::translate(&bad).unwrap();
+            [
+                OptimizedVM::new().execute(bad.clone()),
+                crate::vm::VM::new().execute(bad.clone()),
+                crate::vm_nanbox::NanBoxVM::new().execute(bad.clone()),
+                crate::vm_threaded::ThreadedVM::new().execute(bad),
+                crate::vm_register::RegisterVM::new().execute(registers),
+            ]
+            .into_iter()
+            .map(|result| format!("{:#}", result.unwrap_err()))
+            .collect()
+        };
+
+        for err in run_all(Instruction::CallIndex(add, 1)) {
+            assert!(
+                err.contains("Function 'add' expects 2 arguments, got 1"),
+                "{}",
+                err
+            );
+        }
+        for err in run_all(Instruction::TailCallIndex(add, 1)) {
+            assert!(
+                err.contains("Function 'add' expects 2 arguments, got 1"),
+                "{}",
+                err
+            );
+        }
+        for err in run_all(Instruction::CallIndex(9, 2)) {
+            assert!(err.contains("Undefined function: #9"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_header() {
+        let mut bytes = compile("function main() { return 0 }").to_bytes();
+        assert!(Chunk::from_bytes(b"nope").is_err());
+
+        bytes[4] = 0xFF;
+        let err = Chunk::from_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("Unsupported bytecode version"));
+    }
+
+    #[test]
+    fn test_rejects_truncated_bytecode() {
+        let bytes = compile("function main() { print \"hi\"\n return 0 }").to_bytes();
+        for len in 0..bytes.len() {
+            assert!(Chunk::from_bytes(&bytes[..len]).is_err(), "{} bytes", len);
+        }
+        let err = Chunk::from_bytes(&bytes[..bytes.len() - 1]).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid bytecode"), "{}", err);
+    }
+
+    #[test]
+    fn test_rejects_operands_that_point_nowhere() {
+        let chunk = compile(
+            r#"
+function add(a, b) {
+    return a plus b
+}
+
+function main() {
+    var i is 0
+    while i less than 3 {
+        i is add(i, 1)
+    }
+    return i
+}
+"#,
+        );
+        let corruptions = [
+            ("constant 7 out of range", Instruction::LoadConst(7)),
+            ("local slot 9 out of range", Instruction::LoadVar(9)),
+            ("jump target 500 out of range", Instruction::Jump(500)),
+            (
+                "function index 4 out of range",
+                Instruction::CallIndex(4, 0),
+            ),
+            (
+                "call passes 1 arguments to a function taking 2",
+                Instruction::CallIndex(0, 1),
+            ),
+        ];
+
+        for (expected, instruction) in corruptions {
+            let mut bad = chunk.clone();
+            bad.functions.get_mut("main").unwrap().code[0] = instruction;
+            let err = Chunk::from_bytes(&bad.to_bytes()).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains(&format!("{} at instruction 0 in function 'main'", expected)),
+                "{}",
+                err
+            );
+        }
+
+        let mut underflow = chunk.clone();
+        underflow.functions.get_mut("add").unwrap().code[0] = Instruction::Pop;
+        let err = Chunk::from_bytes(&underflow.to_bytes()).unwrap_err();
+        assert!(err.to_string().contains("Stack underflow"), "{}", err);
+    }
+
+    #[test]
+    fn test_disassembly_labels_loop_back_target() {
+        let chunk = compile(
+            r#"
+function main() {
+    var i is 0
+    while i less than 3 {
+        i is i plus 1
+    }
+    return i
+}
+"#,
+        );
+        let main = &chunk.functions["main"];
+        let loop_start = main
+            .code
+            .iter()
+            .enumerate()
+            .find_map(|(i, instruction)| match instruction {
+                Instruction::Jump(target) if *target < i => Some(*target),
+                _ => None,
+            })
+            .expect("while loop has no backward jump");
+
+        let listing = main.disassembly("main");
+        assert!(listing.contains(&format!("L{:04}:\n{:04} ", loop_start, loop_start)));
+        assert!(listing.contains(&format!("Jump -> L{:04} (back, line", loop_start)));
+        assert!(listing.contains("(fwd, line"));
+    }
+}
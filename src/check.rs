@@ -0,0 +1,721 @@
+/// Static checks run by `topc --check`
+///
+/// TopLang is dynamically typed, so this is a best-effort pass over the AST.
+/// It reports calls to functions that don't exist or with the wrong number
+/// of arguments, operators applied to values whose types are known from the
+/// source and can never work, and variables read where nothing could have
+/// given them a value. Anything it can't be sure about is left to runtime.
+///
+/// Expressions don't record positions, so lines come from the tokens: the
+/// checker visits names and operators in source order and finds each one
+/// in the token stream after the previous one.
+use crate::ast::*;
+use crate::builtins::Builtin;
+use crate::error::RuntimeError;
+use crate::lexer::Lexer;
+use crate::token::{StringPart, Token, TokenType};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Might be fine at runtime, such as a global the host provides
+    Warning,
+    /// Fails whenever the code runs
+    Error,
+}
+
+/// A problem found by [`check`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at line {}", self.message, self.line)
+    }
+}
+
+/// The type of a value, where the source alone decides it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Number,
+    String,
+    Boolean,
+    Array,
+    Map,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Boolean => "boolean",
+            Type::Array => "array",
+            Type::Map => "map",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Check the functions of `program` that were parsed from `tokens`.
+/// Functions merged in by imports are only used to resolve calls.
+pub fn check(program: &Program, tokens: &[Token]) -> Vec<Diagnostic> {
+    let mut globals = HashSet::new();
+    for function in &program.functions {
+        collect_assigned(&function.body, &mut globals);
+    }
+
+    let mut checker = Checker {
+        tokens,
+        cursor: 0,
+        string: None,
+        arities: program
+            .functions
+            .iter()
+            .map(|f| (f.name.clone(), f.params.len()))
+            .collect(),
+        globals,
+        scopes: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+
+    for function in &program.functions {
+        let Some(start) = definition(tokens, &function.name) else {
+            continue;
+        };
+        checker.cursor = start;
+        checker.scopes = vec![function.params.iter().cloned().collect()];
+        checker.block(&function.body);
+    }
+
+    checker.diagnostics
+}
+
+/// Index of the token after the name in `function name(...)`
+fn definition(tokens: &[Token], name: &str) -> Option<usize> {
+    tokens
+        .windows(2)
+        .position(|pair| {
+            matches!(pair[0].token_type, TokenType::Function)
+                && matches!(&pair[1].token_type, TokenType::Identifier(n) if n == name)
+        })
+        .map(|i| i + 2)
+}
+
+/// Names assigned without a declaration anywhere, which makes them globals
+/// that any function may read
+fn collect_assigned(stmts: &[Stmt], names: &mut HashSet<String>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::Assignment { name, .. } | StmtKind::Ask { name, .. } => {
+                names.insert(name.clone());
+            }
+            StmtKind::If {
+                then_block,
+                else_block,
+                ..
+            } => {
+                collect_assigned(then_block, names);
+                if let Some(else_block) = else_block {
+                    collect_assigned(else_block, names);
+                }
+            }
+            StmtKind::For {
+                init,
+                increment,
+                body,
+                ..
+            } => {
+                collect_assigned(std::slice::from_ref(init), names);
+                collect_assigned(std::slice::from_ref(increment), names);
+                collect_assigned(body, names);
+            }
+            StmtKind::While { body, .. }
+            | StmtKind::Repeat { body, .. }
+            | StmtKind::DoWhile { body, .. }
+            | StmtKind::ForEach { body, .. }
+            | StmtKind::Block(body) => collect_assigned(body, names),
+            _ => {}
+        }
+    }
+}
+
+struct Checker<'a> {
+    tokens: &'a [Token],
+    /// Where the search for the next token starts
+    cursor: usize,
+    /// The string with holes at `cursor`, and its tokens not yet found
+    string: Option<(usize, Vec<TokenType>)>,
+    arities: HashMap<String, usize>,
+    globals: HashSet<String>,
+    /// Variables declared in each enclosing block, innermost last
+    scopes: Vec<HashSet<String>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, severity: Severity, message: String, line: usize) {
+        self.diagnostics.push(Diagnostic {
+            severity,
+            message,
+            line,
+        });
+    }
+
+    /// Line of the current position, for anything that can't be found
+    fn line(&self) -> usize {
+        let index = self.cursor.min(self.tokens.len()).saturating_sub(1);
+        self.tokens.get(index).map_or(1, |token| token.line)
+    }
+
+    /// Move past the next `wanted` token and return its line. The tokens
+    /// of a string's `{...}` holes, and the `plus` joining its pieces, are
+    /// found in the string token, which stays the position until they have
+    /// all been found.
+    fn locate(&mut self, wanted: TokenType) -> usize {
+        for i in self.cursor..self.tokens.len() {
+            let token = &self.tokens[i];
+            match &token.token_type {
+                found if *found == wanted => {
+                    self.cursor = i + 1;
+                    return token.line;
+                }
+                TokenType::InterpolatedString(parts) => {
+                    let mut left = match self.string.take() {
+                        Some((index, left)) if index == i => left,
+                        _ => string_tokens(parts),
+                    };
+                    let Some(position) = left.iter().position(|t| *t == wanted) else {
+                        continue;
+                    };
+                    left.remove(position);
+                    self.string = Some((i, left));
+                    self.cursor = i;
+                    return token.line;
+                }
+                // Never search into the next function
+                TokenType::Function => break,
+                _ => {}
+            }
+        }
+        self.line()
+    }
+
+    fn locate_name(&mut self, name: &str) -> usize {
+        self.locate(TokenType::Identifier(name.to_string()))
+    }
+
+    fn locate_op(&mut self, op: &BinaryOp) -> usize {
+        let token = match op {
+            BinaryOp::Add => TokenType::Plus,
+            BinaryOp::Subtract => TokenType::Minus,
+            BinaryOp::Multiply => TokenType::Multiply,
+            BinaryOp::Divide => TokenType::Divide,
+            BinaryOp::IntDivide => TokenType::IntDivide,
+            BinaryOp::Modulo => TokenType::Modulo,
+            BinaryOp::Power => TokenType::Power,
+            BinaryOp::BitAnd => TokenType::BitAnd,
+            BinaryOp::BitOr => TokenType::BitOr,
+            BinaryOp::Xor => TokenType::Xor,
+            BinaryOp::ShiftLeft => TokenType::ShiftLeft,
+            BinaryOp::ShiftRight => TokenType::ShiftRight,
+            BinaryOp::Greater | BinaryOp::GreaterOrEquals => TokenType::Greater,
+            BinaryOp::Less | BinaryOp::LessOrEquals => TokenType::Less,
+            BinaryOp::Contains => TokenType::Contains,
+            BinaryOp::IndexOf => TokenType::IndexOf,
+            BinaryOp::Join => TokenType::Join,
+            BinaryOp::Equals | BinaryOp::NotEquals => TokenType::Equals,
+            BinaryOp::And => TokenType::And,
+            BinaryOp::Or => TokenType::Or,
+            BinaryOp::Otherwise => unreachable!("never reports an error"),
+        };
+        self.locate(token)
+    }
+
+    fn declare(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string());
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.globals.contains(name) || self.scopes.iter().any(|scope| scope.contains(name))
+    }
+
+    fn block(&mut self, stmts: &[Stmt]) {
+        self.scopes.push(HashSet::new());
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    /// Visit `stmt`, taking its parts in the order they appear in the source
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::VarDecl { name, value, .. } => {
+                self.locate_name(name);
+                self.expr(value);
+                self.declare(name);
+            }
+            StmtKind::Assignment { name, value } => {
+                self.locate_name(name);
+                let compound = self.cursor >= 2
+                    && matches!(
+                        self.tokens[self.cursor - 2].token_type,
+                        TokenType::Increase | TokenType::Decrease | TokenType::MultiplyVerb
+                    );
+                match value {
+                    // `increase x by n` names `x` once and has no operator
+                    Expr::Binary { op, right, .. } if compound => {
+                        let line = self.line();
+                        let right = self.expr(right);
+                        self.binary(op, None, right, line);
+                    }
+                    _ => {
+                        self.expr(value);
+                    }
+                }
+            }
+            StmtKind::IndexAssignment {
+                array,
+                index,
+                value,
+            } => {
+                self.expr(array);
+                self.expr(index);
+                self.expr(value);
+            }
+            StmtKind::Push { array, value } => {
+                self.expr(value);
+                self.variable(array);
+            }
+            StmtKind::Print(value) | StmtKind::Write(value) | StmtKind::Expression(value) => {
+                self.expr(value);
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+            StmtKind::Ask { name, prompt, .. } => {
+                self.locate_name(name);
+                if let Some(prompt) = prompt {
+                    self.expr(prompt);
+                }
+            }
+            StmtKind::Assert { condition, .. } => {
+                self.expr(condition);
+            }
+            StmtKind::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.expr(condition);
+                self.block(then_block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            StmtKind::While {
+                condition, body, ..
+            } => {
+                self.expr(condition);
+                self.block(body);
+            }
+            StmtKind::Repeat { count, body } => {
+                self.expr(count);
+                self.block(body);
+            }
+            StmtKind::DoWhile { body, condition } => {
+                self.block(body);
+                self.expr(condition);
+            }
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                ..
+            } => {
+                self.locate_name(var);
+                self.expr(iterable);
+                self.scopes.push(HashSet::from([var.clone()]));
+                self.block(body);
+                self.scopes.pop();
+            }
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                self.scopes.push(HashSet::new());
+                self.stmt(init);
+                self.expr(condition);
+                self.stmt(increment);
+                self.block(body);
+                self.scopes.pop();
+            }
+            StmtKind::Break(_) | StmtKind::Continue(_) => {}
+            StmtKind::Block(stmts) => self.block(stmts),
+        }
+    }
+
+    /// Read of a variable, warned about if nothing declares or assigns it
+    fn variable(&mut self, name: &str) {
+        let line = self.locate_name(name);
+        if !self.is_defined(name) {
+            self.report(
+                Severity::Warning,
+                RuntimeError::UndefinedVariable(name.to_string()).to_string(),
+                line,
+            );
+        }
+    }
+
+    /// Visit `expr`, returning its type if the source decides it
+    fn expr(&mut self, expr: &Expr) -> Option<Type> {
+        match expr {
+            Expr::Number(_) | Expr::Integer(_) => Some(Type::Number),
+            Expr::String(_) => Some(Type::String),
+            Expr::Boolean(_) => Some(Type::Boolean),
+            Expr::Identifier(name) => {
+                self.variable(name);
+                None
+            }
+            Expr::Binary {
+                left,
+                op: op @ BinaryOp::IndexOf,
+                right,
+            } => {
+                // `index of right in left`
+                let line = self.locate_op(op);
+                let needle = self.expr(right);
+                let string = self.expr(left);
+                self.binary(op, string, needle, line)
+            }
+            Expr::Binary {
+                left,
+                op: op @ BinaryOp::Join,
+                right,
+            } => {
+                let line = self.locate_op(op);
+                let array = self.expr(left);
+                let separator = self.expr(right);
+                self.binary(op, array, separator, line)
+            }
+            Expr::Binary {
+                left,
+                op: BinaryOp::Otherwise,
+                right,
+            } => {
+                // A value of a known type is never null, so the right side
+                // can't decide the type
+                let left = self.expr(left);
+                self.expr(right);
+                left
+            }
+            Expr::Binary { left, op, right } => {
+                let left = self.expr(left);
+                let line = self.locate_op(op);
+                let right = self.expr(right);
+                self.binary(op, left, right, line)
+            }
+            Expr::Unary { op, operand } => {
+                let operand = self.expr(operand);
+                match op {
+                    UnaryOp::Not | UnaryOp::IsNull => Some(Type::Boolean),
+                    UnaryOp::Negate => {
+                        if let Some(operand) = operand.filter(|t| *t != Type::Number) {
+                            let line = self.line();
+                            self.report(
+                                Severity::Error,
+                                format!("Cannot use 'minus' on {}", operand),
+                                line,
+                            );
+                        }
+                        Some(Type::Number)
+                    }
+                    UnaryOp::Length => Some(Type::Number),
+                    UnaryOp::Uppercase | UnaryOp::Lowercase | UnaryOp::Trim | UnaryOp::TypeOf => {
+                        Some(Type::String)
+                    }
+                    UnaryOp::Reverse | UnaryOp::Sort => Some(Type::Array),
+                }
+            }
+            Expr::Call { name, args } => {
+                let line = self.locate_name(name);
+                self.call(name, args.len(), line);
+                for arg in args {
+                    self.expr(arg);
+                }
+                if self.arities.contains_key(name) {
+                    return None;
+                }
+                match Builtin::from_name(name)? {
+                    Builtin::Range => Some(Type::Array),
+                    Builtin::ToString => Some(Type::String),
+                    Builtin::ToBoolean => Some(Type::Boolean),
+                    Builtin::Exit => None,
+                    _ => Some(Type::Number),
+                }
+            }
+            Expr::Array(elements) => {
+                for element in elements {
+                    self.expr(element);
+                }
+                Some(Type::Array)
+            }
+            Expr::Map(pairs) => {
+                for (key, value) in pairs {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                Some(Type::Map)
+            }
+            Expr::Index { array, index } => {
+                self.expr(array);
+                self.expr(index);
+                None
+            }
+            Expr::Substring { string, from, to } => {
+                self.expr(string);
+                self.expr(from);
+                self.expr(to);
+                Some(Type::String)
+            }
+            Expr::Slice { array, from, to } => {
+                self.expr(array);
+                self.expr(from);
+                self.expr(to);
+                Some(Type::Array)
+            }
+            Expr::Replace {
+                string,
+                pattern,
+                replacement,
+            } => {
+                self.expr(pattern);
+                self.expr(replacement);
+                self.expr(string);
+                Some(Type::String)
+            }
+            Expr::Pop(name) => {
+                self.variable(name);
+                None
+            }
+            Expr::Ternary {
+                condition,
+                then_value,
+                else_value,
+            } => {
+                self.expr(condition);
+                let then_type = self.expr(then_value);
+                let else_type = self.expr(else_value);
+                if then_type == else_type {
+                    then_type
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Report a call to a function that doesn't exist or with the wrong
+    /// number of arguments. User functions take precedence over built-ins.
+    fn call(&mut self, name: &str, count: usize, line: usize) {
+        let error = if let Some(&arity) = self.arities.get(name) {
+            (count != arity).then(|| RuntimeError::WrongArgumentCount {
+                function: name.to_string(),
+                expected: arity.to_string(),
+                got: count,
+            })
+        } else if let Some(builtin) = Builtin::from_name(name) {
+            builtin
+                .check_arity(count)
+                .err()
+                .and_then(|e| e.downcast::<RuntimeError>().ok())
+        } else {
+            Some(RuntimeError::UndefinedFunction(name.to_string()))
+        };
+        if let Some(error) = error {
+            self.report(Severity::Error, error.to_string(), line);
+        }
+    }
+
+    /// Report `op` on operands of known types it can't take, and return the
+    /// type of the result where it is known
+    fn binary(
+        &mut self,
+        op: &BinaryOp,
+        left: Option<Type>,
+        right: Option<Type>,
+        line: usize,
+    ) -> Option<Type> {
+        use Type::*;
+        let (valid, result) = match op {
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Equals | BinaryOp::NotEquals => {
+                return Some(Boolean);
+            }
+            // Strings join with strings and numbers; numbers add up
+            BinaryOp::Add => match (left, right) {
+                (Some(Number), Some(Number)) => (true, Some(Number)),
+                (Some(String), Some(String | Number)) | (Some(Number), Some(String)) => {
+                    (true, Some(String))
+                }
+                (Some(_), Some(_)) => (false, None),
+                (Some(String), None) | (None, Some(String)) => (true, Some(String)),
+                _ => (true, None),
+            },
+            BinaryOp::Contains | BinaryOp::IndexOf => {
+                let result = if *op == BinaryOp::Contains {
+                    Boolean
+                } else {
+                    Number
+                };
+                // Arrays can hold anything, so any needle may be in one
+                let in_array = *op == BinaryOp::Contains && left == Some(Array);
+                (
+                    in_array || (left == Some(String) && right == Some(String)),
+                    Some(result),
+                )
+            }
+            BinaryOp::Join => (left == Some(Array) && right == Some(String), Some(String)),
+            BinaryOp::Greater
+            | BinaryOp::GreaterOrEquals
+            | BinaryOp::Less
+            | BinaryOp::LessOrEquals => {
+                (left == Some(Number) && right == Some(Number), Some(Boolean))
+            }
+            _ => (left == Some(Number) && right == Some(Number), Some(Number)),
+        };
+
+        if let (false, Some(left), Some(right)) = (valid, left, right) {
+            let message = match op {
+                BinaryOp::Add => format!("Cannot add {} and {}", left, right),
+                _ => format!(
+                    "Cannot use '{}' on {} and {}",
+                    crate::fmt::operator_word(op),
+                    left,
+                    right
+                ),
+            };
+            self.report(Severity::Error, message, line);
+        }
+        result
+    }
+}
+
+/// The tokens of the holes in a string, plus one `plus` for each piece the
+/// parser joins on
+fn string_tokens(parts: &[StringPart]) -> Vec<TokenType> {
+    let joins = match parts.first() {
+        Some(StringPart::Literal(_)) => parts.len() - 1,
+        _ => parts.len(),
+    };
+    let mut tokens = vec![TokenType::Plus; joins];
+    for part in parts {
+        if let StringPart::Expr(source) = part {
+            tokens.extend(
+                Lexer::new(source.clone())
+                    .tokenize()
+                    .into_iter()
+                    .map(|token| token.token_type),
+            );
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn check_source(source: &str) -> Vec<String> {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens.clone()).parse().unwrap();
+        check(&program, &tokens)
+            .iter()
+            .map(|d| format!("{:?}: {}", d.severity, d))
+            .collect()
+    }
+
+    #[test]
+    fn test_calls_are_resolved() {
+        let source = "function add(a, b) { return a plus b }
+function main() {
+    print add(1, 2)
+    print add(1)
+    print sqrt(4, 2)
+    print range(1, 5)
+    print missing(3)
+    return 0
+}";
+        assert_eq!(
+            check_source(source),
+            vec![
+                "Error: Function 'add' expects 2 arguments, got 1 at line 4",
+                "Error: Function 'sqrt' expects 1 arguments, got 2 at line 5",
+                "Error: Undefined function: missing at line 7",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operators_on_known_types() {
+        let source = "function main() {
+    var n is 1
+    print n plus true
+    print 1 plus true
+    print \"a\" plus 2 minus 1
+    print (1 greater than 2) times 3
+    print \"total: {n}\" plus false
+    increase n by \"x\"
+    return 0
+}";
+        assert_eq!(
+            check_source(source),
+            vec![
+                "Error: Cannot add number and boolean at line 4",
+                "Error: Cannot use 'minus' on string and number at line 5",
+                "Error: Cannot use 'times' on boolean and number at line 6",
+                "Error: Cannot add string and boolean at line 7",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undefined_variables_are_warnings() {
+        let source = "function setup() {
+    limit is 10
+}
+function main() {
+    if true {
+        var inner is 1
+    }
+    print inner
+    print limit
+    for each item in list 1, 2 {
+        print \"{item} of {count}\"
+    }
+    print item
+    return 0
+}";
+        assert_eq!(
+            check_source(source),
+            vec![
+                "Warning: Undefined variable: inner at line 8",
+                "Warning: Undefined variable: count at line 11",
+                "Warning: Undefined variable: item at line 13",
+            ]
+        );
+    }
+}
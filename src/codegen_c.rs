@@ -34,21 +34,37 @@
 ///
 /// Result: **Native machine speed** ⚡
 ///
+use crate::builtins::{BitOp, Builtin};
 use crate::bytecode::*;
-use anyhow::Result;
+use crate::registers;
+use anyhow::{anyhow, Context, Result};
 use std::fmt::Write as FmtWrite;
 
 pub struct CCodeGen {
     output: String,
+    /// Seed passed to `srand`; the clock is used when unset
+    seed: Option<u64>,
+}
+
+impl Default for CCodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CCodeGen {
     pub fn new() -> Self {
         CCodeGen {
             output: String::new(),
+            seed: None,
         }
     }
 
+    /// Make `random` and `random_int` reproducible in the generated program
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
     pub fn compile_chunk(&mut self, chunk: &Chunk) -> Result<String> {
         // Generate C header
         writeln!(&mut self.output, "// Generated C code from TopLang").unwrap();
@@ -56,6 +72,9 @@ impl CCodeGen {
         writeln!(&mut self.output, "#include <stdlib.h>").unwrap();
         writeln!(&mut self.output, "#include <string.h>").unwrap();
         writeln!(&mut self.output, "#include <stdint.h>").unwrap();
+        writeln!(&mut self.output, "#include <ctype.h>").unwrap();
+        writeln!(&mut self.output, "#include <math.h>").unwrap();
+        writeln!(&mut self.output, "#include <time.h>").unwrap();
         writeln!(&mut self.output).unwrap();
 
         // Value type
@@ -77,24 +96,42 @@ impl CCodeGen {
 
         self.generate_helpers()?;
 
+        // One static per global, so reading one is a plain memory access
+        let globals = chunk.global_names();
+        for (index, name) in globals.iter().enumerate() {
+            writeln!(
+                &mut self.output,
+                "static Value global_{} = TAG_NULL; // {}",
+                index, name
+            )
+            .unwrap();
+        }
+        writeln!(&mut self.output).unwrap();
+
         // Forward declare functions
-        for name in chunk.functions.keys() {
+        for (name, func_chunk) in &chunk.functions {
             writeln!(
                 &mut self.output,
-                "Value func_{}(void);",
-                name.replace("-", "_")
+                "Value func_{}({});",
+                name.replace("-", "_"),
+                Self::param_list(func_chunk.arity)
             )
             .unwrap();
         }
         writeln!(&mut self.output).unwrap();
 
         // Compile functions
+        let names: Vec<String> = chunk.function_names().into_iter().cloned().collect();
         for (name, func_chunk) in &chunk.functions {
-            self.compile_function(name, func_chunk)?;
+            self.compile_function(name, func_chunk, &names, &globals)?;
         }
 
         // Main
         writeln!(&mut self.output, "int main(void) {{").unwrap();
+        match self.seed {
+            Some(seed) => writeln!(&mut self.output, "    srand({}u);", seed as u32).unwrap(),
+            None => writeln!(&mut self.output, "    srand((unsigned int)time(NULL));").unwrap(),
+        }
         writeln!(&mut self.output, "    func_main();").unwrap();
         writeln!(&mut self.output, "    return 0;").unwrap();
         writeln!(&mut self.output, "}}").unwrap();
@@ -173,37 +210,303 @@ impl CCodeGen {
         writeln!(&mut self.output, "}}").unwrap();
         writeln!(&mut self.output).unwrap();
 
-        // Print function
-        writeln!(&mut self.output, "void value_print(Value v) {{").unwrap();
+        // Truthiness, matching Value::is_truthy in the VM
+        writeln!(&mut self.output, "static inline int is_truthy(Value v) {{").unwrap();
+        writeln!(
+            &mut self.output,
+            "    if (is_number(v)) return as_number(v) != 0.0;"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "    if (is_string(v)) return as_string(v)->data[0] != '\\0';"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    return v == TAG_TRUE;").unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        // random() and random_int(lo, hi), both ends inclusive
+        writeln!(
+            &mut self.output,
+            "static inline double random_double(void) {{"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "    return (double)rand() / ((double)RAND_MAX + 1.0);"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(
+            &mut self.output,
+            "static inline double random_int(double lo, double hi) {{"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    lo = floor(lo); hi = floor(hi);").unwrap();
+        writeln!(&mut self.output, "    if (lo > hi) {{").unwrap();
+        writeln!(
+            &mut self.output,
+            "        fprintf(stderr, \"Error: random_int expects lo <= hi\\n\");"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "        exit(1);").unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        writeln!(
+            &mut self.output,
+            "    return lo + floor(random_double() * (hi - lo + 1.0));"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        // clamp(x, lo, hi)
+        writeln!(
+            &mut self.output,
+            "static inline double clamp_number(double x, double lo, double hi) {{"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    if (!(lo <= hi)) {{").unwrap();
+        writeln!(
+            &mut self.output,
+            "        fprintf(stderr, \"Error: clamp expects lo <= hi\\n\");"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "        exit(1);").unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        writeln!(&mut self.output, "    return fmin(fmax(x, lo), hi);").unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        // bitand, bitor, xor, shift left and shift right, by `BitOp::ALL` index
+        writeln!(&mut self.output, "static inline int is_whole(double n) {{").unwrap();
+        writeln!(
+            &mut self.output,
+            "    return n == floor(n) && n >= -9223372036854775808.0 && n < 9223372036854775808.0;"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(
+            &mut self.output,
+            "static double bitwise(int op, const char* name, double a, double b) {{"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "    if (!is_whole(a) || (op < 3 && !is_whole(b))) {{"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "        fprintf(stderr, \"Error: '%s' needs whole numbers, got %g\\n\", name, is_whole(a) ? b : a);"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "        exit(1);").unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        writeln!(
+            &mut self.output,
+            "    if (op >= 3 && !(b == floor(b) && b >= 0.0 && b < 64.0)) {{"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "        fprintf(stderr, \"Error: can't shift by %g places: it must be a whole number from 0 to 63\\n\", b);"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "        exit(1);").unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        writeln!(
+            &mut self.output,
+            "    long long x = (long long)a, y = (long long)b;"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    switch (op) {{").unwrap();
+        writeln!(&mut self.output, "        case 0: return (double)(x & y);").unwrap();
+        writeln!(&mut self.output, "        case 1: return (double)(x | y);").unwrap();
+        writeln!(&mut self.output, "        case 2: return (double)(x ^ y);").unwrap();
+        writeln!(
+            &mut self.output,
+            "        case 3: return (double)(long long)((unsigned long long)x << y);"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "        default: return (double)(x >> y);"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        writeln!(
+            &mut self.output,
+            "static inline const char* value_type_name(Value v) {{"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    if (is_number(v)) return \"number\";").unwrap();
+        writeln!(&mut self.output, "    if (is_string(v)) return \"string\";").unwrap();
+        writeln!(
+            &mut self.output,
+            "    if (v == TAG_TRUE || v == TAG_FALSE) return \"boolean\";"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    return \"null\";").unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        // to_number, to_string and to_boolean
+        writeln!(&mut self.output, "Value value_to_number(Value v) {{").unwrap();
+        writeln!(&mut self.output, "    if (is_number(v)) return v;").unwrap();
+        writeln!(&mut self.output, "    if (is_string(v)) {{").unwrap();
+        writeln!(
+            &mut self.output,
+            "        const char* s = as_string(v)->data;"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "        char* end;").unwrap();
+        writeln!(&mut self.output, "        double n = strtod(s, &end);").unwrap();
+        writeln!(
+            &mut self.output,
+            "        while (isspace((unsigned char)*end)) end++;"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "        if (end != s && !*end) return make_number(n);"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "        fprintf(stderr, \"Error: Cannot convert \\\"%s\\\" to a number\\n\", s);"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    }} else {{").unwrap();
+        writeln!(
+            &mut self.output,
+            "        fprintf(stderr, \"Error: Cannot convert value to a number\\n\");"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        writeln!(&mut self.output, "    exit(1);").unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        // Matches builtins::format_number; a whole double has at most 309 digits
+        writeln!(&mut self.output, "#define NUMBER_TEXT_SIZE 400").unwrap();
+        writeln!(&mut self.output, "void format_number(char* s, double n) {{").unwrap();
+        // Tested on the bits: -ffast-math lets the compiler assume isinf and isnan are false
+        writeln!(&mut self.output, "    uint64_t bits;").unwrap();
+        writeln!(&mut self.output, "    memcpy(&bits, &n, sizeof bits);").unwrap();
+        writeln!(
+            &mut self.output,
+            "    if ((bits & 0x7FF0000000000000ULL) == 0x7FF0000000000000ULL) {{"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "        if (bits & 0x000FFFFFFFFFFFFFULL) strcpy(s, \"nan\");"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "        else strcpy(s, bits >> 63 ? \"-infinity\" : \"infinity\");"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        // -0 prints as 0, like `n as i64` does
+        writeln!(&mut self.output, "    else if (n == 0) strcpy(s, \"0\");").unwrap();
+        writeln!(
+            &mut self.output,
+            "    else if (n == floor(n)) snprintf(s, NUMBER_TEXT_SIZE, \"%.0f\", n);"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "    else snprintf(s, NUMBER_TEXT_SIZE, \"%g\", n);"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        writeln!(&mut self.output, "Value value_to_string(Value v) {{").unwrap();
+        writeln!(&mut self.output, "    if (is_string(v)) return v;").unwrap();
         writeln!(&mut self.output, "    if (is_number(v)) {{").unwrap();
-        writeln!(&mut self.output, "        double n = as_number(v);").unwrap();
-        writeln!(&mut self.output, "        if (n == (long long)n) {{").unwrap();
         writeln!(
             &mut self.output,
-            "            printf(\"%lld\\n\", (long long)n);"
+            "        char* s = malloc(NUMBER_TEXT_SIZE);"
         )
         .unwrap();
-        writeln!(&mut self.output, "        }} else {{").unwrap();
-        writeln!(&mut self.output, "            printf(\"%g\\n\", n);").unwrap();
-        writeln!(&mut self.output, "        }}").unwrap();
+        writeln!(&mut self.output, "        format_number(s, as_number(v));").unwrap();
+        writeln!(&mut self.output, "        return make_string(s);").unwrap();
+        writeln!(&mut self.output, "    }}").unwrap();
+        writeln!(
+            &mut self.output,
+            "    if (v == TAG_TRUE) return make_string(\"true\");"
+        )
+        .unwrap();
+        writeln!(
+            &mut self.output,
+            "    if (v == TAG_FALSE) return make_string(\"false\");"
+        )
+        .unwrap();
+        writeln!(&mut self.output, "    return make_string(\"null\");").unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        // Print functions, with and without the newline
+        writeln!(&mut self.output, "void value_write(Value v) {{").unwrap();
+        writeln!(&mut self.output, "    if (is_number(v)) {{").unwrap();
+        writeln!(&mut self.output, "        char s[NUMBER_TEXT_SIZE];").unwrap();
+        writeln!(&mut self.output, "        format_number(s, as_number(v));").unwrap();
+        writeln!(&mut self.output, "        fputs(s, stdout);").unwrap();
         writeln!(&mut self.output, "    }} else if (is_string(v)) {{").unwrap();
         writeln!(
             &mut self.output,
-            "        printf(\"%s\\n\", as_string(v)->data);"
+            "        fputs(as_string(v)->data, stdout);"
         )
         .unwrap();
         writeln!(&mut self.output, "    }} else if (v == TAG_TRUE) {{").unwrap();
-        writeln!(&mut self.output, "        printf(\"true\\n\");").unwrap();
+        writeln!(&mut self.output, "        fputs(\"true\", stdout);").unwrap();
         writeln!(&mut self.output, "    }} else if (v == TAG_FALSE) {{").unwrap();
-        writeln!(&mut self.output, "        printf(\"false\\n\");").unwrap();
+        writeln!(&mut self.output, "        fputs(\"false\", stdout);").unwrap();
         writeln!(&mut self.output, "    }} else if (v == TAG_NULL) {{").unwrap();
-        writeln!(&mut self.output, "        printf(\"null\\n\");").unwrap();
+        writeln!(&mut self.output, "        fputs(\"null\", stdout);").unwrap();
         writeln!(&mut self.output, "    }}").unwrap();
         writeln!(&mut self.output, "}}").unwrap();
         writeln!(&mut self.output).unwrap();
 
-        // Input function
-        writeln!(&mut self.output, "Value value_input(const char* prompt) {{").unwrap();
+        writeln!(&mut self.output, "void value_print(Value v) {{").unwrap();
+        writeln!(&mut self.output, "    value_write(v);").unwrap();
+        writeln!(&mut self.output, "    putchar('\\n');").unwrap();
+        writeln!(&mut self.output, "}}").unwrap();
+        writeln!(&mut self.output).unwrap();
+
+        // Case conversion (ASCII, returns a fresh string)
+        for (name, func) in [("uppercase", "toupper"), ("lowercase", "tolower")] {
+            writeln!(&mut self.output, "Value value_{}(Value v) {{", name).unwrap();
+            writeln!(&mut self.output, "    if (!is_string(v)) return v;").unwrap();
+            writeln!(
+                &mut self.output,
+                "    char* s = strdup(as_string(v)->data);"
+            )
+            .unwrap();
+            writeln!(
+                &mut self.output,
+                "    for (char* p = s; *p; p++) *p = (char){}((unsigned char)*p);",
+                func
+            )
+            .unwrap();
+            writeln!(&mut self.output, "    return make_string(s);").unwrap();
+            writeln!(&mut self.output, "}}").unwrap();
+            writeln!(&mut self.output).unwrap();
+        }
+
+        // Input function; `text` keeps the line as a string
+        writeln!(
+            &mut self.output,
+            "Value value_input(const char* prompt, int text) {{"
+        )
+        .unwrap();
         writeln!(&mut self.output, "    if (prompt) {{").unwrap();
         writeln!(&mut self.output, "        printf(\"%s\", prompt);").unwrap();
         writeln!(&mut self.output, "        fflush(stdout);").unwrap();
@@ -229,14 +532,16 @@ impl CCodeGen {
         writeln!(&mut self.output, "    }}").unwrap();
         writeln!(&mut self.output).unwrap();
         writeln!(&mut self.output, "    // Try to parse as number").unwrap();
-        writeln!(&mut self.output, "    char* endptr;").unwrap();
+        writeln!(&mut self.output, "    if (!text) {{").unwrap();
+        writeln!(&mut self.output, "        char* endptr;").unwrap();
         writeln!(
             &mut self.output,
-            "    double num = strtod(buffer, &endptr);"
+            "        double num = strtod(buffer, &endptr);"
         )
         .unwrap();
-        writeln!(&mut self.output, "    if (*buffer && !*endptr) {{").unwrap();
-        writeln!(&mut self.output, "        return make_number(num);").unwrap();
+        writeln!(&mut self.output, "        if (*buffer && !*endptr) {{").unwrap();
+        writeln!(&mut self.output, "            return make_number(num);").unwrap();
+        writeln!(&mut self.output, "        }}").unwrap();
         writeln!(&mut self.output, "    }}").unwrap();
         writeln!(&mut self.output).unwrap();
         writeln!(&mut self.output, "    // Return as string").unwrap();
@@ -245,62 +550,68 @@ impl CCodeGen {
         writeln!(&mut self.output, "}}").unwrap();
         writeln!(&mut self.output).unwrap();
 
-        // Global variables storage
-        writeln!(&mut self.output, "#define MAX_GLOBALS 256").unwrap();
-        writeln!(&mut self.output, "typedef struct {{").unwrap();
-        writeln!(&mut self.output, "    const char* name;").unwrap();
-        writeln!(&mut self.output, "    Value value;").unwrap();
-        writeln!(&mut self.output, "}} GlobalVar;").unwrap();
-        writeln!(&mut self.output).unwrap();
-        writeln!(&mut self.output, "GlobalVar globals[MAX_GLOBALS];").unwrap();
-        writeln!(&mut self.output, "int global_count = 0;").unwrap();
-        writeln!(&mut self.output).unwrap();
-        writeln!(&mut self.output, "Value* get_global(const char* name) {{").unwrap();
-        writeln!(
-            &mut self.output,
-            "    for (int i = 0; i < global_count; i++) {{"
-        )
-        .unwrap();
+        Ok(())
+    }
+
+    /// Stop the program with `message`, as the VMs do, if the divisor `b`
+    /// is zero
+    fn zero_check(&mut self, message: &str) {
+        writeln!(&mut self.output, "        if (as_number(b) == 0.0) {{").unwrap();
         writeln!(
             &mut self.output,
-            "        if (strcmp(globals[i].name, name) == 0) {{"
+            "            fprintf(stderr, \"Error: {}\\n\");",
+            message
         )
         .unwrap();
-        writeln!(&mut self.output, "            return &globals[i].value;").unwrap();
+        writeln!(&mut self.output, "            exit(1);").unwrap();
         writeln!(&mut self.output, "        }}").unwrap();
-        writeln!(&mut self.output, "    }}").unwrap();
-        writeln!(&mut self.output, "    if (global_count < MAX_GLOBALS) {{").unwrap();
-        writeln!(
-            &mut self.output,
-            "        globals[global_count].name = name;"
-        )
-        .unwrap();
+    }
+
+    /// C parameter list for a function taking `arity` values
+    fn param_list(arity: usize) -> String {
+        if arity == 0 {
+            return "void".to_string();
+        }
+        (0..arity)
+            .map(|i| format!("Value p{}", i))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// `names` are the program's functions, for looking up `CallIndex`
+    /// callees, and `globals` its sorted global variables
+    fn compile_function(
+        &mut self,
+        name: &str,
+        chunk: &Chunk,
+        names: &[String],
+        globals: &[String],
+    ) -> Result<()> {
+        let safe_name = name.replace("-", "_");
         writeln!(
             &mut self.output,
-            "        globals[global_count].value = TAG_NULL;"
+            "Value func_{}({}) {{",
+            safe_name,
+            Self::param_list(chunk.arity)
         )
         .unwrap();
+
+        // Stack and locals, sized for this function (C has no empty arrays)
+        let stack_size = registers::max_stack_depth(chunk)
+            .with_context(|| format!("Cannot bound the stack of function '{}'", name))?;
+        writeln!(&mut self.output, "    Value stack[{}];", stack_size.max(1)).unwrap();
+        writeln!(&mut self.output, "    int sp = 0;").unwrap();
         writeln!(
             &mut self.output,
-            "        return &globals[global_count++].value;"
+            "    Value locals[{}] = {{0}};",
+            chunk.local_count.max(1)
         )
         .unwrap();
-        writeln!(&mut self.output, "    }}").unwrap();
-        writeln!(&mut self.output, "    return NULL;").unwrap();
-        writeln!(&mut self.output, "}}").unwrap();
-        writeln!(&mut self.output).unwrap();
-
-        Ok(())
-    }
-
-    fn compile_function(&mut self, name: &str, chunk: &Chunk) -> Result<()> {
-        let safe_name = name.replace("-", "_");
-        writeln!(&mut self.output, "Value func_{}(void) {{", safe_name).unwrap();
 
-        // Stack and locals
-        writeln!(&mut self.output, "    Value stack[256];").unwrap();
-        writeln!(&mut self.output, "    int sp = 0;").unwrap();
-        writeln!(&mut self.output, "    Value locals[64] = {{0}};").unwrap();
+        // Parameters occupy the first local slots, matching the VM frame layout
+        for i in 0..chunk.arity {
+            writeln!(&mut self.output, "    locals[{}] = p{};", i, i).unwrap();
+        }
         writeln!(&mut self.output).unwrap();
 
         // Find jump targets
@@ -325,8 +636,21 @@ impl CCodeGen {
             match instr {
                 Instruction::LoadConst(idx) => match &chunk.constants[*idx] {
                     Constant::Number(n) => {
-                        writeln!(&mut self.output, "    stack[sp++] = make_number({});", n)
-                            .unwrap();
+                        writeln!(
+                            &mut self.output,
+                            "    stack[sp++] = make_number({});",
+                            c_double(*n)
+                        )
+                        .unwrap();
+                    }
+                    // Compiled programs hold every number as a double
+                    Constant::Integer(n) => {
+                        writeln!(
+                            &mut self.output,
+                            "    stack[sp++] = make_number({});",
+                            c_double(*n as f64)
+                        )
+                        .unwrap();
                     }
                     Constant::String(s) => {
                         let escaped = s
@@ -360,74 +684,113 @@ impl CCodeGen {
                 }
 
                 Instruction::LoadGlobal(name) => {
-                    let escaped = name.replace("\\", "\\\\").replace("\"", "\\\"");
+                    let index = globals.binary_search(name).unwrap();
+                    writeln!(&mut self.output, "    stack[sp++] = global_{};", index).unwrap();
+                }
+
+                Instruction::StoreGlobal(name) => {
+                    // The value stays on the stack, as in the VMs
+                    let index = globals.binary_search(name).unwrap();
+                    writeln!(&mut self.output, "    global_{} = stack[sp-1];", index).unwrap();
+                }
+
+                Instruction::Add | Instruction::AddInt => {
                     writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
                     writeln!(
                         &mut self.output,
-                        "        Value* g = get_global(\"{}\");",
-                        escaped
+                        "        stack[sp++] = make_number(as_number(a) + as_number(b));"
                     )
                     .unwrap();
-                    writeln!(&mut self.output, "        stack[sp++] = g ? *g : TAG_NULL;").unwrap();
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
-                Instruction::StoreGlobal(name) => {
-                    let escaped = name.replace("\\", "\\\\").replace("\"", "\\\"");
+                Instruction::Subtract | Instruction::SubInt => {
                     writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
                     writeln!(
                         &mut self.output,
-                        "        Value* g = get_global(\"{}\");",
-                        escaped
+                        "        stack[sp++] = make_number(as_number(a) - as_number(b));"
                     )
                     .unwrap();
-                    writeln!(&mut self.output, "        if (g) *g = stack[--sp];").unwrap();
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
-                Instruction::Add | Instruction::AddInt => {
+                Instruction::Multiply | Instruction::MulInt => {
                     writeln!(&mut self.output, "    {{").unwrap();
                     writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
                     writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
                     writeln!(
                         &mut self.output,
-                        "        stack[sp++] = make_number(as_number(a) + as_number(b));"
+                        "        stack[sp++] = make_number(as_number(a) * as_number(b));"
                     )
                     .unwrap();
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
-                Instruction::Subtract | Instruction::SubInt => {
+                Instruction::Divide => {
                     writeln!(&mut self.output, "    {{").unwrap();
                     writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
                     writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    self.zero_check("Division by zero");
                     writeln!(
                         &mut self.output,
-                        "        stack[sp++] = make_number(as_number(a) - as_number(b));"
+                        "        stack[sp++] = make_number(as_number(a) / as_number(b));"
                     )
                     .unwrap();
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
-                Instruction::Multiply | Instruction::MulInt => {
+                Instruction::IntDivide => {
                     writeln!(&mut self.output, "    {{").unwrap();
                     writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
                     writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    self.zero_check("Division by zero");
                     writeln!(
                         &mut self.output,
-                        "        stack[sp++] = make_number(as_number(a) * as_number(b));"
+                        "        stack[sp++] = make_number(floor(as_number(a) / as_number(b)));"
                     )
                     .unwrap();
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
-                Instruction::Divide => {
+                Instruction::Modulo => {
                     writeln!(&mut self.output, "    {{").unwrap();
                     writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
                     writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    self.zero_check("Modulo by zero");
                     writeln!(
                         &mut self.output,
-                        "        stack[sp++] = make_number(as_number(a) / as_number(b));"
+                        "        stack[sp++] = make_number(fmod(as_number(a), as_number(b)));"
+                    )
+                    .unwrap();
+                    writeln!(&mut self.output, "    }}").unwrap();
+                }
+
+                Instruction::Power => {
+                    writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "        stack[sp++] = make_number(pow(as_number(a), as_number(b)));"
+                    )
+                    .unwrap();
+                    writeln!(&mut self.output, "    }}").unwrap();
+                }
+
+                Instruction::Bitwise(op) => {
+                    let index = BitOp::ALL.iter().position(|o| o == op).unwrap();
+                    writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "        stack[sp++] = make_number(bitwise({}, \"{}\", as_number(a), as_number(b)));",
+                        index,
+                        op.name()
                     )
                     .unwrap();
                     writeln!(&mut self.output, "    }}").unwrap();
@@ -453,6 +816,26 @@ impl CCodeGen {
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
+                Instruction::NotEqual => {
+                    writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "        if (is_number(a) && is_number(b)) {{"
+                    )
+                    .unwrap();
+                    writeln!(&mut self.output, "            stack[sp++] = (as_number(a) != as_number(b)) ? TAG_TRUE : TAG_FALSE;").unwrap();
+                    writeln!(&mut self.output, "        }} else {{").unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "            stack[sp++] = (a != b) ? TAG_TRUE : TAG_FALSE;"
+                    )
+                    .unwrap();
+                    writeln!(&mut self.output, "        }}").unwrap();
+                    writeln!(&mut self.output, "    }}").unwrap();
+                }
+
                 Instruction::Less | Instruction::LessInt => {
                     writeln!(&mut self.output, "    {{").unwrap();
                     writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
@@ -469,27 +852,97 @@ impl CCodeGen {
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
-                Instruction::Not => {
+                Instruction::GreaterEqual => {
                     writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        stack[sp++] = (as_number(a) >= as_number(b)) ? TAG_TRUE : TAG_FALSE;").unwrap();
+                    writeln!(&mut self.output, "    }}").unwrap();
+                }
+
+                Instruction::LessEqual => {
+                    writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        stack[sp++] = (as_number(a) <= as_number(b)) ? TAG_TRUE : TAG_FALSE;").unwrap();
+                    writeln!(&mut self.output, "    }}").unwrap();
+                }
+
+                Instruction::And => {
+                    writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
                     writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
                     writeln!(
                         &mut self.output,
-                        "        int is_truthy = (a != TAG_FALSE && a != TAG_NULL);"
+                        "        stack[sp++] = (is_truthy(a) && is_truthy(b)) ? TAG_TRUE : TAG_FALSE;"
                     )
                     .unwrap();
+                    writeln!(&mut self.output, "    }}").unwrap();
+                }
+
+                Instruction::Or => {
+                    writeln!(&mut self.output, "    {{").unwrap();
+                    writeln!(&mut self.output, "        Value b = stack[--sp];").unwrap();
+                    writeln!(&mut self.output, "        Value a = stack[--sp];").unwrap();
                     writeln!(
                         &mut self.output,
-                        "        stack[sp++] = is_truthy ? TAG_FALSE : TAG_TRUE;"
+                        "        stack[sp++] = (is_truthy(a) || is_truthy(b)) ? TAG_TRUE : TAG_FALSE;"
                     )
                     .unwrap();
                     writeln!(&mut self.output, "    }}").unwrap();
                 }
 
+                Instruction::Not => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = is_truthy(stack[sp-1]) ? TAG_FALSE : TAG_TRUE;"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::Negate => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = make_number(-as_number(stack[sp-1]));"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::Uppercase => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = value_uppercase(stack[sp-1]);"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::Lowercase => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = value_lowercase(stack[sp-1]);"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::TypeOf => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = make_string(value_type_name(stack[sp-1]));"
+                    )
+                    .unwrap();
+                }
+
                 Instruction::Print => {
                     writeln!(&mut self.output, "    value_print(stack[--sp]);").unwrap();
                 }
 
-                Instruction::Input(prompt) => {
+                Instruction::Write => {
+                    writeln!(&mut self.output, "    value_write(stack[--sp]);").unwrap();
+                    writeln!(&mut self.output, "    fflush(stdout);").unwrap();
+                }
+
+                Instruction::Input(prompt) | Instruction::InputText(prompt) => {
+                    let text = matches!(instr, Instruction::InputText(_)) as u8;
                     if let Some(p) = prompt {
                         let escaped = p
                             .replace("\\", "\\\\")
@@ -497,19 +950,53 @@ impl CCodeGen {
                             .replace("\n", "\\n");
                         writeln!(
                             &mut self.output,
-                            "    stack[sp++] = value_input(\"{}\");",
-                            escaped
+                            "    stack[sp++] = value_input(\"{}\", {});",
+                            escaped, text
                         )
                         .unwrap();
                     } else {
-                        writeln!(&mut self.output, "    stack[sp++] = value_input(NULL);").unwrap();
+                        writeln!(
+                            &mut self.output,
+                            "    stack[sp++] = value_input(NULL, {});",
+                            text
+                        )
+                        .unwrap();
                     }
                 }
 
+                Instruction::Assert(message) => {
+                    let mut error = format!("Error: at line {}: Assertion failed", chunk.lines[ip]);
+                    if let Some(message) = message {
+                        error.push_str(&format!(": {}", message));
+                    }
+                    let escaped = error
+                        .replace("\\", "\\\\")
+                        .replace("\"", "\\\"")
+                        .replace("\n", "\\n");
+                    writeln!(
+                        &mut self.output,
+                        "    if (stack[--sp] == TAG_FALSE || stack[sp] == TAG_NULL) {{"
+                    )
+                    .unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "        fputs(\"{}\\n\", stderr);",
+                        escaped
+                    )
+                    .unwrap();
+                    writeln!(&mut self.output, "        exit(1);").unwrap();
+                    writeln!(&mut self.output, "    }}").unwrap();
+                }
+
                 Instruction::Pop => {
                     writeln!(&mut self.output, "    sp--;").unwrap();
                 }
 
+                Instruction::Dup => {
+                    writeln!(&mut self.output, "    stack[sp] = stack[sp-1];").unwrap();
+                    writeln!(&mut self.output, "    sp++;").unwrap();
+                }
+
                 Instruction::Jump(target) => {
                     writeln!(&mut self.output, "    goto L{};", target).unwrap();
                 }
@@ -532,6 +1019,136 @@ impl CCodeGen {
                     .unwrap();
                 }
 
+                Instruction::Call(_, arity) | Instruction::CallIndex(_, arity) => {
+                    let callee = instr.callee(names).unwrap();
+                    // Arguments sit on top of the stack, first argument deepest
+                    let args = (0..*arity)
+                        .map(|i| format!("stack[sp + {}]", i))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    writeln!(&mut self.output, "    sp -= {};", arity).unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp] = func_{}({});",
+                        callee.replace("-", "_"),
+                        args
+                    )
+                    .unwrap();
+                    writeln!(&mut self.output, "    sp++;").unwrap();
+                }
+
+                Instruction::TailCall(_, arity) | Instruction::TailCallIndex(_, arity) => {
+                    let callee = instr.callee(names).unwrap();
+                    // The C compiler turns this into a jump where it can
+                    let args = (0..*arity)
+                        .map(|i| format!("stack[sp + {}]", i))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    writeln!(&mut self.output, "    sp -= {};", arity).unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "    return func_{}({});",
+                        callee.replace("-", "_"),
+                        args
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(Builtin::Random, _) => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp++] = make_number(random_double());"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(Builtin::RandomInt, _) => {
+                    writeln!(&mut self.output, "    sp--;").unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = make_number(random_int(as_number(stack[sp-1]), as_number(stack[sp])));"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(builtin @ (Builtin::Min | Builtin::Max), _) => {
+                    let func = if *builtin == Builtin::Min {
+                        "fmin"
+                    } else {
+                        "fmax"
+                    };
+                    writeln!(&mut self.output, "    sp--;").unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = make_number({}(as_number(stack[sp-1]), as_number(stack[sp])));",
+                        func
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(Builtin::Clamp, _) => {
+                    writeln!(&mut self.output, "    sp -= 2;").unwrap();
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = make_number(clamp_number(as_number(stack[sp-1]), as_number(stack[sp]), as_number(stack[sp+1])));"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(Builtin::ToNumber, _) => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = value_to_number(stack[sp-1]);"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(Builtin::ToString, _) => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = value_to_string(stack[sp-1]);"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(Builtin::ToBoolean, _) => {
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = is_truthy(stack[sp-1]) ? TAG_TRUE : TAG_FALSE;"
+                    )
+                    .unwrap();
+                }
+
+                Instruction::CallBuiltin(builtin, _) => {
+                    let func = match builtin {
+                        Builtin::Floor => "floor",
+                        Builtin::Ceil => "ceil",
+                        Builtin::Round => "round",
+                        Builtin::Abs => "fabs",
+                        Builtin::Sqrt => "sqrt",
+                        Builtin::Random
+                        | Builtin::RandomInt
+                        | Builtin::Min
+                        | Builtin::Max
+                        | Builtin::Clamp => unreachable!("matched above"),
+                        Builtin::Range => {
+                            return Err(anyhow!("range is not supported by the C backend"))
+                        }
+                        Builtin::ToNumber | Builtin::ToString | Builtin::ToBoolean => {
+                            unreachable!("matched above")
+                        }
+                        Builtin::Exit => {
+                            return Err(anyhow!("exit is compiled to Exit, not called"))
+                        }
+                    };
+                    writeln!(
+                        &mut self.output,
+                        "    stack[sp-1] = make_number({}(as_number(stack[sp-1])));",
+                        func
+                    )
+                    .unwrap();
+                }
+
                 Instruction::Return => {
                     writeln!(&mut self.output, "    return stack[--sp];").unwrap();
                 }
@@ -540,6 +1157,10 @@ impl CCodeGen {
                     writeln!(&mut self.output, "    return TAG_NULL;").unwrap();
                 }
 
+                Instruction::Exit => {
+                    writeln!(&mut self.output, "    exit((int)as_number(stack[--sp]));").unwrap();
+                }
+
                 Instruction::IncrementInt => {
                     writeln!(
                         &mut self.output,
@@ -548,7 +1169,9 @@ impl CCodeGen {
                     .unwrap();
                 }
 
-                _ => {}
+                Instruction::Nop => {}
+
+                _ => return Err(anyhow!("{:?} is not supported by the C backend", instr)),
             }
         }
 
@@ -559,3 +1182,105 @@ impl CCodeGen {
         Ok(())
     }
 }
+
+/// `n` as a C double literal; constant folding can produce values such as
+/// infinity that have no literal of their own
+fn c_double(n: f64) -> String {
+    if n.is_nan() {
+        "NAN".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "INFINITY" } else { "-INFINITY" }.to_string()
+    } else {
+        // `{:?}` keeps the exponent ("1e20"), so the literal is never an integer
+        format!("{:?}", n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::process::Command;
+
+    fn transpile(source: &str) -> String {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().expect("Failed to parse");
+        let chunk = Compiler::new().compile(program).expect("Failed to compile");
+        CCodeGen::new().compile_chunk(&chunk).unwrap()
+    }
+
+    /// Transpile `source`, build it with the system C compiler and return stdout
+    fn compile_and_run(name: &str, source: &str) -> String {
+        let c_code = transpile(source);
+
+        let dir = std::env::temp_dir().join(format!("toplang_c_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let c_file = dir.join("out.c");
+        let exe = dir.join("out");
+        std::fs::write(&c_file, c_code).unwrap();
+
+        let status = Command::new("cc")
+            .arg(&c_file)
+            .arg("-o")
+            .arg(&exe)
+            .arg("-lm")
+            .status()
+            .expect("Failed to run C compiler");
+        assert!(status.success(), "C compilation failed");
+
+        let output = Command::new(&exe).output().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_recursive_call() {
+        let source = r#"
+function factorial(n) {
+    if n less than 2 {
+        return 1
+    }
+    return n times factorial(n minus 1)
+}
+
+function add(a, b) {
+    var sum is a plus b
+    return sum
+}
+
+function main() {
+    print factorial(10)
+    print add(3, 4)
+    return 0
+}
+"#;
+        assert_eq!(compile_and_run("call", source), "3628800\n7\n");
+    }
+
+    #[test]
+    fn test_arrays_are_sized_for_the_function() {
+        let mut body = String::new();
+        for i in 0..100 {
+            body.push_str(&format!("    var v{} is x plus {}\n", i, i));
+        }
+        let source = format!(
+            "function many(x) {{\n{}    return v0 plus v99\n}}\n\nfunction main() {{\n    print many(0)\n    return 0\n}}\n",
+            body
+        );
+
+        let c_code = transpile(&source);
+        let many = &c_code[c_code.find("Value func_many(Value p0) {").unwrap()..];
+        let size = |array: &str| -> usize {
+            let start = many.find(array).unwrap() + array.len();
+            many[start..start + many[start..].find(']').unwrap()]
+                .parse()
+                .unwrap()
+        };
+        assert!(size("Value locals[") >= 100, "{}", many);
+        assert!(size("Value stack[") >= 2, "{}", many);
+
+        assert_eq!(compile_and_run("many_locals", &source), "99\n");
+    }
+}
@@ -1,25 +1,47 @@
-/// Native code generator for TopLang
-///
-/// Compiles bytecode to native machine code using Cranelift
-/// Supports AOT compilation to standalone executables
+#![allow(dead_code)]
+
+//! Native code generator for TopLang
+//!
+//! Compiles bytecode to native machine code using Cranelift
+//! Supports AOT compilation to object files and JIT compilation into memory
+//!
+//! NOTE: Only the JIT is reachable from the CLI (`--jit`); `--compile` goes
+//! through the C backend.
 
 use crate::bytecode::*;
+use crate::registers;
 use anyhow::{anyhow, Result};
+use cranelift::codegen::isa::OwnedTargetIsa;
 use cranelift::prelude::*;
-use cranelift_module::{Linkage, Module};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{DataDescription, DataId, Linkage, Module};
 use cranelift_object::{ObjectBuilder, ObjectModule};
 use std::collections::HashMap;
-use target_lexicon::Triple;
 
-/// Native code generator
-pub struct NativeCodeGen {
-    module: ObjectModule,
+/// Native code generator, emitting an object file or, with `JITModule`,
+/// code in memory
+pub struct NativeCodeGen<M: Module = ObjectModule> {
+    module: M,
     ctx: codegen::Context,
     builder_context: FunctionBuilderContext,
-    /// Maps function names to their compiled function IDs
-    functions: HashMap<String, cranelift_module::FuncId>,
+    /// The program's functions and their compiled function IDs, in the order
+    /// `CallIndex` numbers them
+    functions: Vec<(String, cranelift_module::FuncId)>,
+    /// String constants and globals in the data section
+    data: DataObjects,
     /// Runtime function declarations
     runtime_funcs: RuntimeFunctions,
+    /// Print each function's Cranelift IR before it is compiled
+    dump_ir: bool,
+}
+
+/// Data objects shared by every function using them
+#[derive(Default)]
+struct DataObjects {
+    /// The bytes of each string constant
+    strings: HashMap<String, DataId>,
+    /// One writable Value per global variable, starting out null
+    globals: HashMap<String, DataId>,
 }
 
 /// Runtime function signatures
@@ -38,27 +60,136 @@ struct RuntimeFunctions {
     array_new: cranelift_module::FuncId,
     array_get: cranelift_module::FuncId,
     array_set: cranelift_module::FuncId,
-    array_length: cranelift_module::FuncId,
+    array_with: cranelift_module::FuncId,
+    length: cranelift_module::FuncId,
+    expect_array: cranelift_module::FuncId,
+    exit_code: cranelift_module::FuncId,
+    exit: cranelift_module::FuncId,
 }
 
-impl NativeCodeGen {
-    pub fn new() -> Result<Self> {
-        // Create module for target platform
-        let target_triple = Triple::host();
-        let isa_builder = cranelift_native::builder()
-            .map_err(|e| anyhow!("Failed to create ISA builder: {:?}", e))?;
-        let isa = isa_builder
-            .finish(settings::Flags::new(settings::builder()))
-            .map_err(|e| anyhow!("Failed to create ISA: {:?}", e))?;
+/// Target description for the machine we're running on
+fn host_isa() -> Result<OwnedTargetIsa> {
+    let isa_builder = cranelift_native::builder()
+        .map_err(|e| anyhow!("Failed to create ISA builder: {:?}", e))?;
+    isa_builder
+        .finish(settings::Flags::new(settings::builder()))
+        .map_err(|e| anyhow!("Failed to create ISA: {:?}", e))
+}
+
+/// Addresses of the runtime functions, which JIT-compiled code calls directly
+fn runtime_symbols() -> Vec<(&'static str, *const u8)> {
+    vec![
+        ("toplang_print", crate::runtime::toplang_print as *const u8),
+        ("toplang_input", crate::runtime::toplang_input as *const u8),
+        ("toplang_add", crate::runtime::toplang_add as *const u8),
+        (
+            "toplang_subtract",
+            crate::runtime::toplang_subtract as *const u8,
+        ),
+        (
+            "toplang_multiply",
+            crate::runtime::toplang_multiply as *const u8,
+        ),
+        (
+            "toplang_divide",
+            crate::runtime::toplang_divide as *const u8,
+        ),
+        ("toplang_equal", crate::runtime::toplang_equal as *const u8),
+        ("toplang_less", crate::runtime::toplang_less as *const u8),
+        (
+            "toplang_greater",
+            crate::runtime::toplang_greater as *const u8,
+        ),
+        ("toplang_not", crate::runtime::toplang_not as *const u8),
+        (
+            "toplang_string_new",
+            crate::runtime::toplang_string_new as *const u8,
+        ),
+        (
+            "toplang_array_new",
+            crate::runtime::toplang_array_new as *const u8,
+        ),
+        (
+            "toplang_array_get",
+            crate::runtime::toplang_array_get as *const u8,
+        ),
+        (
+            "toplang_array_set",
+            crate::runtime::toplang_array_set as *const u8,
+        ),
+        (
+            "toplang_array_with",
+            crate::runtime::toplang_array_with as *const u8,
+        ),
+        (
+            "toplang_length",
+            crate::runtime::toplang_length as *const u8,
+        ),
+        (
+            "toplang_expect_array",
+            crate::runtime::toplang_expect_array as *const u8,
+        ),
+        (
+            "toplang_exit_code",
+            crate::runtime::toplang_exit_code as *const u8,
+        ),
+        ("toplang_exit", crate::runtime::toplang_exit as *const u8),
+    ]
+}
 
+impl NativeCodeGen<ObjectModule> {
+    /// Code generator producing an object file to link against the runtime library
+    pub fn new() -> Result<Self> {
         let builder = ObjectBuilder::new(
-            isa,
+            host_isa()?,
             "toplang_program".to_string(),
             cranelift_module::default_libcall_names(),
         )
         .map_err(|e| anyhow!("Failed to create object builder: {:?}", e))?;
-        let module = ObjectModule::new(builder);
+        Self::with_module(ObjectModule::new(builder))
+    }
+
+    /// Compile a chunk to native code
+    pub fn compile_chunk(mut self, chunk: &Chunk) -> Result<Vec<u8>> {
+        self.compile_program(chunk)?;
+
+        // Finalize and get object code
+        let product = self.module.finish();
+        product
+            .emit()
+            .map_err(|e| anyhow!("Failed to emit object code: {:?}", e))
+    }
+}
 
+impl NativeCodeGen<JITModule> {
+    /// Code generator compiling into memory, linked against the runtime in this process
+    pub fn jit() -> Result<Self> {
+        let mut builder =
+            JITBuilder::with_isa(host_isa()?, cranelift_module::default_libcall_names());
+        builder.symbols(runtime_symbols());
+        Self::with_module(JITModule::new(builder))
+    }
+
+    /// Compile a chunk in memory and run it, returning the exit code
+    pub fn run(mut self, chunk: &Chunk) -> Result<i32> {
+        let main_id = self.compile_program(chunk)?;
+        self.module
+            .finalize_definitions()
+            .map_err(|e| anyhow!("Failed to finalize JIT code: {:?}", e))?;
+
+        let code = self.module.get_finalized_function(main_id);
+        // SAFETY: `compile_main` defined this function as `extern "C" fn() -> i32`
+        let main = unsafe { std::mem::transmute::<*const u8, extern "C" fn() -> i32>(code) };
+        let exit_code = main();
+
+        // SAFETY: the compiled code has returned and nothing points into it
+        unsafe { self.module.free_memory() };
+        Ok(exit_code)
+    }
+}
+
+impl<M: Module> NativeCodeGen<M> {
+    fn with_module(module: M) -> Result<Self> {
         let mut ctx = codegen::Context::new();
         ctx.func.signature.call_conv = module.target_config().default_call_conv;
 
@@ -68,7 +199,9 @@ impl NativeCodeGen {
             module,
             ctx,
             builder_context,
-            functions: HashMap::new(),
+            functions: Vec::new(),
+            data: DataObjects::default(),
+            dump_ir: false,
             runtime_funcs: RuntimeFunctions {
                 print: cranelift_module::FuncId::from_u32(0),
                 input: cranelift_module::FuncId::from_u32(0),
@@ -84,7 +217,11 @@ impl NativeCodeGen {
                 array_new: cranelift_module::FuncId::from_u32(0),
                 array_get: cranelift_module::FuncId::from_u32(0),
                 array_set: cranelift_module::FuncId::from_u32(0),
-                array_length: cranelift_module::FuncId::from_u32(0),
+                array_with: cranelift_module::FuncId::from_u32(0),
+                length: cranelift_module::FuncId::from_u32(0),
+                expect_array: cranelift_module::FuncId::from_u32(0),
+                exit_code: cranelift_module::FuncId::from_u32(0),
+                exit: cranelift_module::FuncId::from_u32(0),
             },
         };
 
@@ -212,14 +349,51 @@ impl NativeCodeGen {
             .declare_function("toplang_array_set", Linkage::Import, &sig)
             .map_err(|e| anyhow!("Failed to declare array_set: {:?}", e))?;
 
-        // array_length(array) -> Value
+        // array_with(array, index, value) -> Value
         let mut sig = self.module.make_signature();
         sig.params.push(AbiParam::new(val_type));
+        sig.params.push(AbiParam::new(val_type));
+        sig.params.push(AbiParam::new(val_type));
         sig.returns.push(AbiParam::new(val_type));
-        let array_length = self
+        let array_with = self
             .module
-            .declare_function("toplang_array_length", Linkage::Import, &sig)
-            .map_err(|e| anyhow!("Failed to declare array_length: {:?}", e))?;
+            .declare_function("toplang_array_with", Linkage::Import, &sig)
+            .map_err(|e| anyhow!("Failed to declare array_with: {:?}", e))?;
+
+        // length(string or array) -> Value
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(val_type));
+        sig.returns.push(AbiParam::new(val_type));
+        let length = self
+            .module
+            .declare_function("toplang_length", Linkage::Import, &sig)
+            .map_err(|e| anyhow!("Failed to declare length: {:?}", e))?;
+
+        // expect_array(Value) -> the same Value
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(val_type));
+        sig.returns.push(AbiParam::new(val_type));
+        let expect_array = self
+            .module
+            .declare_function("toplang_expect_array", Linkage::Import, &sig)
+            .map_err(|e| anyhow!("Failed to declare expect_array: {:?}", e))?;
+
+        // exit_code(Value) -> i32
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(val_type));
+        sig.returns.push(AbiParam::new(types::I32));
+        let exit_code = self
+            .module
+            .declare_function("toplang_exit_code", Linkage::Import, &sig)
+            .map_err(|e| anyhow!("Failed to declare exit_code: {:?}", e))?;
+
+        // exit(Value) -> never returns
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(val_type));
+        let exit = self
+            .module
+            .declare_function("toplang_exit", Linkage::Import, &sig)
+            .map_err(|e| anyhow!("Failed to declare exit: {:?}", e))?;
 
         Ok(RuntimeFunctions {
             print,
@@ -236,38 +410,70 @@ impl NativeCodeGen {
             array_new,
             array_get,
             array_set,
-            array_length,
+            array_with,
+            length,
+            expect_array,
+            exit_code,
+            exit,
         })
     }
 
-    /// Compile a chunk to native code
-    pub fn compile_chunk(mut self, chunk: &Chunk) -> Result<Vec<u8>> {
-        // First, compile all functions
+    /// Compile every function and the entry point, returning the entry point
+    fn compile_program(&mut self, chunk: &Chunk) -> Result<cranelift_module::FuncId> {
+        // Declare every function up front so calls can reference any of them
+        for name in chunk.function_names() {
+            self.declare_user_function(name, chunk.functions[name].arity)?;
+        }
+
+        // Then compile their bodies
         for (name, func_chunk) in &chunk.functions {
             self.compile_function(name, func_chunk)?;
         }
 
         // Compile the main entry point
-        self.compile_main(chunk)?;
+        self.compile_main(chunk)
+    }
 
-        // Finalize and get object code
-        let product = self.module.finish();
-        Ok(product.emit().map_err(|e| anyhow!("Failed to emit object code: {:?}", e))?)
+    /// Print the Cranelift IR of every function as it is compiled, to debug
+    /// the code generator
+    pub fn set_dump_ir(&mut self, dump_ir: bool) {
+        self.dump_ir = dump_ir;
     }
 
-    /// Compile a function
-    fn compile_function(&mut self, name: &str, chunk: &Chunk) -> Result<()> {
-        // Create function signature (no params for now, returns Value)
-        let val_type = types::I64;
-        self.ctx.func.signature.returns.push(AbiParam::new(val_type));
+    /// Print the IR built in the context, under `name`
+    fn print_ir(&self, name: &str) {
+        if self.dump_ir {
+            println!("== {} ==\n{}", name, self.ctx.func.display());
+        }
+    }
 
-        // Declare the function
+    /// Signature of a user function: one Value per parameter, returns a Value
+    fn user_signature(&self, arity: usize) -> Signature {
+        let mut sig = self.module.make_signature();
+        for _ in 0..arity {
+            sig.params.push(AbiParam::new(types::I64));
+        }
+        sig.returns.push(AbiParam::new(types::I64));
+        sig
+    }
+
+    /// Declare a user function. Symbols are prefixed so the user's `main`
+    /// doesn't collide with the C entry point.
+    fn declare_user_function(&mut self, name: &str, arity: usize) -> Result<()> {
+        let sig = self.user_signature(arity);
         let func_id = self
             .module
-            .declare_function(name, Linkage::Export, &self.ctx.func.signature)
+            .declare_function(&format!("toplang_fn_{}", name), Linkage::Local, &sig)
             .map_err(|e| anyhow!("Failed to declare function {}: {:?}", name, e))?;
 
-        self.functions.insert(name.to_string(), func_id);
+        self.functions.push((name.to_string(), func_id));
+        Ok(())
+    }
+
+    /// Compile a function
+    fn compile_function(&mut self, name: &str, chunk: &Chunk) -> Result<()> {
+        let func_id = function_id(&self.functions, name).unwrap();
+        self.ctx.func.signature = self.user_signature(chunk.arity);
 
         // Build function body
         {
@@ -277,21 +483,27 @@ impl NativeCodeGen {
             builder.switch_to_block(entry_block);
             builder.seal_block(entry_block);
 
+            // Parameters occupy the first local slots, matching the VM frame layout
+            let params = builder.block_params(entry_block).to_vec();
+
             // Compile bytecode instructions - inline the logic to avoid borrowing issues
-            let result = Self::compile_instructions_static(
+            Self::compile_instructions_static(
                 &mut builder,
                 chunk,
+                &params,
+                &self.functions,
                 &self.runtime_funcs,
-                &self.module,
+                &mut self.module,
+                &mut self.data,
             )?;
 
-            // Return the result
-            builder.ins().return_(&[result]);
-
             // Finalize
+            builder.seal_all_blocks();
             builder.finalize();
         }
 
+        self.print_ir(name);
+
         // Define the function
         self.module
             .define_function(func_id, &mut self.ctx)
@@ -304,7 +516,7 @@ impl NativeCodeGen {
     }
 
     /// Compile main entry point
-    fn compile_main(&mut self, _chunk: &Chunk) -> Result<()> {
+    fn compile_main(&mut self, _chunk: &Chunk) -> Result<cranelift_module::FuncId> {
         // Create main signature: () -> i32
         let mut sig = self.module.make_signature();
         sig.returns.push(AbiParam::new(types::I32));
@@ -322,42 +534,139 @@ impl NativeCodeGen {
             builder.switch_to_block(entry_block);
             builder.seal_block(entry_block);
 
-            // Call the user's main function if it exists
-            if let Some(user_main_id) = self.functions.get("main") {
-                let local_callee = self.module.declare_func_in_func(*user_main_id, builder.func);
-                builder.ins().call(local_callee, &[]);
-            }
-
-            // Return 0
-            let zero = builder.ins().iconst(types::I32, 0);
-            builder.ins().return_(&[zero]);
+            // Call the user's main function if it exists; its result is the exit code
+            let exit_code = if let Some(user_main_id) = function_id(&self.functions, "main") {
+                let local_callee = self.module.declare_func_in_func(user_main_id, builder.func);
+                let call = builder.ins().call(local_callee, &[]);
+                let result = builder.inst_results(call)[0];
+                let exit_code_ref = self
+                    .module
+                    .declare_func_in_func(self.runtime_funcs.exit_code, builder.func);
+                let call = builder.ins().call(exit_code_ref, &[result]);
+                builder.inst_results(call)[0]
+            } else {
+                builder.ins().iconst(types::I32, 0)
+            };
+            builder.ins().return_(&[exit_code]);
 
             builder.finalize();
         }
 
+        self.print_ir("entry point");
+
         self.module
             .define_function(main_id, &mut self.ctx)
             .map_err(|e| anyhow!("Failed to define main: {:?}", e))?;
 
         self.module.clear_context(&mut self.ctx);
 
-        Ok(())
+        Ok(main_id)
     }
 
-    /// Compile bytecode instructions to Cranelift IR
+    /// Data object holding the bytes of a string constant, defined the first
+    /// time the string is used. The empty string gets a single NUL byte, as
+    /// cranelift-jit can't place a zero-sized object; its length stays 0.
+    fn string_data(module: &mut M, data: &mut DataObjects, s: &str) -> Result<DataId> {
+        if let Some(data_id) = data.strings.get(s) {
+            return Ok(*data_id);
+        }
+
+        let data_id = module
+            .declare_data(
+                &format!("toplang_str_{}", data.strings.len()),
+                Linkage::Local,
+                false,
+                false,
+            )
+            .map_err(|e| anyhow!("Failed to declare string constant: {:?}", e))?;
+        let mut description = DataDescription::new();
+        let bytes: Box<[u8]> = if s.is_empty() {
+            Box::new([0])
+        } else {
+            s.as_bytes().into()
+        };
+        description.define(bytes);
+        module
+            .define_data(data_id, &description)
+            .map_err(|e| anyhow!("Failed to define string constant: {:?}", e))?;
+
+        data.strings.insert(s.to_string(), data_id);
+        Ok(data_id)
+    }
+
+    /// Address of the Value holding global `name`, which is defined, as
+    /// null, the first time any function uses it
+    fn global_address(
+        builder: &mut FunctionBuilder,
+        module: &mut M,
+        data: &mut DataObjects,
+        name: &str,
+    ) -> Result<cranelift::prelude::Value> {
+        let data_id = match data.globals.get(name) {
+            Some(data_id) => *data_id,
+            None => {
+                let data_id = module
+                    .declare_data(
+                        &format!("toplang_global_{}", data.globals.len()),
+                        Linkage::Local,
+                        true,
+                        false,
+                    )
+                    .map_err(|e| anyhow!("Failed to declare global {}: {:?}", name, e))?;
+                let mut description = DataDescription::new();
+                description.define(Box::new(0x7FF8_0000_0000_0001u64.to_le_bytes()));
+                description.set_align(8);
+                module
+                    .define_data(data_id, &description)
+                    .map_err(|e| anyhow!("Failed to define global {}: {:?}", name, e))?;
+                data.globals.insert(name.to_string(), data_id);
+                data_id
+            }
+        };
+
+        let ptr_type = module.target_config().pointer_type();
+        let global = module.declare_data_in_func(data_id, builder.func);
+        Ok(builder.ins().global_value(ptr_type, global))
+    }
+
+    /// Compile bytecode instructions to Cranelift IR, emitting the
+    /// function's returns along the way
     fn compile_instructions_static(
         builder: &mut FunctionBuilder,
         chunk: &Chunk,
+        params: &[cranelift::prelude::Value],
+        functions: &[(String, cranelift_module::FuncId)],
         runtime_funcs: &RuntimeFunctions,
-        module: &ObjectModule,
-    ) -> Result<cranelift::prelude::Value> {
+        module: &mut M,
+        data: &mut DataObjects,
+    ) -> Result<()> {
         let val_type = types::I64;
-
-        // Stack simulation (we'll use Cranelift values directly)
-        let mut stack: Vec<cranelift::prelude::Value> = Vec::new();
-
-        // Local variables (indexed)
-        let mut locals: Vec<cranelift::prelude::Value> = Vec::new();
+        let ptr_type = module.target_config().pointer_type();
+
+        // One Cranelift variable per local slot, so a local assigned inside a
+        // loop gets the block parameters it needs; parameters come first,
+        // matching the VM frame layout, and the rest start out null
+        let local_count = chunk.local_count.max(params.len());
+        for idx in 0..local_count {
+            let var = Variable::from_u32(idx as u32);
+            builder.declare_var(var, val_type);
+            let initial = match params.get(idx) {
+                Some(param) => *param,
+                None => builder
+                    .ins()
+                    .iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64),
+            };
+            builder.def_var(var, initial);
+        }
+        let mut stack = OperandStack::new(builder, local_count, registers::max_stack_depth(chunk)?);
+        let depths = registers::stack_depths(chunk)?;
+        let local = |idx: usize| {
+            if idx < local_count {
+                Ok(Variable::from_u32(idx as u32))
+            } else {
+                Err(anyhow!("Local slot {} out of range", idx))
+            }
+        };
 
         // Constants pool
         let mut constants: Vec<cranelift::prelude::Value> = Vec::new();
@@ -368,18 +677,30 @@ impl NativeCodeGen {
                     let bits = n.to_bits();
                     builder.ins().iconst(val_type, bits as i64)
                 }
-                Constant::Boolean(true) => {
-                    builder.ins().iconst(val_type, 0x7FF8_0000_0000_0002u64 as i64)
-                }
-                Constant::Boolean(false) => {
-                    builder.ins().iconst(val_type, 0x7FF8_0000_0000_0003u64 as i64)
-                }
-                Constant::Null => {
-                    builder.ins().iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64)
+                // Native code holds every number as a double
+                Constant::Integer(n) => {
+                    let bits = (*n as f64).to_bits();
+                    builder.ins().iconst(val_type, bits as i64)
                 }
-                Constant::String(_s) => {
-                    // TODO: Create string constant in data section
-                    builder.ins().iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64)
+                Constant::Boolean(true) => builder
+                    .ins()
+                    .iconst(val_type, 0x7FF8_0000_0000_0002u64 as i64),
+                Constant::Boolean(false) => builder
+                    .ins()
+                    .iconst(val_type, 0x7FF8_0000_0000_0003u64 as i64),
+                Constant::Null => builder
+                    .ins()
+                    .iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64),
+                Constant::String(s) => {
+                    // The text lives in the data section; the runtime copies it into a Value
+                    let data_id = Self::string_data(module, data, s)?;
+                    let data = module.declare_data_in_func(data_id, builder.func);
+                    let ptr = builder.ins().global_value(ptr_type, data);
+                    let len = builder.ins().iconst(ptr_type, s.len() as i64);
+                    let string_new_ref =
+                        module.declare_func_in_func(runtime_funcs.string_new, builder.func);
+                    let call = builder.ins().call(string_new_ref, &[ptr, len]);
+                    builder.inst_results(call)[0]
                 }
             };
             constants.push(val);
@@ -389,164 +710,320 @@ impl NativeCodeGen {
         let mut blocks: HashMap<usize, Block> = HashMap::new();
 
         // First pass: create all blocks for jump targets
-        for (_ip, instr) in chunk.code.iter().enumerate() {
+        for instr in &chunk.code {
             match instr {
                 Instruction::Jump(target)
                 | Instruction::JumpIfFalse(target)
                 | Instruction::JumpIfTrue(target) => {
-                    if !blocks.contains_key(target) {
-                        blocks.insert(*target, builder.create_block());
-                    }
+                    blocks
+                        .entry(*target)
+                        .or_insert_with(|| builder.create_block());
                 }
                 _ => {}
             }
         }
 
-        // Create a default fallback block
-        let fallback_block = builder.create_block();
-        builder.switch_to_block(fallback_block);
-
         // Second pass: compile instructions
-        let mut ip = 0;
-
-        while ip < chunk.code.len() {
+        for (ip, instr) in chunk.code.iter().enumerate() {
             // Check if this instruction is a jump target
             if let Some(block) = blocks.get(&ip) {
                 builder.ins().jump(*block, &[]);
                 builder.switch_to_block(*block);
             }
 
-            let instr = &chunk.code[ip];
+            // Every path here leaves the stack equally deep; code no path
+            // reaches is left out
+            let Some(depth) = depths[ip] else {
+                continue;
+            };
+            stack.depth = depth;
+
             match instr {
                 Instruction::LoadConst(idx) => {
-                    stack.push(constants[*idx]);
+                    stack.push(builder, constants[*idx]);
                 }
 
                 Instruction::LoadVar(idx) => {
-                    while locals.len() <= *idx {
-                        let null = builder.ins().iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64);
-                        locals.push(null);
-                    }
-                    stack.push(locals[*idx]);
+                    let value = builder.use_var(local(*idx)?);
+                    stack.push(builder, value);
                 }
 
                 Instruction::StoreVar(idx) => {
-                    let val = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    while locals.len() <= *idx {
-                        let null = builder.ins().iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64);
-                        locals.push(null);
-                    }
-                    locals[*idx] = val;
+                    let val = stack.pop(builder)?;
+                    builder.def_var(local(*idx)?, val);
+                }
+
+                Instruction::LoadGlobal(name) => {
+                    let address = Self::global_address(builder, module, data, name)?;
+                    let value = builder
+                        .ins()
+                        .load(val_type, MemFlags::trusted(), address, 0);
+                    stack.push(builder, value);
+                }
+
+                Instruction::StoreGlobal(name) => {
+                    // The value stays on the stack, as in the VMs
+                    let value = stack.peek(builder)?;
+                    let address = Self::global_address(builder, module, data, name)?;
+                    builder.ins().store(MemFlags::trusted(), value, address, 0);
                 }
 
                 Instruction::Add | Instruction::AddInt => {
-                    let b = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
                     let add_ref = module.declare_func_in_func(runtime_funcs.add, builder.func);
                     let call = builder.ins().call(add_ref, &[a, b]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
+                }
+
+                Instruction::IncrementInt => {
+                    let a = stack.pop(builder)?;
+                    let one = builder.ins().iconst(val_type, 1.0f64.to_bits() as i64);
+                    let add_ref = module.declare_func_in_func(runtime_funcs.add, builder.func);
+                    let call = builder.ins().call(add_ref, &[a, one]);
+                    let result = builder.inst_results(call)[0];
+                    stack.push(builder, result);
                 }
 
                 Instruction::Subtract | Instruction::SubInt => {
-                    let b = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
                     let sub_ref = module.declare_func_in_func(runtime_funcs.subtract, builder.func);
                     let call = builder.ins().call(sub_ref, &[a, b]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
                 }
 
                 Instruction::Multiply | Instruction::MulInt => {
-                    let b = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
                     let mul_ref = module.declare_func_in_func(runtime_funcs.multiply, builder.func);
                     let call = builder.ins().call(mul_ref, &[a, b]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
                 }
 
                 Instruction::Divide => {
-                    let b = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
                     let div_ref = module.declare_func_in_func(runtime_funcs.divide, builder.func);
                     let call = builder.ins().call(div_ref, &[a, b]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
                 }
 
                 Instruction::Equal => {
-                    let b = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
+                    let eq_ref = module.declare_func_in_func(runtime_funcs.equal, builder.func);
+                    let call = builder.ins().call(eq_ref, &[a, b]);
+                    let result = builder.inst_results(call)[0];
+                    stack.push(builder, result);
+                }
+
+                Instruction::NotEqual => {
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
                     let eq_ref = module.declare_func_in_func(runtime_funcs.equal, builder.func);
                     let call = builder.ins().call(eq_ref, &[a, b]);
+                    let equal = builder.inst_results(call)[0];
+                    let not_ref = module.declare_func_in_func(runtime_funcs.not, builder.func);
+                    let call = builder.ins().call(not_ref, &[equal]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
                 }
 
                 Instruction::Less | Instruction::LessInt => {
-                    let b = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
                     let less_ref = module.declare_func_in_func(runtime_funcs.less, builder.func);
                     let call = builder.ins().call(less_ref, &[a, b]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
                 }
 
                 Instruction::Greater => {
-                    let b = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let b = stack.pop(builder)?;
+                    let a = stack.pop(builder)?;
                     let gt_ref = module.declare_func_in_func(runtime_funcs.greater, builder.func);
                     let call = builder.ins().call(gt_ref, &[a, b]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
                 }
 
                 Instruction::Not => {
-                    let a = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let a = stack.pop(builder)?;
                     let not_ref = module.declare_func_in_func(runtime_funcs.not, builder.func);
                     let call = builder.ins().call(not_ref, &[a]);
                     let result = builder.inst_results(call)[0];
-                    stack.push(result);
+                    stack.push(builder, result);
                 }
 
                 Instruction::Print => {
-                    let val = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                    let val = stack.pop(builder)?;
                     let print_ref = module.declare_func_in_func(runtime_funcs.print, builder.func);
                     builder.ins().call(print_ref, &[val]);
                 }
 
                 Instruction::Pop => {
-                    stack.pop();
+                    stack.pop(builder)?;
+                }
+
+                Instruction::Dup => {
+                    let value = stack.peek(builder)?;
+                    stack.push(builder, value);
+                }
+
+                Instruction::MakeArray(size) => {
+                    let items = stack.pop_n(builder, *size)?;
+                    let len = builder.ins().iconst(val_type, *size as i64);
+                    let new_ref =
+                        module.declare_func_in_func(runtime_funcs.array_new, builder.func);
+                    let call = builder.ins().call(new_ref, &[len]);
+                    let array = builder.inst_results(call)[0];
+
+                    let set_ref =
+                        module.declare_func_in_func(runtime_funcs.array_set, builder.func);
+                    for (i, item) in items.into_iter().enumerate() {
+                        let index = builder.ins().iconst(val_type, (i as f64).to_bits() as i64);
+                        builder.ins().call(set_ref, &[array, index, item]);
+                    }
+                    stack.push(builder, array);
+                }
+
+                Instruction::GetIndex => {
+                    let index = stack.pop(builder)?;
+                    let array = stack.pop(builder)?;
+                    let get_ref =
+                        module.declare_func_in_func(runtime_funcs.array_get, builder.func);
+                    let call = builder.ins().call(get_ref, &[array, index]);
+                    let result = builder.inst_results(call)[0];
+                    stack.push(builder, result);
+                }
+
+                Instruction::SetIndex => {
+                    let value = stack.pop(builder)?;
+                    let index = stack.pop(builder)?;
+                    let array = stack.pop(builder)?;
+                    // Other variables may hold the same array, so the runtime
+                    // writes to a copy, which the VMs push in its place
+                    let with_ref =
+                        module.declare_func_in_func(runtime_funcs.array_with, builder.func);
+                    let call = builder.ins().call(with_ref, &[array, index, value]);
+                    let result = builder.inst_results(call)[0];
+                    stack.push(builder, result);
+                }
+
+                Instruction::Length => {
+                    let value = stack.pop(builder)?;
+                    let length_ref =
+                        module.declare_func_in_func(runtime_funcs.length, builder.func);
+                    let call = builder.ins().call(length_ref, &[value]);
+                    let result = builder.inst_results(call)[0];
+                    stack.push(builder, result);
+                }
+
+                Instruction::ExpectArray => {
+                    let value = stack.pop(builder)?;
+                    let expect_ref =
+                        module.declare_func_in_func(runtime_funcs.expect_array, builder.func);
+                    let call = builder.ins().call(expect_ref, &[value]);
+                    let result = builder.inst_results(call)[0];
+                    stack.push(builder, result);
+                }
+
+                Instruction::Call(_, arity)
+                | Instruction::CallIndex(_, arity)
+                | Instruction::TailCall(_, arity)
+                | Instruction::TailCallIndex(_, arity) => {
+                    let func_id = match instr {
+                        Instruction::CallIndex(id, _) | Instruction::TailCallIndex(id, _) => {
+                            functions[*id].1
+                        }
+                        Instruction::Call(name, _) | Instruction::TailCall(name, _) => {
+                            function_id(functions, name)
+                                .ok_or_else(|| anyhow!("Undefined function: {}", name))?
+                        }
+                        _ => unreachable!(),
+                    };
+                    let args = stack.pop_n(builder, *arity)?;
+                    let func_ref = module.declare_func_in_func(func_id, builder.func);
+                    let call = builder.ins().call(func_ref, &args);
+                    let result = builder.inst_results(call)[0];
+
+                    // A tail call is an ordinary call whose result is returned
+                    // straight away, so deep tail recursion still grows the
+                    // native stack
+                    if matches!(
+                        instr,
+                        Instruction::TailCall(..) | Instruction::TailCallIndex(..)
+                    ) {
+                        builder.ins().return_(&[result]);
+                        let unreachable = builder.create_block();
+                        builder.switch_to_block(unreachable);
+                    } else {
+                        stack.push(builder, result);
+                    }
                 }
 
                 Instruction::Return => {
-                    let val = stack.pop().unwrap_or_else(|| {
-                        builder.ins().iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64)
-                    });
-                    return Ok(val);
+                    let val = stack.pop(builder)?;
+                    builder.ins().return_(&[val]);
+                    // Create unreachable block for subsequent instructions
+                    let unreachable = builder.create_block();
+                    builder.switch_to_block(unreachable);
                 }
 
                 Instruction::ReturnNull => {
-                    let null = builder.ins().iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64);
-                    return Ok(null);
+                    let null = builder
+                        .ins()
+                        .iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64);
+                    builder.ins().return_(&[null]);
+                    let unreachable = builder.create_block();
+                    builder.switch_to_block(unreachable);
                 }
 
-                Instruction::JumpIfFalse(target) => {
-                    let condition = stack.pop().ok_or_else(|| anyhow!("Stack underflow"))?;
+                Instruction::JumpIfFalse(target) | Instruction::JumpIfTrue(target) => {
+                    let condition = stack.pop(builder)?;
 
-                    // Check if value is truthy (not null, not false, not 0)
-                    let tag_true = builder.ins().iconst(val_type, 0x7FF8_0000_0000_0002u64 as i64);
+                    // Only the true tag counts as true
+                    let tag_true = builder
+                        .ins()
+                        .iconst(val_type, 0x7FF8_0000_0000_0002u64 as i64);
                     let is_true = builder.ins().icmp(IntCC::Equal, condition, tag_true);
 
-                    let then_block = blocks[target];
-                    let else_block = builder.create_block();
+                    let target_block = blocks[target];
+                    let next_block = builder.create_block();
+
+                    if matches!(instr, Instruction::JumpIfTrue(_)) {
+                        builder
+                            .ins()
+                            .brif(is_true, target_block, &[], next_block, &[]);
+                    } else {
+                        builder
+                            .ins()
+                            .brif(is_true, next_block, &[], target_block, &[]);
+                    }
+                    builder.switch_to_block(next_block);
+                    builder.seal_block(next_block);
+                }
 
-                    builder.ins().brif(is_true, else_block, &[], then_block, &[]);
-                    builder.switch_to_block(else_block);
-                    builder.seal_block(else_block);
+                Instruction::Exit => {
+                    let code = stack.pop(builder)?;
+                    let exit_ref = module.declare_func_in_func(runtime_funcs.exit, builder.func);
+                    builder.ins().call(exit_ref, &[code]);
+                    // The call never returns, but the block still needs an end
+                    let null = builder
+                        .ins()
+                        .iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64);
+                    builder.ins().return_(&[null]);
+                    let unreachable = builder.create_block();
+                    builder.switch_to_block(unreachable);
                 }
 
+                Instruction::Nop => {}
+
                 Instruction::Jump(target) => {
                     let target_block = blocks[target];
                     builder.ins().jump(target_block, &[]);
@@ -556,16 +1033,235 @@ impl NativeCodeGen {
                 }
 
                 _ => {
-                    // Other instructions not yet implemented
-                    eprintln!("Warning: Instruction {:?} not yet implemented in native codegen", instr);
+                    return Err(anyhow!(
+                        "{:?} is not supported by the native backend",
+                        instr
+                    ))
                 }
             }
-
-            ip += 1;
         }
 
         // If we get here without returning, return null
-        let null = builder.ins().iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64);
-        Ok(null)
+        let null = builder
+            .ins()
+            .iconst(val_type, 0x7FF8_0000_0000_0001u64 as i64);
+        builder.ins().return_(&[null]);
+        Ok(())
+    }
+}
+
+/// The bytecode value stack, held in one Cranelift variable per depth so a
+/// value pushed before a jump is still there in the block it jumps to
+struct OperandStack {
+    /// Variable holding the bottom of the stack, after the locals
+    base: usize,
+    depth: usize,
+}
+
+impl OperandStack {
+    fn new(builder: &mut FunctionBuilder, base: usize, max_depth: usize) -> Self {
+        for depth in 0..max_depth {
+            builder.declare_var(Variable::from_u32((base + depth) as u32), types::I64);
+        }
+        OperandStack { base, depth: 0 }
+    }
+
+    fn variable(&self, depth: usize) -> Variable {
+        Variable::from_u32((self.base + depth) as u32)
+    }
+
+    fn push(&mut self, builder: &mut FunctionBuilder, value: cranelift::prelude::Value) {
+        builder.def_var(self.variable(self.depth), value);
+        self.depth += 1;
+    }
+
+    fn pop(&mut self, builder: &mut FunctionBuilder) -> Result<cranelift::prelude::Value> {
+        self.depth = self
+            .depth
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("Stack underflow"))?;
+        Ok(builder.use_var(self.variable(self.depth)))
+    }
+
+    /// The top `count` values, deepest first
+    fn pop_n(
+        &mut self,
+        builder: &mut FunctionBuilder,
+        count: usize,
+    ) -> Result<Vec<cranelift::prelude::Value>> {
+        let mut values = (0..count)
+            .map(|_| self.pop(builder))
+            .collect::<Result<Vec<_>>>()?;
+        values.reverse();
+        Ok(values)
+    }
+
+    fn peek(&self, builder: &mut FunctionBuilder) -> Result<cranelift::prelude::Value> {
+        let top = self
+            .depth
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("Stack underflow"))?;
+        Ok(builder.use_var(self.variable(top)))
+    }
+}
+
+/// Compiled ID of the function called `name`
+fn function_id(
+    functions: &[(String, cranelift_module::FuncId)],
+    name: &str,
+) -> Option<cranelift_module::FuncId> {
+    functions
+        .iter()
+        .find(|(function, _)| function == name)
+        .map(|(_, id)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::process::Command;
+
+    /// Compile `source` to an object file, link it against the runtime
+    /// library and return the program's stdout
+    fn compile_and_run(name: &str, source: &str) -> String {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().expect("Failed to parse");
+        let chunk = Compiler::new().compile(program).expect("Failed to compile");
+        let object = NativeCodeGen::new().unwrap().compile_chunk(&chunk).unwrap();
+
+        // The runtime staticlib sits next to the test binary's deps directory
+        let exe_dir = std::env::current_exe().unwrap();
+        let runtime = exe_dir
+            .parent()
+            .and_then(|deps| deps.parent())
+            .unwrap()
+            .join("libtoplang.a");
+
+        let dir =
+            std::env::temp_dir().join(format!("toplang_native_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let obj_file = dir.join("out.o");
+        let exe = dir.join("out");
+        std::fs::write(&obj_file, object).unwrap();
+
+        let status = Command::new("cc")
+            .arg(&obj_file)
+            .arg(&runtime)
+            .arg("-o")
+            .arg(&exe)
+            .args(["-lpthread", "-ldl", "-lm"])
+            .status()
+            .expect("Failed to run linker");
+        assert!(status.success(), "Linking failed");
+
+        let output = Command::new(&exe).output().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_call_with_arguments() {
+        let source = r#"
+function add(a, b) {
+    return a plus b
+}
+
+function main() {
+    print add(2, 3)
+    print add(add(1, 2), 10)
+    return 0
+}
+"#;
+        assert_eq!(compile_and_run("add", source), "5\n13\n");
+    }
+
+    #[test]
+    fn test_string_constants() {
+        let source = r#"
+function greet() {
+    print "hello"
+}
+
+function main() {
+    print "hello"
+    greet()
+    print ""
+    return 0
+}
+"#;
+        assert_eq!(compile_and_run("strings", source), "hello\nhello\n\n");
+    }
+
+    #[test]
+    fn test_arrays() {
+        let source = r#"
+function second(items) {
+    return items at 1
+}
+
+function main() {
+    var items is list 10, 20, 30
+    print items at 0
+    print second(items)
+    items at 2 is 99
+    print items at 2
+    print length of items
+    return 0
+}
+"#;
+        assert_eq!(compile_and_run("arrays", source), "10\n20\n99\n3\n");
+    }
+
+    #[test]
+    fn test_jit_exit_code_matches_interpreter() {
+        let source = r#"
+function add(a, b) {
+    return a plus b
+}
+
+function main() {
+    var total is add(2, 3)
+    return total times 4
+}
+"#;
+        let parse = || {
+            let tokens = Lexer::new(source.to_string()).tokenize();
+            Parser::new(tokens).parse().expect("Failed to parse")
+        };
+        let expected = crate::interpreter::Interpreter::new()
+            .interpret(parse())
+            .unwrap();
+        let chunk = Compiler::new().compile(parse()).expect("Failed to compile");
+
+        let exit_code = NativeCodeGen::jit().unwrap().run(&chunk).unwrap();
+        assert_eq!(exit_code, expected);
+        assert_eq!(exit_code, 20);
+    }
+
+    #[test]
+    fn test_globals_live_in_data_objects() {
+        let source = r#"
+function bump() {
+    count is count plus 1
+    return 0
+}
+
+function main() {
+    count is 40
+    bump()
+    bump()
+    print count
+    return count
+}
+"#;
+        assert_eq!(compile_and_run("globals", source), "42\n");
+
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let chunk = Compiler::new().compile(program).unwrap();
+        assert_eq!(NativeCodeGen::jit().unwrap().run(&chunk).unwrap(), 42);
     }
 }
@@ -0,0 +1,1059 @@
+/// WebAssembly code generator for TopLang
+///
+/// Translates bytecode into a standalone `.wasm` module (`--target wasm`),
+/// so programs can run in a browser or any other WebAssembly host.
+///
+/// ## Host interface
+///
+/// The module imports three functions from `env`:
+///
+/// - `print_number(value: f64)`: print a number and a newline, formatted the
+///   way TopLang does (`3`, not `3.0`)
+/// - `print_string(ptr: i32, len: i32)`: print the `len` bytes of UTF-8 text
+///   at `ptr` in the exported memory, and a newline
+/// - `pow(base: f64, exponent: f64) -> f64`: `base` raised to `exponent`,
+///   like JavaScript's `Math.pow`
+///
+/// and exports `memory` and `main`, which takes no arguments and returns
+/// the program's exit code as an `f64`.
+///
+/// ## Supported subset
+///
+/// Numbers and booleans are `f64`s at runtime. Each value's type is worked
+/// out at compile time, which lets `print` show booleans as `true`/`false`
+/// and lets string constants be printed straight from the data section.
+/// Functions take numbers and return numbers or booleans. Arrays, maps,
+/// string operations, globals and `ask` are rejected with an error, and
+/// division by zero traps instead of reporting an error.
+use crate::builtins::Builtin;
+use crate::bytecode::{Chunk, Constant, Instruction};
+use crate::registers;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeSet, HashMap};
+
+/// Function indices of the imported host functions; the program's own
+/// functions follow them
+const PRINT_NUMBER: u32 = 0;
+const PRINT_STRING: u32 = 1;
+const POW: u32 = 2;
+const IMPORT_COUNT: u32 = 3;
+
+const F64: u8 = 0x7C;
+const I32: u8 = 0x7F;
+/// Block type of blocks that leave nothing on the stack
+const EMPTY: u8 = 0x40;
+
+/// Type of a value, known at compile time
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Number,
+    Bool,
+    /// A string constant, by its index in `WasmCodeGen::strings`
+    Str(usize),
+    Null,
+    /// A variable holding values of different types on different paths
+    Mixed,
+}
+
+impl Ty {
+    /// Whether values of this type live in a wasm local at runtime; the
+    /// others are known from the type alone
+    fn at_runtime(self) -> bool {
+        matches!(self, Ty::Number | Ty::Bool)
+    }
+}
+
+/// Types of the stack and the locals before an instruction
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    stack: Vec<Ty>,
+    locals: Vec<Ty>,
+}
+
+pub struct WasmCodeGen {
+    /// Text of each string constant, including those `print` needs for
+    /// booleans and null
+    strings: Vec<String>,
+    string_ids: HashMap<String, usize>,
+    /// Type each function returns
+    returns: HashMap<String, Ty>,
+    /// The program's functions in the order `CallIndex` numbers them
+    names: Vec<String>,
+}
+
+impl Default for WasmCodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WasmCodeGen {
+    pub fn new() -> Self {
+        let mut codegen = WasmCodeGen {
+            strings: Vec::new(),
+            string_ids: HashMap::new(),
+            returns: HashMap::new(),
+            names: Vec::new(),
+        };
+        for text in ["true", "false", "null"] {
+            codegen.intern(text);
+        }
+        codegen
+    }
+
+    /// Compile a chunk to the bytes of a wasm module
+    pub fn compile_chunk(&mut self, chunk: &Chunk) -> Result<Vec<u8>> {
+        let names = chunk.function_names();
+        self.names = names.iter().map(|name| name.to_string()).collect();
+        match chunk.functions.get("main") {
+            Some(main) if main.arity == 0 => {}
+            Some(_) => return Err(anyhow!("main must not take parameters")),
+            None => return Err(anyhow!("Program has no main function")),
+        }
+
+        let states = self.infer_types(chunk, &names)?;
+
+        let indices: HashMap<&str, u32> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.as_str(), IMPORT_COUNT + i as u32))
+            .collect();
+        let (data, offsets) = self.data();
+        let mut bodies = Vec::new();
+        for name in &names {
+            let function = &chunk.functions[*name];
+            let body = FunctionBuilder {
+                chunk: function,
+                states: &states[*name],
+                strings: &self.strings,
+                offsets: &offsets,
+                indices: &indices,
+                names: &self.names,
+            }
+            .build()
+            .with_context(|| format!("in function '{}'", name))?;
+            bodies.push(body);
+        }
+
+        Ok(module(&names, chunk, &indices, &bodies, &data))
+    }
+
+    fn intern(&mut self, text: &str) -> usize {
+        if let Some(&id) = self.string_ids.get(text) {
+            return id;
+        }
+        self.strings.push(text.to_string());
+        self.string_ids
+            .insert(text.to_string(), self.strings.len() - 1);
+        self.strings.len() - 1
+    }
+
+    /// The contents of memory, and where each string starts in it
+    fn data(&self) -> (Vec<u8>, Vec<u32>) {
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for text in &self.strings {
+            offsets.push(data.len() as u32);
+            data.extend_from_slice(text.as_bytes());
+        }
+        (data, offsets)
+    }
+
+    /// Types before every instruction of every function. Return types feed
+    /// into the callers' types, so this repeats until they settle.
+    fn infer_types(
+        &mut self,
+        chunk: &Chunk,
+        names: &[&String],
+    ) -> Result<HashMap<String, Vec<Option<State>>>> {
+        self.returns = names
+            .iter()
+            .map(|name| (name.to_string(), Ty::Number))
+            .collect();
+
+        for _ in 0..=names.len() {
+            let mut states = HashMap::new();
+            let mut settled = true;
+            for name in names {
+                let (function_states, returned) = self
+                    .infer_function(&chunk.functions[*name])
+                    .with_context(|| format!("in function '{}'", name))?;
+                if let Some(returned) = returned {
+                    if self.returns.insert(name.to_string(), returned) != Some(returned) {
+                        settled = false;
+                    }
+                }
+                states.insert(name.to_string(), function_states);
+            }
+            if settled {
+                return Ok(states);
+            }
+        }
+        Err(anyhow!("Cannot work out the return types of the functions"))
+    }
+
+    /// Types before each instruction of `chunk` (`None` where unreachable)
+    /// and the type it returns, if it returns at all
+    fn infer_function(&mut self, chunk: &Chunk) -> Result<(Vec<Option<State>>, Option<Ty>)> {
+        let mut locals = vec![Ty::Null; chunk.local_count];
+        for local in locals.iter_mut().take(chunk.arity) {
+            *local = Ty::Number;
+        }
+        let mut states: Vec<Option<State>> = vec![None; chunk.code.len()];
+        let mut pending = vec![(
+            0,
+            State {
+                stack: Vec::new(),
+                locals,
+            },
+        )];
+        let mut returned: Option<Ty> = None;
+
+        while let Some((ip, state)) = pending.pop() {
+            // Running off the end traps; see FunctionBuilder::build
+            if ip >= chunk.code.len() {
+                continue;
+            }
+            let state = match &states[ip] {
+                None => state,
+                Some(known) => {
+                    let merged =
+                        merge(known, &state).with_context(|| format!("at instruction {}", ip))?;
+                    if &merged == known {
+                        continue;
+                    }
+                    merged
+                }
+            };
+            states[ip] = Some(state.clone());
+
+            let instruction = &chunk.code[ip];
+            let (after, value) = self
+                .step(chunk, instruction, state)
+                .with_context(|| format!("at instruction {}", ip))?;
+            if let Some(value) = value {
+                returned = match returned {
+                    Some(other) if other != value => {
+                        return Err(anyhow!("Function returns values of different types"));
+                    }
+                    _ => Some(value),
+                };
+            }
+
+            match instruction {
+                Instruction::Jump(target) => pending.push((*target, after)),
+                Instruction::JumpIfFalse(target) | Instruction::JumpIfTrue(target) => {
+                    pending.push((*target, after.clone()));
+                    pending.push((ip + 1, after));
+                }
+                Instruction::Return
+                | Instruction::ReturnNull
+                | Instruction::TailCall(_, _)
+                | Instruction::TailCallIndex(_, _)
+                | Instruction::Halt => {}
+                _ => pending.push((ip + 1, after)),
+            }
+        }
+
+        Ok((states, returned))
+    }
+
+    /// The state after `instruction`, and the type it returns if it's a return
+    fn step(
+        &mut self,
+        chunk: &Chunk,
+        instruction: &Instruction,
+        mut state: State,
+    ) -> Result<(State, Option<Ty>)> {
+        use Instruction::*;
+
+        let stack = &mut state.stack;
+        let mut pop = |count: usize| -> Result<Vec<Ty>> {
+            if stack.len() < count {
+                return Err(anyhow!("Stack underflow"));
+            }
+            Ok(stack.split_off(stack.len() - count))
+        };
+        let numbers = |operands: &[Ty]| -> Result<()> {
+            if operands.iter().all(|ty| *ty == Ty::Number) {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "{} only works on numbers in the wasm backend",
+                    name(instruction)
+                ))
+            }
+        };
+
+        let mut returned = None;
+        let pushed = match instruction {
+            LoadConst(index) => Some(match &chunk.constants[*index] {
+                Constant::Number(_) | Constant::Integer(_) => Ty::Number,
+                Constant::Boolean(_) => Ty::Bool,
+                Constant::String(text) => Ty::Str(self.intern(text)),
+                Constant::Null => Ty::Null,
+            }),
+            LoadVar(slot) => match state.locals[*slot] {
+                Ty::Mixed => {
+                    return Err(anyhow!(
+                        "Variable in slot {} holds values of different types",
+                        slot
+                    ))
+                }
+                ty => Some(ty),
+            },
+            StoreVar(slot) => {
+                state.locals[*slot] = pop(1)?[0];
+                None
+            }
+            Add | Subtract | Multiply | Divide | IntDivide | Modulo | Power | AddInt | SubInt
+            | MulInt => {
+                numbers(&pop(2)?)?;
+                Some(Ty::Number)
+            }
+            Negate | IncrementInt => {
+                numbers(&pop(1)?)?;
+                Some(Ty::Number)
+            }
+            Less | LessEqual | Greater | GreaterEqual | LessInt => {
+                numbers(&pop(2)?)?;
+                Some(Ty::Bool)
+            }
+            Equal | NotEqual | And | Or => {
+                pop(2)?;
+                Some(Ty::Bool)
+            }
+            Not => {
+                pop(1)?;
+                Some(Ty::Bool)
+            }
+            JumpIfFalse(_) | JumpIfTrue(_) | Pop | Print => {
+                pop(1)?;
+                None
+            }
+            Dup => {
+                let top = pop(1)?[0];
+                state.stack.extend([top, top]);
+                None
+            }
+            Call(_, arity) | TailCall(_, arity) | CallIndex(_, arity) | TailCallIndex(_, arity) => {
+                let callee = instruction.callee(&self.names).unwrap();
+                let args = pop(*arity)?;
+                if args.iter().any(|ty| *ty != Ty::Number) {
+                    return Err(anyhow!(
+                        "Only numbers can be passed to functions in the wasm backend"
+                    ));
+                }
+                let result = *self
+                    .returns
+                    .get(callee)
+                    .ok_or_else(|| anyhow!("Undefined function: {}", callee))?;
+                if matches!(instruction, TailCall(_, _) | TailCallIndex(_, _)) {
+                    returned = Some(result);
+                    None
+                } else {
+                    Some(result)
+                }
+            }
+            CallBuiltin(builtin, arity) => {
+                if !supported_builtin(*builtin) {
+                    return Err(anyhow!(
+                        "{} is not supported by the wasm backend",
+                        builtin.name()
+                    ));
+                }
+                numbers(&pop(*arity)?)?;
+                Some(Ty::Number)
+            }
+            Return => {
+                let value = pop(1)?[0];
+                if !matches!(value, Ty::Number | Ty::Bool | Ty::Null) {
+                    return Err(anyhow!(
+                        "Functions can only return numbers and booleans in the wasm backend"
+                    ));
+                }
+                returned = Some(value);
+                None
+            }
+            ReturnNull => {
+                returned = Some(Ty::Null);
+                None
+            }
+            Jump(_) | Halt | Nop => None,
+            _ => {
+                return Err(anyhow!(
+                    "{} is not supported by the wasm backend",
+                    name(instruction)
+                ))
+            }
+        };
+        if let Some(ty) = pushed {
+            state.stack.push(ty);
+        }
+        Ok((state, returned))
+    }
+}
+
+fn name(instruction: &Instruction) -> &'static str {
+    Instruction::NAMES[instruction.opcode() as usize]
+}
+
+fn supported_builtin(builtin: Builtin) -> bool {
+    matches!(
+        builtin,
+        Builtin::Floor
+            | Builtin::Ceil
+            | Builtin::Round
+            | Builtin::Abs
+            | Builtin::Sqrt
+            | Builtin::Min
+            | Builtin::Max
+    )
+}
+
+/// Combine the states of two paths reaching the same instruction
+fn merge(a: &State, b: &State) -> Result<State> {
+    if a.stack != b.stack {
+        return Err(anyhow!(
+            "Reached with different values on the stack: {:?} and {:?}",
+            a.stack,
+            b.stack
+        ));
+    }
+    Ok(State {
+        stack: a.stack.clone(),
+        locals: a
+            .locals
+            .iter()
+            .zip(&b.locals)
+            .map(|(x, y)| if x == y { *x } else { Ty::Mixed })
+            .collect(),
+    })
+}
+
+/// Generates the body of one function.
+///
+/// Wasm only has structured control flow, so the body is a loop around a
+/// `br_table` that dispatches on a program counter local to the basic block
+/// to run next. Blocks fall through to the following block, and jumps set
+/// the counter and branch back to the loop. Stack slots and variables are
+/// all locals, so nothing is left on the wasm stack between instructions.
+struct FunctionBuilder<'a> {
+    chunk: &'a Chunk,
+    states: &'a [Option<State>],
+    strings: &'a [String],
+    offsets: &'a [u32],
+    indices: &'a HashMap<&'a str, u32>,
+    /// The program's functions in the order `CallIndex` numbers them
+    names: &'a [String],
+}
+
+impl FunctionBuilder<'_> {
+    fn build(&self) -> Result<Vec<u8>> {
+        let code = &self.chunk.code;
+        let max_stack = registers::max_stack_depth(self.chunk)?;
+        let pc = (self.chunk.local_count + max_stack) as u32;
+
+        // Basic blocks start at jump targets and after jumps and returns;
+        // a jump to the end of the code gets a block that traps
+        let mut starts = BTreeSet::from([0]);
+        for (ip, instruction) in code.iter().enumerate() {
+            match instruction {
+                Instruction::Jump(target)
+                | Instruction::JumpIfFalse(target)
+                | Instruction::JumpIfTrue(target) => {
+                    starts.insert(*target);
+                    starts.insert(ip + 1);
+                }
+                Instruction::Return
+                | Instruction::ReturnNull
+                | Instruction::TailCall(_, _)
+                | Instruction::TailCallIndex(_, _)
+                | Instruction::Halt => {
+                    starts.insert(ip + 1);
+                }
+                _ => {}
+            }
+        }
+        let starts: Vec<usize> = starts.into_iter().filter(|&s| s <= code.len()).collect();
+        let block_of: HashMap<usize, u32> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| (start, i as u32))
+            .collect();
+        let count = starts.len() as u32;
+
+        let mut out = Code::default();
+        // Variables other than parameters and the stack slots are f64s;
+        // the program counter is an i32
+        let f64_locals = (self.chunk.local_count - self.chunk.arity + max_stack) as u32;
+        out.uleb(2);
+        out.uleb(f64_locals);
+        out.byte(F64);
+        out.uleb(1);
+        out.byte(I32);
+
+        out.byte(0x03); // loop
+        out.byte(EMPTY);
+        for _ in 0..count {
+            out.byte(0x02); // block
+            out.byte(EMPTY);
+        }
+        out.local_get(pc);
+        out.byte(0x0E); // br_table
+        out.uleb(count);
+        for label in 0..count {
+            out.uleb(label);
+        }
+        out.uleb(0);
+
+        for (block, &start) in starts.iter().enumerate() {
+            out.byte(0x0B); // end of the block the br_table jumps past
+            let end = starts.get(block + 1).copied().unwrap_or(code.len());
+            // Depth of the dispatch loop from inside this block's code
+            let dispatch = count - 1 - block as u32;
+            if start == code.len() {
+                out.byte(0x00); // unreachable
+            }
+            let instructions = code[start..end].iter().zip(&self.states[start..end]);
+            for (ip, (instruction, state)) in (start..).zip(instructions) {
+                if let Some(state) = state {
+                    self.instruction(&mut out, instruction, state, dispatch, pc, &block_of)
+                        .with_context(|| format!("at instruction {}", ip))?;
+                }
+            }
+        }
+        out.byte(0x0B); // end loop
+        out.byte(0x00); // unreachable: every block ends in a jump or return
+        out.byte(0x0B); // end function
+
+        let mut body = Code::default();
+        body.uleb(out.bytes.len() as u32);
+        body.bytes.extend(out.bytes);
+        Ok(body.bytes)
+    }
+
+    /// Local holding stack slot `depth`
+    fn slot(&self, depth: usize) -> u32 {
+        (self.chunk.local_count + depth) as u32
+    }
+
+    fn instruction(
+        &self,
+        out: &mut Code,
+        instruction: &Instruction,
+        state: &State,
+        dispatch: u32,
+        pc: u32,
+        block_of: &HashMap<usize, u32>,
+    ) -> Result<()> {
+        use Instruction::*;
+
+        let depth = state.stack.len();
+        let top = || self.slot(depth - 1);
+        let second = || self.slot(depth - 2);
+        let jump = |out: &mut Code, target: usize, extra_depth: u32| {
+            out.i32_const(block_of[&target] as i32);
+            out.local_set(pc);
+            out.byte(0x0C); // br
+            out.uleb(dispatch + extra_depth);
+        };
+
+        match instruction {
+            LoadConst(index) => match &self.chunk.constants[*index] {
+                Constant::Number(n) => {
+                    out.f64_const(*n);
+                    out.local_set(self.slot(depth));
+                }
+                // Wasm locals hold every number as an f64
+                Constant::Integer(n) => {
+                    out.f64_const(*n as f64);
+                    out.local_set(self.slot(depth));
+                }
+                Constant::Boolean(b) => {
+                    out.f64_const(if *b { 1.0 } else { 0.0 });
+                    out.local_set(self.slot(depth));
+                }
+                Constant::String(_) | Constant::Null => {}
+            },
+            LoadVar(index) => {
+                if state.locals[*index].at_runtime() {
+                    out.local_get(*index as u32);
+                    out.local_set(self.slot(depth));
+                }
+            }
+            StoreVar(index) => {
+                if state.stack[depth - 1].at_runtime() {
+                    out.local_get(top());
+                    out.local_set(*index as u32);
+                }
+            }
+            Add | AddInt => self.binary(out, depth, &[0xA0]),
+            Subtract | SubInt => self.binary(out, depth, &[0xA1]),
+            Multiply | MulInt => self.binary(out, depth, &[0xA2]),
+            Divide => {
+                self.trap_if_zero(out, top());
+                self.binary(out, depth, &[0xA3]);
+            }
+            IntDivide => {
+                self.trap_if_zero(out, top());
+                self.binary(out, depth, &[0xA3, 0x9C]); // div, floor
+            }
+            Modulo => {
+                // a - b * trunc(a / b), the sign following a like Rust's %
+                self.trap_if_zero(out, top());
+                out.local_get(second());
+                out.local_get(second());
+                out.local_get(top());
+                out.byte(0xA3); // div
+                out.byte(0x9D); // trunc
+                out.local_get(top());
+                out.byte(0xA2); // mul
+                out.byte(0xA1); // sub
+                out.local_set(second());
+            }
+            Power => {
+                out.local_get(second());
+                out.local_get(top());
+                out.call(POW);
+                out.local_set(second());
+            }
+            Negate => {
+                out.local_get(top());
+                out.byte(0x9A); // neg
+                out.local_set(top());
+            }
+            IncrementInt => {
+                out.local_get(top());
+                out.f64_const(1.0);
+                out.byte(0xA0); // add
+                out.local_set(top());
+            }
+            Less | LessInt => self.comparison(out, depth, 0x63),
+            Greater => self.comparison(out, depth, 0x64),
+            LessEqual => self.comparison(out, depth, 0x65),
+            GreaterEqual => self.comparison(out, depth, 0x66),
+            Equal | NotEqual => {
+                let (a, b) = (state.stack[depth - 2], state.stack[depth - 1]);
+                let negate = matches!(instruction, NotEqual);
+                match (a, b) {
+                    (Ty::Number, Ty::Number) | (Ty::Bool, Ty::Bool) => {
+                        // Numbers within epsilon of each other are equal, as in the VMs
+                        out.local_get(second());
+                        out.local_get(top());
+                        out.byte(0xA1); // sub
+                        out.byte(0x99); // abs
+                        out.f64_const(f64::EPSILON);
+                        out.byte(if negate { 0x66 } else { 0x63 }); // ge / lt
+                        out.byte(0xB8); // f64.convert_i32_u
+                    }
+                    _ => {
+                        let equal = match (a, b) {
+                            (Ty::Str(x), Ty::Str(y)) => x == y,
+                            (Ty::Null, Ty::Null) => true,
+                            _ => false,
+                        };
+                        out.f64_const(if equal != negate { 1.0 } else { 0.0 });
+                    }
+                }
+                out.local_set(second());
+            }
+            And | Or => {
+                self.truthy(out, state.stack[depth - 2], second());
+                self.truthy(out, state.stack[depth - 1], top());
+                out.byte(if matches!(instruction, And) {
+                    0x71
+                } else {
+                    0x72
+                });
+                out.byte(0xB8); // f64.convert_i32_u
+                out.local_set(second());
+            }
+            Not => {
+                self.truthy(out, state.stack[depth - 1], top());
+                out.byte(0x45); // i32.eqz
+                out.byte(0xB8); // f64.convert_i32_u
+                out.local_set(top());
+            }
+            Jump(target) => jump(out, *target, 0),
+            JumpIfFalse(target) | JumpIfTrue(target) => {
+                let ty = state.stack[depth - 1];
+                let when = matches!(instruction, JumpIfTrue(_));
+                match self.known_truthiness(ty) {
+                    Some(truthy) if truthy == when => jump(out, *target, 0),
+                    Some(_) => {}
+                    None => {
+                        self.truthy(out, ty, top());
+                        if !when {
+                            out.byte(0x45); // i32.eqz
+                        }
+                        out.byte(0x04); // if
+                        out.byte(EMPTY);
+                        jump(out, *target, 1);
+                        out.byte(0x0B);
+                    }
+                }
+            }
+            Call(_, arity) | TailCall(_, arity) | CallIndex(_, arity) | TailCallIndex(_, arity) => {
+                let callee = instruction.callee(self.names).unwrap();
+                for i in depth - arity..depth {
+                    out.local_get(self.slot(i));
+                }
+                out.call(self.indices[callee]);
+                if matches!(instruction, TailCall(_, _) | TailCallIndex(_, _)) {
+                    out.byte(0x0F); // return
+                } else {
+                    out.local_set(self.slot(depth - arity));
+                }
+            }
+            CallBuiltin(builtin, arity) => {
+                let first = self.slot(depth - arity);
+                out.local_get(first);
+                match builtin {
+                    Builtin::Floor => out.byte(0x9C),
+                    Builtin::Ceil => out.byte(0x9B),
+                    Builtin::Abs => out.byte(0x99),
+                    Builtin::Sqrt => out.byte(0x9F),
+                    Builtin::Round => {
+                        // Halves round away from zero, as in Rust: copysign(floor(|x| + 0.5), x)
+                        out.byte(0x99); // abs
+                        out.f64_const(0.5);
+                        out.byte(0xA0); // add
+                        out.byte(0x9C); // floor
+                        out.local_get(first);
+                        out.byte(0xA6); // copysign
+                    }
+                    Builtin::Min | Builtin::Max => {
+                        out.local_get(top());
+                        out.byte(if *builtin == Builtin::Min { 0xA4 } else { 0xA5 });
+                    }
+                    _ => unreachable!("rejected while inferring types"),
+                }
+                out.local_set(first);
+            }
+            Return => {
+                if state.stack[depth - 1].at_runtime() {
+                    out.local_get(top());
+                } else {
+                    out.f64_const(0.0);
+                }
+                out.byte(0x0F);
+            }
+            ReturnNull | Halt => {
+                out.f64_const(0.0);
+                out.byte(0x0F);
+            }
+            Print => match state.stack[depth - 1] {
+                Ty::Number => {
+                    out.local_get(top());
+                    out.call(PRINT_NUMBER);
+                }
+                Ty::Bool => {
+                    self.truthy(out, Ty::Bool, top());
+                    out.byte(0x04); // if
+                    out.byte(EMPTY);
+                    self.print_string(out, 0);
+                    out.byte(0x05); // else
+                    self.print_string(out, 1);
+                    out.byte(0x0B);
+                }
+                Ty::Str(id) => self.print_string(out, id),
+                Ty::Null => self.print_string(out, 2),
+                Ty::Mixed => unreachable!("never on the stack"),
+            },
+            Dup => {
+                if state.stack[depth - 1].at_runtime() {
+                    out.local_get(top());
+                    out.local_set(self.slot(depth));
+                }
+            }
+            Pop | Nop => {}
+            _ => unreachable!("rejected while inferring types"),
+        }
+        Ok(())
+    }
+
+    /// Replace the two numbers on top of the stack by `ops` applied to them
+    fn binary(&self, out: &mut Code, depth: usize, ops: &[u8]) {
+        out.local_get(self.slot(depth - 2));
+        out.local_get(self.slot(depth - 1));
+        for op in ops {
+            out.byte(*op);
+        }
+        out.local_set(self.slot(depth - 2));
+    }
+
+    fn comparison(&self, out: &mut Code, depth: usize, op: u8) {
+        self.binary(out, depth, &[op, 0xB8]); // compare, f64.convert_i32_u
+    }
+
+    fn trap_if_zero(&self, out: &mut Code, local: u32) {
+        out.local_get(local);
+        out.f64_const(0.0);
+        out.byte(0x61); // eq
+        out.byte(0x04); // if
+        out.byte(EMPTY);
+        out.byte(0x00); // unreachable
+        out.byte(0x0B);
+    }
+
+    /// Truthiness of values whose type alone decides it
+    fn known_truthiness(&self, ty: Ty) -> Option<bool> {
+        match ty {
+            Ty::Str(id) => Some(!self.strings[id].is_empty()),
+            Ty::Null => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Push whether the value in `local` is truthy, as an i32
+    fn truthy(&self, out: &mut Code, ty: Ty, local: u32) {
+        match self.known_truthiness(ty) {
+            Some(truthy) => out.i32_const(truthy as i32),
+            None => {
+                out.local_get(local);
+                out.f64_const(0.0);
+                out.byte(0x62); // ne
+            }
+        }
+    }
+
+    fn print_string(&self, out: &mut Code, id: usize) {
+        out.i32_const(self.offsets[id] as i32);
+        out.i32_const(self.strings[id].len() as i32);
+        out.call(PRINT_STRING);
+    }
+}
+
+/// Bytes of a module section or function body
+#[derive(Default)]
+struct Code {
+    bytes: Vec<u8>,
+}
+
+impl Code {
+    fn byte(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn uleb(&mut self, mut value: u32) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                self.bytes.push(byte);
+                return;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+
+    fn sleb(&mut self, mut value: i32) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+            if done {
+                self.bytes.push(byte);
+                return;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+    }
+
+    fn name(&mut self, name: &str) {
+        self.uleb(name.len() as u32);
+        self.bytes.extend_from_slice(name.as_bytes());
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.byte(0x41);
+        self.sleb(value);
+    }
+
+    fn f64_const(&mut self, value: f64) {
+        self.byte(0x44);
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn local_get(&mut self, local: u32) {
+        self.byte(0x20);
+        self.uleb(local);
+    }
+
+    fn local_set(&mut self, local: u32) {
+        self.byte(0x21);
+        self.uleb(local);
+    }
+
+    fn call(&mut self, function: u32) {
+        self.byte(0x10);
+        self.uleb(function);
+    }
+
+    /// Append a section with the given id and contents
+    fn section(&mut self, id: u8, contents: Code) {
+        self.byte(id);
+        self.uleb(contents.bytes.len() as u32);
+        self.bytes.extend(contents.bytes);
+    }
+}
+
+/// Assemble the module from the compiled function bodies
+fn module(
+    names: &[&String],
+    chunk: &Chunk,
+    indices: &HashMap<&str, u32>,
+    bodies: &[Vec<u8>],
+    data: &[u8],
+) -> Vec<u8> {
+    // Signatures: the three imports, then one per arity of the functions
+    let mut signatures: Vec<(Vec<u8>, Vec<u8>)> = vec![
+        (vec![F64], vec![]),
+        (vec![I32, I32], vec![]),
+        (vec![F64, F64], vec![F64]),
+    ];
+    let mut function_types = Vec::new();
+    for name in names {
+        let signature = (vec![F64; chunk.functions[*name].arity], vec![F64]);
+        let index = match signatures.iter().position(|s| *s == signature) {
+            Some(index) => index,
+            None => {
+                signatures.push(signature);
+                signatures.len() - 1
+            }
+        };
+        function_types.push(index as u32);
+    }
+
+    let mut out = Code::default();
+    out.bytes.extend_from_slice(b"\0asm");
+    out.bytes.extend_from_slice(&1u32.to_le_bytes());
+
+    let mut types = Code::default();
+    types.uleb(signatures.len() as u32);
+    for (params, results) in &signatures {
+        types.byte(0x60);
+        types.uleb(params.len() as u32);
+        types.bytes.extend(params);
+        types.uleb(results.len() as u32);
+        types.bytes.extend(results);
+    }
+    out.section(1, types);
+
+    let mut imports = Code::default();
+    imports.uleb(IMPORT_COUNT);
+    for (index, name) in ["print_number", "print_string", "pow"].iter().enumerate() {
+        imports.name("env");
+        imports.name(name);
+        imports.byte(0x00); // function
+        imports.uleb(index as u32);
+    }
+    out.section(2, imports);
+
+    let mut functions = Code::default();
+    functions.uleb(function_types.len() as u32);
+    for index in function_types {
+        functions.uleb(index);
+    }
+    out.section(3, functions);
+
+    let mut memory = Code::default();
+    memory.uleb(1);
+    memory.byte(0x00); // no maximum
+    memory.uleb((data.len() as u32).div_ceil(65536).max(1));
+    out.section(5, memory);
+
+    let mut exports = Code::default();
+    exports.uleb(2);
+    exports.name("memory");
+    exports.byte(0x02);
+    exports.uleb(0);
+    exports.name("main");
+    exports.byte(0x00);
+    exports.uleb(indices["main"]);
+    out.section(7, exports);
+
+    let mut code = Code::default();
+    code.uleb(bodies.len() as u32);
+    for body in bodies {
+        code.bytes.extend(body);
+    }
+    out.section(10, code);
+
+    let mut segments = Code::default();
+    segments.uleb(1);
+    segments.byte(0x00); // active, memory 0
+    segments.i32_const(0);
+    segments.byte(0x0B);
+    segments.uleb(data.len() as u32);
+    segments.bytes.extend_from_slice(data);
+    out.section(11, segments);
+
+    out.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn compile(source: &str) -> Result<Vec<u8>> {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().expect("Failed to parse");
+        let chunk = Compiler::new().compile(program).expect("Failed to compile");
+        WasmCodeGen::new().compile_chunk(&chunk)
+    }
+
+    /// Ids of the sections of a module, checking each one's length
+    fn section_ids(bytes: &[u8]) -> Vec<u8> {
+        assert_eq!(&bytes[..8], b"\0asm\x01\0\0\0");
+        let mut ids = Vec::new();
+        let mut pos = 8;
+        while pos < bytes.len() {
+            ids.push(bytes[pos]);
+            pos += 1;
+            let (mut size, mut shift) = (0usize, 0);
+            loop {
+                let byte = bytes[pos];
+                pos += 1;
+                size |= ((byte & 0x7F) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            pos += size;
+        }
+        assert_eq!(pos, bytes.len(), "sections overrun the module");
+        ids
+    }
+
+    #[test]
+    fn test_module_layout() {
+        let bytes = compile(
+            "function square(x) {\n return x times x\n}\nfunction main() {\n var i is 0\n while i less than 3 {\n print square(i)\n i is i plus 1\n }\n print \"done\"\n print i equals 3\n return 0\n}",
+        )
+        .unwrap();
+        assert_eq!(section_ids(&bytes), vec![1, 2, 3, 5, 7, 10, 11]);
+        let contains = |needle: &[u8]| bytes.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(b"print_number"));
+        assert!(contains(b"done"));
+    }
+
+    #[test]
+    fn test_unsupported_instructions_are_rejected() {
+        let error = format!(
+            "{:#}",
+            compile("function main() {\n var items is list 1, 2\n return 0\n}").unwrap_err()
+        );
+        assert!(error.starts_with("in function 'main'"), "{}", error);
+        assert!(
+            error.contains("MakeArray is not supported by the wasm backend"),
+            "{}",
+            error
+        );
+    }
+}
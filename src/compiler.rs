@@ -3,26 +3,68 @@
 /// This compiler performs a single pass over the AST and generates
 /// efficient bytecode instructions for the VM to execute.
 use crate::ast::*;
+use crate::builtins::Builtin;
 use crate::bytecode::*;
+use crate::error::CompileError;
 use crate::optimizer;
 use crate::peephole;
-use anyhow::{anyhow, Result};
+use crate::registers;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
 
 /// Local variable information
 #[derive(Debug, Clone)]
 struct Local {
     name: String,
     depth: usize,
+    is_const: bool,
+}
+
+/// Which optimizations `Compiler::compile` runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// None: the bytecode follows the source as written
+    None,
+    /// Constant folding only
+    Fold,
+    /// Everything, including constant propagation, loop hoisting and the
+    /// peephole pass
+    Full,
 }
 
 /// Compiler state
 pub struct Compiler {
     chunk: Chunk,
     locals: Vec<Local>,
+    /// Globals declared with `const`
+    const_globals: HashSet<String>,
+    /// Names of user functions, which shadow built-ins
+    function_names: HashSet<String>,
+    /// Function whose body is being compiled, for spotting self tail calls
+    current_function: Option<String>,
     scope_depth: usize,
     current_line: usize,
-    loop_starts: Vec<usize>,
+    /// Where `continue` jumps in each enclosing loop, `None` while that is
+    /// still ahead
+    loop_starts: Vec<Option<usize>>,
     loop_exits: Vec<Vec<usize>>,
+    /// `continue` jumps waiting for a target still ahead, parallel to
+    /// `loop_starts`
+    loop_continues: Vec<Vec<usize>>,
+    /// Label of each enclosing loop, parallel to `loop_starts`
+    loop_labels: Vec<Option<String>>,
+    /// Whether calls to small functions are replaced by their bodies
+    inline: bool,
+    optimize_level: OptLevel,
+    /// Time the last `compile` spent in the AST optimizer
+    optimize_time: Duration,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
@@ -30,17 +72,58 @@ impl Compiler {
         Compiler {
             chunk: Chunk::new(),
             locals: Vec::new(),
+            const_globals: HashSet::new(),
+            function_names: HashSet::new(),
+            current_function: None,
             scope_depth: 0,
             current_line: 1,
             loop_starts: Vec::new(),
             loop_exits: Vec::new(),
+            loop_continues: Vec::new(),
+            loop_labels: Vec::new(),
+            inline: false,
+            optimize_level: OptLevel::Full,
+            optimize_time: Duration::ZERO,
         }
     }
 
+    /// Inline calls to small, non-recursive functions before compiling
+    pub fn enable_inlining(&mut self) {
+        self.inline = true;
+    }
+
+    /// Choose which optimizations run; `OptLevel::Full` by default
+    pub fn set_optimize_level(&mut self, level: OptLevel) {
+        self.optimize_level = level;
+    }
+
+    /// Time the last `compile` spent optimizing the AST, part of its total
+    pub fn optimize_time(&self) -> Duration {
+        self.optimize_time
+    }
+
     /// Compile a program (multiple functions) into bytecode
     pub fn compile(&mut self, program: Program) -> Result<Chunk> {
-        // First: optimize the program (constant folding, etc.)
-        let optimized_program = optimizer::optimize_program(&program);
+        // First: optimize the program (constant folding, etc.), after
+        // inlining so the inlined bodies get folded too
+        let start = Instant::now();
+        let program = if self.inline {
+            optimizer::inline_functions(&program)
+        } else {
+            program
+        };
+        let optimized_program = match self.optimize_level {
+            OptLevel::None => program,
+            OptLevel::Fold => optimizer::fold_program(&program),
+            OptLevel::Full => optimizer::optimize_program(&program),
+        };
+        self.optimize_time = start.elapsed();
+
+        self.function_names = optimized_program
+            .functions
+            .iter()
+            .map(|f| f.name.clone())
+            .collect();
 
         // Then compile all functions
         for function in &optimized_program.functions {
@@ -50,16 +133,26 @@ impl Compiler {
                 .insert(function.name.clone(), func_chunk);
         }
 
-        // Generate main entry point that calls main()
-        self.chunk.emit(Instruction::Call("main".to_string(), 0), 1);
-        self.chunk.emit(Instruction::Halt, 1);
+        // Generate main entry point that calls main(), which has no line
+        self.chunk
+            .emit(Instruction::Call("main".to_string(), 0), NO_LINE);
+        self.chunk.emit(Instruction::Halt, NO_LINE);
 
         // Apply peephole optimizations
-        peephole::optimize_chunk(&mut self.chunk);
+        if self.optimize_level == OptLevel::Full {
+            peephole::optimize_chunk(&mut self.chunk);
+        }
+        self.chunk.resolve_calls();
 
         Ok(self.chunk.clone())
     }
 
+    /// Compile a program to register bytecode, for the register VM
+    pub fn compile_to_registers(&mut self, program: Program) -> Result<RegisterChunk> {
+        let chunk = self.compile(program)?;
+        registers::translate(&chunk)
+    }
+
     /// Compile a single function into its own chunk
     fn compile_function(&mut self, function: &Function) -> Result<Chunk> {
         // Save current state
@@ -69,25 +162,40 @@ impl Compiler {
 
         // Create new chunk for this function
         self.chunk = Chunk::new();
+        self.chunk.arity = function.params.len();
         self.locals.clear();
         self.scope_depth = 0;
+        self.current_function = Some(function.name.clone());
 
         // Create locals for parameters
         self.begin_scope();
         for param in &function.params {
-            let _ = self.add_local(param.clone())?;
+            let _ = self.add_local(param.clone(), false)?;
         }
 
-        // Compile function body
-        for stmt in &function.body {
+        // Compile function body; a trailing expression is its return value
+        let (result, body) = match (function.result(), function.body.split_last()) {
+            (Some(expr), Some((last, body))) => (Some((expr, last.span)), body),
+            _ => (None, function.body.as_slice()),
+        };
+        for stmt in body {
             self.compile_stmt(stmt)?;
         }
+        if let Some((expr, span)) = result {
+            self.compile_stmt(&Stmt::new(StmtKind::Return(Some(expr.clone())), span))?;
+        }
 
-        // Ensure function returns null if no explicit return
+        // Ensure function returns null if no explicit return, at the line
+        // where the body ends
+        if let Some(last) = function.body.last() {
+            self.current_line = last.span.line;
+        }
         if self.chunk.code.is_empty()
             || !matches!(
                 self.chunk.code.last(),
-                Some(Instruction::Return) | Some(Instruction::ReturnNull)
+                Some(Instruction::Return)
+                    | Some(Instruction::ReturnNull)
+                    | Some(Instruction::TailCall(_, _))
             )
         {
             self.chunk.emit(Instruction::ReturnNull, self.current_line);
@@ -100,71 +208,118 @@ impl Compiler {
         self.chunk = saved_chunk;
         self.locals = saved_locals;
         self.scope_depth = saved_scope_depth;
+        self.current_function = None;
 
         Ok(func_chunk)
     }
 
-    /// Compile a statement
+    /// Compile a statement, recording its line against what it emits
     fn compile_stmt(&mut self, stmt: &Stmt) -> Result<()> {
-        match stmt {
-            Stmt::VarDecl {
+        // Restored afterwards so a loop's closing jump carries the loop's
+        // line rather than that of the last statement in its body
+        let enclosing = std::mem::replace(&mut self.current_line, stmt.span.line);
+        let result = self.compile_stmt_kind(&stmt.kind);
+        self.current_line = enclosing;
+        result
+    }
+
+    fn compile_stmt_kind(&mut self, kind: &StmtKind) -> Result<()> {
+        match kind {
+            StmtKind::VarDecl {
                 name,
                 value,
-                is_const: _,
+                is_const,
             } => {
                 self.compile_expr(value)?;
 
                 if self.scope_depth == 0 {
                     // Global variable
+                    if *is_const {
+                        self.const_globals.insert(name.clone());
+                    }
                     self.chunk
                         .emit(Instruction::StoreGlobal(name.clone()), self.current_line);
                 } else {
                     // Local variable
-                    let local_idx = self.add_local(name.clone())?;
+                    let local_idx = self.add_local(name.clone(), *is_const)?;
                     self.chunk
                         .emit(Instruction::StoreVar(local_idx), self.current_line);
                 }
                 Ok(())
             }
 
-            Stmt::Assignment { name, value } => {
+            StmtKind::Assignment { name, value } => {
+                self.check_assignable(name)?;
                 self.compile_expr(value)?;
 
-                // Try to find as local first
-                if let Some(local_idx) = self.resolve_local(name) {
-                    self.chunk
-                        .emit(Instruction::StoreVar(local_idx), self.current_line);
-                } else {
-                    // Global variable
-                    self.chunk
-                        .emit(Instruction::StoreGlobal(name.clone()), self.current_line);
-                }
+                self.emit_store_back(name);
                 Ok(())
             }
 
-            Stmt::IndexAssignment {
+            StmtKind::IndexAssignment {
                 array,
                 index,
                 value,
             } => {
+                if let Expr::Identifier(name) = array.as_ref() {
+                    self.check_assignable(name)?;
+                }
+
                 // Compile array expression
                 self.compile_expr(array)?;
                 // Compile index expression
                 self.compile_expr(index)?;
                 // Compile value expression
                 self.compile_expr(value)?;
-                // Set the index
-                self.chunk.emit(Instruction::SetIndex, self.current_line);
+                // Set the index (leaves the updated array on the stack).
+                // A string literal key can only address a map.
+                let set = if matches!(index.as_ref(), Expr::String(_)) {
+                    Instruction::MapSet
+                } else {
+                    Instruction::SetIndex
+                };
+                self.chunk.emit(set, self.current_line);
+
+                // Write the updated array back to its variable
+                match array.as_ref() {
+                    Expr::Identifier(name) => self.emit_store_back(name),
+                    _ => {
+                        self.chunk.emit(Instruction::Pop, self.current_line);
+                    }
+                }
+                Ok(())
+            }
+
+            StmtKind::Push { array, value } => {
+                self.check_assignable(array)?;
+                self.compile_expr(&Expr::Identifier(array.clone()))?;
+                self.compile_expr(value)?;
+                self.chunk.emit(Instruction::ArrayPush, self.current_line);
+                self.emit_store_back(array);
                 Ok(())
             }
 
-            Stmt::Print(expr) => {
+            StmtKind::Print(expr) => {
                 self.compile_expr(expr)?;
                 self.chunk.emit(Instruction::Print, self.current_line);
                 Ok(())
             }
 
-            Stmt::Ask { name, prompt } => {
+            StmtKind::Write(expr) => {
+                self.compile_expr(expr)?;
+                self.chunk.emit(Instruction::Write, self.current_line);
+                Ok(())
+            }
+
+            StmtKind::Assert { condition, message } => {
+                self.compile_expr(condition)?;
+                // The VMs report failures at the line recorded here
+                self.chunk
+                    .emit(Instruction::Assert(message.clone()), self.current_line);
+                Ok(())
+            }
+
+            StmtKind::Ask { name, prompt, text } => {
                 // For simplicity, we expect a string literal
                 let prompt_str = if let Some(Expr::String(s)) = prompt {
                     Some(s.clone())
@@ -172,21 +327,28 @@ impl Compiler {
                     None
                 };
 
-                self.chunk
-                    .emit(Instruction::Input(prompt_str), self.current_line);
+                let input = if *text {
+                    Instruction::InputText(prompt_str)
+                } else {
+                    Instruction::Input(prompt_str)
+                };
+                self.chunk.emit(input, self.current_line);
 
                 // Store the result
-                if let Some(local_idx) = self.resolve_local(name) {
-                    self.chunk
-                        .emit(Instruction::StoreVar(local_idx), self.current_line);
-                } else {
-                    self.chunk
-                        .emit(Instruction::StoreGlobal(name.clone()), self.current_line);
+                self.emit_store_back(name);
+                Ok(())
+            }
+
+            StmtKind::Block(stmts) => {
+                self.begin_scope();
+                for stmt in stmts {
+                    self.compile_stmt(stmt)?;
                 }
+                self.end_scope();
                 Ok(())
             }
 
-            Stmt::If {
+            StmtKind::If {
                 condition,
                 then_block,
                 else_block,
@@ -207,9 +369,11 @@ impl Compiler {
                 self.end_scope();
 
                 if let Some(else_stmts) = else_block {
-                    // Jump over else block from then block
+                    // Jump over else block from then block, as part of its
+                    // last line so a trace doesn't show the condition again
                     let jump_to_end = self.chunk.current_position();
-                    self.chunk.emit(Instruction::Jump(0), self.current_line);
+                    let then_end = then_block.last().map_or(self.current_line, |s| s.span.line);
+                    self.chunk.emit(Instruction::Jump(0), then_end);
 
                     // Patch jump to else
                     let else_start = self.chunk.current_position();
@@ -234,10 +398,13 @@ impl Compiler {
                 Ok(())
             }
 
-            Stmt::While { condition, body } => {
+            StmtKind::While {
+                condition,
+                body,
+                label,
+            } => {
                 let loop_start = self.chunk.current_position();
-                self.loop_starts.push(loop_start);
-                self.loop_exits.push(Vec::new());
+                self.begin_loop(Some(loop_start), label);
 
                 // Compile condition
                 self.compile_expr(condition)?;
@@ -261,32 +428,170 @@ impl Compiler {
                 // Patch exit jump
                 let end = self.chunk.current_position();
                 self.chunk.patch_jump(exit_jump, end);
+                self.end_loop(end);
 
-                // Patch all break jumps
-                if let Some(exits) = self.loop_exits.pop() {
-                    for exit_pos in exits {
-                        self.chunk.patch_jump(exit_pos, end);
-                    }
+                Ok(())
+            }
+
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                label,
+            } => {
+                // The loop variable is a new local, but reusing a constant's
+                // name for it is still rejected
+                self.check_assignable(var)?;
+                self.begin_scope();
+
+                // Hidden locals for the array (evaluated once) and the
+                // position in it; their names can't clash with identifiers
+                self.compile_expr(iterable)?;
+                self.chunk.emit(Instruction::ExpectArray, self.current_line);
+                let array = self.add_local("(for each array)".to_string(), false)?;
+                self.chunk
+                    .emit(Instruction::StoreVar(array), self.current_line);
+                self.compile_expr(&Expr::Integer(0))?;
+                let index = self.add_local("(for each index)".to_string(), false)?;
+                self.chunk
+                    .emit(Instruction::StoreVar(index), self.current_line);
+                let item = self.add_local(var.clone(), false)?;
+
+                let loop_start = self.chunk.current_position();
+                self.begin_loop(Some(loop_start), label);
+
+                // while index < length of array
+                self.chunk
+                    .emit(Instruction::LoadVar(index), self.current_line);
+                self.chunk
+                    .emit(Instruction::LoadVar(array), self.current_line);
+                self.chunk.emit(Instruction::Length, self.current_line);
+                self.chunk.emit(Instruction::Less, self.current_line);
+                let exit_jump = self.chunk.current_position();
+                self.chunk
+                    .emit(Instruction::JumpIfFalse(0), self.current_line);
+
+                // item is array at index
+                self.chunk
+                    .emit(Instruction::LoadVar(array), self.current_line);
+                self.chunk
+                    .emit(Instruction::LoadVar(index), self.current_line);
+                self.chunk.emit(Instruction::GetIndex, self.current_line);
+                self.chunk
+                    .emit(Instruction::StoreVar(item), self.current_line);
+
+                // Advance before the body so `continue` can jump to the top
+                self.chunk
+                    .emit(Instruction::LoadVar(index), self.current_line);
+                self.compile_expr(&Expr::Integer(1))?;
+                self.chunk.emit(Instruction::Add, self.current_line);
+                self.chunk
+                    .emit(Instruction::StoreVar(index), self.current_line);
+
+                self.begin_scope();
+                for stmt in body {
+                    self.compile_stmt(stmt)?;
+                }
+                self.end_scope();
+                self.chunk
+                    .emit(Instruction::Jump(loop_start), self.current_line);
+
+                let end = self.chunk.current_position();
+                self.chunk.patch_jump(exit_jump, end);
+                self.end_loop(end);
+
+                self.end_scope();
+                Ok(())
+            }
+
+            StmtKind::Repeat { count, body } => {
+                self.begin_scope();
+
+                // Hidden counter of remaining iterations; the name can't clash
+                // with an identifier
+                self.compile_expr(count)?;
+                let remaining = self.add_local("(repeat)".to_string(), false)?;
+                self.chunk
+                    .emit(Instruction::StoreVar(remaining), self.current_line);
+
+                let loop_start = self.chunk.current_position();
+                self.begin_loop(Some(loop_start), &None);
+
+                // while remaining > 0
+                self.chunk
+                    .emit(Instruction::LoadVar(remaining), self.current_line);
+                self.compile_expr(&Expr::Integer(0))?;
+                self.chunk.emit(Instruction::Greater, self.current_line);
+                let exit_jump = self.chunk.current_position();
+                self.chunk
+                    .emit(Instruction::JumpIfFalse(0), self.current_line);
+
+                // Count down before the body so `continue` can jump to the top
+                self.chunk
+                    .emit(Instruction::LoadVar(remaining), self.current_line);
+                self.compile_expr(&Expr::Integer(1))?;
+                self.chunk.emit(Instruction::Subtract, self.current_line);
+                self.chunk
+                    .emit(Instruction::StoreVar(remaining), self.current_line);
+
+                for stmt in body {
+                    self.compile_stmt(stmt)?;
                 }
-                self.loop_starts.pop();
+                self.chunk
+                    .emit(Instruction::Jump(loop_start), self.current_line);
 
+                let end = self.chunk.current_position();
+                self.chunk.patch_jump(exit_jump, end);
+                self.end_loop(end);
+
+                self.end_scope();
                 Ok(())
             }
 
-            Stmt::For {
+            StmtKind::DoWhile { body, condition } => {
+                let body_start = self.chunk.current_position();
+                // `continue` goes to the condition, which isn't compiled yet
+                self.begin_loop(None, &None);
+
+                self.begin_scope();
+                for stmt in body {
+                    self.compile_stmt(stmt)?;
+                }
+                self.end_scope();
+
+                // Go round again while the condition holds
+                let condition_start = self.chunk.current_position();
+                self.patch_continues(condition_start);
+                self.compile_expr(condition)?;
+                self.chunk
+                    .emit(Instruction::JumpIfTrue(body_start), self.current_line);
+
+                let end = self.chunk.current_position();
+                self.end_loop(end);
+                Ok(())
+            }
+
+            StmtKind::For {
                 init,
                 condition,
                 increment,
                 body,
+                label,
             } => {
                 self.begin_scope();
 
-                // Compile initialization
+                // Compile initialization, then skip the increment the first
+                // time round
                 self.compile_stmt(init)?;
+                let skip_increment = self.chunk.current_position();
+                self.chunk.emit(Instruction::Jump(0), self.current_line);
 
+                // The increment comes first so `continue` can jump to it
                 let loop_start = self.chunk.current_position();
-                self.loop_starts.push(loop_start);
-                self.loop_exits.push(Vec::new());
+                self.begin_loop(Some(loop_start), label);
+                self.compile_stmt(increment)?;
+                let condition_start = self.chunk.current_position();
+                self.chunk.patch_jump(skip_increment, condition_start);
 
                 // Compile condition
                 self.compile_expr(condition)?;
@@ -301,30 +606,34 @@ impl Compiler {
                     self.compile_stmt(stmt)?;
                 }
 
-                // Compile increment
-                self.compile_stmt(increment)?;
-
-                // Jump back to condition
+                // Jump back to the increment
                 self.chunk
                     .emit(Instruction::Jump(loop_start), self.current_line);
 
                 // Patch exit jump
                 let end = self.chunk.current_position();
                 self.chunk.patch_jump(exit_jump, end);
-
-                // Patch all break jumps
-                if let Some(exits) = self.loop_exits.pop() {
-                    for exit_pos in exits {
-                        self.chunk.patch_jump(exit_pos, end);
-                    }
-                }
-                self.loop_starts.pop();
+                self.end_loop(end);
 
                 self.end_scope();
                 Ok(())
             }
 
-            Stmt::Return(expr) => {
+            StmtKind::Return(expr) => {
+                // A function returning a call to itself can reuse its frame
+                if let Some(Expr::Call { name, args }) = expr {
+                    if self.current_function.as_ref() == Some(name) {
+                        for arg in args {
+                            self.compile_expr(arg)?;
+                        }
+                        self.chunk.emit(
+                            Instruction::TailCall(name.clone(), args.len()),
+                            self.current_line,
+                        );
+                        return Ok(());
+                    }
+                }
+
                 if let Some(e) = expr {
                     self.compile_expr(e)?;
                     self.chunk.emit(Instruction::Return, self.current_line);
@@ -334,26 +643,34 @@ impl Compiler {
                 Ok(())
             }
 
-            Stmt::Break => {
+            StmtKind::Break(label) => {
                 let jump_pos = self.chunk.current_position();
                 self.chunk.emit(Instruction::Jump(0), self.current_line);
 
-                // Add to current loop's exit list
-                if let Some(exits) = self.loop_exits.last_mut() {
-                    exits.push(jump_pos);
+                // Add to the target loop's exit list
+                if let Some(target) = self.loop_target(label) {
+                    self.loop_exits[target].push(jump_pos);
                 }
                 Ok(())
             }
 
-            Stmt::Continue => {
-                if let Some(&loop_start) = self.loop_starts.last() {
-                    self.chunk
-                        .emit(Instruction::Jump(loop_start), self.current_line);
+            StmtKind::Continue(label) => {
+                if let Some(target) = self.loop_target(label) {
+                    let jump_pos = self.chunk.current_position();
+                    match self.loop_starts[target] {
+                        Some(loop_start) => self
+                            .chunk
+                            .emit(Instruction::Jump(loop_start), self.current_line),
+                        None => {
+                            self.chunk.emit(Instruction::Jump(0), self.current_line);
+                            self.loop_continues[target].push(jump_pos);
+                        }
+                    }
                 }
                 Ok(())
             }
 
-            Stmt::Expression(expr) => {
+            StmtKind::Expression(expr) => {
                 self.compile_expr(expr)?;
                 self.chunk.emit(Instruction::Pop, self.current_line);
                 Ok(())
@@ -371,8 +688,15 @@ impl Compiler {
                 Ok(())
             }
 
+            Expr::Integer(n) => {
+                let const_idx = self.chunk.add_constant(Constant::Integer(*n));
+                self.chunk
+                    .emit(Instruction::LoadConst(const_idx), self.current_line);
+                Ok(())
+            }
+
             Expr::String(s) => {
-                let const_idx = self.chunk.add_constant(Constant::String(s.clone()));
+                let const_idx = self.chunk.add_constant(Constant::String(s.as_str().into()));
                 self.chunk
                     .emit(Instruction::LoadConst(const_idx), self.current_line);
                 Ok(())
@@ -398,6 +722,79 @@ impl Compiler {
                 Ok(())
             }
 
+            Expr::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+            } => {
+                // Skip the right operand once the left one decides the result:
+                // `and` stops at the first false operand, `or` at the first true one
+                let decided_by = matches!(op, BinaryOp::Or);
+                let jump = |target| {
+                    if decided_by {
+                        Instruction::JumpIfTrue(target)
+                    } else {
+                        Instruction::JumpIfFalse(target)
+                    }
+                };
+
+                self.compile_expr(left)?;
+                let left_jump = self.chunk.current_position();
+                self.chunk.emit(jump(0), self.current_line);
+                self.compile_expr(right)?;
+                let right_jump = self.chunk.current_position();
+                self.chunk.emit(jump(0), self.current_line);
+
+                let undecided = self.chunk.add_constant(Constant::Boolean(!decided_by));
+                self.chunk
+                    .emit(Instruction::LoadConst(undecided), self.current_line);
+                let jump_to_end = self.chunk.current_position();
+                self.chunk.emit(Instruction::Jump(0), self.current_line);
+
+                let decided = self.chunk.current_position();
+                self.chunk.patch_jump(left_jump, decided);
+                self.chunk.patch_jump(right_jump, decided);
+                let decided_const = self.chunk.add_constant(Constant::Boolean(decided_by));
+                self.chunk
+                    .emit(Instruction::LoadConst(decided_const), self.current_line);
+
+                let end = self.chunk.current_position();
+                self.chunk.patch_jump(jump_to_end, end);
+                Ok(())
+            }
+
+            Expr::Binary {
+                left,
+                op: BinaryOp::Otherwise,
+                right,
+            } => {
+                // Keep the left value unless it is null, and only then
+                // evaluate the right one in its place
+                self.compile_expr(left)?;
+                self.chunk.emit(Instruction::Dup, self.current_line);
+                self.emit_null();
+                self.chunk.emit(Instruction::NotEqual, self.current_line);
+                let jump_to_end = self.chunk.current_position();
+                self.chunk
+                    .emit(Instruction::JumpIfTrue(0), self.current_line);
+                self.chunk.emit(Instruction::Pop, self.current_line);
+                self.compile_expr(right)?;
+
+                let end = self.chunk.current_position();
+                self.chunk.patch_jump(jump_to_end, end);
+                Ok(())
+            }
+
+            Expr::Unary {
+                op: UnaryOp::IsNull,
+                operand,
+            } => {
+                self.compile_expr(operand)?;
+                self.emit_null();
+                self.chunk.emit(Instruction::Equal, self.current_line);
+                Ok(())
+            }
+
             Expr::Binary { left, op, right } => {
                 self.compile_expr(left)?;
                 self.compile_expr(right)?;
@@ -407,15 +804,26 @@ impl Compiler {
                     BinaryOp::Subtract => Instruction::Subtract,
                     BinaryOp::Multiply => Instruction::Multiply,
                     BinaryOp::Divide => Instruction::Divide,
+                    BinaryOp::IntDivide => Instruction::IntDivide,
                     BinaryOp::Modulo => Instruction::Modulo,
+                    BinaryOp::Power => Instruction::Power,
                     BinaryOp::Equals => Instruction::Equal,
                     BinaryOp::NotEquals => Instruction::NotEqual,
                     BinaryOp::Greater => Instruction::Greater,
                     BinaryOp::GreaterOrEquals => Instruction::GreaterEqual,
                     BinaryOp::Less => Instruction::Less,
                     BinaryOp::LessOrEquals => Instruction::LessEqual,
-                    BinaryOp::And => Instruction::And,
-                    BinaryOp::Or => Instruction::Or,
+                    BinaryOp::And | BinaryOp::Or | BinaryOp::Otherwise => {
+                        unreachable!("compiled with jumps above")
+                    }
+                    BinaryOp::Contains => Instruction::Contains,
+                    BinaryOp::IndexOf => Instruction::IndexOf,
+                    BinaryOp::Join => Instruction::Join,
+                    BinaryOp::BitAnd
+                    | BinaryOp::BitOr
+                    | BinaryOp::Xor
+                    | BinaryOp::ShiftLeft
+                    | BinaryOp::ShiftRight => Instruction::Bitwise(op.bit_op().unwrap()),
                 };
 
                 self.chunk.emit(instruction, self.current_line);
@@ -430,6 +838,12 @@ impl Compiler {
                     UnaryOp::Negate => Instruction::Negate,
                     UnaryOp::Length => Instruction::Length,
                     UnaryOp::Uppercase => Instruction::Uppercase,
+                    UnaryOp::Lowercase => Instruction::Lowercase,
+                    UnaryOp::Trim => Instruction::Trim,
+                    UnaryOp::TypeOf => Instruction::TypeOf,
+                    UnaryOp::Reverse => Instruction::Reverse,
+                    UnaryOp::Sort => Instruction::Sort,
+                    UnaryOp::IsNull => unreachable!("compiled above"),
                 };
 
                 self.chunk.emit(instruction, self.current_line);
@@ -437,15 +851,31 @@ impl Compiler {
             }
 
             Expr::Call { name, args } => {
+                let builtin = if self.function_names.contains(name) {
+                    None
+                } else {
+                    Builtin::from_name(name)
+                };
+                if let Some(builtin) = builtin {
+                    builtin.check_arity(args.len())?;
+                }
+                if builtin == Some(Builtin::Range) {
+                    if let Some(array) = optimizer::fold_range(args) {
+                        return self.compile_expr(&array);
+                    }
+                }
+
                 // Compile arguments (they'll be on the stack)
                 for arg in args {
                     self.compile_expr(arg)?;
                 }
 
-                self.chunk.emit(
-                    Instruction::Call(name.clone(), args.len()),
-                    self.current_line,
-                );
+                let instruction = match builtin {
+                    Some(Builtin::Exit) => Instruction::Exit,
+                    Some(builtin) => Instruction::CallBuiltin(builtin, args.len()),
+                    None => Instruction::Call(name.clone(), args.len()),
+                };
+                self.chunk.emit(instruction, self.current_line);
                 Ok(())
             }
 
@@ -460,10 +890,28 @@ impl Compiler {
                 Ok(())
             }
 
+            Expr::Map(entries) => {
+                // Compile key/value pairs onto the stack
+                for (key, value) in entries {
+                    self.compile_expr(key)?;
+                    self.compile_expr(value)?;
+                }
+
+                self.chunk
+                    .emit(Instruction::MakeMap(entries.len()), self.current_line);
+                Ok(())
+            }
+
             Expr::Index { array, index } => {
                 self.compile_expr(array)?;
                 self.compile_expr(index)?;
-                self.chunk.emit(Instruction::GetIndex, self.current_line);
+                // GetIndex also handles maps; a string literal key can only address one
+                let get = if matches!(index.as_ref(), Expr::String(_)) {
+                    Instruction::MapGet
+                } else {
+                    Instruction::GetIndex
+                };
+                self.chunk.emit(get, self.current_line);
                 Ok(())
             }
 
@@ -474,6 +922,101 @@ impl Compiler {
                 self.chunk.emit(Instruction::Substring, self.current_line);
                 Ok(())
             }
+
+            Expr::Pop(array) => {
+                self.check_assignable(array)?;
+                self.compile_expr(&Expr::Identifier(array.clone()))?;
+                // Leaves the popped element under the shortened array
+                self.chunk.emit(Instruction::ArrayPop, self.current_line);
+                self.emit_store_back(array);
+                Ok(())
+            }
+
+            Expr::Replace {
+                string,
+                pattern,
+                replacement,
+            } => {
+                self.compile_expr(string)?;
+                self.compile_expr(pattern)?;
+                self.compile_expr(replacement)?;
+                self.chunk.emit(Instruction::Replace, self.current_line);
+                Ok(())
+            }
+
+            Expr::Slice { array, from, to } => {
+                self.compile_expr(array)?;
+                self.compile_expr(from)?;
+                self.compile_expr(to)?;
+                self.chunk.emit(Instruction::Slice, self.current_line);
+                Ok(())
+            }
+
+            Expr::Ternary {
+                condition,
+                then_value,
+                else_value,
+            } => {
+                // Either branch leaves exactly one value on the stack
+                self.compile_expr(condition)?;
+                let jump_to_else = self.chunk.current_position();
+                self.chunk
+                    .emit(Instruction::JumpIfFalse(0), self.current_line);
+                self.compile_expr(then_value)?;
+                let jump_to_end = self.chunk.current_position();
+                self.chunk.emit(Instruction::Jump(0), self.current_line);
+
+                let else_start = self.chunk.current_position();
+                self.chunk.patch_jump(jump_to_else, else_start);
+                self.compile_expr(else_value)?;
+
+                let end = self.chunk.current_position();
+                self.chunk.patch_jump(jump_to_end, end);
+                Ok(())
+            }
+        }
+    }
+
+    // Loop management
+    fn begin_loop(&mut self, loop_start: Option<usize>, label: &Option<String>) {
+        self.loop_starts.push(loop_start);
+        self.loop_exits.push(Vec::new());
+        self.loop_continues.push(Vec::new());
+        self.loop_labels.push(label.clone());
+    }
+
+    fn emit_null(&mut self) {
+        let null = self.chunk.add_constant(Constant::Null);
+        self.chunk
+            .emit(Instruction::LoadConst(null), self.current_line);
+    }
+
+    /// Point the innermost loop's pending `continue` jumps at `target`
+    fn patch_continues(&mut self, target: usize) {
+        if let Some(continues) = self.loop_continues.last_mut() {
+            for jump_pos in std::mem::take(continues) {
+                self.chunk.patch_jump(jump_pos, target);
+            }
+        }
+    }
+
+    /// Close the innermost loop, pointing its `break` jumps at `end`
+    fn end_loop(&mut self, end: usize) {
+        if let Some(exits) = self.loop_exits.pop() {
+            for exit_pos in exits {
+                self.chunk.patch_jump(exit_pos, end);
+            }
+        }
+        self.loop_starts.pop();
+        self.loop_continues.pop();
+        self.loop_labels.pop();
+    }
+
+    /// Index of the loop a `break` or `continue` with `label` applies to
+    fn loop_target(&self, label: &Option<String>) -> Option<usize> {
+        match label {
+            None => self.loop_labels.len().checked_sub(1),
+            Some(_) => self.loop_labels.iter().rposition(|l| l == label),
         }
     }
 
@@ -485,31 +1028,60 @@ impl Compiler {
     fn end_scope(&mut self) {
         self.scope_depth -= 1;
 
-        // Pop all locals from this scope
+        // Release the slots of this scope's locals so later declarations reuse them.
+        // Slots live in the frame reserved at call time, so nothing is popped at runtime.
         while !self.locals.is_empty() && self.locals.last().unwrap().depth > self.scope_depth {
-            self.chunk.emit(Instruction::Pop, self.current_line);
             self.locals.pop();
         }
     }
 
-    fn add_local(&mut self, name: String) -> Result<usize> {
+    /// Store the top of the stack into variable `name`, consuming it
+    fn emit_store_back(&mut self, name: &str) {
+        if let Some(local_idx) = self.resolve_local(name) {
+            self.chunk
+                .emit(Instruction::StoreVar(local_idx), self.current_line);
+        } else {
+            self.chunk.emit(
+                Instruction::StoreGlobal(name.to_string()),
+                self.current_line,
+            );
+            self.chunk.emit(Instruction::Pop, self.current_line);
+        }
+    }
+
+    /// Reject writes to a variable declared with `const`
+    fn check_assignable(&self, name: &str) -> Result<()> {
+        let is_const = match self.resolve_local(name) {
+            Some(idx) => self.locals[idx].is_const,
+            None => self.const_globals.contains(name),
+        };
+        if is_const {
+            return Err(CompileError::AssignToConstant {
+                name: name.to_string(),
+                line: self.current_line,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn add_local(&mut self, name: String, is_const: bool) -> Result<usize> {
         // Check for duplicate in current scope
         for local in self.locals.iter().rev() {
             if local.depth < self.scope_depth {
                 break;
             }
             if local.name == name {
-                return Err(anyhow!(
-                    "Variable '{}' already declared in this scope",
-                    name
-                ));
+                return Err(CompileError::AlreadyDeclared(name).into());
             }
         }
 
         self.locals.push(Local {
             name,
             depth: self.scope_depth,
+            is_const,
         });
+        self.chunk.local_count = self.chunk.local_count.max(self.locals.len());
         Ok(self.locals.len() - 1)
     }
 
@@ -523,3 +1095,279 @@ impl Compiler {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm_optimized::OptimizedVM;
+
+    fn compile_source(source: &str) -> Result<Chunk> {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().expect("Failed to parse");
+        Compiler::new().compile(program)
+    }
+
+    #[test]
+    fn test_const_local_reassignment() {
+        let err =
+            compile_source("function main() {\n const x is 5\n x is 6\n return 0\n}").unwrap_err();
+        assert!(err.to_string().contains("Cannot assign to constant 'x'"));
+
+        let err = compile_source(
+            "function main() {\n const xs is list 1, 2\n xs at 0 is 3\n return 0\n}",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("Cannot assign to constant 'xs'"));
+
+        assert!(compile_source("function main() {\n var x is 5\n x is 6\n return 0\n}").is_ok());
+    }
+
+    #[test]
+    fn test_const_global_reassignment() {
+        let mut compiler = Compiler::new();
+        compiler
+            .compile_stmt(&Stmt::new(
+                StmtKind::VarDecl {
+                    name: "limit".to_string(),
+                    value: Expr::Number(10.0),
+                    is_const: true,
+                },
+                Span { line: 1, column: 1 },
+            ))
+            .unwrap();
+
+        let err = compiler
+            .compile_stmt(&Stmt::new(
+                StmtKind::Assignment {
+                    name: "limit".to_string(),
+                    value: Expr::Number(11.0),
+                },
+                Span { line: 1, column: 1 },
+            ))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot assign to constant 'limit' at line 1"
+        );
+    }
+
+    #[test]
+    fn test_constant_if_leaves_no_branch() {
+        let chunk = compile_source(
+            "function main() {\n if true {\n var x is 1\n print x\n print 2\n }\n if false {\n print 3\n } else {\n print 4\n print 5\n }\n var x is 6\n return x\n}",
+        )
+        .unwrap();
+        let main = &chunk.functions["main"];
+        assert!(!main
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::JumpIfFalse(_) | Instruction::Jump(_))));
+        let prints = main
+            .code
+            .iter()
+            .filter(|i| matches!(i, Instruction::Print))
+            .count();
+        assert_eq!(prints, 4);
+    }
+
+    #[test]
+    fn test_statements_after_return_are_dropped() {
+        let chunk = compile_source(
+            "function main() {\n var i is 0\n while i less than 3 {\n i is i plus 1\n continue\n print \"skipped\"\n }\n return i\n print \"unreachable\"\n}",
+        )
+        .unwrap();
+        let main = &chunk.functions["main"];
+        assert!(!main.code.contains(&Instruction::Print));
+        assert_eq!(main.code.last(), Some(&Instruction::Return));
+    }
+
+    #[test]
+    fn test_or_jumps_when_true() {
+        let chunk = compile_source(
+            "function either(a, b) {\n return a or b\n}\nfunction main() {\n if either(false, true) and not either(false, false) {\n return 1\n }\n return 2\n}",
+        )
+        .unwrap();
+        assert!(chunk.functions["either"]
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::JumpIfTrue(_))));
+        assert_eq!(OptimizedVM::new().execute(chunk).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_small_functions_are_inlined() {
+        let source = "function square(x) {\n return x times x\n}\nfunction main() {\n var n is 7\n var m is square(n plus 1)\n return square(n) plus m\n}";
+        let program = Parser::new(Lexer::new(source.to_string()).tokenize())
+            .parse()
+            .unwrap();
+
+        let mut compiler = Compiler::new();
+        compiler.enable_inlining();
+        let inlined = compiler.compile(program.clone()).unwrap();
+        assert!(!inlined.functions["main"]
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::Call(..) | Instruction::CallIndex(..))));
+
+        let plain = Compiler::new().compile(program).unwrap();
+        assert_eq!(OptimizedVM::new().execute(inlined).unwrap(), 113);
+        assert_eq!(OptimizedVM::new().execute(plain).unwrap(), 113);
+    }
+
+    #[test]
+    fn test_loop_invariant_condition_is_hoisted() {
+        let chunk = compile_source(
+            "function main() {\n const items is list 4, 5, 6\n var total is 0\n for (var i is 0 i less than length of items i is i plus 1) {\n total is total plus items at i\n }\n return total\n}",
+        )
+        .unwrap();
+        let main = &chunk.functions["main"];
+        let loop_start = main
+            .code
+            .iter()
+            .enumerate()
+            .find_map(|(i, instruction)| match instruction {
+                Instruction::Jump(target) if *target < i => Some(*target),
+                _ => None,
+            })
+            .unwrap();
+        let lengths: Vec<usize> = main
+            .code
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| matches!(i, Instruction::Length))
+            .map(|(pos, _)| pos)
+            .collect();
+        assert_eq!(lengths.len(), 1);
+        assert!(
+            lengths[0] < loop_start,
+            "length is still computed in the loop"
+        );
+
+        assert_eq!(OptimizedVM::new().execute(chunk).unwrap(), 15);
+    }
+
+    #[test]
+    fn test_constants_propagate_into_expressions() {
+        let chunk =
+            compile_source("function main() {\n const x is 5\n print x plus 3\n return 0\n}")
+                .unwrap();
+        let main = &chunk.functions["main"];
+        let print = main
+            .code
+            .iter()
+            .position(|i| matches!(i, Instruction::Print))
+            .unwrap();
+        match &main.code[print - 1] {
+            Instruction::LoadConst(idx) => assert_eq!(main.constants[*idx], Constant::Integer(8)),
+            other => panic!("expected LoadConst 8 before Print, got {:?}", other),
+        }
+        assert!(!main
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::LoadVar(_) | Instruction::Add)));
+    }
+
+    #[test]
+    fn test_reassigned_or_out_of_scope_variables_are_not_propagated() {
+        let chunk = compile_source(
+            "function main() {\n var i is 0\n while i less than 3 {\n i is i plus 1\n }\n if true {\n var y is 2\n }\n print y\n return i\n}",
+        )
+        .unwrap();
+        let main = &chunk.functions["main"];
+        assert!(main
+            .code
+            .iter()
+            .any(|i| matches!(i, Instruction::LoadGlobal(name) if name == "y")));
+        assert!(main.code.contains(&Instruction::LoadVar(0)));
+    }
+
+    #[test]
+    fn test_numeric_loop_condition_uses_less_int() {
+        let chunk = compile_source(
+            "function main() {\n var i is 0\n while i less than 100 {\n  i is i plus 1\n }\n return i\n}",
+        )
+        .unwrap();
+        let main = &chunk.functions["main"];
+        assert!(main.code.contains(&Instruction::LessInt), "{:?}", main.code);
+        assert!(!main.code.contains(&Instruction::Less), "{:?}", main.code);
+        // `i is i plus 1` is an increment
+        assert!(
+            main.code.contains(&Instruction::IncrementInt),
+            "{:?}",
+            main.code
+        );
+    }
+
+    #[test]
+    fn test_lines_follow_the_source() {
+        let chunk = compile_source(
+            "function main() {\n var x is 1\n\n print x\n while x less than 3 {\n  increase x by 1\n }\n return x\n}",
+        )
+        .unwrap();
+        let main = &chunk.functions["main"];
+        assert_eq!(main.lines.len(), main.code.len());
+        let print = main
+            .code
+            .iter()
+            .position(|i| matches!(i, Instruction::Print))
+            .unwrap();
+        assert_eq!(main.lines[print], 4);
+
+        // The jump back to the condition belongs to the loop, not its body
+        let mut lines = main.lines.clone();
+        lines.dedup();
+        assert_eq!(lines, [2, 4, 5, 6, 5, 8]);
+    }
+
+    #[test]
+    fn test_jump_over_else_belongs_to_the_then_branch() {
+        let chunk = compile_source(
+            "function pick(flag) {\n if flag {\n  print 1\n } else {\n  print 2\n }\n return 0\n}\n\nfunction main() {\n return pick(true)\n}",
+        )
+        .unwrap();
+        let pick = &chunk.functions["pick"];
+        let jump = pick
+            .code
+            .iter()
+            .position(|i| matches!(i, Instruction::Jump(_)))
+            .unwrap();
+        assert_eq!(pick.lines[jump], 3);
+
+        // The entry point calling main isn't part of the source
+        assert_eq!(chunk.line(0), None);
+    }
+
+    #[test]
+    fn test_print_records_its_line() {
+        let chunk = compile_source(
+            "function greet() {\n    var name is \"Ada\"\n\n\n    print name\n}\n\nfunction main() {\n    greet()\n    return 0\n}",
+        )
+        .unwrap();
+        let greet = &chunk.functions["greet"];
+        let print = greet
+            .code
+            .iter()
+            .position(|i| matches!(i, Instruction::Print))
+            .unwrap();
+        assert_eq!(greet.lines[print], 5);
+        // The implicit return belongs to the end of the body, not line 1
+        assert_eq!(greet.code.last(), Some(&Instruction::ReturnNull));
+        assert_eq!(greet.lines.last(), Some(&5));
+    }
+
+    #[test]
+    fn test_trailing_expression_is_returned() {
+        let chunk = compile_source(
+            "function double(n) {\n    n times 2\n}\n\nfunction main() {\n    print double(4)\n    return 0\n}",
+        )
+        .unwrap();
+        let double = &chunk.functions["double"];
+        // Returned, rather than popped and followed by a null return
+        assert_eq!(double.code.last(), Some(&Instruction::Return));
+        assert!(!double.code.contains(&Instruction::Pop));
+        assert!(!double.code.contains(&Instruction::ReturnNull));
+        assert_eq!(double.lines.last(), Some(&2));
+    }
+}
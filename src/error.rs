@@ -0,0 +1,368 @@
+/// Error types for each stage of the pipeline
+///
+/// Stages still return `anyhow::Result`, but the errors they raise are these
+/// types, so an embedder can tell a division by zero from an undefined
+/// variable with `downcast_ref` rather than by matching on message text.
+/// The VMs attach the source line as context, which `downcast_ref` sees
+/// through, so `{:#}` still prints "at line 3: Division by zero".
+use std::fmt;
+
+pub use crate::lexer::LexError;
+
+/// Source that doesn't follow the grammar
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Expected {
+        expected: String,
+        found: String,
+        line: usize,
+    },
+    UnexpectedToken {
+        found: String,
+        line: usize,
+    },
+    /// The file ended inside the `{` or `(` at `line`
+    Unclosed {
+        delimiter: char,
+        line: usize,
+        column: usize,
+    },
+    /// `break label` or `continue label` outside any loop with that label
+    UnknownLabel {
+        label: String,
+        line: usize,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Expected {
+                expected,
+                found,
+                line,
+            } => write!(f, "Expected {}, found {} at line {}", expected, found, line),
+            ParseError::UnexpectedToken { found, line } => {
+                write!(f, "Unexpected token: {} at line {}", found, line)
+            }
+            ParseError::Unclosed {
+                delimiter,
+                line,
+                column,
+            } => write!(
+                f,
+                "unclosed '{}' opened at line {}, column {}",
+                delimiter, line, column
+            ),
+            ParseError::UnknownLabel { label, line } => {
+                write!(
+                    f,
+                    "No enclosing loop is labeled '{}' at line {}",
+                    label, line
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// A well-formed program the compiler refuses
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    AssignToConstant { name: String, line: usize },
+    AlreadyDeclared(String),
+    InvalidBytecode(String),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::AssignToConstant { name, line } => {
+                write!(f, "Cannot assign to constant '{}' at line {}", name, line)
+            }
+            CompileError::AlreadyDeclared(name) => {
+                write!(f, "Variable '{}' already declared in this scope", name)
+            }
+            CompileError::InvalidBytecode(reason) => write!(f, "Invalid bytecode: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Failures while a program runs, shared by the interpreter and the VMs
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    AssignToConstant(String),
+    /// `for each` over something other than an array
+    NotIterable(String),
+    DivisionByZero,
+    ModuloByZero,
+    TypeMismatch {
+        expected: &'static str,
+        got: String,
+    },
+    WrongArgumentCount {
+        function: String,
+        expected: String,
+        got: usize,
+    },
+    IndexOutOfBounds {
+        index: f64,
+        length: usize,
+    },
+    InvalidSubstring {
+        from: f64,
+        to: f64,
+        length: usize,
+    },
+    InvalidSlice {
+        from: f64,
+        to: f64,
+        length: usize,
+    },
+    /// `pop` on an array with nothing left in it
+    EmptyArray,
+    /// An `assert` whose condition was false, with its message if it had one
+    AssertionFailed(Option<String>),
+    /// A call that would go deeper than the VM allows, into the named function
+    RecursionLimit(String),
+    /// A value stack that would grow past the given number of slots
+    StackOverflow(usize),
+    /// An operand of a bitwise operator that isn't a whole number
+    NotAWholeNumber {
+        operator: &'static str,
+        value: f64,
+    },
+    /// A shift by a number of places other than a whole number from 0 to 63
+    InvalidShift(f64),
+}
+
+impl RuntimeError {
+    /// A value of the wrong type where a value of type `expected` was needed
+    pub fn type_mismatch(expected: &'static str, got: &impl fmt::Debug) -> Self {
+        RuntimeError::TypeMismatch {
+            expected,
+            got: format!("{:?}", got),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::UndefinedVariable(name) => write!(f, "Undefined variable: {}", name),
+            RuntimeError::UndefinedFunction(name) => write!(f, "Undefined function: {}", name),
+            RuntimeError::AssignToConstant(name) => {
+                write!(f, "Cannot assign to constant '{}'", name)
+            }
+            RuntimeError::NotIterable(value) => {
+                write!(f, "for each expects an array, got {}", value)
+            }
+            RuntimeError::DivisionByZero => write!(f, "Division by zero"),
+            RuntimeError::ModuloByZero => write!(f, "Modulo by zero"),
+            RuntimeError::EmptyArray => write!(f, "Cannot pop from an empty array"),
+            RuntimeError::TypeMismatch { expected, got } => {
+                write!(f, "Expected {}, got {}", expected, got)
+            }
+            RuntimeError::WrongArgumentCount {
+                function,
+                expected,
+                got,
+            } => write!(
+                f,
+                "Function '{}' expects {} arguments, got {}",
+                function, expected, got
+            ),
+            RuntimeError::IndexOutOfBounds { index, length } => {
+                write!(
+                    f,
+                    "index {} out of bounds for array of length {}",
+                    index, length
+                )?;
+                if *index < 0.0 {
+                    write!(f, ": indices can't be negative")?;
+                }
+                Ok(())
+            }
+            RuntimeError::InvalidSubstring { from, to, length } => {
+                write!(
+                    f,
+                    "substring {}..{} of string length {} is invalid",
+                    from, to, length
+                )?;
+                if *from < 0.0 || *to < 0.0 {
+                    write!(f, ": indices can't be negative")?;
+                }
+                Ok(())
+            }
+            RuntimeError::InvalidSlice { from, to, length } => {
+                write!(
+                    f,
+                    "slice {}..{} of array length {} is invalid",
+                    from, to, length
+                )?;
+                if *from < 0.0 || *to < 0.0 {
+                    write!(f, ": indices can't be negative")?;
+                }
+                Ok(())
+            }
+            RuntimeError::AssertionFailed(None) => write!(f, "Assertion failed"),
+            RuntimeError::AssertionFailed(Some(message)) => {
+                write!(f, "Assertion failed: {}", message)
+            }
+            RuntimeError::RecursionLimit(function) => write!(
+                f,
+                "maximum recursion depth exceeded, possible infinite recursion in '{}'",
+                function
+            ),
+            RuntimeError::StackOverflow(slots) => write!(
+                f,
+                "value stack overflow: more than {} values in use at once",
+                slots
+            ),
+            RuntimeError::NotAWholeNumber { operator, value } => {
+                write!(f, "'{}' needs whole numbers, got {}", operator, value)
+            }
+            RuntimeError::InvalidShift(places) => write!(
+                f,
+                "can't shift by {} places: it must be a whole number from 0 to 63",
+                places
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parse_error(src: &str) -> ParseError {
+        let tokens = Lexer::new(src.to_string()).tokenize();
+        let err = Parser::new(tokens).parse().unwrap_err();
+        err.downcast_ref::<ParseError>().unwrap().clone()
+    }
+
+    fn runtime_error(src: &str) -> RuntimeError {
+        let err = crate::run_source(src).unwrap_err();
+        err.downcast_ref::<RuntimeError>().unwrap().clone()
+    }
+
+    #[test]
+    fn test_parse_errors_carry_the_line() {
+        assert_eq!(
+            parse_error("function main() {\n var is 3\n}"),
+            ParseError::Expected {
+                expected: "variable name".to_string(),
+                found: "is".to_string(),
+                line: 2,
+            }
+        );
+        assert!(matches!(
+            parse_error("function main() {\n\n return )\n}"),
+            ParseError::UnexpectedToken { line: 3, .. }
+        ));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let err =
+            crate::compile_source("function main() {\n const x is 1\n x is 2\n}").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CompileError>(),
+            Some(CompileError::AssignToConstant { name, .. }) if name == "x"
+        ));
+
+        let err =
+            crate::compile_source("function main() {\n var x is 1\n var x is 2\n}").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<CompileError>(),
+            Some(&CompileError::AlreadyDeclared("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_runtime_errors_survive_line_context() {
+        assert_eq!(
+            runtime_error("function main() {\n var d is 0\n return 1 divided by d\n}"),
+            RuntimeError::DivisionByZero
+        );
+        assert_eq!(
+            runtime_error("function main() { return missing }"),
+            RuntimeError::UndefinedVariable("missing".to_string())
+        );
+        assert_eq!(
+            runtime_error("function main() {\n var xs is list 1, 2, 3\n return xs at 7\n}"),
+            RuntimeError::IndexOutOfBounds {
+                index: 7.0,
+                length: 3
+            }
+        );
+        assert!(matches!(
+            runtime_error("function main() {\n var s is \"a\"\n return s minus 1\n}"),
+            RuntimeError::TypeMismatch {
+                expected: "number",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_interpreter_raises_the_same_errors() {
+        let src = "function main() {\n var d is 0\n return 5 modulo by d\n}";
+        let tokens = Lexer::new(src.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let err = Interpreter::new().interpret(program).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RuntimeError>(),
+            Some(&RuntimeError::ModuloByZero)
+        );
+    }
+
+    #[test]
+    fn test_every_engine_raises_typed_errors() {
+        use crate::vm::VM;
+        use crate::vm_nanbox::NanBoxVM;
+
+        let cases = [
+            (
+                "function main() {\n var s is \"a\"\n return s minus 1\n}",
+                RuntimeError::type_mismatch("number", &()),
+            ),
+            (
+                "function main() {\n var xs is list 1\n pop from xs\n return pop from xs\n}",
+                RuntimeError::EmptyArray,
+            ),
+            (
+                "function main() {\n return length of 5\n}",
+                RuntimeError::type_mismatch("string, array or map", &()),
+            ),
+        ];
+        for (src, expected) in cases {
+            let tokens = Lexer::new(src.to_string()).tokenize();
+            let program = Parser::new(tokens).parse().unwrap();
+            let chunk = crate::compile_source(src).unwrap();
+            let errors = [
+                Interpreter::new().interpret(program).unwrap_err(),
+                VM::new().execute(chunk.clone()).unwrap_err(),
+                NanBoxVM::new().execute(chunk).unwrap_err(),
+            ];
+            for err in errors {
+                // Each engine renders the offending value its own way
+                let mut err = err.downcast_ref::<RuntimeError>().unwrap().clone();
+                if let RuntimeError::TypeMismatch { got, .. } = &mut err {
+                    *got = "()".to_string();
+                }
+                assert_eq!(err, expected, "{}", src);
+            }
+        }
+    }
+}
@@ -0,0 +1,640 @@
+/// Source formatter
+///
+/// Prints a parsed program back as TopLang source in one canonical layout:
+/// four-space indentation, one statement per line, single spaces around
+/// operators and a blank line between functions. Formatting is idempotent,
+/// since formatting the output again parses to the same tree.
+///
+/// The printer works from the AST, so comments and blank lines inside
+/// functions are not kept, and string interpolation and predicates like
+/// `is even` come back as the expressions the parser desugars them to.
+use crate::ast::*;
+
+// Binding strength of each level of the grammar, loosest first
+const LIST: u8 = 0;
+const OTHERWISE: u8 = 1;
+const OR: u8 = 2;
+const AND: u8 = 3;
+const NOT: u8 = 4;
+const EQUALITY: u8 = 5;
+const COMPARISON: u8 = 6;
+const BITOR: u8 = 7;
+const XOR: u8 = 8;
+const BITAND: u8 = 9;
+const SHIFT: u8 = 10;
+const TERM: u8 = 11;
+const FACTOR: u8 = 12;
+const POWER: u8 = 13;
+const UNARY: u8 = 14;
+const PRIMARY: u8 = 15;
+
+const INDENT: &str = "    ";
+
+/// Format a whole program
+pub fn format_program(program: &Program) -> String {
+    let mut printer = Printer::default();
+
+    for import in &program.imports {
+        printer.out.push_str(&format!("import {}\n", quote(import)));
+    }
+
+    for (i, function) in program.functions.iter().enumerate() {
+        if i > 0 || !program.imports.is_empty() {
+            printer.out.push('\n');
+        }
+        printer.function(function);
+    }
+
+    printer.out
+}
+
+#[derive(Default)]
+struct Printer {
+    out: String,
+    depth: usize,
+}
+
+impl Printer {
+    fn function(&mut self, function: &Function) {
+        self.out.push_str(&format!(
+            "function {}({}) ",
+            function.name,
+            function.params.join(", ")
+        ));
+        self.block(&function.body);
+        self.out.push('\n');
+    }
+
+    /// `{`, the statements one level deeper, then `}` at the current level
+    fn block(&mut self, stmts: &[Stmt]) {
+        if stmts.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+
+        self.out.push_str("{\n");
+        self.depth += 1;
+        for stmt in stmts {
+            self.indent();
+            self.stmt(stmt);
+            self.out.push('\n');
+        }
+        self.depth -= 1;
+        self.indent();
+        self.out.push('}');
+    }
+
+    fn indent(&mut self) {
+        self.out.push_str(&INDENT.repeat(self.depth));
+    }
+
+    /// Write a statement from the current position; nested blocks are
+    /// indented relative to the current depth
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::VarDecl {
+                name,
+                value,
+                is_const,
+            } => {
+                let keyword = if *is_const { "const" } else { "var" };
+                self.out
+                    .push_str(&format!("{} {} is {}", keyword, name, expr(value, LIST).0));
+            }
+            StmtKind::Assignment { name, value } => {
+                self.out
+                    .push_str(&format!("{} is {}", name, expr(value, LIST).0));
+            }
+            StmtKind::IndexAssignment {
+                array,
+                index,
+                value,
+            } => {
+                self.out.push_str(&format!(
+                    "{} at {} is {}",
+                    closed(array),
+                    expr(index, TERM).0,
+                    expr(value, LIST).0
+                ));
+            }
+            StmtKind::Push { array, value } => {
+                self.out
+                    .push_str(&format!("push {} onto {}", expr(value, LIST).0, array));
+            }
+            StmtKind::Print(value) => {
+                self.out.push_str(&format!("print {}", expr(value, LIST).0));
+            }
+            StmtKind::Write(value) => {
+                self.out.push_str(&format!("write {}", expr(value, LIST).0));
+            }
+            StmtKind::Assert { condition, message } => {
+                self.out
+                    .push_str(&format!("assert {}", expr(condition, LIST).0));
+                if let Some(message) = message {
+                    self.out.push_str(&format!(" message {}", quote(message)));
+                }
+            }
+            StmtKind::Ask { name, prompt, text } => {
+                self.out.push_str("ask ");
+                if *text {
+                    self.out.push_str("text ");
+                }
+                self.out.push_str(name);
+                if let Some(prompt) = prompt {
+                    // A prompt starting with a name would be read as the next statement
+                    let prompt = if starts_with_name(prompt) {
+                        format!("({})", expr(prompt, LIST).0)
+                    } else {
+                        expr(prompt, LIST).0
+                    };
+                    self.out.push_str(&format!(" {}", prompt));
+                }
+            }
+            StmtKind::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                self.out
+                    .push_str(&format!("if {} ", expr(condition, LIST).0));
+                self.block(then_block);
+                match else_block.as_deref() {
+                    None => {}
+                    Some([nested]) if matches!(nested.kind, StmtKind::If { .. }) => {
+                        self.out.push_str(" else ");
+                        self.stmt(nested);
+                    }
+                    Some(block) => {
+                        self.out.push_str(" else ");
+                        self.block(block);
+                    }
+                }
+            }
+            StmtKind::While {
+                condition,
+                body,
+                label,
+            } => {
+                self.out.push_str(&format!(
+                    "while {} {}",
+                    expr(condition, LIST).0,
+                    loop_label(label)
+                ));
+                self.block(body);
+            }
+            StmtKind::Repeat { count, body } => {
+                self.out
+                    .push_str(&format!("repeat {} times ", expr(count, LIST).0));
+                self.block(body);
+            }
+            StmtKind::DoWhile { body, condition } => {
+                self.out.push_str("do ");
+                self.block(body);
+                self.out
+                    .push_str(&format!(" while {}", expr(condition, LIST).0));
+            }
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                label,
+            } => {
+                self.out.push_str(&format!(
+                    "for each {} in {} {}",
+                    var,
+                    expr(iterable, LIST).0,
+                    loop_label(label)
+                ));
+                self.block(body);
+            }
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+                label,
+            } => {
+                self.out.push_str("for (");
+                self.stmt(init);
+                self.out.push_str(&format!(" {} ", expr(condition, LIST).0));
+                self.stmt(increment);
+                self.out.push_str(&format!(") {}", loop_label(label)));
+                self.block(body);
+            }
+            StmtKind::Return(None) => self.out.push_str("return"),
+            StmtKind::Return(Some(value)) => {
+                self.out
+                    .push_str(&format!("return {}", expr(value, LIST).0));
+            }
+            StmtKind::Break(None) => self.out.push_str("break"),
+            StmtKind::Break(Some(label)) => self.out.push_str(&format!("break {}", label)),
+            StmtKind::Continue(None) => self.out.push_str("continue"),
+            StmtKind::Continue(Some(label)) => self.out.push_str(&format!("continue {}", label)),
+            StmtKind::Expression(value) => self.out.push_str(&expr(value, LIST).0),
+            // Only the optimizer makes these; an always-true `if` scopes the same way
+            StmtKind::Block(stmts) => {
+                self.out.push_str("if true ");
+                self.block(stmts);
+            }
+        }
+    }
+}
+
+/// `as label ` after a loop header, if the loop has one
+fn loop_label(label: &Option<String>) -> String {
+    match label {
+        Some(label) => format!("as {} ", label),
+        None => String::new(),
+    }
+}
+
+/// Render `e` where the grammar expects something binding at least as
+/// tightly as `min`, parenthesizing it if it binds more loosely.
+///
+/// The flag is set when the text ends in an `at` index or a `list`/`map`
+/// literal. Their trailing terms would swallow an operator written after
+/// them, so they are parenthesized before one.
+fn expr(e: &Expr, min: u8) -> (String, bool) {
+    let (text, open, level) = match e {
+        // `{:?}` keeps the `.0` of a whole float, which would otherwise read
+        // back as an integer
+        Expr::Number(n) => (format!("{:?}", n), false, PRIMARY),
+        Expr::Integer(n) => (n.to_string(), false, PRIMARY),
+        Expr::String(s) => (quote(s), false, PRIMARY),
+        Expr::Boolean(b) => (b.to_string(), false, PRIMARY),
+        Expr::Identifier(name) => (name.clone(), false, PRIMARY),
+        Expr::Call { name, args } => {
+            let args: Vec<String> = args.iter().map(|arg| expr(arg, LIST).0).collect();
+            (format!("{}({})", name, args.join(", ")), false, PRIMARY)
+        }
+        Expr::Array(elements) => {
+            let mut text = String::from("list");
+            let elements: Vec<String> = elements.iter().map(|e| expr(e, TERM).0).collect();
+            if !elements.is_empty() {
+                text.push(' ');
+                text.push_str(&elements.join(", "));
+            }
+            (text, true, LIST)
+        }
+        Expr::Map(entries) => {
+            let mut text = String::from("map");
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| format!("{} to {}", expr(key, TERM).0, expr(value, TERM).0))
+                .collect();
+            if !entries.is_empty() {
+                text.push(' ');
+                text.push_str(&entries.join(", "));
+            }
+            (text, true, LIST)
+        }
+        Expr::Index { array, index } => (
+            format!("{} at {}", closed(array), expr(index, TERM).0),
+            true,
+            PRIMARY,
+        ),
+        Expr::Substring { string, from, to } => {
+            let (to, open) = expr(to, PRIMARY);
+            (
+                format!(
+                    "substring {} from {} to {}",
+                    expr(string, PRIMARY).0,
+                    expr(from, PRIMARY).0,
+                    to
+                ),
+                open,
+                UNARY,
+            )
+        }
+        Expr::Slice { array, from, to } => {
+            let (to, open) = expr(to, PRIMARY);
+            (
+                format!(
+                    "slice {} from {} to {}",
+                    expr(array, PRIMARY).0,
+                    expr(from, PRIMARY).0,
+                    to
+                ),
+                open,
+                UNARY,
+            )
+        }
+        Expr::Replace {
+            string,
+            pattern,
+            replacement,
+        } => {
+            let (string, open) = expr(string, PRIMARY);
+            (
+                format!(
+                    "replace {} with {} in {}",
+                    expr(pattern, PRIMARY).0,
+                    expr(replacement, PRIMARY).0,
+                    string
+                ),
+                open,
+                UNARY,
+            )
+        }
+        Expr::Pop(name) => (format!("pop from {}", name), false, UNARY),
+        // The else branch runs to the end of the expression, so it is
+        // parenthesized anywhere but on its own
+        Expr::Ternary {
+            condition,
+            then_value,
+            else_value,
+        } => (
+            format!(
+                "if {} then {} else {}",
+                expr(condition, LIST).0,
+                expr(then_value, LIST).0,
+                expr(else_value, LIST).0
+            ),
+            false,
+            LIST,
+        ),
+        Expr::Unary {
+            op: UnaryOp::IsNull,
+            operand,
+        } => (
+            format!("{} is null", expr(operand, EQUALITY).0),
+            false,
+            EQUALITY,
+        ),
+        Expr::Unary { op, operand } => match (op, &**operand) {
+            (
+                UnaryOp::Not,
+                Expr::Unary {
+                    op: UnaryOp::IsNull,
+                    operand,
+                },
+            ) => (
+                format!("{} is not null", expr(operand, EQUALITY).0),
+                false,
+                EQUALITY,
+            ),
+            _ => {
+                let (operand, open) = expr(operand, UNARY);
+                // A leading `not` takes in a whole comparison, so `(not a) equals b`
+                // keeps its parentheses
+                let level = if *op == UnaryOp::Not { NOT } else { UNARY };
+                (format!("{}{}", unary_prefix(op), operand), open, level)
+            }
+        },
+        Expr::Binary {
+            left,
+            op: BinaryOp::IndexOf,
+            right,
+        } => {
+            let (string, open) = expr(left, PRIMARY);
+            (
+                format!("index of {} in {}", expr(right, PRIMARY).0, string),
+                open,
+                UNARY,
+            )
+        }
+        Expr::Binary {
+            left,
+            op: BinaryOp::Join,
+            right,
+        } => {
+            let (separator, open) = expr(right, PRIMARY);
+            (
+                format!("join {} with {}", expr(left, PRIMARY).0, separator),
+                open,
+                UNARY,
+            )
+        }
+        Expr::Binary { left, op, right } => {
+            let (word, level) = binary_op(op);
+            // `to the power of` groups to the right, everything else to the left
+            let (left_min, right_min) = if *op == BinaryOp::Power {
+                (UNARY, POWER)
+            } else {
+                (level, level + 1)
+            };
+
+            let (mut left, left_open) = expr(left, left_min);
+            if left_open && level >= TERM {
+                left = format!("({})", left);
+            }
+            let (right, open) = expr(right, right_min);
+            (format!("{} {} {}", left, word, right), open, level)
+        }
+    };
+
+    if level < min {
+        (format!("({})", text), false)
+    } else {
+        (text, open)
+    }
+}
+
+/// Render an operand that must be a single closed primary, like the array of
+/// `x at i`
+fn closed(e: &Expr) -> String {
+    match expr(e, PRIMARY) {
+        (text, true) => format!("({})", text),
+        (text, false) => text,
+    }
+}
+
+/// Whether the rendering of `e` begins with a variable or function name
+fn starts_with_name(e: &Expr) -> bool {
+    match e {
+        Expr::Identifier(_) | Expr::Call { .. } => true,
+        Expr::Index { array, .. } => starts_with_name(array),
+        Expr::Binary { left, op, .. } if !matches!(op, BinaryOp::IndexOf | BinaryOp::Join) => {
+            starts_with_name(left)
+        }
+        _ => false,
+    }
+}
+
+/// The words `op` is written with
+pub(crate) fn operator_word(op: &BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::IndexOf => "index of",
+        BinaryOp::Join => "join",
+        _ => binary_op(op).0,
+    }
+}
+
+fn binary_op(op: &BinaryOp) -> (&'static str, u8) {
+    match op {
+        BinaryOp::Otherwise => ("otherwise", OTHERWISE),
+        BinaryOp::Or => ("or", OR),
+        BinaryOp::And => ("and", AND),
+        BinaryOp::Equals => ("equals", EQUALITY),
+        BinaryOp::NotEquals => ("not equals", EQUALITY),
+        BinaryOp::Greater => ("greater than", COMPARISON),
+        BinaryOp::GreaterOrEquals => ("greater than or equals", COMPARISON),
+        BinaryOp::Less => ("less than", COMPARISON),
+        BinaryOp::LessOrEquals => ("less than or equals", COMPARISON),
+        BinaryOp::Contains => ("contains", COMPARISON),
+        BinaryOp::Add => ("plus", TERM),
+        BinaryOp::Subtract => ("minus", TERM),
+        BinaryOp::Multiply => ("times", FACTOR),
+        BinaryOp::Divide => ("divided by", FACTOR),
+        BinaryOp::IntDivide => ("integer divided by", FACTOR),
+        BinaryOp::Modulo => ("modulo by", FACTOR),
+        BinaryOp::Power => ("to the power of", POWER),
+        BinaryOp::BitOr => ("bitor", BITOR),
+        BinaryOp::Xor => ("xor", XOR),
+        BinaryOp::BitAnd => ("bitand", BITAND),
+        BinaryOp::ShiftLeft => ("shift left", SHIFT),
+        BinaryOp::ShiftRight => ("shift right", SHIFT),
+        BinaryOp::IndexOf | BinaryOp::Join => unreachable!("printed as a prefix form"),
+    }
+}
+
+fn unary_prefix(op: &UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Not => "not ",
+        UnaryOp::Negate => "minus ",
+        UnaryOp::Length => "length of ",
+        UnaryOp::Uppercase => "uppercase ",
+        UnaryOp::Lowercase => "lowercase ",
+        UnaryOp::Trim => "trim ",
+        UnaryOp::TypeOf => "type of ",
+        UnaryOp::Reverse => "reverse ",
+        UnaryOp::Sort => "sort ",
+        UnaryOp::IsNull => unreachable!("printed as a postfix form"),
+    }
+}
+
+/// A string literal that lexes back to `s`
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\0' => quoted.push_str("\\0"),
+            '{' => quoted.push_str("\\{"),
+            '}' => quoted.push_str("\\}"),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use std::fs;
+
+    fn parse(source: &str) -> Program {
+        Parser::new(Lexer::new(source.to_string()).tokenize())
+            .parse()
+            .unwrap()
+    }
+
+    /// `{:?}` of `program` without the statements' positions, which
+    /// formatting is free to change
+    fn shape(program: &Program) -> String {
+        let debug = format!("{:?}", program);
+        let mut pieces = debug.split(", span: Span { ");
+        let mut shape = pieces.next().unwrap().to_string();
+        for piece in pieces {
+            shape.push_str(piece.split_once(" }").unwrap().1);
+        }
+        shape
+    }
+
+    #[test]
+    fn test_examples_reparse_to_the_same_program() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let program = parse(&fs::read_to_string(&path).unwrap());
+
+            let formatted = format_program(&program);
+            let reparsed = parse(&formatted);
+            assert_eq!(
+                shape(&reparsed),
+                shape(&program),
+                "{} changed meaning when formatted:\n{}",
+                path.display(),
+                formatted
+            );
+            assert_eq!(format_program(&reparsed), formatted);
+        }
+    }
+
+    #[test]
+    fn test_parenthesizes_where_the_grammar_needs_it() {
+        let program = parse(
+            "function main() {
+                var a is (1 plus 2) times 3
+                var b is (xs at i) plus 1
+                var c is 2 to the power of 3 to the power of 2
+                var d is (2 to the power of 3) to the power of 2
+                var e is 10 minus (4 minus 3)
+                var f is not (a equals b)
+                var h is (not a) equals b
+                var g is length of (list 1, 2)
+                ask name (prompt plus \"> \")
+                var t is (if a then 1 else 2) plus 1
+            }",
+        );
+        let formatted = format_program(&program);
+        assert_eq!(
+            formatted,
+            "function main() {
+    var a is (1 plus 2) times 3
+    var b is (xs at i) plus 1
+    var c is 2 to the power of 3 to the power of 2
+    var d is (2 to the power of 3) to the power of 2
+    var e is 10 minus (4 minus 3)
+    var f is not (a equals b)
+    var h is (not a) equals b
+    var g is length of (list 1, 2)
+    ask name (prompt plus \"> \")
+    var t is (if a then 1 else 2) plus 1
+}
+"
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let source = "function main() {
+    while true as outer {
+        do {
+            break outer
+        } while n less than 3
+    }
+}
+";
+        assert_eq!(format_program(&parse(source)), source);
+    }
+
+    #[test]
+    fn test_otherwise_and_null_checks() {
+        let program = parse(
+            "function main() {
+                var a is ages at name or else 0
+                var b is (a otherwise 1) plus 2
+                var c is a or b otherwise c
+                print a is not null and b is null
+            }",
+        );
+        assert_eq!(
+            format_program(&program),
+            "function main() {
+    var a is ages at name otherwise 0
+    var b is (a otherwise 1) plus 2
+    var c is a or b otherwise c
+    print a is not null and b is null
+}
+"
+        );
+    }
+}
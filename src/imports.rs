@@ -0,0 +1,96 @@
+/// Multi-file programs
+///
+/// `import "path/to/file.top"` at the top of a file makes the functions
+/// defined in that file callable. Paths are relative to the importing file,
+/// imports are followed recursively, and each file is loaded only once, so
+/// files importing each other don't loop forever.
+use crate::ast::{Function, Program};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Merge the functions of every file `program` imports, directly or not,
+/// into it. `file` is the path `program` was parsed from.
+pub fn resolve(file: &Path, program: Program) -> Result<Program> {
+    let mut resolver = Resolver {
+        loaded: HashSet::new(),
+        defined_in: HashMap::new(),
+        functions: Vec::new(),
+    };
+    resolver.loaded.insert(canonical(file)?);
+    resolver.add(file, program)?;
+
+    Ok(Program {
+        imports: Vec::new(),
+        functions: resolver.functions,
+    })
+}
+
+struct Resolver {
+    /// Files already merged, by canonical path
+    loaded: HashSet<PathBuf>,
+    /// The file each function came from, to report duplicates
+    defined_in: HashMap<String, PathBuf>,
+    functions: Vec<Function>,
+}
+
+impl Resolver {
+    /// Add the functions of `program`, parsed from `file`, then load its imports
+    fn add(&mut self, file: &Path, program: Program) -> Result<()> {
+        for function in program.functions {
+            match self.defined_in.get(&function.name) {
+                Some(other) if other != file => {
+                    return Err(anyhow!(
+                        "Function '{}' is defined in both {} and {}",
+                        function.name,
+                        other.display(),
+                        file.display()
+                    ));
+                }
+                _ => {
+                    self.defined_in
+                        .insert(function.name.clone(), file.to_path_buf());
+                    self.functions.push(function);
+                }
+            }
+        }
+
+        let dir = file.parent().unwrap_or(Path::new(""));
+        for import in program.imports {
+            let path = dir.join(&import);
+            if self.loaded.insert(canonical(&path)?) {
+                let imported = parse_file(&path)?;
+                self.add(&path, imported)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn canonical(path: &Path) -> Result<PathBuf> {
+    fs::canonicalize(path).with_context(|| format!("Failed to read file: {}", path.display()))
+}
+
+/// Lex and parse an imported file
+fn parse_file(path: &Path) -> Result<Program> {
+    let source = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read file: {}", path.display()))?;
+
+    let mut lexer = Lexer::new(source.clone());
+    let tokens = lexer.tokenize();
+    if let Some(error) = lexer.errors().first() {
+        return Err(anyhow!(
+            "Invalid token in {}:\n{}",
+            path.display(),
+            error.render(&source)
+        ));
+    }
+
+    Parser::new(tokens)
+        .parse()
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
@@ -1,14 +1,24 @@
 use crate::ast::*;
+use crate::builtins::{
+    array_index, parse_numeric, range_values, slice_range, sort_array, substring, Builtin, Numeric,
+    Rng, SortKey,
+};
+use crate::error::RuntimeError;
+use crate::output::{print_array, print_map, render, Formatter, Plain, Printable};
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::io::{self, BufRead, Write};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
+    /// A whole number, kept exact until arithmetic needs a float
+    Integer(i64),
     String(String),
     Boolean(bool),
     Array(Vec<Value>),
+    Map(HashMap<String, Value>),
     Null,
 }
 
@@ -17,89 +27,274 @@ impl Value {
         match self {
             Value::Boolean(b) => *b,
             Value::Number(n) => *n != 0.0,
+            Value::Integer(n) => *n != 0,
             Value::String(s) => !s.is_empty(),
             Value::Array(a) => !a.is_empty(),
+            Value::Map(m) => !m.is_empty(),
             Value::Null => false,
         }
     }
+
+    /// Name of the value's type, as returned by `type of`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) | Value::Integer(_) => "number",
+            Value::String(_) => "string",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Null => "null",
+        }
+    }
+
+    /// The number this value holds, if it is one
+    pub fn numeric(&self) -> Option<Numeric> {
+        match self {
+            Value::Number(x) => Some(Numeric::Float(*x)),
+            Value::Integer(n) => Some(Numeric::Integer(*n)),
+            _ => None,
+        }
+    }
+}
+
+impl From<Numeric> for Value {
+    fn from(n: Numeric) -> Value {
+        match n {
+            Numeric::Integer(n) => Value::Integer(n),
+            Numeric::Float(x) => Value::Number(x),
+        }
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&render(self, &Plain))
+    }
+}
+
+impl Printable for Value {
+    fn print_to(&self, formatter: &dyn Formatter, out: &mut String, nested: bool) {
         match self {
-            Value::Number(n) => {
-                if n.fract() == 0.0 {
-                    write!(f, "{}", *n as i64)
-                } else {
-                    write!(f, "{}", n)
-                }
-            }
-            Value::String(s) => write!(f, "{}", s),
-            Value::Boolean(b) => write!(f, "{}", b),
-            Value::Array(arr) => {
-                write!(f, "[")?;
-                for (i, val) in arr.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, ", ")?;
-                    }
-                    write!(f, "{}", val)?;
-                }
-                write!(f, "]")
-            }
-            Value::Null => write!(f, "null"),
+            Value::Number(n) => formatter.number(out, Numeric::Float(*n)),
+            Value::Integer(n) => formatter.number(out, Numeric::Integer(*n)),
+            Value::String(s) => formatter.string(out, s, nested),
+            Value::Boolean(b) => formatter.boolean(out, *b),
+            Value::Array(items) => print_array(items, formatter, out),
+            Value::Map(map) => print_map(map, formatter, out),
+            Value::Null => formatter.null(out),
         }
     }
 }
 
+/// Raised by `exit(code)`. It travels up like an error, through every call
+/// in progress, until `interpret` turns it back into the exit code.
+#[derive(Debug)]
+pub struct Exit(pub i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "exit({})", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
 pub struct Interpreter {
     globals: HashMap<String, Value>,
     locals: Vec<HashMap<String, Value>>,
+    /// Names declared with `const`, globally and per local scope
+    global_constants: HashSet<String>,
+    local_constants: Vec<HashSet<String>>,
     functions: HashMap<String, Function>,
     return_value: Option<Value>,
     break_flag: bool,
     continue_flag: bool,
+    /// The loop a pending `break` or `continue` is for, `None` for the
+    /// innermost one
+    jump_label: Option<String>,
+    /// Source of lines for `ask` (stdin unless overridden)
+    input: Box<dyn BufRead>,
+    /// Generator behind `random` and `random_int`
+    rng: Rng,
+
+    /// How `print` and `write` show values
+    formatter: Box<dyn Formatter>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interpreter {
     pub fn new() -> Self {
+        Self::with_input(Box::new(io::stdin().lock()))
+    }
+
+    /// Create an interpreter that reads `ask` input from the given reader
+    pub fn with_input(input: Box<dyn BufRead>) -> Self {
         Interpreter {
             globals: HashMap::new(),
             locals: Vec::new(),
+            global_constants: HashSet::new(),
+            local_constants: Vec::new(),
             functions: HashMap::new(),
             return_value: None,
             break_flag: false,
             continue_flag: false,
+            jump_label: None,
+            input,
+            rng: Rng::from_entropy(),
+            formatter: Box::new(Plain),
         }
     }
 
+    /// Show printed values with `formatter` instead of the plain output
+    pub fn set_formatter(&mut self, formatter: Box<dyn Formatter>) {
+        self.formatter = formatter;
+    }
+
+    /// Make `random` and `random_int` reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
     pub fn interpret(&mut self, program: Program) -> Result<i32> {
+        self.run_function(program, "main")
+    }
+
+    /// Run `program` starting from the function `name` rather than `main`,
+    /// taking its result as the exit code
+    pub fn run_function(&mut self, program: Program, name: &str) -> Result<i32> {
         // Store all functions
         for func in program.functions {
             self.functions.insert(func.name.clone(), func);
         }
 
-        // Look for main function
-        if let Some(main_func) = self.functions.get("main").cloned() {
-            let result = self.call_function(&main_func, Vec::new())?;
+        // Look for the entry function
+        if let Some(entry) = self.functions.get(name).cloned() {
+            let result = match self.call_function(&entry, Vec::new()) {
+                Ok(result) => result,
+                Err(e) => match e.downcast_ref::<Exit>() {
+                    Some(Exit(code)) => return Ok(*code),
+                    None => return Err(e),
+                },
+            };
 
             // Return the exit code
             match result {
                 Value::Number(n) => Ok(n as i32),
+                Value::Integer(n) => Ok(n as i32),
                 _ => Ok(0),
             }
         } else {
-            Err(anyhow!("No main function found"))
+            Err(RuntimeError::UndefinedFunction(name.to_string()).into())
+        }
+    }
+
+    /// Register (or replace) a function without running anything
+    pub fn define_function(&mut self, func: Function) {
+        self.functions.insert(func.name.clone(), func);
+    }
+
+    /// Execute a statement at global scope, as the REPL does. Returns the
+    /// value of an expression statement.
+    pub fn run_global(&mut self, stmt: &Stmt) -> Result<Option<Value>> {
+        let result = match &stmt.kind {
+            StmtKind::Expression(expr) => self.eval_expr(expr).map(Some),
+            _ => self.execute_stmt(stmt).map(|_| None),
+        };
+
+        // A failed or stray return/break must not leak into the next input
+        self.locals.clear();
+        self.local_constants.clear();
+        self.return_value = None;
+        self.break_flag = false;
+        self.continue_flag = false;
+        self.jump_label = None;
+
+        result
+    }
+
+    /// Read a line from the same source `ask` uses
+    pub fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        self.input.read_line(buf)
+    }
+
+    fn call_builtin(&mut self, builtin: Builtin, args: Vec<Value>) -> Result<Value> {
+        builtin.check_arity(args.len())?;
+        match (builtin, &args[..]) {
+            (Builtin::ToNumber, [n @ (Value::Number(_) | Value::Integer(_))]) => {
+                return Ok(n.clone())
+            }
+            (Builtin::ToNumber, [Value::String(s)]) => return Ok(parse_numeric(s)?.into()),
+            (Builtin::ToNumber, [other]) => {
+                return Err(RuntimeError::type_mismatch("number or string", other).into())
+            }
+            (Builtin::ToString, [value]) => return Ok(Value::String(value.to_string())),
+            (Builtin::ToBoolean, [value]) => return Ok(Value::Boolean(value.is_truthy())),
+            _ => {}
+        }
+        let numbers = args
+            .iter()
+            .map(|arg| match arg.numeric() {
+                Some(n) => Ok(n.as_f64()),
+                None => Err(RuntimeError::type_mismatch("number", arg).into()),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        match builtin {
+            Builtin::Range => Ok(Value::Array(
+                range_values(&numbers)?
+                    .into_iter()
+                    .map(Value::Number)
+                    .collect(),
+            )),
+            Builtin::Exit => Err(Exit(numbers[0] as i32).into()),
+            _ => builtin.apply(&numbers, &mut self.rng).map(Value::Number),
+        }
+    }
+
+    /// Run one iteration of the body of the loop labeled `label`, handling
+    /// `break` and `continue`. Returns true when the loop should stop: a
+    /// `break`, a `return`, or a `break` or `continue` for an outer loop,
+    /// which is left pending for that loop.
+    fn execute_loop_body(&mut self, body: &[Stmt], label: Option<&String>) -> Result<bool> {
+        self.execute_block(body)?;
+        if self.return_value.is_some() {
+            return Ok(true);
+        }
+        if !self.break_flag && !self.continue_flag {
+            return Ok(false);
+        }
+        if self.jump_label.is_some() && self.jump_label.as_ref() != label {
+            return Ok(true);
+        }
+
+        self.jump_label = None;
+        self.continue_flag = false;
+        Ok(std::mem::take(&mut self.break_flag))
+    }
+
+    /// Run statements until one returns, breaks or continues
+    fn execute_block(&mut self, stmts: &[Stmt]) -> Result<()> {
+        for stmt in stmts {
+            self.execute_stmt(stmt)?;
+            if self.return_value.is_some() || self.break_flag || self.continue_flag {
+                break;
+            }
         }
+        Ok(())
     }
 
     fn call_function(&mut self, func: &Function, args: Vec<Value>) -> Result<Value> {
         if args.len() != func.params.len() {
-            return Err(anyhow!(
-                "Function '{}' expects {} arguments, got {}",
-                func.name,
-                func.params.len(),
-                args.len()
-            ));
+            return Err(RuntimeError::WrongArgumentCount {
+                function: func.name.clone(),
+                expected: func.params.len().to_string(),
+                got: args.len(),
+            }
+            .into());
         }
 
         // Create new scope
@@ -108,9 +303,14 @@ impl Interpreter {
             local_scope.insert(param.clone(), arg.clone());
         }
         self.locals.push(local_scope);
+        self.local_constants.push(HashSet::new());
 
-        // Execute function body
-        for stmt in &func.body {
+        // Execute function body; a trailing expression is its return value
+        let body = match func.result() {
+            Some(_) => &func.body[..func.body.len() - 1],
+            None => &func.body[..],
+        };
+        for stmt in body {
             self.execute_stmt(stmt)?;
 
             if self.return_value.is_some() {
@@ -118,31 +318,37 @@ impl Interpreter {
             }
         }
 
+        // Get return value, the trailing expression's or default to Null
+        let result = match (self.return_value.take(), func.result()) {
+            (Some(value), _) => value,
+            (None, Some(expr)) => self.eval_expr(expr)?,
+            (None, None) => Value::Null,
+        };
+
         // Pop scope
         self.locals.pop();
+        self.local_constants.pop();
 
-        // Get return value or default to Null
-        let result = self.return_value.take().unwrap_or(Value::Null);
         Ok(result)
     }
 
     fn execute_stmt(&mut self, stmt: &Stmt) -> Result<()> {
-        match stmt {
-            Stmt::VarDecl {
+        match &stmt.kind {
+            StmtKind::VarDecl {
                 name,
                 value,
-                is_const: _,
+                is_const,
             } => {
                 let val = self.eval_expr(value)?;
-                self.set_variable(name.clone(), val);
+                self.declare_variable(name.clone(), val, *is_const);
                 Ok(())
             }
-            Stmt::Assignment { name, value } => {
+            StmtKind::Assignment { name, value } => {
                 let val = self.eval_expr(value)?;
-                self.set_variable(name.clone(), val);
+                self.set_variable(name.clone(), val)?;
                 Ok(())
             }
-            Stmt::IndexAssignment {
+            StmtKind::IndexAssignment {
                 array,
                 index,
                 value,
@@ -154,34 +360,63 @@ impl Interpreter {
                 if let Expr::Identifier(name) = array.as_ref() {
                     let mut arr_val = self.get_variable(name)?;
 
-                    if let Value::Array(ref mut arr) = arr_val {
-                        if let Value::Number(idx) = index_val {
-                            let idx = idx as usize;
-                            if idx < arr.len() {
-                                arr[idx] = new_val;
-                                self.set_variable(name.clone(), arr_val);
-                                Ok(())
-                            } else {
-                                Err(anyhow!("Array index out of bounds: {}", idx))
-                            }
+                    if let Value::Map(ref mut map) = arr_val {
+                        if let Value::String(key) = index_val {
+                            map.insert(key, new_val);
+                            self.set_variable(name.clone(), arr_val)?;
+                            Ok(())
+                        } else {
+                            Err(RuntimeError::type_mismatch("string", &index_val).into())
+                        }
+                    } else if let Value::Array(ref mut arr) = arr_val {
+                        if let Some(idx) = index_val.numeric() {
+                            let idx = array_index(idx.as_f64(), arr.len())?;
+                            arr[idx] = new_val;
+                            self.set_variable(name.clone(), arr_val)?;
+                            Ok(())
                         } else {
-                            Err(anyhow!("Array index must be a number"))
+                            Err(RuntimeError::type_mismatch("number", &index_val).into())
                         }
                     } else {
-                        Err(anyhow!("Cannot index non-array value"))
+                        Err(RuntimeError::type_mismatch("array", &arr_val).into())
                     }
                 } else {
                     Err(anyhow!("Can only assign to array variables"))
                 }
             }
-            Stmt::Print(expr) => {
+            StmtKind::Push { array, value } => {
+                let value = self.eval_expr(value)?;
+                match self.get_variable(array)? {
+                    Value::Array(mut arr) => {
+                        arr.push(value);
+                        self.set_variable(array.clone(), Value::Array(arr))
+                    }
+                    other => Err(RuntimeError::type_mismatch("array", &other).into()),
+                }
+            }
+            StmtKind::Print(expr) => {
                 let val = self.eval_expr(expr)?;
-                println!("{}", val);
+                println!("{}", render(&val, &*self.formatter));
                 Ok(())
             }
-            Stmt::Ask { name, prompt } => {
-                use std::io::{self, Write};
-
+            StmtKind::Write(expr) => {
+                let val = self.eval_expr(expr)?;
+                print!("{}", render(&val, &*self.formatter));
+                io::stdout().flush()?;
+                Ok(())
+            }
+            StmtKind::Assert { condition, message } => {
+                if self.eval_expr(condition)?.is_truthy() {
+                    Ok(())
+                } else {
+                    // The VMs report the line the same way
+                    Err(
+                        anyhow::Error::from(RuntimeError::AssertionFailed(message.clone()))
+                            .context(format!("at line {}", stmt.span.line)),
+                    )
+                }
+            }
+            StmtKind::Ask { name, prompt, text } => {
                 // Print prompt if provided
                 if let Some(prompt_expr) = prompt {
                     let prompt_val = self.eval_expr(prompt_expr)?;
@@ -191,112 +426,124 @@ impl Interpreter {
 
                 // Read user input
                 let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                let input = input.trim().to_string();
+                self.input.read_line(&mut input)?;
+                let line = input.trim_end_matches(['\n', '\r']);
 
                 // Try to parse as number, otherwise store as string
-                let value = if let Ok(num) = input.parse::<f64>() {
-                    Value::Number(num)
+                let value = if *text {
+                    Value::String(line.to_string())
+                } else if let Ok(num) = parse_numeric(line) {
+                    num.into()
                 } else {
-                    Value::String(input)
+                    Value::String(line.trim().to_string())
                 };
 
-                self.set_variable(name.clone(), value);
+                self.set_variable(name.clone(), value)?;
                 Ok(())
             }
-            Stmt::If {
+            StmtKind::If {
                 condition,
                 then_block,
                 else_block,
             } => {
                 let cond_val = self.eval_expr(condition)?;
                 if cond_val.is_truthy() {
-                    for stmt in then_block {
-                        self.execute_stmt(stmt)?;
-                        if self.return_value.is_some() {
-                            break;
-                        }
-                    }
+                    self.execute_block(then_block)?;
                 } else if let Some(else_stmts) = else_block {
-                    for stmt in else_stmts {
-                        self.execute_stmt(stmt)?;
-                        if self.return_value.is_some() {
-                            break;
-                        }
-                    }
+                    self.execute_block(else_stmts)?;
                 }
                 Ok(())
             }
-            Stmt::While { condition, body } => {
-                loop {
-                    let cond_val = self.eval_expr(condition)?;
-                    if !cond_val.is_truthy() {
+            StmtKind::Block(stmts) => self.execute_block(stmts),
+            StmtKind::While {
+                condition,
+                body,
+                label,
+            } => {
+                while self.eval_expr(condition)?.is_truthy() {
+                    if self.execute_loop_body(body, label.as_ref())? {
                         break;
                     }
+                }
+                Ok(())
+            }
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                label,
+            } => {
+                let items = match self.eval_expr(iterable)? {
+                    Value::Array(items) => items,
+                    other => return Err(RuntimeError::NotIterable(other.to_string()).into()),
+                };
 
-                    for stmt in body {
-                        self.execute_stmt(stmt)?;
-                        if self.return_value.is_some() {
-                            return Ok(());
-                        }
-                        if self.break_flag {
-                            break;
-                        }
-                        if self.continue_flag {
+                // The loop variable shadows one of the same name until the
+                // loop ends, as it does on the VM, but a constant can't be
+                // reused for it
+                self.check_assignable(var)?;
+                let shadowed = self.current_scope().remove(var);
+                let mut result = Ok(());
+                for item in items {
+                    self.declare_variable(var.clone(), item, false);
+                    match self.execute_loop_body(body, label.as_ref()) {
+                        Ok(false) => {}
+                        Ok(true) => break,
+                        Err(e) => {
+                            result = Err(e);
                             break;
                         }
                     }
+                }
+                let scope = self.current_scope();
+                scope.remove(var);
+                if let Some(value) = shadowed {
+                    scope.insert(var.clone(), value);
+                }
+                result
+            }
+            StmtKind::Repeat { count, body } => {
+                let count = match self.eval_expr(count)? {
+                    Value::Number(n) => n,
+                    Value::Integer(n) => n as f64,
+                    other => return Err(RuntimeError::type_mismatch("number", &other).into()),
+                };
 
-                    if self.break_flag {
-                        self.break_flag = false;
+                let mut remaining = count;
+                while remaining > 0.0 {
+                    remaining -= 1.0;
+                    if self.execute_loop_body(body, None)? {
                         break;
                     }
-                    if self.continue_flag {
-                        self.continue_flag = false;
+                }
+                Ok(())
+            }
+            StmtKind::DoWhile { body, condition } => {
+                while !self.execute_loop_body(body, None)? {
+                    if !self.eval_expr(condition)?.is_truthy() {
+                        break;
                     }
                 }
                 Ok(())
             }
-            Stmt::For {
+            StmtKind::For {
                 init,
                 condition,
                 increment,
                 body,
+                label,
             } => {
                 self.execute_stmt(init)?;
 
-                loop {
-                    let cond_val = self.eval_expr(condition)?;
-                    if !cond_val.is_truthy() {
+                while self.eval_expr(condition)?.is_truthy() {
+                    if self.execute_loop_body(body, label.as_ref())? {
                         break;
                     }
-
-                    for stmt in body {
-                        self.execute_stmt(stmt)?;
-                        if self.return_value.is_some() {
-                            return Ok(());
-                        }
-                        if self.break_flag {
-                            break;
-                        }
-                        if self.continue_flag {
-                            break;
-                        }
-                    }
-
-                    if self.break_flag {
-                        self.break_flag = false;
-                        break;
-                    }
-                    if self.continue_flag {
-                        self.continue_flag = false;
-                    }
-
                     self.execute_stmt(increment)?;
                 }
                 Ok(())
             }
-            Stmt::Return(expr) => {
+            StmtKind::Return(expr) => {
                 let val = if let Some(e) = expr {
                     self.eval_expr(e)?
                 } else {
@@ -305,15 +552,17 @@ impl Interpreter {
                 self.return_value = Some(val);
                 Ok(())
             }
-            Stmt::Break => {
+            StmtKind::Break(label) => {
                 self.break_flag = true;
+                self.jump_label = label.clone();
                 Ok(())
             }
-            Stmt::Continue => {
+            StmtKind::Continue(label) => {
                 self.continue_flag = true;
+                self.jump_label = label.clone();
                 Ok(())
             }
-            Stmt::Expression(expr) => {
+            StmtKind::Expression(expr) => {
                 self.eval_expr(expr)?;
                 Ok(())
             }
@@ -323,11 +572,21 @@ impl Interpreter {
     fn eval_expr(&mut self, expr: &Expr) -> Result<Value> {
         match expr {
             Expr::Number(n) => Ok(Value::Number(*n)),
+            Expr::Integer(n) => Ok(Value::Integer(*n)),
             Expr::String(s) => Ok(Value::String(s.clone())),
             Expr::Boolean(b) => Ok(Value::Boolean(*b)),
             Expr::Identifier(name) => self.get_variable(name),
             Expr::Binary { left, op, right } => {
                 let left_val = self.eval_expr(left)?;
+                // `and`/`or` don't evaluate the right operand once the left decides,
+                // and `otherwise` only evaluates it in place of a null
+                match (op, &left_val) {
+                    (BinaryOp::And, Value::Boolean(false)) => return Ok(Value::Boolean(false)),
+                    (BinaryOp::Or, Value::Boolean(true)) => return Ok(Value::Boolean(true)),
+                    (BinaryOp::Otherwise, Value::Null) => return self.eval_expr(right),
+                    (BinaryOp::Otherwise, _) => return Ok(left_val),
+                    _ => {}
+                }
                 let right_val = self.eval_expr(right)?;
                 self.eval_binary_op(&left_val, op, &right_val)
             }
@@ -336,39 +595,57 @@ impl Interpreter {
                 self.eval_unary_op(op, &val)
             }
             Expr::Call { name, args } => {
-                let func = self
-                    .functions
-                    .get(name)
-                    .cloned()
-                    .ok_or_else(|| anyhow!("Undefined function: {}", name))?;
+                let func = self.functions.get(name).cloned();
+                let builtin = Builtin::from_name(name);
+                if func.is_none() && builtin.is_none() {
+                    return Err(RuntimeError::UndefinedFunction(name.to_string()).into());
+                }
 
                 let arg_values: Result<Vec<Value>> =
                     args.iter().map(|arg| self.eval_expr(arg)).collect();
 
-                self.call_function(&func, arg_values?)
+                match (func, builtin) {
+                    (Some(func), _) => self.call_function(&func, arg_values?),
+                    (None, Some(builtin)) => self.call_builtin(builtin, arg_values?),
+                    (None, None) => unreachable!(),
+                }
             }
             Expr::Array(elements) => {
                 let values: Result<Vec<Value>> =
                     elements.iter().map(|e| self.eval_expr(e)).collect();
                 Ok(Value::Array(values?))
             }
+            Expr::Map(entries) => {
+                let mut map = HashMap::new();
+                for (key, value) in entries {
+                    let key = match self.eval_expr(key)? {
+                        Value::String(s) => s,
+                        other => return Err(RuntimeError::type_mismatch("string", &other).into()),
+                    };
+                    let value = self.eval_expr(value)?;
+                    map.insert(key, value);
+                }
+                Ok(Value::Map(map))
+            }
             Expr::Index { array, index } => {
                 let arr_val = self.eval_expr(array)?;
                 let idx_val = self.eval_expr(index)?;
 
-                if let Value::Array(arr) = arr_val {
-                    if let Value::Number(idx) = idx_val {
-                        let idx = idx as usize;
-                        if idx < arr.len() {
-                            Ok(arr[idx].clone())
-                        } else {
-                            Err(anyhow!("Array index out of bounds: {}", idx))
-                        }
+                if let Value::Map(map) = arr_val {
+                    if let Value::String(key) = idx_val {
+                        // Missing keys read as null
+                        Ok(map.get(&key).cloned().unwrap_or(Value::Null))
                     } else {
-                        Err(anyhow!("Array index must be a number"))
+                        Err(RuntimeError::type_mismatch("string", &idx_val).into())
+                    }
+                } else if let Value::Array(arr) = arr_val {
+                    if let Some(idx) = idx_val.numeric() {
+                        Ok(arr[array_index(idx.as_f64(), arr.len())?].clone())
+                    } else {
+                        Err(RuntimeError::type_mismatch("number", &idx_val).into())
                     }
                 } else {
-                    Err(anyhow!("Cannot index non-array value"))
+                    Err(RuntimeError::type_mismatch("array", &arr_val).into())
                 }
             }
             Expr::Substring { string, from, to } => {
@@ -376,109 +653,277 @@ impl Interpreter {
                 let from_val = self.eval_expr(from)?;
                 let to_val = self.eval_expr(to)?;
 
-                if let Value::String(s) = str_val {
-                    if let (Value::Number(f), Value::Number(t)) = (from_val, to_val) {
-                        let from_idx = f as usize;
-                        let to_idx = t as usize;
-                        let chars: Vec<char> = s.chars().collect();
+                match (str_val, from_val.numeric(), to_val.numeric()) {
+                    (Value::String(s), Some(f), Some(t)) => {
+                        Ok(Value::String(substring(&s, f.as_f64(), t.as_f64())?))
+                    }
+                    (Value::String(_), None, _) => {
+                        Err(RuntimeError::type_mismatch("number", &from_val).into())
+                    }
+                    (Value::String(_), _, None) => {
+                        Err(RuntimeError::type_mismatch("number", &to_val).into())
+                    }
+                    (other, _, _) => Err(RuntimeError::type_mismatch("string", &other).into()),
+                }
+            }
+            Expr::Slice { array, from, to } => {
+                let array = self.eval_expr(array)?;
+                let from = self.eval_expr(from)?;
+                let to = self.eval_expr(to)?;
 
-                        if from_idx <= to_idx && to_idx <= chars.len() {
-                            let result: String = chars[from_idx..to_idx].iter().collect();
-                            Ok(Value::String(result))
-                        } else {
-                            Err(anyhow!("Substring indices out of bounds"))
-                        }
-                    } else {
-                        Err(anyhow!("Substring indices must be numbers"))
+                match (array, from.numeric(), to.numeric()) {
+                    (Value::Array(items), Some(from), Some(to)) => {
+                        let range = slice_range(from.as_f64(), to.as_f64(), items.len())?;
+                        Ok(Value::Array(items[range].to_vec()))
+                    }
+                    (Value::Array(_), None, _) => {
+                        Err(RuntimeError::type_mismatch("number", &from).into())
                     }
+                    (Value::Array(_), _, None) => {
+                        Err(RuntimeError::type_mismatch("number", &to).into())
+                    }
+                    (other, _, _) => Err(RuntimeError::type_mismatch("array", &other).into()),
+                }
+            }
+            Expr::Ternary {
+                condition,
+                then_value,
+                else_value,
+            } => {
+                if self.eval_expr(condition)?.is_truthy() {
+                    self.eval_expr(then_value)
                 } else {
-                    Err(anyhow!("Substring can only be applied to strings"))
+                    self.eval_expr(else_value)
+                }
+            }
+            Expr::Pop(array) => match self.get_variable(array)? {
+                Value::Array(mut arr) => {
+                    let last = arr.pop().ok_or(RuntimeError::EmptyArray)?;
+                    self.set_variable(array.clone(), Value::Array(arr))?;
+                    Ok(last)
+                }
+                other => Err(RuntimeError::type_mismatch("array", &other).into()),
+            },
+            Expr::Replace {
+                string,
+                pattern,
+                replacement,
+            } => {
+                let string = self.eval_expr(string)?;
+                let pattern = self.eval_expr(pattern)?;
+                let replacement = self.eval_expr(replacement)?;
+
+                match (string, pattern, replacement) {
+                    (Value::String(s), Value::String(p), Value::String(r)) => {
+                        Ok(Value::String(s.replace(p.as_str(), &r)))
+                    }
+                    (Value::String(_), Value::String(_), other)
+                    | (Value::String(_), other, _)
+                    | (other, _, _) => Err(RuntimeError::type_mismatch("string", &other).into()),
                 }
             }
         }
     }
 
     fn eval_binary_op(&self, left: &Value, op: &BinaryOp, right: &Value) -> Result<Value> {
-        match (left, right) {
-            (Value::Number(l), Value::Number(r)) => match op {
-                BinaryOp::Add => Ok(Value::Number(l + r)),
-                BinaryOp::Subtract => Ok(Value::Number(l - r)),
-                BinaryOp::Multiply => Ok(Value::Number(l * r)),
+        // Blame the operand the stack VMs would: the first that isn't what
+        // the operator takes
+        let mismatch = |expected: &'static str| -> anyhow::Error {
+            let culprit = if expected.contains(left.type_name()) {
+                right
+            } else {
+                left
+            };
+            RuntimeError::type_mismatch(expected, culprit).into()
+        };
+
+        // Equality is defined for every pair of values, mirroring the VM
+        match op {
+            BinaryOp::Equals => return Ok(Value::Boolean(self.values_equal(left, right))),
+            BinaryOp::NotEquals => return Ok(Value::Boolean(!self.values_equal(left, right))),
+            _ => {}
+        }
+
+        if let (Some(l), Some(r)) = (left.numeric(), right.numeric()) {
+            return match op {
+                BinaryOp::Add => Ok(l.plus(r).into()),
+                BinaryOp::Subtract => Ok(l.minus(r).into()),
+                BinaryOp::Multiply => Ok(l.times(r).into()),
                 BinaryOp::Divide => {
-                    if *r == 0.0 {
-                        Err(anyhow!("Division by zero"))
+                    if r.is_zero() {
+                        Err(RuntimeError::DivisionByZero.into())
                     } else {
-                        Ok(Value::Number(l / r))
+                        Ok(l.divide(r).into())
+                    }
+                }
+                BinaryOp::IntDivide => {
+                    if r.is_zero() {
+                        Err(RuntimeError::DivisionByZero.into())
+                    } else {
+                        Ok(l.floor_divide(r).into())
                     }
                 }
                 BinaryOp::Modulo => {
-                    if *r == 0.0 {
-                        Err(anyhow!("Modulo by zero"))
+                    if r.is_zero() {
+                        Err(RuntimeError::ModuloByZero.into())
                     } else {
-                        Ok(Value::Number(l % r))
+                        Ok(l.modulo(r).into())
                     }
                 }
-                BinaryOp::Equals => Ok(Value::Boolean((l - r).abs() < f64::EPSILON)),
-                BinaryOp::NotEquals => Ok(Value::Boolean((l - r).abs() >= f64::EPSILON)),
+                BinaryOp::Power => Ok(l.power(r).into()),
+                op if op.bit_op().is_some() => Ok(op.bit_op().unwrap().apply(l, r)?.into()),
                 BinaryOp::Greater => Ok(Value::Boolean(l > r)),
                 BinaryOp::GreaterOrEquals => Ok(Value::Boolean(l >= r)),
                 BinaryOp::Less => Ok(Value::Boolean(l < r)),
                 BinaryOp::LessOrEquals => Ok(Value::Boolean(l <= r)),
-                _ => Err(anyhow!("Invalid operation for numbers")),
-            },
+                _ => Err(mismatch(op.operand_type())),
+            };
+        }
+
+        match (left, right) {
             (Value::String(l), Value::String(r)) => match op {
                 BinaryOp::Add => Ok(Value::String(format!("{}{}", l, r))),
-                BinaryOp::Equals => Ok(Value::Boolean(l == r)),
-                BinaryOp::NotEquals => Ok(Value::Boolean(l != r)),
-                _ => Err(anyhow!("Invalid operation for strings")),
+                BinaryOp::Contains => Ok(Value::Boolean(l.contains(r.as_str()))),
+                BinaryOp::IndexOf => Ok(Value::Integer(
+                    l.find(r.as_str())
+                        .map_or(-1, |i| l[..i].chars().count() as i64),
+                )),
+                _ => Err(mismatch(op.operand_type())),
             },
             (Value::Boolean(l), Value::Boolean(r)) => match op {
                 BinaryOp::And => Ok(Value::Boolean(*l && *r)),
                 BinaryOp::Or => Ok(Value::Boolean(*l || *r)),
-                BinaryOp::Equals => Ok(Value::Boolean(l == r)),
-                BinaryOp::NotEquals => Ok(Value::Boolean(l != r)),
-                _ => Err(anyhow!("Invalid operation for booleans")),
+                _ => Err(mismatch(op.operand_type())),
             },
             // String concatenation with numbers
-            (Value::String(s), Value::Number(n)) => match op {
+            (Value::String(s), n @ (Value::Number(_) | Value::Integer(_))) => match op {
                 BinaryOp::Add => Ok(Value::String(format!("{}{}", s, n))),
-                _ => Err(anyhow!("Invalid operation between string and number")),
+                _ => Err(mismatch(op.operand_type())),
             },
-            (Value::Number(n), Value::String(s)) => match op {
+            (n @ (Value::Number(_) | Value::Integer(_)), Value::String(s)) => match op {
                 BinaryOp::Add => Ok(Value::String(format!("{}{}", n, s))),
-                _ => Err(anyhow!("Invalid operation between number and string")),
+                _ => Err(mismatch(op.operand_type())),
             },
-            _ => Err(anyhow!("Type mismatch in binary operation")),
+            (Value::Array(items), needle) if *op == BinaryOp::Contains => Ok(Value::Boolean(
+                items.iter().any(|item| self.values_equal(item, needle)),
+            )),
+            (Value::Array(items), Value::String(separator)) if *op == BinaryOp::Join => {
+                let parts: Vec<String> = items.iter().map(Value::to_string).collect();
+                Ok(Value::String(parts.join(separator)))
+            }
+            _ => Err(mismatch(op.operand_type())),
+        }
+    }
+
+    #[allow(clippy::only_used_in_recursion)]
+    fn values_equal(&self, a: &Value, b: &Value) -> bool {
+        if let (Some(x), Some(y)) = (a.numeric(), b.numeric()) {
+            return x.equals(y);
+        }
+        match (a, b) {
+            (Value::String(x), Value::String(y)) => x == y,
+            (Value::Boolean(x), Value::Boolean(y)) => x == y,
+            (Value::Null, Value::Null) => true,
+            (Value::Array(x), Value::Array(y)) => {
+                x.len() == y.len() && x.iter().zip(y.iter()).all(|(a, b)| self.values_equal(a, b))
+            }
+            (Value::Map(x), Value::Map(y)) => {
+                x.len() == y.len()
+                    && x.iter()
+                        .all(|(k, a)| y.get(k).is_some_and(|b| self.values_equal(a, b)))
+            }
+            _ => false,
         }
     }
 
     fn eval_unary_op(&self, op: &UnaryOp, operand: &Value) -> Result<Value> {
         match op {
             UnaryOp::Not => Ok(Value::Boolean(!operand.is_truthy())),
-            UnaryOp::Negate => match operand {
-                Value::Number(n) => Ok(Value::Number(-n)),
-                _ => Err(anyhow!("Cannot negate non-number")),
+            UnaryOp::Negate => match operand.numeric() {
+                Some(n) => Ok(n.negate().into()),
+                None => Err(RuntimeError::type_mismatch("number", operand).into()),
             },
             UnaryOp::Length => match operand {
-                Value::String(s) => Ok(Value::Number(s.len() as f64)),
-                Value::Array(arr) => Ok(Value::Number(arr.len() as f64)),
-                _ => Err(anyhow!("Cannot get length of non-string/non-array")),
+                Value::String(s) => Ok(Value::Integer(s.chars().count() as i64)),
+                Value::Array(arr) => Ok(Value::Integer(arr.len() as i64)),
+                Value::Map(map) => Ok(Value::Integer(map.len() as i64)),
+                other => Err(RuntimeError::type_mismatch("string, array or map", other).into()),
             },
             UnaryOp::Uppercase => match operand {
                 Value::String(s) => Ok(Value::String(s.to_uppercase())),
-                _ => Err(anyhow!("Cannot uppercase non-string")),
+                other => Err(RuntimeError::type_mismatch("string", other).into()),
+            },
+            UnaryOp::Lowercase => match operand {
+                Value::String(s) => Ok(Value::String(s.to_lowercase())),
+                other => Err(RuntimeError::type_mismatch("string", other).into()),
+            },
+            UnaryOp::Trim => match operand {
+                Value::String(s) => Ok(Value::String(s.trim().to_string())),
+                other => Err(RuntimeError::type_mismatch("string", other).into()),
+            },
+            UnaryOp::TypeOf => Ok(Value::String(operand.type_name().to_string())),
+            UnaryOp::IsNull => Ok(Value::Boolean(matches!(operand, Value::Null))),
+            UnaryOp::Reverse => match operand {
+                Value::Array(items) => Ok(Value::Array(items.iter().rev().cloned().collect())),
+                other => Err(RuntimeError::type_mismatch("array", other).into()),
+            },
+            UnaryOp::Sort => match operand {
+                Value::Array(items) => Ok(Value::Array(sort_array(
+                    items.clone(),
+                    |value| match value {
+                        Value::Number(x) => SortKey::Number(Numeric::Float(*x)),
+                        Value::Integer(n) => SortKey::Number(Numeric::Integer(*n)),
+                        Value::String(s) => SortKey::String(s.clone()),
+                        other => SortKey::Other(other.type_name()),
+                    },
+                )?)),
+                other => Err(RuntimeError::type_mismatch("array", other).into()),
             },
         }
     }
 
-    fn set_variable(&mut self, name: String, value: Value) {
-        if let Some(scope) = self.locals.last_mut() {
-            scope.insert(name, value);
+    /// Introduce a variable in the current scope, replacing any earlier one
+    fn declare_variable(&mut self, name: String, value: Value, is_const: bool) {
+        let constants = match self.local_constants.last_mut() {
+            Some(constants) => constants,
+            None => &mut self.global_constants,
+        };
+        if is_const {
+            constants.insert(name.clone());
         } else {
-            self.globals.insert(name, value);
+            constants.remove(&name);
+        }
+
+        self.current_scope().insert(name, value);
+    }
+
+    /// The innermost scope: the function's locals, or the globals outside
+    /// any function
+    fn current_scope(&mut self) -> &mut HashMap<String, Value> {
+        match self.locals.last_mut() {
+            Some(scope) => scope,
+            None => &mut self.globals,
         }
     }
 
+    /// Reject writes to a variable declared with `const`
+    fn check_assignable(&self, name: &str) -> Result<()> {
+        let is_const = match (self.locals.last(), self.local_constants.last()) {
+            (Some(scope), Some(constants)) if scope.contains_key(name) => constants.contains(name),
+            _ => self.global_constants.contains(name),
+        };
+        if is_const {
+            return Err(RuntimeError::AssignToConstant(name.to_string()).into());
+        }
+        Ok(())
+    }
+
+    fn set_variable(&mut self, name: String, value: Value) -> Result<()> {
+        self.check_assignable(&name)?;
+        self.current_scope().insert(name, value);
+        Ok(())
+    }
+
     fn get_variable(&self, name: &str) -> Result<Value> {
         // Check local scopes (from innermost to outermost)
         for scope in self.locals.iter().rev() {
@@ -491,7 +936,7 @@ impl Interpreter {
         if let Some(val) = self.globals.get(name) {
             Ok(val.clone())
         } else {
-            Err(anyhow!("Undefined variable: {}", name))
+            Err(RuntimeError::UndefinedVariable(name.to_string()).into())
         }
     }
 }
@@ -509,5 +954,181 @@ mod tests {
         assert!(Value::String("hello".to_string()).is_truthy());
         assert!(!Value::String("".to_string()).is_truthy());
         assert!(!Value::Null.is_truthy());
+        assert!(!Value::Array(vec![]).is_truthy());
+        assert!(Value::Array(vec![Value::Null]).is_truthy());
+    }
+
+    #[test]
+    fn test_array_equality() {
+        let interp = Interpreter::new();
+        let a = Value::Array(vec![Value::Number(1.0), Value::Number(2.0)]);
+        let b = Value::Array(vec![Value::Number(1.0), Value::Number(2.0)]);
+        let c = Value::Array(vec![Value::Number(1.0)]);
+
+        assert_eq!(
+            interp.eval_binary_op(&a, &BinaryOp::Equals, &b).unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            interp.eval_binary_op(&a, &BinaryOp::Equals, &c).unwrap(),
+            Value::Boolean(false)
+        );
+        assert_eq!(
+            interp.eval_binary_op(&a, &BinaryOp::NotEquals, &c).unwrap(),
+            Value::Boolean(true)
+        );
+    }
+
+    #[test]
+    fn test_greater_or_equals() {
+        let interp = Interpreter::new();
+        let op = BinaryOp::GreaterOrEquals;
+
+        for (l, r, expected) in [(5.0, 3.0, true), (3.0, 3.0, true), (2.0, 3.0, false)] {
+            assert_eq!(
+                interp
+                    .eval_binary_op(&Value::Number(l), &op, &Value::Number(r))
+                    .unwrap(),
+                Value::Boolean(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_less_or_equals() {
+        let interp = Interpreter::new();
+        let op = BinaryOp::LessOrEquals;
+
+        for (l, r, expected) in [(2.0, 3.0, true), (3.0, 3.0, true), (5.0, 3.0, false)] {
+            assert_eq!(
+                interp
+                    .eval_binary_op(&Value::Number(l), &op, &Value::Number(r))
+                    .unwrap(),
+                Value::Boolean(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ask_reads_from_input() {
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+
+        let source = r#"
+function main() {
+    ask age
+    ask name
+    if name equals "Ada" {
+        return age plus 1
+    }
+    return 0
+}
+"#
+        .to_string();
+
+        let tokens = Lexer::new(source).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+
+        let input = io::Cursor::new("41\nAda\n");
+        let mut interp = Interpreter::with_input(Box::new(input));
+        assert_eq!(interp.interpret(program).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_lowercase() {
+        let interp = Interpreter::new();
+        assert_eq!(
+            interp
+                .eval_unary_op(&UnaryOp::Lowercase, &Value::String("HeLLo".to_string()))
+                .unwrap(),
+            Value::String("hello".to_string())
+        );
+        assert!(interp
+            .eval_unary_op(&UnaryOp::Lowercase, &Value::Number(1.0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_int_divide_floors() {
+        let interp = Interpreter::new();
+        let op = BinaryOp::IntDivide;
+
+        for (l, r, expected) in [
+            (7.0, 2.0, 3.0),
+            (-7.0, 2.0, -4.0),
+            (7.0, -2.0, -4.0),
+            (-7.0, -2.0, 3.0),
+        ] {
+            assert_eq!(
+                interp
+                    .eval_binary_op(&Value::Number(l), &op, &Value::Number(r))
+                    .unwrap(),
+                Value::Number(expected)
+            );
+        }
+
+        let err = interp
+            .eval_binary_op(&Value::Number(1.0), &op, &Value::Number(0.0))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Division by zero");
+    }
+
+    #[test]
+    fn test_map_display_and_equality() {
+        let interp = Interpreter::new();
+        let mut entries = HashMap::new();
+        entries.insert("b".to_string(), Value::Number(2.0));
+        entries.insert("a".to_string(), Value::Number(1.0));
+        let map = Value::Map(entries.clone());
+
+        assert_eq!(map.to_string(), "{a: 1, b: 2}");
+        assert_eq!(
+            interp
+                .eval_binary_op(&map, &BinaryOp::Equals, &Value::Map(entries))
+                .unwrap(),
+            Value::Boolean(true)
+        );
+        assert_eq!(
+            interp
+                .eval_binary_op(&map, &BinaryOp::Equals, &Value::Map(HashMap::new()))
+                .unwrap(),
+            Value::Boolean(false)
+        );
+    }
+
+    #[test]
+    fn test_const_reassignment() {
+        use crate::lexer::Lexer;
+        use crate::parser::Parser;
+
+        let source = "function main() {\n const x is 5\n x is 6\n return 0\n}";
+        let program = Parser::new(Lexer::new(source.to_string()).tokenize())
+            .parse()
+            .unwrap();
+        let err = Interpreter::new().interpret(program).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot assign to constant 'x'");
+
+        // Globals, as the REPL creates them
+        let mut interpreter = Interpreter::new();
+        interpreter
+            .run_global(&Stmt::new(
+                StmtKind::VarDecl {
+                    name: "limit".to_string(),
+                    value: Expr::Number(10.0),
+                    is_const: true,
+                },
+                Span::default(),
+            ))
+            .unwrap();
+        let err = interpreter
+            .run_global(&Stmt::new(
+                StmtKind::Assignment {
+                    name: "limit".to_string(),
+                    value: Expr::Number(11.0),
+                },
+                Span::default(),
+            ))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Cannot assign to constant 'limit'");
     }
 }
@@ -1,5 +1,44 @@
-use crate::token::{Token, TokenType};
+use crate::token::{StringPart, Token, TokenType};
 use std::collections::HashMap;
+use std::fmt;
+
+/// Input the lexer could not turn into a token
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl LexError {
+    /// The error followed by the offending source line with a caret under the column
+    pub fn render(&self, source: &str) -> String {
+        let text = source.lines().nth(self.line - 1).unwrap_or("");
+        // Keep tabs so the caret lines up however they are displayed
+        let padding: String = text
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let gutter = " ".repeat(self.line.to_string().len());
+        format!(
+            "{}\n {} | {}\n {} | {}^",
+            self, self.line, text, gutter, padding
+        )
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+impl std::error::Error for LexError {}
 
 pub struct Lexer {
     source: Vec<char>,
@@ -7,6 +46,7 @@ pub struct Lexer {
     line: usize,
     column: usize,
     keywords: HashMap<String, TokenType>,
+    errors: Vec<LexError>,
 }
 
 impl Lexer {
@@ -15,6 +55,7 @@ impl Lexer {
 
         // Keywords
         keywords.insert("function".to_string(), TokenType::Function);
+        keywords.insert("import".to_string(), TokenType::Import);
         keywords.insert("return".to_string(), TokenType::Return);
         keywords.insert("if".to_string(), TokenType::If);
         keywords.insert("else".to_string(), TokenType::Else);
@@ -23,17 +64,43 @@ impl Lexer {
         keywords.insert("var".to_string(), TokenType::Variable);
         keywords.insert("const".to_string(), TokenType::Constant);
         keywords.insert("print".to_string(), TokenType::Print);
+        keywords.insert("write".to_string(), TokenType::Write);
         keywords.insert("ask".to_string(), TokenType::Ask);
+        keywords.insert("assert".to_string(), TokenType::Assert);
         keywords.insert("list".to_string(), TokenType::List);
+        keywords.insert("map".to_string(), TokenType::Map);
         keywords.insert("at".to_string(), TokenType::At);
         keywords.insert("break".to_string(), TokenType::Break);
         keywords.insert("continue".to_string(), TokenType::Continue);
         keywords.insert("length".to_string(), TokenType::Length);
         keywords.insert("uppercase".to_string(), TokenType::Uppercase);
+        keywords.insert("lowercase".to_string(), TokenType::Lowercase);
         keywords.insert("substring".to_string(), TokenType::Substring);
         keywords.insert("from".to_string(), TokenType::From);
         keywords.insert("to".to_string(), TokenType::To);
         keywords.insert("of".to_string(), TokenType::Of);
+        keywords.insert("contains".to_string(), TokenType::Contains);
+        keywords.insert("replace".to_string(), TokenType::Replace);
+        keywords.insert("with".to_string(), TokenType::With);
+        keywords.insert("in".to_string(), TokenType::In);
+        keywords.insert("trim".to_string(), TokenType::Trim);
+        keywords.insert("slice".to_string(), TokenType::Slice);
+        keywords.insert("reverse".to_string(), TokenType::Reverse);
+        keywords.insert("sort".to_string(), TokenType::Sort);
+        keywords.insert("join".to_string(), TokenType::Join);
+        keywords.insert("null".to_string(), TokenType::Null);
+        keywords.insert("otherwise".to_string(), TokenType::Otherwise);
+        keywords.insert("push".to_string(), TokenType::Push);
+        keywords.insert("onto".to_string(), TokenType::Onto);
+        keywords.insert("pop".to_string(), TokenType::Pop);
+        keywords.insert("increase".to_string(), TokenType::Increase);
+        keywords.insert("decrease".to_string(), TokenType::Decrease);
+        keywords.insert("multiply".to_string(), TokenType::MultiplyVerb);
+        keywords.insert("repeat".to_string(), TokenType::Repeat);
+        keywords.insert("do".to_string(), TokenType::Do);
+        keywords.insert("each".to_string(), TokenType::Each);
+        keywords.insert("as".to_string(), TokenType::As);
+        keywords.insert("type".to_string(), TokenType::Type);
         keywords.insert("true".to_string(), TokenType::Boolean(true));
         keywords.insert("false".to_string(), TokenType::Boolean(false));
 
@@ -42,6 +109,9 @@ impl Lexer {
         keywords.insert("minus".to_string(), TokenType::Minus);
         keywords.insert("times".to_string(), TokenType::Multiply);
         keywords.insert("divided".to_string(), TokenType::Divide);
+        keywords.insert("bitand".to_string(), TokenType::BitAnd);
+        keywords.insert("bitor".to_string(), TokenType::BitOr);
+        keywords.insert("xor".to_string(), TokenType::Xor);
         keywords.insert("is".to_string(), TokenType::Assign);
         keywords.insert("equals".to_string(), TokenType::Equals);
         keywords.insert("greater".to_string(), TokenType::Greater);
@@ -56,9 +126,29 @@ impl Lexer {
             line: 1,
             column: 1,
             keywords,
+            errors: Vec::new(),
         }
     }
 
+    /// Problems found while tokenizing; each left an `Unknown` token in the output
+    pub fn errors(&self) -> &[LexError] {
+        &self.errors
+    }
+
+    /// Record an error and return the `Unknown` token standing in for the bad input
+    fn error(&mut self, message: String, line: usize, column: usize) -> Token {
+        self.errors.push(LexError {
+            message,
+            line,
+            column,
+        });
+        Token::new(TokenType::Unknown, line, column)
+    }
+
+    fn text_from(&self, start: usize) -> String {
+        self.source[start..self.position].iter().collect()
+    }
+
     fn current_char(&self) -> Option<char> {
         if self.position < self.source.len() {
             Some(self.source[self.position])
@@ -113,41 +203,100 @@ impl Lexer {
         result
     }
 
-    fn read_number(&mut self) -> f64 {
+    /// Read digits in `radix`, dropping `_` separators between them
+    fn read_digits(&mut self, radix: u32) -> String {
         let mut result = String::new();
 
         while let Some(ch) = self.current_char() {
-            if ch.is_numeric() {
+            if ch.is_digit(radix) {
                 result.push(ch);
                 self.advance();
+            } else if ch == '_'
+                && !result.is_empty()
+                && self.peek_char(1).is_some_and(|c| c.is_digit(radix))
+            {
+                self.advance();
             } else {
                 break;
             }
         }
 
+        result
+    }
+
+    /// Read a number literal; `None` if it is malformed (e.g. `0x`, `0b12`).
+    /// Whole literals that fit in an `i64` are integers.
+    fn read_number(&mut self) -> Option<TokenType> {
+        // 0x, 0b and 0o integer literals
+        if self.current_char() == Some('0') {
+            let radix = match self.peek_char(1) {
+                Some('x' | 'X') => Some(16),
+                Some('b' | 'B') => Some(2),
+                Some('o' | 'O') => Some(8),
+                _ => None,
+            };
+            if let Some(radix) = radix {
+                self.advance();
+                self.advance();
+                let digits = self.read_digits(radix);
+                // Letters or digits run on past the valid ones: reject the lot
+                if self
+                    .current_char()
+                    .is_some_and(|c| c.is_alphanumeric() || c == '_')
+                {
+                    self.read_identifier();
+                    return None;
+                }
+                return u64::from_str_radix(&digits, radix).ok().map(|n| {
+                    i64::try_from(n).map_or(TokenType::Number(n as f64), TokenType::Integer)
+                });
+            }
+        }
+
+        let mut result = self.read_digits(10);
+        let mut whole = true;
+
         // Handle decimal point
         if let Some('.') = self.current_char() {
-            if let Some(next) = self.peek_char(1) {
-                if next.is_numeric() {
-                    result.push('.');
-                    self.advance();
+            if self.peek_char(1).is_some_and(|c| c.is_ascii_digit()) {
+                whole = false;
+                result.push('.');
+                self.advance();
+                result.push_str(&self.read_digits(10));
+            }
+        }
 
-                    while let Some(ch) = self.current_char() {
-                        if ch.is_numeric() {
-                            result.push(ch);
-                            self.advance();
-                        } else {
-                            break;
-                        }
-                    }
+        // Exponent, only when digits follow: `1e` is the number 1 and then `e`
+        if let Some('e' | 'E') = self.current_char() {
+            let digits_at = match self.peek_char(1) {
+                Some('+' | '-') => 2,
+                _ => 1,
+            };
+            if self
+                .peek_char(digits_at)
+                .is_some_and(|c| c.is_ascii_digit())
+            {
+                whole = false;
+                for _ in 0..digits_at {
+                    result.push(self.current_char().unwrap());
+                    self.advance();
                 }
+                result.push_str(&self.read_digits(10));
             }
         }
 
-        result.parse().unwrap_or(0.0)
+        if whole {
+            if let Ok(n) = result.parse() {
+                return Some(TokenType::Integer(n));
+            }
+        }
+        result.parse().ok().map(TokenType::Number)
     }
 
-    fn read_string(&mut self) -> String {
+    /// Read a string literal, splitting out `{expr}` holes. Returns None if a hole is
+    /// never closed.
+    fn read_string(&mut self) -> Option<TokenType> {
+        let mut parts = Vec::new();
         let mut result = String::new();
         self.advance(); // Skip opening quote
 
@@ -164,6 +313,15 @@ impl Lexer {
                         'r' => result.push('\r'),
                         '\\' => result.push('\\'),
                         '"' => result.push('"'),
+                        '{' => result.push('{'),
+                        '}' => result.push('}'),
+                        '0' => result.push('\0'),
+                        'x' | 'u' => {
+                            if let Some(decoded) = self.read_code_escape(escaped) {
+                                result.push(decoded);
+                            }
+                            continue;
+                        }
                         _ => {
                             result.push('\\');
                             result.push(escaped);
@@ -171,15 +329,177 @@ impl Lexer {
                     }
                     self.advance();
                 }
+            } else if ch == '{' {
+                self.advance();
+                let source = self.read_interpolation()?;
+                if !result.is_empty() {
+                    parts.push(StringPart::Literal(std::mem::take(&mut result)));
+                }
+                parts.push(StringPart::Expr(source));
             } else {
                 result.push(ch);
                 self.advance();
             }
         }
 
+        if parts.is_empty() {
+            return Some(TokenType::StringLit(result));
+        }
+        if !result.is_empty() {
+            parts.push(StringPart::Literal(result));
+        }
+        Some(TokenType::InterpolatedString(parts))
+    }
+
+    /// Read the rest of a `\xNN` or `\u{...}` escape, with the position on the
+    /// `x` or `u`. A malformed escape is reported and yields None; lexing
+    /// carries on after it.
+    fn read_code_escape(&mut self, kind: char) -> Option<char> {
+        // Errors point at the backslash
+        let line = self.line;
+        let column = self.column - 1;
+        self.advance();
+
+        let (digits, what) = if kind == 'x' {
+            let mut digits = String::new();
+            while digits.len() < 2 {
+                match self.current_char() {
+                    Some(ch) if ch.is_ascii_hexdigit() => {
+                        digits.push(ch);
+                        self.advance();
+                    }
+                    _ => break,
+                }
+            }
+            if digits.len() < 2 {
+                self.error("\\x escape needs two hex digits".to_string(), line, column);
+                return None;
+            }
+            (digits, "\\x")
+        } else {
+            if self.current_char() != Some('{') {
+                self.error(
+                    "\\u escape needs braces, as in \\u{1F600}".to_string(),
+                    line,
+                    column,
+                );
+                return None;
+            }
+            self.advance();
+            let mut digits = String::new();
+            while let Some(ch) = self.current_char() {
+                if !ch.is_ascii_hexdigit() {
+                    break;
+                }
+                digits.push(ch);
+                self.advance();
+            }
+            if self.current_char() != Some('}') || digits.is_empty() || digits.len() > 6 {
+                self.error(
+                    "\\u escape needs 1 to 6 hex digits and a closing '}'".to_string(),
+                    line,
+                    column,
+                );
+                return None;
+            }
+            self.advance();
+            (digits, "\\u")
+        };
+
+        let code = u32::from_str_radix(&digits, 16).unwrap();
+        let decoded = match kind {
+            'x' if code > 0x7F => None,
+            _ => char::from_u32(code),
+        };
+        if decoded.is_none() {
+            let reason = if kind == 'x' {
+                "is not ASCII; use \\u{...} for other characters"
+            } else {
+                "is not a Unicode scalar value"
+            };
+            self.error(
+                format!("{} escape {} {}", what, digits, reason),
+                line,
+                column,
+            );
+        }
+        decoded
+    }
+
+    /// Read a `raw"..."` string, with the position on its opening quote.
+    /// Backslashes and braces are kept as written.
+    fn read_raw_string(&mut self) -> String {
+        let mut result = String::new();
+        self.advance(); // Skip opening quote
+
+        while let Some(ch) = self.current_char() {
+            self.advance();
+            if ch == '"' {
+                break;
+            }
+            result.push(ch);
+        }
+
         result
     }
 
+    /// Read the source of a `{...}` hole up to its closing brace, skipping over
+    /// braces nested inside it or inside string literals within it
+    fn read_interpolation(&mut self) -> Option<String> {
+        let mut source = String::new();
+        let mut depth = 0;
+        let mut in_string = false;
+
+        while let Some(ch) = self.current_char() {
+            self.advance();
+            match ch {
+                '"' => in_string = !in_string,
+                '\\' if in_string => {
+                    source.push(ch);
+                    if let Some(escaped) = self.current_char() {
+                        source.push(escaped);
+                        self.advance();
+                    }
+                    continue;
+                }
+                '{' if !in_string => depth += 1,
+                '}' if !in_string => {
+                    if depth == 0 {
+                        return Some(source);
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            source.push(ch);
+        }
+
+        None
+    }
+
+    /// Consume the given words if they come next, otherwise leave the position untouched
+    fn match_phrase(&mut self, words: &[&str]) -> bool {
+        let saved_pos = self.position;
+        let saved_line = self.line;
+        let saved_column = self.column;
+
+        for word in words {
+            self.skip_whitespace();
+            let matches = match self.current_char() {
+                Some(ch) if ch.is_alphabetic() => self.read_identifier() == *word,
+                _ => false,
+            };
+            if !matches {
+                self.position = saved_pos;
+                self.line = saved_line;
+                self.column = saved_column;
+                return false;
+            }
+        }
+
+        true
+    }
+
     fn skip_comment(&mut self) {
         while let Some(ch) = self.current_char() {
             if ch == '\n' {
@@ -189,12 +509,29 @@ impl Lexer {
         }
     }
 
+    /// Skip a `/* ... */` comment, returning false if it runs off the end of the source
+    fn skip_block_comment(&mut self) -> bool {
+        self.advance(); // Skip '/'
+        self.advance(); // Skip '*'
+
+        while let Some(ch) = self.current_char() {
+            if ch == '*' && self.peek_char(1) == Some('/') {
+                self.advance();
+                self.advance();
+                return true;
+            }
+            self.advance();
+        }
+        false
+    }
+
     pub fn tokenize(&mut self) -> Vec<Token> {
         let mut tokens = Vec::new();
 
         while let Some(ch) = self.current_char() {
             let start_column = self.column;
             let start_line = self.line;
+            let start_position = self.position;
 
             match ch {
                 ' ' | '\t' | '\r' => {
@@ -209,6 +546,17 @@ impl Lexer {
                     self.skip_comment();
                     continue;
                 }
+                '/' if self.peek_char(1) == Some('*') => {
+                    if !self.skip_block_comment() {
+                        let token = self.error(
+                            "Unterminated block comment".to_string(),
+                            start_line,
+                            start_column,
+                        );
+                        tokens.push(token);
+                    }
+                    continue;
+                }
                 '{' => {
                     tokens.push(Token::new(TokenType::LeftBrace, start_line, start_column));
                     self.advance();
@@ -230,16 +578,29 @@ impl Lexer {
                     self.advance();
                 }
                 '"' => {
-                    let string_val = self.read_string();
-                    tokens.push(Token::new(
-                        TokenType::StringLit(string_val),
-                        start_line,
-                        start_column,
-                    ));
+                    let token = match self.read_string() {
+                        Some(token_type) => Token::new(token_type, start_line, start_column),
+                        None => self.error(
+                            "Unclosed '{' in string".to_string(),
+                            start_line,
+                            start_column,
+                        ),
+                    };
+                    tokens.push(token);
                 }
                 _ if ch.is_alphabetic() || ch == '_' => {
                     let identifier = self.read_identifier();
 
+                    if identifier == "raw" && self.current_char() == Some('"') {
+                        let text = self.read_raw_string();
+                        tokens.push(Token::new(
+                            TokenType::StringLit(text),
+                            start_line,
+                            start_column,
+                        ));
+                        continue;
+                    }
+
                     // Check for multi-word operators
                     if identifier == "divided" {
                         self.skip_whitespace();
@@ -256,6 +617,21 @@ impl Lexer {
                                 }
                             }
                         }
+                    } else if identifier == "integer" && self.match_phrase(&["divided", "by"]) {
+                        tokens.push(Token::new(TokenType::IntDivide, start_line, start_column));
+                        continue;
+                    } else if identifier == "to" && self.match_phrase(&["the", "power", "of"]) {
+                        tokens.push(Token::new(TokenType::Power, start_line, start_column));
+                        continue;
+                    } else if identifier == "index" && self.match_phrase(&["of"]) {
+                        tokens.push(Token::new(TokenType::IndexOf, start_line, start_column));
+                        continue;
+                    } else if identifier == "shift" && self.match_phrase(&["left"]) {
+                        tokens.push(Token::new(TokenType::ShiftLeft, start_line, start_column));
+                        continue;
+                    } else if identifier == "shift" && self.match_phrase(&["right"]) {
+                        tokens.push(Token::new(TokenType::ShiftRight, start_line, start_column));
+                        continue;
                     } else if identifier == "modulo"
                         || identifier == "mod"
                         || identifier == "remainder"
@@ -297,17 +673,41 @@ impl Lexer {
 
                     tokens.push(Token::new(token_type, start_line, start_column));
                 }
-                _ if ch.is_numeric() => {
-                    let number = self.read_number();
-                    tokens.push(Token::new(
-                        TokenType::Number(number),
-                        start_line,
-                        start_column,
-                    ));
+                // `-` has no other meaning, so `-5` is a negative literal
+                _ if ch.is_numeric()
+                    || (ch == '-' && self.peek_char(1).is_some_and(|c| c.is_ascii_digit())) =>
+                {
+                    let negative = ch == '-';
+                    if negative {
+                        self.advance();
+                    }
+                    let token = match self.read_number() {
+                        Some(TokenType::Integer(n)) if negative => {
+                            Token::new(TokenType::Integer(-n), start_line, start_column)
+                        }
+                        Some(TokenType::Number(x)) if negative => {
+                            Token::new(TokenType::Number(-x), start_line, start_column)
+                        }
+                        Some(number) => Token::new(number, start_line, start_column),
+                        None => {
+                            let text = self.text_from(start_position);
+                            self.error(
+                                format!("Invalid number literal '{}'", text),
+                                start_line,
+                                start_column,
+                            )
+                        }
+                    };
+                    tokens.push(token);
                 }
                 _ => {
-                    tokens.push(Token::new(TokenType::Unknown, start_line, start_column));
                     self.advance();
+                    let token = self.error(
+                        format!("Unexpected character '{}'", ch),
+                        start_line,
+                        start_column,
+                    );
+                    tokens.push(token);
                 }
             }
         }
@@ -339,4 +739,247 @@ mod tests {
         assert!(matches!(tokens[0].token_type, TokenType::Print));
         assert!(matches!(tokens[1].token_type, TokenType::StringLit(_)));
     }
+
+    #[test]
+    fn test_power_phrase() {
+        let tokens = Lexer::new("2 to the power of 10".to_string()).tokenize();
+        assert_eq!(tokens[1].token_type, TokenType::Power);
+        assert_eq!(tokens[2].token_type, TokenType::Integer(10));
+
+        // A lone "to" (as in substring) is untouched
+        let tokens = Lexer::new("from 0 to the".to_string()).tokenize();
+        assert_eq!(tokens[2].token_type, TokenType::To);
+        assert_eq!(
+            tokens[3].token_type,
+            TokenType::Identifier("the".to_string())
+        );
+    }
+
+    #[test]
+    fn test_integer_divided_by() {
+        let tokens = Lexer::new("7 integer divided by 2".to_string()).tokenize();
+        assert_eq!(tokens[1].token_type, TokenType::IntDivide);
+        assert_eq!(tokens[2].token_type, TokenType::Integer(2));
+    }
+
+    #[test]
+    fn test_shift_phrases() {
+        let tokens = Lexer::new("1 shift left 4 shift right 2".to_string()).tokenize();
+        assert_eq!(tokens[1].token_type, TokenType::ShiftLeft);
+        assert_eq!(tokens[3].token_type, TokenType::ShiftRight);
+
+        // "shift" on its own stays an identifier
+        let tokens = Lexer::new("var shift is 0".to_string()).tokenize();
+        assert_eq!(
+            tokens[1].token_type,
+            TokenType::Identifier("shift".to_string())
+        );
+    }
+
+    #[test]
+    fn test_index_of_phrase() {
+        let tokens = Lexer::new("index of x in s".to_string()).tokenize();
+        assert_eq!(tokens[0].token_type, TokenType::IndexOf);
+        assert_eq!(tokens[2].token_type, TokenType::In);
+
+        // "index" on its own stays an identifier
+        let tokens = Lexer::new("var index is 0".to_string()).tokenize();
+        assert_eq!(
+            tokens[1].token_type,
+            TokenType::Identifier("index".to_string())
+        );
+    }
+
+    #[test]
+    fn test_scientific_and_negative_literals() {
+        let number = |source: &str| {
+            Lexer::new(source.to_string()).tokenize()[0]
+                .token_type
+                .clone()
+        };
+        assert_eq!(number("6.022e23"), TokenType::Number(6.022e23));
+        assert_eq!(number("1E-9"), TokenType::Number(1e-9));
+        assert_eq!(number("1.5e+3"), TokenType::Number(1500.0));
+        assert_eq!(number("-2.5"), TokenType::Number(-2.5));
+        assert_eq!(number("-1e3"), TokenType::Number(-1000.0));
+
+        // Whole literals stay exact, past where a float could hold them
+        assert_eq!(
+            number("9007199254740993"),
+            TokenType::Integer(9_007_199_254_740_993)
+        );
+        assert_eq!(number("-42"), TokenType::Integer(-42));
+        assert_eq!(number("2.0"), TokenType::Number(2.0));
+        assert_eq!(number("99999999999999999999"), TokenType::Number(1e20));
+
+        // A trailing `e` without digits is not part of the number
+        let tokens = Lexer::new("1e".to_string()).tokenize();
+        assert_eq!(tokens[0].token_type, TokenType::Integer(1));
+        assert_eq!(tokens[1].token_type, TokenType::Identifier("e".to_string()));
+        let tokens = Lexer::new("2e-x".to_string()).tokenize();
+        assert_eq!(tokens[0].token_type, TokenType::Integer(2));
+        assert_eq!(tokens[1].token_type, TokenType::Identifier("e".to_string()));
+    }
+
+    #[test]
+    fn test_radix_literals_and_separators() {
+        let number = |source: &str| {
+            Lexer::new(source.to_string()).tokenize()[0]
+                .token_type
+                .clone()
+        };
+        assert_eq!(number("0xFF"), TokenType::Integer(255));
+        assert_eq!(number("0b1010"), TokenType::Integer(10));
+        assert_eq!(number("0o777"), TokenType::Integer(511));
+        assert_eq!(number("0xFF_FF"), TokenType::Integer(65535));
+        assert_eq!(number("1_000_000"), TokenType::Integer(1_000_000));
+        assert_eq!(number("-0x10"), TokenType::Integer(-16));
+
+        assert_eq!(number("0x"), TokenType::Unknown);
+        assert_eq!(number("0b102"), TokenType::Unknown);
+        assert_eq!(number("0xFG"), TokenType::Unknown);
+
+        // The whole malformed literal is consumed
+        let tokens = Lexer::new("0x print".to_string()).tokenize();
+        assert_eq!(tokens[1].token_type, TokenType::Print);
+    }
+
+    #[test]
+    fn test_block_comments() {
+        let source = "var /* a * b / c # not a line comment */ x\n/* spans\ntwo lines */ print x";
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let types: Vec<_> = tokens.iter().map(|t| t.token_type.clone()).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Variable,
+                TokenType::Identifier("x".to_string()),
+                TokenType::Print,
+                TokenType::Identifier("x".to_string()),
+                TokenType::Eof,
+            ]
+        );
+        // Lines inside the comment are still counted
+        assert_eq!(tokens[2].line, 3);
+
+        // Block comments don't nest: the first `*/` closes it
+        let tokens = Lexer::new("/* /* inner */ print".to_string()).tokenize();
+        assert_eq!(tokens[0].token_type, TokenType::Print);
+
+        let tokens = Lexer::new("print 1 /* never closed\nprint 2".to_string()).tokenize();
+        assert_eq!(tokens[2].token_type, TokenType::Unknown);
+        assert_eq!(tokens[2].line, 1);
+        assert_eq!(tokens[3].token_type, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_string_interpolation_parts() {
+        let tokens = Lexer::new(r#""a {x plus "}"} b \{c}""#.to_string()).tokenize();
+        assert_eq!(
+            tokens[0].token_type,
+            TokenType::InterpolatedString(vec![
+                StringPart::Literal("a ".to_string()),
+                StringPart::Expr(r#"x plus "}""#.to_string()),
+                StringPart::Literal(" b {c}".to_string()),
+            ])
+        );
+
+        // Unclosed holes are rejected rather than swallowing the rest of the file
+        let tokens = Lexer::new(r#"print "{x" print 1"#.to_string()).tokenize();
+        assert_eq!(tokens[1].token_type, TokenType::Unknown);
+    }
+
+    #[test]
+    fn test_code_escapes() {
+        let string = |source: &str| {
+            Lexer::new(source.to_string()).tokenize()[0]
+                .token_type
+                .clone()
+        };
+        let lit = |s: &str| TokenType::StringLit(s.to_string());
+        assert_eq!(string(r#""a\0b""#), lit("a\0b"));
+        assert_eq!(string(r#""\x41\x7e""#), lit("A~"));
+        assert_eq!(string(r#""\u{e9}\u{1F600}""#), lit("é😀"));
+        // Unknown escapes keep their backslash
+        assert_eq!(string(r#""\q""#), lit("\\q"));
+    }
+
+    #[test]
+    fn test_malformed_escapes_are_reported() {
+        let errors = |source: &str| {
+            let mut lexer = Lexer::new(source.to_string());
+            lexer.tokenize();
+            lexer
+                .errors()
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            errors(r#"print "ab\x4""#),
+            vec!["\\x escape needs two hex digits at line 1, column 10"]
+        );
+        assert_eq!(
+            errors(r#""\xFF""#),
+            vec![
+                "\\x escape FF is not ASCII; use \\u{...} for other characters at line 1, column 2"
+            ]
+        );
+        assert_eq!(
+            errors(r#""\u41""#),
+            vec!["\\u escape needs braces, as in \\u{1F600} at line 1, column 2"]
+        );
+        assert_eq!(errors(r#""\u{}" "\u{1234567}" "\u{12""#).len(), 3);
+        assert_eq!(
+            errors(r#""\u{D800}""#),
+            vec!["\\u escape D800 is not a Unicode scalar value at line 1, column 2"]
+        );
+
+        // Lexing carries on past the bad escape
+        let tokens = Lexer::new(r#""\xZ ok" print"#.to_string()).tokenize();
+        assert_eq!(
+            tokens[0].token_type,
+            TokenType::StringLit("Z ok".to_string())
+        );
+        assert_eq!(tokens[1].token_type, TokenType::Print);
+    }
+
+    #[test]
+    fn test_raw_strings() {
+        let tokens = Lexer::new(r#"print raw"C:\path\no\escapes{x}\n" raw"#.to_string()).tokenize();
+        assert_eq!(
+            tokens[1].token_type,
+            TokenType::StringLit(r"C:\path\no\escapes{x}\n".to_string())
+        );
+        // Without a quote straight after it, `raw` is an ordinary name
+        assert_eq!(
+            tokens[2].token_type,
+            TokenType::Identifier("raw".to_string())
+        );
+    }
+
+    #[test]
+    fn test_illegal_characters_are_reported() {
+        let source = "var x is 1\n\tprint x @ 2;\nprint 0xZ";
+        let mut lexer = Lexer::new(source.to_string());
+        lexer.tokenize();
+
+        let messages: Vec<String> = lexer.errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Unexpected character '@' at line 2, column 10",
+                "Unexpected character ';' at line 2, column 13",
+                "Invalid number literal '0xZ' at line 3, column 7",
+            ]
+        );
+        assert_eq!(
+            lexer.errors()[0].render(source),
+            "Unexpected character '@' at line 2, column 10\n 2 | \tprint x @ 2;\n   | \t        ^"
+        );
+
+        let mut lexer = Lexer::new("print 1".to_string());
+        lexer.tokenize();
+        assert!(lexer.errors().is_empty());
+    }
 }
@@ -0,0 +1,182 @@
+//! TopLang - a simple, human-first programming language
+//!
+//! Besides the `topc` command, TopLang can be embedded in Rust programs.
+//! The quickest way is [`run_source`]:
+//!
+//! ```
+//! let exit_code = toplang::run_source("function main() { return 7 }").unwrap();
+//! assert_eq!(exit_code, 7);
+//! ```
+//!
+//! An [`Engine`] keeps its state between runs and lets the host hand values
+//! to the program as global variables:
+//!
+//! ```
+//! use toplang::{Engine, Value};
+//!
+//! let mut engine = Engine::new();
+//! engine.set_global("limit", Value::Number(3.0));
+//! let exit_code = engine
+//!     .run("function main() { return limit times 2 }")
+//!     .unwrap();
+//! assert_eq!(exit_code, 6);
+//! ```
+//!
+//! Host functions written in Rust can be called from TopLang once registered:
+//!
+//! ```
+//! use toplang::{Engine, Value};
+//!
+//! let mut engine = Engine::new();
+//! engine.register_builtin(
+//!     "double",
+//!     1,
+//!     Box::new(|args| Ok(Value::Number(args[0].as_number()? * 2.0))),
+//! );
+//! let exit_code = engine
+//!     .run("function main() { return double(21) }")
+//!     .unwrap();
+//! assert_eq!(exit_code, 42);
+//! ```
+//!
+//! Errors are `anyhow` errors wrapping the types in [`error`], so a host can
+//! tell failures apart without matching on their text:
+//!
+//! ```
+//! use toplang::error::RuntimeError;
+//!
+//! let err = toplang::run_source("function main() { return missing }").unwrap_err();
+//! assert_eq!(
+//!     err.downcast_ref::<RuntimeError>(),
+//!     Some(&RuntimeError::UndefinedVariable("missing".to_string()))
+//! );
+//! ```
+//!
+//! The functions and types at the crate root are the stable embedding API.
+//! The modules are the compiler's internals, public for the `topc` binary;
+//! they may change between releases.
+
+pub mod ast;
+pub mod builtins;
+pub mod bytecode;
+pub mod check;
+pub mod codegen_c;
+pub mod codegen_native;
+pub mod codegen_wasm;
+pub mod compiler;
+pub mod error;
+pub mod fmt;
+pub mod imports;
+pub mod interpreter;
+pub mod lexer;
+pub mod nanbox;
+pub mod nanbox_safe;
+pub mod optimizer;
+pub mod output;
+pub mod parser;
+pub mod peephole;
+pub mod profile;
+pub mod registers;
+pub mod repl;
+pub mod runtime;
+pub mod token;
+pub mod trace;
+pub mod vm;
+pub mod vm_nanbox;
+pub mod vm_optimized;
+pub mod vm_register;
+pub mod vm_threaded;
+pub mod watch;
+
+pub use bytecode::Chunk;
+pub use vm::Value;
+pub use vm_optimized::NativeFunction;
+
+use anyhow::{anyhow, Result};
+use compiler::Compiler;
+use lexer::Lexer;
+use parser::Parser;
+use vm_optimized::OptimizedVM;
+
+/// Compile TopLang source to bytecode
+///
+/// Imports need the path of the importing file, so source using `import`
+/// is rejected; run such programs with `topc` instead.
+pub fn compile_source(src: &str) -> Result<Chunk> {
+    let mut lexer = Lexer::new(src.to_string());
+    let tokens = lexer.tokenize();
+    if let Some(error) = lexer.errors().first() {
+        return Err(anyhow!("{}", error.render(src)));
+    }
+
+    let program = Parser::new(tokens).parse()?;
+    if !program.imports.is_empty() {
+        return Err(anyhow!("import is only supported in files run by topc"));
+    }
+
+    Compiler::new().compile(program)
+}
+
+/// Compile and run TopLang source, returning the exit code `main` produced
+pub fn run_source(src: &str) -> Result<i32> {
+    Engine::new().run(src)
+}
+
+/// A TopLang virtual machine for a host program to drive
+///
+/// Globals set by the host, or assigned by the program, persist across runs.
+pub struct Engine {
+    vm: OptimizedVM,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Engine {
+            vm: OptimizedVM::new(),
+        }
+    }
+
+    /// Make a value visible to the program as a global variable
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.vm.set_global(name, value);
+    }
+
+    /// Current value of a global variable
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.vm.global(name)
+    }
+
+    /// Make a Rust function callable from TopLang as `name`, taking `arity`
+    /// arguments. Functions the program defines itself take precedence.
+    pub fn register_builtin(&mut self, name: &str, arity: usize, function: NativeFunction) {
+        self.vm.register_builtin(name, arity, function);
+    }
+
+    /// Make `random` and `random_int` reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.vm.set_seed(seed);
+    }
+
+    /// Stop programs that nest calls more than `depth` deep
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.vm.set_max_depth(depth);
+    }
+
+    /// Compile and run TopLang source, returning the exit code `main` produced
+    pub fn run(&mut self, src: &str) -> Result<i32> {
+        let chunk = compile_source(src)?;
+        self.vm.execute(chunk)
+    }
+
+    /// Run already compiled bytecode, rejecting it if it is malformed
+    pub fn run_chunk(&mut self, chunk: Chunk) -> Result<i32> {
+        chunk.validate()?;
+        self.vm.execute(chunk)
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,32 +1,30 @@
-mod ast;
-mod bytecode;
-mod codegen_c;
-mod compiler;
-mod interpreter;
-mod lexer;
-mod nanbox;
-mod nanbox_safe;
-mod optimizer;
-mod parser;
-mod peephole;
-mod token;
-mod vm;
-mod vm_nanbox;
-mod vm_optimized;
-mod vm_threaded;
-
-use anyhow::{Context, Result};
-use clap::Parser as ClapParser;
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser as ClapParser, ValueEnum};
 use colored::Colorize;
-use compiler::Compiler;
-use interpreter::Interpreter;
-use lexer::Lexer;
-use parser::Parser;
+use std::env;
+use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process;
-use vm_nanbox::NanBoxVM;
-use vm_optimized::OptimizedVM;
+use std::time::{Duration, Instant};
+use toplang::ast::Program;
+use toplang::bytecode::{Chunk, RegisterChunk, DEFAULT_MAX_DEPTH, DEFAULT_MAX_STACK};
+use toplang::check::Severity;
+use toplang::compiler::{Compiler, OptLevel};
+use toplang::interpreter::Interpreter;
+use toplang::lexer::Lexer;
+use toplang::output::{Formatter, Json, Plain};
+use toplang::parser::Parser;
+use toplang::profile::Profile;
+use toplang::token::Token;
+use toplang::vm_nanbox::NanBoxVM;
+use toplang::vm_optimized::OptimizedVM;
+use toplang::vm_register::RegisterVM;
+use toplang::vm_threaded::ThreadedVM;
+use toplang::{
+    check, codegen_c, codegen_native, codegen_wasm, fmt, imports, registers, repl, watch,
+};
 
 #[derive(ClapParser)]
 #[command(name = "topc")]
@@ -34,9 +32,13 @@ use vm_optimized::OptimizedVM;
 #[command(version)]
 #[command(about = "TopLang - A simple, human-first programming language", long_about = None)]
 struct Cli {
-    /// The TopLang source file to compile and run
+    /// The TopLang source file to compile and run (omit to start the REPL)
     #[arg(value_name = "FILE")]
-    file: PathBuf,
+    file: Option<PathBuf>,
+
+    /// Start an interactive session (interpreter only)
+    #[arg(long)]
+    repl: bool,
 
     /// Print tokens after lexing
     #[arg(short = 't', long)]
@@ -46,6 +48,27 @@ struct Cli {
     #[arg(short = 'a', long)]
     show_ast: bool,
 
+    /// Print the file in the canonical layout instead of running it
+    ///
+    /// Comments are not kept, so review the output before replacing the file.
+    #[arg(long)]
+    format: bool,
+
+    /// Look for mistakes without running the program
+    ///
+    /// Reports calls to missing functions or with the wrong number of
+    /// arguments, operators on values of the wrong type and undefined
+    /// variables. Fails if any of them is certain to be an error
+    #[arg(long)]
+    check: bool,
+
+    /// Run every function named `test_*` instead of `main`
+    ///
+    /// Each test runs on its own and passes when it returns 0 (or nothing)
+    /// without an error such as a failed `assert`. Fails if any test does
+    #[arg(long)]
+    run_tests: bool,
+
     /// Enable verbose output
     #[arg(short, long)]
     verbose: bool,
@@ -65,6 +88,39 @@ struct Cli {
     #[arg(long)]
     debug_vm: bool,
 
+    /// Count executed instructions and report them at the end (requires --bytecode)
+    #[arg(long)]
+    profile: bool,
+
+    /// Print each source line to stderr as it runs (requires --bytecode)
+    #[arg(long)]
+    trace: bool,
+
+    /// Inline calls to small helper functions (bytecode and native code only)
+    #[arg(long)]
+    inline: bool,
+
+    /// Optimization level: 0 compiles the program as written, 1 only folds
+    /// constants, 2 runs every pass (bytecode and native code only)
+    #[arg(
+        short = 'O',
+        long = "optimize-level",
+        value_name = "LEVEL",
+        default_value_t = 2,
+        value_parser = clap::value_parser!(u8).range(0..=2)
+    )]
+    optimize_level: u8,
+
+    /// Report how long each phase took, on stderr
+    #[arg(long)]
+    time: bool,
+
+    /// Run the program again whenever it or a file it imports changes
+    ///
+    /// Errors are printed and watching continues; stop with Ctrl+C
+    #[arg(long)]
+    watch: bool,
+
     /// Use NaN-boxed VM for maximum performance (requires --bytecode)
     ///
     /// Performance: 2.3x faster than interpreter
@@ -72,6 +128,19 @@ struct Cli {
     #[arg(long)]
     nanbox: bool,
 
+    /// Use the direct-threaded VM (requires --bytecode)
+    ///
+    /// Dispatches through a table of handler functions instead of a match
+    #[arg(long)]
+    threaded: bool,
+
+    /// Use the register-based VM (requires --bytecode)
+    ///
+    /// Translates the stack bytecode so arithmetic works directly on
+    /// variables instead of shuffling them through the stack
+    #[arg(long)]
+    registers: bool,
+
     /// Compile to native executable (AOT compilation)
     ///
     /// **EXCEPTIONAL PERFORMANCE**: 117.3x faster than interpreter (avg 15ms vs 1760ms)
@@ -90,27 +159,279 @@ struct Cli {
     #[arg(short = 'c', long)]
     compile: bool,
 
+    /// Compile to native code in memory and run it immediately (JIT)
+    ///
+    /// Skips the C compiler, so it starts faster than --compile.
+    /// Experimental: only part of the language is supported so far
+    #[arg(long)]
+    jit: bool,
+
+    /// Print the Cranelift IR of each function (requires --jit)
+    #[arg(long)]
+    dump_ir: bool,
+
     /// Output file for compiled executable
     #[arg(short = 'o', long, value_name = "FILE")]
     output: Option<PathBuf>,
+
+    /// Compile to bytecode and write it to FILE instead of running
+    ///
+    /// The resulting `.topc` file can be run directly: topc program.topc
+    #[arg(long, value_name = "FILE")]
+    emit_bytecode: Option<PathBuf>,
+
+    /// Translate to C and write the source to FILE without compiling it
+    ///
+    /// Useful without a C compiler, or to inspect the code `--compile` builds
+    #[arg(long, value_name = "FILE")]
+    emit_c: Option<PathBuf>,
+
+    /// Compile for another platform and write the result instead of running
+    ///
+    /// `wasm` writes a WebAssembly module (FILE.wasm, or the -o path).
+    /// Experimental: only numbers, booleans and printing string constants
+    /// are supported so far
+    #[arg(long, value_enum, value_name = "TARGET")]
+    target: Option<Target>,
+
+    /// Seed for `random` and `random_int`, for reproducible runs
+    ///
+    /// Without it the generator is seeded from system entropy
+    #[arg(long, value_name = "U64")]
+    seed: Option<u64>,
+
+    /// Most calls that may be in progress at once (bytecode only)
+    ///
+    /// Runaway recursion stops with an error at this depth instead of
+    /// exhausting memory
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+
+    /// Most values that may be on the stack at once (stack VMs only)
+    ///
+    /// Runaway programs stop with an error at this size instead of
+    /// exhausting memory
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_STACK)]
+    max_stack: usize,
+
+    /// How `print` and `write` show values
+    ///
+    /// `json` prints every value as JSON, for output another program reads.
+    /// Not supported when compiling to native code or WebAssembly
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "plain")]
+    print_format: PrintFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Target {
+    /// WebAssembly module importing `print_number`, `print_string` and `pow`
+    Wasm,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum PrintFormat {
+    /// Strings bare, arrays and maps as `[1, "a"]` and `{k: 1}`
+    Plain,
+    /// Every value as JSON
+    Json,
+}
+
+impl PrintFormat {
+    fn formatter(self) -> Box<dyn Formatter> {
+        match self {
+            PrintFormat::Plain => Box::new(Plain),
+            PrintFormat::Json => Box::new(Json),
+        }
+    }
 }
 
 fn main() {
     let cli = Cli::parse();
 
     if let Err(e) = run(cli) {
-        eprintln!("{} {}", "Error:".red().bold(), e);
+        // `{:#}` includes the causes, e.g. "VM runtime error: at line 3: Division by zero"
+        eprintln!("{} {:#}", "Error:".red().bold(), e);
         process::exit(1);
     }
 }
 
+/// Run `file` in a child `topc` with the same options whenever it changes,
+/// so a failing or exiting program doesn't end the watch
+fn watch_program(file: &Path) -> Result<()> {
+    let exe = env::current_exe().context("Failed to find the topc executable")?;
+    let args: Vec<OsString> = env::args_os()
+        .skip(1)
+        .filter(|arg| arg != "--watch")
+        .collect();
+
+    watch::watch(file, |paths| {
+        if io::stdout().is_terminal() {
+            print!("\x1b[2J\x1b[H");
+        }
+        let _ = io::stdout().flush();
+
+        match process::Command::new(&exe).args(&args).status() {
+            Ok(status) if !status.success() => {
+                if let Some(code) = status.code() {
+                    println!("{}", format!("Exited with code {}", code).dimmed());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!(
+                "{} Failed to run {}: {}",
+                "Error:".red().bold(),
+                exe.display(),
+                e
+            ),
+        }
+
+        let names: Vec<String> = paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        println!(
+            "{}",
+            format!("Watching {} for changes (Ctrl+C to stop)", names.join(", ")).dimmed()
+        );
+        let _ = io::stdout().flush();
+    })
+}
+
+/// Print what the checker finds in `file`, failing if any of it is an error
+fn check_program(file: &Path, program: &Program, tokens: &[Token]) -> Result<()> {
+    let diagnostics = check::check(program, tokens);
+    for diagnostic in &diagnostics {
+        let label = match diagnostic.severity {
+            Severity::Error => "error:".red().bold(),
+            Severity::Warning => "warning:".yellow().bold(),
+        };
+        eprintln!("{} {}", label, diagnostic);
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow!(
+            "Found {} error{} in {}",
+            errors,
+            if errors == 1 { "" } else { "s" },
+            file.display()
+        ));
+    }
+    println!("{} {}", "No errors in".green().bold(), file.display());
+    Ok(())
+}
+
+/// Run each `test_*` function in `program` on its own and report which
+/// passed, exiting non-zero if any failed
+fn run_tests(cli: &Cli, file: &Path, program: Program, source: &str) -> Result<()> {
+    if cli.compile || cli.jit || cli.emit_c.is_some() || cli.target.is_some() {
+        return Err(anyhow!(
+            "--run-tests needs the interpreter or a bytecode VM"
+        ));
+    }
+    let names: Vec<String> = program
+        .functions
+        .iter()
+        .filter(|f| f.name.starts_with("test_"))
+        .map(|f| f.name.clone())
+        .collect();
+    if names.is_empty() {
+        return Err(anyhow!("No test_ functions found in {}", file.display()));
+    }
+
+    // Compiled once; each test only changes which function the entry calls
+    let chunk = if cli.bytecode {
+        Some(
+            new_compiler(cli)
+                .compile(program.clone())
+                .with_context(|| "Failed to compile to bytecode")?,
+        )
+    } else {
+        None
+    };
+
+    let mut failed = 0;
+    for name in &names {
+        let result = match &chunk {
+            Some(chunk) if cli.registers => {
+                registers::translate(&chunk.calling(name)).and_then(|c| run_registers(cli, c))
+            }
+            Some(chunk) => run_chunk(cli, chunk.calling(name), Some(source)),
+            None => {
+                let mut interpreter = Interpreter::new();
+                if let Some(seed) = cli.seed {
+                    interpreter.set_seed(seed);
+                }
+                interpreter.set_formatter(cli.print_format.formatter());
+                interpreter.run_function(program.clone(), name)
+            }
+        };
+        match result {
+            Ok(0) => println!("test {} ... {}", name, "ok".green()),
+            Ok(code) => {
+                failed += 1;
+                println!("test {} ... {} (returned {})", name, "FAILED".red(), code);
+            }
+            Err(e) => {
+                failed += 1;
+                println!("test {} ... {}: {:#}", name, "FAILED".red(), e);
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed", names.len() - failed, failed);
+    process::exit(if failed > 0 { 1 } else { 0 });
+}
+
 fn run(cli: Cli) -> Result<()> {
+    let file = match &cli.file {
+        Some(file) if !cli.repl => file.clone(),
+        _ => {
+            let mut interpreter = Interpreter::new();
+            if let Some(seed) = cli.seed {
+                interpreter.set_seed(seed);
+            }
+            interpreter.set_formatter(cli.print_format.formatter());
+            let exit_code = repl::run(&mut interpreter, &mut std::io::stdout())?;
+            process::exit(exit_code);
+        }
+    };
+
+    if cli.watch {
+        return watch_program(&file);
+    }
+
+    let native = cli.compile || cli.jit || cli.emit_c.is_some() || cli.target.is_some();
+    if native && cli.print_format == PrintFormat::Json {
+        return Err(anyhow!(
+            "--print-format json is not supported when compiling to native code or WebAssembly"
+        ));
+    }
+
+    // Precompiled bytecode skips lexing, parsing and compiling entirely
+    if file.extension().is_some_and(|ext| ext == "topc") {
+        let bytes =
+            fs::read(&file).with_context(|| format!("Failed to read file: {}", file.display()))?;
+        let chunk = Chunk::from_bytes(&bytes)?;
+        let exit_code = if cli.registers {
+            run_registers(&cli, registers::translate(&chunk)?)?
+        } else {
+            run_chunk(&cli, chunk, None)?
+        };
+        process::exit(exit_code);
+    }
+
+    let mut timings = Timings::new();
+
     // Read source file
-    let source = fs::read_to_string(&cli.file)
-        .with_context(|| format!("Failed to read file: {}", cli.file.display()))?;
+    let source = fs::read_to_string(&file)
+        .with_context(|| format!("Failed to read file: {}", file.display()))?;
 
     if cli.verbose {
-        println!("{} {}", "Reading file:".blue().bold(), cli.file.display());
+        println!("{} {}", "Reading file:".blue().bold(), file.display());
     }
 
     // Lexing
@@ -118,8 +439,22 @@ fn run(cli: Cli) -> Result<()> {
         println!("{}", "Lexing...".blue().bold());
     }
 
-    let mut lexer = Lexer::new(source);
+    let mut lexer = Lexer::new(source.clone());
     let tokens = lexer.tokenize();
+    timings.lap("lex");
+
+    let lex_errors = lexer.errors();
+    if !lex_errors.is_empty() {
+        for error in lex_errors {
+            eprintln!("{} {}\n", "error:".red().bold(), error.render(&source));
+        }
+        return Err(anyhow!(
+            "Found {} invalid token{} in {}",
+            lex_errors.len(),
+            if lex_errors.len() == 1 { "" } else { "s" },
+            file.display()
+        ));
+    }
 
     if cli.show_tokens {
         println!("\n{}", "=== Tokens ===".yellow().bold());
@@ -134,15 +469,77 @@ fn run(cli: Cli) -> Result<()> {
         println!("{}", "Parsing...".blue().bold());
     }
 
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(tokens.clone());
     let program = parser.parse().with_context(|| "Failed to parse program")?;
 
+    if cli.format {
+        print!("{}", fmt::format_program(&program));
+        return Ok(());
+    }
+
+    let program = imports::resolve(&file, program)?;
+    timings.lap("parse");
+
+    if cli.check {
+        return check_program(&file, &program, &tokens);
+    }
+
     if cli.show_ast {
         println!("\n{}", "=== AST ===".yellow().bold());
         println!("{:#?}", program);
         println!();
     }
 
+    if cli.run_tests {
+        return run_tests(&cli, &file, program, &source);
+    }
+
+    if let Some(path) = &cli.emit_bytecode {
+        let chunk = new_compiler(&cli)
+            .compile(program)
+            .with_context(|| "Failed to compile to bytecode")?;
+        fs::write(path, chunk.to_bytes())
+            .with_context(|| format!("Failed to write bytecode: {}", path.display()))?;
+        if cli.verbose {
+            println!("{} {}", "Wrote bytecode to:".green().bold(), path.display());
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &cli.emit_c {
+        let chunk = new_compiler(&cli)
+            .compile(program)
+            .with_context(|| "Failed to compile to bytecode")?;
+        fs::write(path, generate_c(&cli, &chunk)?)
+            .with_context(|| format!("Failed to write C file: {}", path.display()))?;
+        if cli.verbose {
+            println!("{} {}", "Wrote C code to:".green().bold(), path.display());
+        }
+        return Ok(());
+    }
+
+    if let Some(Target::Wasm) = cli.target {
+        let chunk = new_compiler(&cli)
+            .compile(program)
+            .with_context(|| "Failed to compile to bytecode")?;
+        let wasm = codegen_wasm::WasmCodeGen::new()
+            .compile_chunk(&chunk)
+            .with_context(|| "Failed to generate WebAssembly")?;
+        let path = cli.output.clone().unwrap_or_else(|| {
+            PathBuf::from(file.file_name().unwrap_or_default()).with_extension("wasm")
+        });
+        fs::write(&path, wasm)
+            .with_context(|| format!("Failed to write WebAssembly: {}", path.display()))?;
+        if cli.verbose {
+            println!(
+                "{} {}",
+                "Wrote WebAssembly to:".green().bold(),
+                path.display()
+            );
+        }
+        return Ok(());
+    }
+
     // Execution: Choose between native compilation, bytecode VM, or interpreter
     let exit_code = if cli.compile {
         // Native AOT compilation
@@ -151,10 +548,11 @@ fn run(cli: Cli) -> Result<()> {
         }
 
         // First compile to bytecode
-        let mut compiler = Compiler::new();
+        let mut compiler = new_compiler(&cli);
         let chunk = compiler
             .compile(program)
             .with_context(|| "Failed to compile to bytecode")?;
+        timings.compiled(&compiler);
 
         if cli.show_bytecode {
             println!("\n{}", "=== Bytecode ===".yellow().bold());
@@ -167,14 +565,11 @@ fn run(cli: Cli) -> Result<()> {
         }
 
         // Then transpile bytecode to C code
-        let mut codegen = codegen_c::CCodeGen::new();
-        let c_code = codegen
-            .compile_chunk(&chunk)
-            .with_context(|| "Failed to generate C code")?;
+        let c_code = generate_c(&cli, &chunk)?;
 
         // Determine output filename
         let output_file = cli.output.clone().unwrap_or_else(|| {
-            let mut path = cli.file.clone();
+            let mut path = file.clone();
             path.set_extension(if cfg!(windows) { "exe" } else { "" });
             path.file_name()
                 .map(PathBuf::from)
@@ -243,60 +638,42 @@ fn run(cli: Cli) -> Result<()> {
                 1
             }
         }
-    } else if cli.bytecode {
-        // Bytecode compilation
+    } else if cli.jit {
+        // Native code generated in memory and run in this process
         if cli.verbose {
-            println!("{}", "Compiling to bytecode...".blue().bold());
+            println!("{}", "JIT compiling to native code...".blue().bold());
+            println!();
         }
 
-        let mut compiler = Compiler::new();
+        let mut compiler = new_compiler(&cli);
         let chunk = compiler
             .compile(program)
             .with_context(|| "Failed to compile to bytecode")?;
-
-        if cli.show_bytecode {
-            println!("\n{}", "=== Bytecode ===".yellow().bold());
-            chunk.disassemble("main");
-            for (name, func_chunk) in &chunk.functions {
-                println!();
-                func_chunk.disassemble(name);
-            }
-            println!();
+        timings.compiled(&compiler);
+        let mut codegen = codegen_native::NativeCodeGen::jit()?;
+        codegen.set_dump_ir(cli.dump_ir);
+        codegen
+            .run(&chunk)
+            .with_context(|| "JIT compilation failed")?
+    } else if cli.bytecode {
+        // Bytecode compilation
+        if cli.verbose {
+            println!("{}", "Compiling to bytecode...".blue().bold());
         }
 
-        // Execute with VM - choose between NaN-boxed or standard optimized VM
-        if cli.nanbox {
-            // Use NaN-boxed VM for maximum performance
-            if cli.verbose {
-                println!(
-                    "{}",
-                    "Executing with NaN-boxed VM (maximum performance)..."
-                        .blue()
-                        .bold()
-                );
-                println!();
-            }
-
-            let mut vm = NanBoxVM::new();
-            if cli.debug_vm {
-                vm.set_debug(true);
-            }
-
-            vm.execute(chunk)
-                .with_context(|| "NaN-boxed VM runtime error")?
+        let mut compiler = new_compiler(&cli);
+        if cli.registers {
+            let chunk = compiler
+                .compile_to_registers(program)
+                .with_context(|| "Failed to compile to register bytecode")?;
+            timings.compiled(&compiler);
+            run_registers(&cli, chunk)?
         } else {
-            // Use standard optimized VM
-            if cli.verbose {
-                println!("{}", "Executing with optimized VM...".blue().bold());
-                println!();
-            }
-
-            let mut vm = OptimizedVM::new();
-            if cli.debug_vm {
-                vm.set_debug(true);
-            }
-
-            vm.execute(chunk).with_context(|| "VM runtime error")?
+            let chunk = compiler
+                .compile(program)
+                .with_context(|| "Failed to compile to bytecode")?;
+            timings.compiled(&compiler);
+            run_chunk(&cli, chunk, Some(&source))?
         }
     } else {
         // Use traditional tree-walking interpreter
@@ -306,11 +683,21 @@ fn run(cli: Cli) -> Result<()> {
         }
 
         let mut interpreter = Interpreter::new();
+        if let Some(seed) = cli.seed {
+            interpreter.set_seed(seed);
+        }
+        interpreter.set_formatter(cli.print_format.formatter());
         interpreter
             .interpret(program)
             .with_context(|| "Runtime error")?
     };
 
+    // With --compile the last phase is the C compiler, not the program
+    timings.lap(if cli.compile { "cc" } else { "run" });
+    if cli.time {
+        eprintln!("{}", timings.report());
+    }
+
     if cli.verbose {
         println!(
             "\n{} {}",
@@ -322,6 +709,227 @@ fn run(cli: Cli) -> Result<()> {
     process::exit(exit_code);
 }
 
+/// Wall-clock time of each phase, for --time
+struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+    /// When the current phase started
+    start: Instant,
+}
+
+impl Timings {
+    fn new() -> Self {
+        Timings {
+            phases: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    /// End the current phase, naming it `phase`, and start the next one
+    fn lap(&mut self, phase: &'static str) {
+        self.phases.push((phase, self.start.elapsed()));
+        self.start = Instant::now();
+    }
+
+    /// End the compile phase, split into the optimizer's share and the rest
+    fn compiled(&mut self, compiler: &Compiler) {
+        let total = self.start.elapsed();
+        let optimize = compiler.optimize_time();
+        self.phases.push(("optimize", optimize));
+        self.phases
+            .push(("compile", total.saturating_sub(optimize)));
+        self.start = Instant::now();
+    }
+
+    /// `lex: 1.2ms, parse: 3.4ms, ...`
+    fn report(&self) -> String {
+        self.phases
+            .iter()
+            .map(|(phase, time)| format!("{}: {:.1}ms", phase, time.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Transpile a compiled chunk to C source
+fn generate_c(cli: &Cli, chunk: &Chunk) -> Result<String> {
+    let mut codegen = codegen_c::CCodeGen::new();
+    if let Some(seed) = cli.seed {
+        codegen.set_seed(seed);
+    }
+    codegen
+        .compile_chunk(chunk)
+        .with_context(|| "Failed to generate C code")
+}
+
+/// A bytecode compiler configured by the CLI flags
+fn new_compiler(cli: &Cli) -> Compiler {
+    let mut compiler = Compiler::new();
+    if cli.inline {
+        compiler.enable_inlining();
+    }
+    compiler.set_optimize_level(match cli.optimize_level {
+        0 => OptLevel::None,
+        1 => OptLevel::Fold,
+        _ => OptLevel::Full,
+    });
+    compiler
+}
+
+/// Run a compiled chunk on the VM selected by the CLI flags, with the
+/// `source` it was compiled from if there is one
+fn run_chunk(cli: &Cli, chunk: Chunk, source: Option<&str>) -> Result<i32> {
+    if cli.show_bytecode {
+        println!("\n{}", "=== Bytecode ===".yellow().bold());
+        chunk.disassemble("main");
+        for (name, func_chunk) in &chunk.functions {
+            println!();
+            func_chunk.disassemble(name);
+        }
+        println!();
+    }
+
+    // Execute with VM - choose between NaN-boxed, threaded or standard optimized VM
+    let exit_code = if cli.nanbox {
+        // Use NaN-boxed VM for maximum performance
+        if cli.verbose {
+            println!(
+                "{}",
+                "Executing with NaN-boxed VM (maximum performance)..."
+                    .blue()
+                    .bold()
+            );
+            println!();
+        }
+
+        let mut vm = NanBoxVM::new();
+        if cli.debug_vm {
+            vm.set_debug(true);
+        }
+        if let Some(seed) = cli.seed {
+            vm.set_seed(seed);
+        }
+        vm.set_max_depth(cli.max_depth);
+        vm.set_max_stack(cli.max_stack);
+        vm.set_formatter(cli.print_format.formatter());
+        if cli.profile {
+            vm.enable_profiling();
+        }
+        if cli.trace {
+            vm.enable_tracing(source);
+        }
+
+        let exit_code = vm
+            .execute(chunk)
+            .with_context(|| "NaN-boxed VM runtime error")?;
+        print_profile(vm.profile());
+        exit_code
+    } else if cli.threaded {
+        // Use direct-threaded dispatch
+        if cli.verbose {
+            println!("{}", "Executing with threaded VM...".blue().bold());
+            println!();
+        }
+
+        let mut vm = ThreadedVM::new();
+        if cli.debug_vm {
+            vm.set_debug(true);
+        }
+        if let Some(seed) = cli.seed {
+            vm.set_seed(seed);
+        }
+        vm.set_max_depth(cli.max_depth);
+        vm.set_max_stack(cli.max_stack);
+        vm.set_formatter(cli.print_format.formatter());
+        if cli.profile {
+            vm.enable_profiling();
+        }
+        if cli.trace {
+            vm.enable_tracing(source);
+        }
+
+        let exit_code = vm
+            .execute(chunk)
+            .with_context(|| "Threaded VM runtime error")?;
+        print_profile(vm.profile());
+        exit_code
+    } else {
+        // Use standard optimized VM
+        if cli.verbose {
+            println!("{}", "Executing with optimized VM...".blue().bold());
+            println!();
+        }
+
+        let mut vm = OptimizedVM::new();
+        if cli.debug_vm {
+            vm.set_debug(true);
+        }
+        if let Some(seed) = cli.seed {
+            vm.set_seed(seed);
+        }
+        vm.set_max_depth(cli.max_depth);
+        vm.set_max_stack(cli.max_stack);
+        vm.set_formatter(cli.print_format.formatter());
+        if cli.profile {
+            vm.enable_profiling();
+        }
+        if cli.trace {
+            vm.enable_tracing(source);
+        }
+
+        let exit_code = vm.execute(chunk).with_context(|| "VM runtime error")?;
+        print_profile(vm.profile());
+        exit_code
+    };
+
+    Ok(exit_code)
+}
+
+/// Print the instruction counts gathered by a VM run with --profile
+fn print_profile(profile: Option<&Profile>) {
+    if let Some(profile) = profile {
+        println!("\n{}", "=== Profile ===".yellow().bold());
+        print!("{}", profile.report());
+    }
+}
+
+/// Run a chunk of register bytecode on the register VM
+fn run_registers(cli: &Cli, chunk: RegisterChunk) -> Result<i32> {
+    if cli.profile {
+        return Err(anyhow!("--profile is not supported on the register VM"));
+    }
+    if cli.trace {
+        return Err(anyhow!("--trace is not supported on the register VM"));
+    }
+
+    if cli.show_bytecode {
+        println!("\n{}", "=== Register bytecode ===".yellow().bold());
+        chunk.disassemble("main");
+        for (name, func_chunk) in &chunk.functions {
+            println!();
+            func_chunk.disassemble(name);
+        }
+        println!();
+    }
+
+    if cli.verbose {
+        println!("{}", "Executing with register VM...".blue().bold());
+        println!();
+    }
+
+    let mut vm = RegisterVM::new();
+    if cli.debug_vm {
+        vm.set_debug(true);
+    }
+    if let Some(seed) = cli.seed {
+        vm.set_seed(seed);
+    }
+    vm.set_max_depth(cli.max_depth);
+    vm.set_formatter(cli.print_format.formatter());
+
+    vm.execute(chunk)
+        .with_context(|| "Register VM runtime error")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -20,6 +20,7 @@
 //!
 //! NOTE: This is the original implementation. The production version is in nanbox_safe.rs
 
+use crate::builtins::{format_number, quote_string};
 use anyhow::{anyhow, Result};
 
 // NaN mask: exponent all 1s, mantissa non-zero
@@ -183,6 +184,14 @@ impl Value {
         }
     }
 
+    /// Show the value as an element of an array, where strings are quoted
+    fn fmt_element(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.as_string_ref() {
+            Ok(s) => write!(f, "{}", quote_string(s)),
+            Err(_) => write!(f, "{}", self),
+        }
+    }
+
     #[inline]
     pub fn as_array(&self) -> Result<Vec<Value>> {
         if self.is_array() {
@@ -271,6 +280,9 @@ impl Value {
     }
 
     /// Drop heap-allocated data
+    ///
+    /// # Safety
+    /// Neither this value nor any copy of it may be used afterwards
     pub unsafe fn drop_in_place(&mut self) {
         if self.is_string() {
             let ptr = (self.0 & POINTER_MASK) as *mut String;
@@ -293,11 +305,7 @@ impl std::fmt::Display for Value {
         } else if let Ok(b) = self.as_boolean() {
             write!(f, "{}", b)
         } else if let Ok(n) = self.as_number() {
-            if n.fract() == 0.0 && n.is_finite() {
-                write!(f, "{}", n as i64)
-            } else {
-                write!(f, "{}", n)
-            }
+            write!(f, "{}", format_number(n))
         } else if let Ok(s) = self.as_string_ref() {
             write!(f, "{}", s)
         } else if let Ok(arr) = self.as_array_ref() {
@@ -306,7 +314,7 @@ impl std::fmt::Display for Value {
                 if i > 0 {
                     write!(f, ", ")?;
                 }
-                write!(f, "{}", val)?;
+                val.fmt_element(f)?;
             }
             write!(f, "]")
         } else {
@@ -1,7 +1,7 @@
 /// Safe NaN-boxed value representation using Rc for heap types
 ///
 /// All values fit in a single 64-bit word by exploiting IEEE 754 NaN representation.
-/// Heap-allocated types (String, Array) use Rc for safe automatic memory management.
+/// Heap-allocated types (String, Array, Map) use Rc for safe automatic memory management.
 ///
 /// Encoding scheme:
 /// - Normal numbers: Standard IEEE 754 f64
@@ -11,6 +11,16 @@
 ///   - True:  0x7FF8_0000_0000_0002
 ///   - String: 0x7FF8_0000_0000_0003 + 48-bit Rc pointer
 ///   - Array:  0x7FF8_0000_0000_0004 + 48-bit Rc pointer
+///   - Map:    0x7FF8_0000_0000_0005 + 48-bit Rc pointer
+///   - Big integer: 0x7FF8_0000_0000_0006 + 48-bit Rc pointer to an `i64`
+///   - Integer: 0x7FF9 in the top 16 bits + a 48-bit two's complement integer
+///
+/// Whole numbers are kept apart from floats, as the other engines keep
+/// them, so they stay exact across the full `i64` range: those that fit in
+/// 48 bits are stored inline and the rest are boxed.
+use crate::builtins::Numeric;
+use crate::output::{print_array, print_map, render, Formatter, Plain, Printable};
+use std::collections::HashMap;
 use std::rc::Rc;
 
 // NaN mask: exponent all 1s, mantissa non-zero
@@ -22,6 +32,11 @@ const TAG_FALSE: u64 = QNAN | 1;
 const TAG_TRUE: u64 = QNAN | 2;
 const TAG_STRING: u64 = QNAN | 3;
 const TAG_ARRAY: u64 = QNAN | 4;
+const TAG_MAP: u64 = QNAN | 5;
+const TAG_BIG_INTEGER: u64 = QNAN | 6;
+
+// Tag for inline integers, which use all 48 low bits as payload
+const TAG_INTEGER: u64 = 0x7FF9_0000_0000_0000;
 
 // Mask for extracting pointer (lower 48 bits)
 const POINTER_MASK: u64 = 0x0000_FFFF_FFFF_FFFF;
@@ -29,6 +44,10 @@ const POINTER_MASK: u64 = 0x0000_FFFF_FFFF_FFFF;
 // Mask for checking type tags (upper 16 bits + lower 4 bits)
 const TYPE_MASK: u64 = 0xFFFF_0000_0000_000F;
 
+// Smallest and largest integers stored inline
+const INLINE_MIN: i64 = -(1 << 47);
+const INLINE_MAX: i64 = (1 << 47) - 1;
+
 /// A NaN-boxed value - all types fit in 64 bits
 /// Uses Rc for safe heap memory management
 pub struct NanValue(u64);
@@ -41,6 +60,24 @@ impl NanValue {
         NanValue(n.to_bits())
     }
 
+    #[inline]
+    pub fn integer(n: i64) -> Self {
+        if (INLINE_MIN..=INLINE_MAX).contains(&n) {
+            NanValue(TAG_INTEGER | (n as u64 & POINTER_MASK))
+        } else {
+            let ptr = Rc::into_raw(Rc::new(n)) as u64;
+            NanValue(TAG_BIG_INTEGER | (ptr & POINTER_MASK))
+        }
+    }
+
+    #[inline]
+    pub fn numeric(n: Numeric) -> Self {
+        match n {
+            Numeric::Integer(n) => NanValue::integer(n),
+            Numeric::Float(x) => NanValue::number(x),
+        }
+    }
+
     #[inline]
     pub fn null() -> Self {
         NanValue(TAG_NULL)
@@ -69,17 +106,40 @@ impl NanValue {
         NanValue(TAG_ARRAY | (ptr & POINTER_MASK))
     }
 
+    #[inline]
+    pub fn map(map: HashMap<String, NanValue>) -> Self {
+        let rc = Rc::new(map);
+        let ptr = Rc::into_raw(rc) as u64;
+        NanValue(TAG_MAP | (ptr & POINTER_MASK))
+    }
+
     // Constant for stack initialization
     #[allow(dead_code)]
     pub const NULL_VALUE: NanValue = NanValue(TAG_NULL);
 
     // ===== Type checks =====
 
+    /// Whether this is a number of either kind
     #[inline]
     pub fn is_number(&self) -> bool {
+        self.is_float() || self.is_integer()
+    }
+
+    #[inline]
+    pub fn is_float(&self) -> bool {
         (self.0 & QNAN) != QNAN
     }
 
+    #[inline]
+    pub fn is_integer(&self) -> bool {
+        (self.0 & !POINTER_MASK) == TAG_INTEGER || self.is_big_integer()
+    }
+
+    #[inline]
+    fn is_big_integer(&self) -> bool {
+        (self.0 & TYPE_MASK) == TAG_BIG_INTEGER
+    }
+
     #[inline]
     pub fn is_null(&self) -> bool {
         self.0 == TAG_NULL
@@ -101,12 +161,30 @@ impl NanValue {
         (self.0 & TYPE_MASK) == TAG_ARRAY
     }
 
+    #[inline]
+    pub fn is_map(&self) -> bool {
+        (self.0 & TYPE_MASK) == TAG_MAP
+    }
+
     // ===== Extractors =====
 
+    /// The number this value holds as a float, rounding wide integers
     #[inline]
     pub fn as_number(&self) -> Option<f64> {
-        if self.is_number() {
-            Some(f64::from_bits(self.0))
+        self.as_numeric().map(Numeric::as_f64)
+    }
+
+    /// The number this value holds, keeping integers exact
+    #[inline]
+    pub fn as_numeric(&self) -> Option<Numeric> {
+        if self.is_float() {
+            Some(Numeric::Float(f64::from_bits(self.0)))
+        } else if (self.0 & !POINTER_MASK) == TAG_INTEGER {
+            // Shift the payload's sign bit up to the top to sign-extend it
+            Some(Numeric::Integer(((self.0 << 16) as i64) >> 16))
+        } else if self.is_big_integer() {
+            let ptr = ((self.0 & POINTER_MASK) & !0xF) as *const i64;
+            Some(Numeric::Integer(unsafe { *ptr }))
         } else {
             None
         }
@@ -153,15 +231,45 @@ impl NanValue {
         }
     }
 
+    #[inline]
+    pub fn as_map(&self) -> Option<Rc<HashMap<String, NanValue>>> {
+        if self.is_map() {
+            let ptr = ((self.0 & POINTER_MASK) & !0xF) as *const HashMap<String, NanValue>;
+            // Clone the Rc to increment reference count
+            unsafe {
+                Rc::increment_strong_count(ptr);
+                Some(Rc::from_raw(ptr))
+            }
+        } else {
+            None
+        }
+    }
+
     // ===== Truthiness =====
 
     #[inline]
+    /// Name of the value's type, as returned by `type of`
+    pub fn type_name(&self) -> &'static str {
+        if self.is_number() {
+            "number"
+        } else if self.is_string() {
+            "string"
+        } else if self.is_boolean() {
+            "boolean"
+        } else if self.is_array() {
+            "array"
+        } else if self.is_map() {
+            "map"
+        } else {
+            "null"
+        }
+    }
+
     pub fn is_truthy(&self) -> bool {
         if self.is_null() || self.0 == TAG_FALSE {
             false
-        } else if self.is_number() {
-            let n = f64::from_bits(self.0);
-            n != 0.0
+        } else if let Some(n) = self.as_numeric() {
+            !n.is_zero()
         } else if self.is_string() {
             if let Some(s) = self.as_string() {
                 !s.is_empty()
@@ -174,6 +282,8 @@ impl NanValue {
             } else {
                 false
             }
+        } else if let Some(m) = self.as_map() {
+            !m.is_empty()
         } else {
             true
         }
@@ -188,10 +298,8 @@ impl NanValue {
         }
 
         // Type-specific comparison
-        if self.is_number() && other.is_number() {
-            let a = f64::from_bits(self.0);
-            let b = f64::from_bits(other.0);
-            (a - b).abs() < f64::EPSILON
+        if let (Some(a), Some(b)) = (self.as_numeric(), other.as_numeric()) {
+            a.equals(b)
         } else if self.is_string() && other.is_string() {
             if let (Some(a), Some(b)) = (self.as_string(), other.as_string()) {
                 *a == *b
@@ -207,6 +315,8 @@ impl NanValue {
             } else {
                 false
             }
+        } else if let (Some(a), Some(b)) = (self.as_map(), other.as_map()) {
+            a.len() == b.len() && a.iter().all(|(k, x)| b.get(k).is_some_and(|y| x.equals(y)))
         } else {
             false
         }
@@ -230,6 +340,19 @@ impl Clone for NanValue {
                 Rc::increment_strong_count(ptr);
             }
             NanValue(self.0)
+        } else if self.is_map() {
+            let ptr = ((self.0 & POINTER_MASK) & !0xF) as *const HashMap<String, NanValue>;
+            unsafe {
+                // Increment reference count for the new clone
+                Rc::increment_strong_count(ptr);
+            }
+            NanValue(self.0)
+        } else if self.is_big_integer() {
+            let ptr = ((self.0 & POINTER_MASK) & !0xF) as *const i64;
+            unsafe {
+                Rc::increment_strong_count(ptr);
+            }
+            NanValue(self.0)
         } else {
             // Numbers, booleans, null are just copied
             NanValue(self.0)
@@ -252,35 +375,43 @@ impl Drop for NanValue {
                 // Decrement reference count (and free if zero)
                 drop(Rc::from_raw(ptr));
             }
+        } else if self.is_map() {
+            let ptr = ((self.0 & POINTER_MASK) & !0xF) as *const HashMap<String, NanValue>;
+            unsafe {
+                // Decrement reference count (and free if zero)
+                drop(Rc::from_raw(ptr));
+            }
+        } else if self.is_big_integer() {
+            let ptr = ((self.0 & POINTER_MASK) & !0xF) as *const i64;
+            unsafe {
+                drop(Rc::from_raw(ptr));
+            }
         }
     }
 }
 
 impl std::fmt::Display for NanValue {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&render(self, &Plain))
+    }
+}
+
+impl Printable for NanValue {
+    fn print_to(&self, formatter: &dyn Formatter, out: &mut String, nested: bool) {
         if self.is_null() {
-            write!(f, "null")
+            formatter.null(out);
         } else if let Some(b) = self.as_boolean() {
-            write!(f, "{}", b)
-        } else if let Some(n) = self.as_number() {
-            if n.fract() == 0.0 && n.is_finite() {
-                write!(f, "{}", n as i64)
-            } else {
-                write!(f, "{}", n)
-            }
+            formatter.boolean(out, b);
+        } else if let Some(n) = self.as_numeric() {
+            formatter.number(out, n);
         } else if let Some(s) = self.as_string() {
-            write!(f, "{}", *s)
-        } else if let Some(arr) = self.as_array() {
-            write!(f, "[")?;
-            for (i, val) in arr.iter().enumerate() {
-                if i > 0 {
-                    write!(f, ", ")?;
-                }
-                write!(f, "{}", val)?;
-            }
-            write!(f, "]")
+            formatter.string(out, &s, nested);
+        } else if let Some(items) = self.as_array() {
+            print_array(&items, formatter, out);
+        } else if let Some(map) = self.as_map() {
+            print_map(&map, formatter, out);
         } else {
-            write!(f, "<unknown>")
+            out.push_str("<unknown>");
         }
     }
 }
@@ -291,12 +422,16 @@ impl std::fmt::Debug for NanValue {
             write!(f, "Null")
         } else if let Some(b) = self.as_boolean() {
             write!(f, "Boolean({})", b)
+        } else if let Some(Numeric::Integer(n)) = self.as_numeric() {
+            write!(f, "Integer({})", n)
         } else if let Some(n) = self.as_number() {
             write!(f, "Number({})", n)
         } else if let Some(s) = self.as_string() {
             write!(f, "String({:?})", *s)
         } else if let Some(arr) = self.as_array() {
             write!(f, "Array({:?})", *arr)
+        } else if let Some(map) = self.as_map() {
+            write!(f, "Map({:?})", *map)
         } else {
             write!(f, "Unknown(0x{:016x})", self.0)
         }
@@ -314,6 +449,27 @@ mod tests {
         assert_eq!(v.as_number(), Some(42.0));
     }
 
+    #[test]
+    fn test_integers_stay_exact() {
+        for n in [
+            0,
+            -1,
+            INLINE_MAX,
+            INLINE_MIN,
+            9007199254740993,
+            i64::MIN,
+            i64::MAX,
+        ] {
+            let v = NanValue::integer(n);
+            assert!(v.is_number() && v.is_integer() && !v.is_float());
+            assert_eq!(v.clone().to_string(), n.to_string());
+            assert!(matches!(v.as_numeric(), Some(Numeric::Integer(m)) if m == n));
+        }
+        assert!(NanValue::integer(3).equals(&NanValue::number(3.0)));
+        assert!(!NanValue::integer(0).is_truthy());
+        assert_eq!(NanValue::integer(i64::MAX).type_name(), "number");
+    }
+
     #[test]
     fn test_boolean() {
         let t = NanValue::boolean(true);
@@ -387,4 +543,18 @@ mod tests {
         let n2 = NanValue::null();
         assert!(n1.equals(&n2));
     }
+
+    #[test]
+    fn test_map() {
+        let mut entries = HashMap::new();
+        entries.insert("a".to_string(), NanValue::number(1.0));
+        let m1 = NanValue::map(entries.clone());
+        let m2 = m1.clone();
+        assert!(m1.is_map());
+        assert!(!m1.is_array());
+        assert!(m1.is_truthy());
+        assert!(m1.equals(&NanValue::map(entries)));
+        assert_eq!(m2.to_string(), "{a: 1}");
+        assert!(!NanValue::map(HashMap::new()).is_truthy());
+    }
 }
@@ -3,6 +3,8 @@
 /// This module performs compile-time evaluation of constant expressions,
 /// eliminating runtime overhead for operations with known values.
 use crate::ast::*;
+use crate::builtins::{range_values, substring, Builtin, Numeric};
+use std::collections::{HashMap, HashSet};
 
 /// Optimize an expression by folding constants
 pub fn fold_constants(expr: &Expr) -> Expr {
@@ -11,58 +13,51 @@ pub fn fold_constants(expr: &Expr) -> Expr {
             let left = fold_constants(left);
             let right = fold_constants(right);
 
+            if let (Some(a), Some(b)) = (numeric(&left), numeric(&right)) {
+                if let Some(folded) = fold_numbers(a, op, b) {
+                    return folded;
+                }
+            }
+
             // Try to fold if both sides are constants
             match (&left, op, &right) {
-                (Expr::Number(a), BinaryOp::Add, Expr::Number(b)) => Expr::Number(a + b),
-                (Expr::Number(a), BinaryOp::Subtract, Expr::Number(b)) => Expr::Number(a - b),
-                (Expr::Number(a), BinaryOp::Multiply, Expr::Number(b)) => Expr::Number(a * b),
-                (Expr::Number(a), BinaryOp::Divide, Expr::Number(b)) => {
-                    if *b != 0.0 {
-                        Expr::Number(a / b)
-                    } else {
-                        // Can't fold division by zero
-                        Expr::Binary {
-                            left: Box::new(left),
-                            op: op.clone(),
-                            right: Box::new(right),
-                        }
-                    }
-                }
-                (Expr::Number(a), BinaryOp::Modulo, Expr::Number(b)) => {
-                    if *b != 0.0 {
-                        Expr::Number(a % b)
-                    } else {
-                        Expr::Binary {
-                            left: Box::new(left),
-                            op: op.clone(),
-                            right: Box::new(right),
-                        }
-                    }
-                }
                 (Expr::Boolean(a), BinaryOp::And, Expr::Boolean(b)) => Expr::Boolean(*a && *b),
                 (Expr::Boolean(a), BinaryOp::Or, Expr::Boolean(b)) => Expr::Boolean(*a || *b),
-                (Expr::Number(a), BinaryOp::Equals, Expr::Number(b)) => {
-                    Expr::Boolean((a - b).abs() < f64::EPSILON)
+                (Expr::String(a), BinaryOp::Add, Expr::String(b)) => {
+                    Expr::String(format!("{}{}", a, b))
                 }
-                (Expr::Number(a), BinaryOp::NotEquals, Expr::Number(b)) => {
-                    Expr::Boolean((a - b).abs() >= f64::EPSILON)
+                (Expr::String(a), BinaryOp::Add, Expr::Number(_) | Expr::Integer(_)) => {
+                    Expr::String(format!("{}{}", a, numeric(&right).unwrap()))
                 }
-                (Expr::Number(a), BinaryOp::Greater, Expr::Number(b)) => Expr::Boolean(a > b),
-                (Expr::Number(a), BinaryOp::GreaterOrEquals, Expr::Number(b)) => {
-                    Expr::Boolean(a >= b)
+                (Expr::Number(_) | Expr::Integer(_), BinaryOp::Add, Expr::String(b)) => {
+                    Expr::String(format!("{}{}", numeric(&left).unwrap(), b))
                 }
-                (Expr::Number(a), BinaryOp::Less, Expr::Number(b)) => Expr::Boolean(a < b),
-                (Expr::Number(a), BinaryOp::LessOrEquals, Expr::Number(b)) => Expr::Boolean(a <= b),
-                (Expr::String(a), BinaryOp::Add, Expr::String(b)) => {
-                    Expr::String(format!("{}{}", a, b))
+                (Expr::String(a), BinaryOp::Contains, Expr::String(b)) => {
+                    Expr::Boolean(a.contains(b.as_str()))
                 }
+                (Expr::Array(items), BinaryOp::Contains, needle)
+                    if is_literal(needle)
+                        && items
+                            .iter()
+                            .all(|item| literals_equal(item, needle).is_some()) =>
+                {
+                    Expr::Boolean(
+                        items
+                            .iter()
+                            .any(|item| literals_equal(item, needle) == Some(true)),
+                    )
+                }
+                (Expr::String(a), BinaryOp::IndexOf, Expr::String(b)) => Expr::Integer(
+                    a.find(b.as_str())
+                        .map_or(-1, |i| a[..i].chars().count() as i64),
+                ),
                 // Special optimizations
-                (Expr::Number(n), BinaryOp::Add, _) if *n == 0.0 => right,
-                (_, BinaryOp::Add, Expr::Number(n)) if *n == 0.0 => left,
-                (Expr::Number(n), BinaryOp::Multiply, _) if *n == 1.0 => right,
-                (_, BinaryOp::Multiply, Expr::Number(n)) if *n == 1.0 => left,
-                (Expr::Number(n), BinaryOp::Multiply, _) if *n == 0.0 => Expr::Number(0.0),
-                (_, BinaryOp::Multiply, Expr::Number(n)) if *n == 0.0 => Expr::Number(0.0),
+                (n, BinaryOp::Add, _) if is_number(n, 0.0) => right,
+                (_, BinaryOp::Add, n) if is_number(n, 0.0) => left,
+                (n, BinaryOp::Multiply, _) if is_number(n, 1.0) => right,
+                (_, BinaryOp::Multiply, n) if is_number(n, 1.0) => left,
+                (n, BinaryOp::Multiply, _) if is_number(n, 0.0) => left,
+                (_, BinaryOp::Multiply, n) if is_number(n, 0.0) => right,
                 _ => Expr::Binary {
                     left: Box::new(left),
                     op: op.clone(),
@@ -76,10 +71,23 @@ pub fn fold_constants(expr: &Expr) -> Expr {
 
             match (op, &operand) {
                 (UnaryOp::Not, Expr::Boolean(b)) => Expr::Boolean(!b),
-                (UnaryOp::Negate, Expr::Number(n)) => Expr::Number(-n),
-                (UnaryOp::Length, Expr::String(s)) => Expr::Number(s.len() as f64),
-                (UnaryOp::Length, Expr::Array(arr)) => Expr::Number(arr.len() as f64),
+                (UnaryOp::Negate, Expr::Number(_) | Expr::Integer(_)) => {
+                    number_literal(numeric(&operand).unwrap().negate())
+                }
+                (UnaryOp::Length, Expr::String(s)) => Expr::Integer(s.chars().count() as i64),
+                (UnaryOp::Length, Expr::Array(arr)) => Expr::Integer(arr.len() as i64),
                 (UnaryOp::Uppercase, Expr::String(s)) => Expr::String(s.to_uppercase()),
+                (UnaryOp::Lowercase, Expr::String(s)) => Expr::String(s.to_lowercase()),
+                (UnaryOp::Trim, Expr::String(s)) => Expr::String(s.trim().to_string()),
+                (UnaryOp::TypeOf, Expr::Number(_) | Expr::Integer(_)) => {
+                    Expr::String("number".to_string())
+                }
+                (UnaryOp::TypeOf, Expr::String(_)) => Expr::String("string".to_string()),
+                (UnaryOp::TypeOf, Expr::Boolean(_)) => Expr::String("boolean".to_string()),
+                // Only when dropping the elements can't skip a side effect
+                (UnaryOp::TypeOf, Expr::Array(elements)) if elements.iter().all(is_literal) => {
+                    Expr::String("array".to_string())
+                }
                 _ => Expr::Unary {
                     op: op.clone(),
                     operand: Box::new(operand),
@@ -92,14 +100,21 @@ pub fn fold_constants(expr: &Expr) -> Expr {
             Expr::Array(folded)
         }
 
+        Expr::Map(entries) => Expr::Map(
+            entries
+                .iter()
+                .map(|(key, value)| (fold_constants(key), fold_constants(value)))
+                .collect(),
+        ),
+
         Expr::Index { array, index } => {
             let array = fold_constants(array);
             let index = fold_constants(index);
 
             // Try to fold constant array indexing
             match (&array, &index) {
-                (Expr::Array(arr), Expr::Number(idx)) => {
-                    let idx = *idx as usize;
+                (Expr::Array(arr), Expr::Number(_) | Expr::Integer(_)) => {
+                    let idx = numeric(&index).unwrap().as_f64() as usize;
                     if idx < arr.len() {
                         arr[idx].clone()
                     } else {
@@ -131,13 +146,14 @@ pub fn fold_constants(expr: &Expr) -> Expr {
 
             // Try to fold constant substring
             match (&string, &from, &to) {
-                (Expr::String(s), Expr::Number(f), Expr::Number(t)) => {
-                    let from_idx = *f as usize;
-                    let to_idx = *t as usize;
-                    let chars: Vec<char> = s.chars().collect();
-
-                    if from_idx <= to_idx && to_idx <= chars.len() {
-                        let result: String = chars[from_idx..to_idx].iter().collect();
+                // Invalid indices are left for the runtime to report
+                (
+                    Expr::String(s),
+                    Expr::Number(_) | Expr::Integer(_),
+                    Expr::Number(_) | Expr::Integer(_),
+                ) => {
+                    let (f, t) = (numeric(&from).unwrap(), numeric(&to).unwrap());
+                    if let Ok(result) = substring(s, f.as_f64(), t.as_f64()) {
                         Expr::String(result)
                     } else {
                         Expr::Substring {
@@ -155,132 +171,1391 @@ pub fn fold_constants(expr: &Expr) -> Expr {
             }
         }
 
+        Expr::Slice { array, from, to } => Expr::Slice {
+            array: Box::new(fold_constants(array)),
+            from: Box::new(fold_constants(from)),
+            to: Box::new(fold_constants(to)),
+        },
+
+        Expr::Replace {
+            string,
+            pattern,
+            replacement,
+        } => {
+            let string = fold_constants(string);
+            let pattern = fold_constants(pattern);
+            let replacement = fold_constants(replacement);
+
+            match (&string, &pattern, &replacement) {
+                (Expr::String(s), Expr::String(p), Expr::String(r)) => {
+                    Expr::String(s.replace(p.as_str(), r))
+                }
+                _ => Expr::Replace {
+                    string: Box::new(string),
+                    pattern: Box::new(pattern),
+                    replacement: Box::new(replacement),
+                },
+            }
+        }
+
+        // A known condition picks its branch outright
+        Expr::Ternary {
+            condition,
+            then_value,
+            else_value,
+        } => match fold_constants(condition) {
+            Expr::Boolean(true) => fold_constants(then_value),
+            Expr::Boolean(false) => fold_constants(else_value),
+            condition => Expr::Ternary {
+                condition: Box::new(condition),
+                then_value: Box::new(fold_constants(then_value)),
+                else_value: Box::new(fold_constants(else_value)),
+            },
+        },
+
         // These can't be folded
-        Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) | Expr::Identifier(_) => expr.clone(),
+        Expr::Number(_)
+        | Expr::Integer(_)
+        | Expr::String(_)
+        | Expr::Boolean(_)
+        | Expr::Identifier(_)
+        | Expr::Pop(_) => expr.clone(),
+    }
+}
+
+/// Largest `range` call folded into an array literal; bigger ones would
+/// bloat the bytecode more than building them at runtime costs
+const MAX_FOLDED_RANGE: usize = 64;
+
+/// The value of a number literal
+fn numeric(expr: &Expr) -> Option<Numeric> {
+    match expr {
+        Expr::Number(x) => Some(Numeric::Float(*x)),
+        Expr::Integer(n) => Some(Numeric::Integer(*n)),
+        _ => None,
+    }
+}
+
+/// The literal written for a folded number
+fn number_literal(n: Numeric) -> Expr {
+    match n {
+        Numeric::Integer(n) => Expr::Integer(n),
+        Numeric::Float(x) => Expr::Number(x),
+    }
+}
+
+/// Whether `expr` is a number literal equal to `value`
+fn is_number(expr: &Expr, value: f64) -> bool {
+    numeric(expr).is_some_and(|n| n.as_f64() == value)
+}
+
+/// `a op b` for two number literals, computed as the VMs would. Division
+/// by zero and operands a bitwise operator rejects are left for the program
+/// to report.
+fn fold_numbers(a: Numeric, op: &BinaryOp, b: Numeric) -> Option<Expr> {
+    let result = match op {
+        BinaryOp::Add => a.plus(b),
+        BinaryOp::Subtract => a.minus(b),
+        BinaryOp::Multiply => a.times(b),
+        BinaryOp::Divide | BinaryOp::IntDivide | BinaryOp::Modulo if b.is_zero() => return None,
+        BinaryOp::Divide => a.divide(b),
+        BinaryOp::IntDivide => a.floor_divide(b),
+        BinaryOp::Modulo => a.modulo(b),
+        BinaryOp::Power => a.power(b),
+        BinaryOp::Equals => return Some(Expr::Boolean(a.equals(b))),
+        BinaryOp::NotEquals => return Some(Expr::Boolean(!a.equals(b))),
+        BinaryOp::Greater => return Some(Expr::Boolean(a > b)),
+        BinaryOp::GreaterOrEquals => return Some(Expr::Boolean(a >= b)),
+        BinaryOp::Less => return Some(Expr::Boolean(a < b)),
+        BinaryOp::LessOrEquals => return Some(Expr::Boolean(a <= b)),
+        op => op.bit_op()?.apply(a, b).ok()?,
+    };
+    Some(number_literal(result))
+}
+
+/// Whether two literals are equal the way the VMs compare values, or `None`
+/// when either isn't a number, string or boolean literal
+fn literals_equal(a: &Expr, b: &Expr) -> Option<bool> {
+    if let (Some(x), Some(y)) = (numeric(a), numeric(b)) {
+        return Some(x.equals(y));
+    }
+    match (a, b) {
+        (Expr::String(x), Expr::String(y)) => Some(x == y),
+        (Expr::Boolean(x), Expr::Boolean(y)) => Some(x == y),
+        _ => (is_literal(a) && is_literal(b)).then_some(false),
+    }
+}
+
+/// Whether `expr` is a number, string or boolean literal
+fn is_literal(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Number(_) | Expr::Integer(_) | Expr::String(_) | Expr::Boolean(_)
+    )
+}
+
+/// Fold a call to the `range` built-in whose arguments are all constant.
+/// The caller checks that `range` isn't shadowed by a user function.
+pub fn fold_range(args: &[Expr]) -> Option<Expr> {
+    let numbers = args
+        .iter()
+        .map(|arg| numeric(arg).map(Numeric::as_f64))
+        .collect::<Option<Vec<_>>>()?;
+    let values = range_values(&numbers).ok()?;
+    if values.len() > MAX_FOLDED_RANGE {
+        return None;
     }
+    Some(Expr::Array(values.into_iter().map(Expr::Number).collect()))
+}
+
+/// Optimize the statements of a block, dropping those after a `return`,
+/// `break` or `continue` since control never reaches them
+pub fn optimize_block(stmts: &[Stmt]) -> Vec<Stmt> {
+    let mut optimized = Vec::with_capacity(stmts.len());
+    for stmt in stmts {
+        optimized.push(optimize_stmt(stmt));
+        if matches!(
+            stmt.kind,
+            StmtKind::Return(_) | StmtKind::Break(_) | StmtKind::Continue(_)
+        ) {
+            break;
+        }
+    }
+    optimized
 }
 
 /// Optimize a statement by folding constants in expressions
 pub fn optimize_stmt(stmt: &Stmt) -> Stmt {
-    match stmt {
-        Stmt::VarDecl {
+    let kind = match &stmt.kind {
+        StmtKind::VarDecl {
             name,
             value,
             is_const,
-        } => Stmt::VarDecl {
+        } => StmtKind::VarDecl {
             name: name.clone(),
             value: fold_constants(value),
             is_const: *is_const,
         },
 
-        Stmt::Assignment { name, value } => Stmt::Assignment {
+        StmtKind::Assignment { name, value } => StmtKind::Assignment {
             name: name.clone(),
             value: fold_constants(value),
         },
 
-        Stmt::IndexAssignment {
+        StmtKind::IndexAssignment {
             array,
             index,
             value,
-        } => Stmt::IndexAssignment {
+        } => StmtKind::IndexAssignment {
             array: Box::new(fold_constants(array)),
             index: Box::new(fold_constants(index)),
             value: fold_constants(value),
         },
 
-        Stmt::Print(expr) => Stmt::Print(fold_constants(expr)),
+        StmtKind::Push { array, value } => StmtKind::Push {
+            array: array.clone(),
+            value: fold_constants(value),
+        },
+
+        StmtKind::Print(expr) => StmtKind::Print(fold_constants(expr)),
+
+        StmtKind::Write(expr) => StmtKind::Write(fold_constants(expr)),
 
-        Stmt::Ask { name, prompt } => Stmt::Ask {
+        StmtKind::Assert { condition, message } => StmtKind::Assert {
+            condition: fold_constants(condition),
+            message: message.clone(),
+        },
+
+        StmtKind::Ask { name, prompt, text } => StmtKind::Ask {
             name: name.clone(),
             prompt: prompt.as_ref().map(fold_constants),
+            text: *text,
         },
 
-        Stmt::If {
+        StmtKind::If {
             condition,
             then_block,
             else_block,
         } => {
             let folded_condition = fold_constants(condition);
 
-            // If condition is constant, we can eliminate branches!
+            // If condition is constant, we can eliminate branches! The kept
+            // branch stays a block so its variables remain scoped to it.
             match &folded_condition {
-                Expr::Boolean(true) => {
-                    // Always true: only keep then_block
-                    if then_block.len() == 1 {
-                        optimize_stmt(&then_block[0])
-                    } else {
-                        Stmt::If {
-                            condition: folded_condition,
-                            then_block: then_block.iter().map(optimize_stmt).collect(),
-                            else_block: None,
-                        }
-                    }
-                }
+                Expr::Boolean(true) => StmtKind::Block(optimize_block(then_block)),
                 Expr::Boolean(false) => {
-                    // Always false: only keep else_block or eliminate
-                    if let Some(else_stmts) = else_block {
-                        if else_stmts.len() == 1 {
-                            optimize_stmt(&else_stmts[0])
-                        } else {
-                            Stmt::If {
-                                condition: folded_condition,
-                                then_block: Vec::new(),
-                                else_block: Some(else_stmts.iter().map(optimize_stmt).collect()),
-                            }
-                        }
-                    } else {
-                        // No-op, but we need to return something
-                        Stmt::Expression(Expr::Number(0.0))
-                    }
+                    StmtKind::Block(optimize_block(else_block.as_deref().unwrap_or_default()))
                 }
-                _ => Stmt::If {
+                _ => StmtKind::If {
                     condition: folded_condition,
-                    then_block: then_block.iter().map(optimize_stmt).collect(),
-                    else_block: else_block
-                        .as_ref()
-                        .map(|stmts| stmts.iter().map(optimize_stmt).collect()),
+                    then_block: optimize_block(then_block),
+                    else_block: else_block.as_deref().map(optimize_block),
                 },
             }
         }
 
-        Stmt::While { condition, body } => Stmt::While {
+        StmtKind::Block(stmts) => StmtKind::Block(optimize_block(stmts)),
+
+        StmtKind::While {
+            condition,
+            body,
+            label,
+        } => StmtKind::While {
             condition: fold_constants(condition),
-            body: body.iter().map(optimize_stmt).collect(),
+            body: optimize_block(body),
+            label: label.clone(),
         },
 
-        Stmt::For {
+        StmtKind::ForEach {
+            var,
+            iterable,
+            body,
+            label,
+        } => StmtKind::ForEach {
+            var: var.clone(),
+            iterable: fold_constants(iterable),
+            body: optimize_block(body),
+            label: label.clone(),
+        },
+
+        StmtKind::Repeat { count, body } => StmtKind::Repeat {
+            count: fold_constants(count),
+            body: optimize_block(body),
+        },
+
+        StmtKind::DoWhile { body, condition } => StmtKind::DoWhile {
+            body: optimize_block(body),
+            condition: fold_constants(condition),
+        },
+
+        StmtKind::For {
             init,
             condition,
             increment,
             body,
-        } => Stmt::For {
+            label,
+        } => StmtKind::For {
             init: Box::new(optimize_stmt(init)),
             condition: fold_constants(condition),
             increment: Box::new(optimize_stmt(increment)),
-            body: body.iter().map(optimize_stmt).collect(),
+            body: optimize_block(body),
+            label: label.clone(),
         },
 
-        Stmt::Return(expr) => Stmt::Return(expr.as_ref().map(fold_constants)),
+        StmtKind::Return(expr) => StmtKind::Return(expr.as_ref().map(fold_constants)),
 
-        Stmt::Expression(expr) => Stmt::Expression(fold_constants(expr)),
+        StmtKind::Expression(expr) => StmtKind::Expression(fold_constants(expr)),
 
         // These don't need optimization
-        Stmt::Break | Stmt::Continue => stmt.clone(),
+        StmtKind::Break(_) | StmtKind::Continue(_) => stmt.kind.clone(),
+    };
+    Stmt::new(kind, stmt.span)
+}
+
+/// Fold constants in a function without propagating or hoisting anything
+pub fn fold_function(func: &Function) -> Function {
+    Function {
+        name: func.name.clone(),
+        params: func.params.clone(),
+        body: optimize_block(&func.body),
     }
 }
 
 /// Optimize a function
 pub fn optimize_function(func: &Function) -> Function {
+    let body = propagate_constants(func);
+    let body = hoist_loop_invariants(&func.params, &body);
     Function {
         name: func.name.clone(),
         params: func.params.clone(),
-        body: func.body.iter().map(optimize_stmt).collect(),
+        body: optimize_block(&body),
+    }
+}
+
+/// Replace uses of variables that always hold the same literal with the
+/// literal, so `const x is 5` then `x plus 3` folds to `8`.
+///
+/// Only variables declared once in the function and never assigned
+/// afterwards qualify, which rules out loop counters, parameters and names
+/// declared in more than one scope. Uses are replaced only where the
+/// declaration is in scope; elsewhere the name may refer to a global.
+fn propagate_constants(func: &Function) -> Vec<Stmt> {
+    let mut declarations = HashMap::new();
+    let mut assigned: HashSet<String> = func.params.iter().cloned().collect();
+    collect_bindings(&func.body, &mut declarations, &mut assigned);
+
+    let mut propagator = Propagator {
+        candidates: declarations
+            .into_iter()
+            .filter(|(name, count)| *count == 1 && !assigned.contains(name))
+            .map(|(name, _)| name)
+            .collect(),
+        known: Vec::new(),
+    };
+    propagator.block(&func.body)
+}
+
+/// Count the declarations of each name and gather every name written after
+/// its declaration
+fn collect_bindings(
+    stmts: &[Stmt],
+    declarations: &mut HashMap<String, usize>,
+    assigned: &mut HashSet<String>,
+) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::VarDecl { name, .. } => *declarations.entry(name.clone()).or_insert(0) += 1,
+            StmtKind::Assignment { name, .. } | StmtKind::Ask { name, .. } => {
+                assigned.insert(name.clone());
+            }
+            StmtKind::IndexAssignment { array, .. } => {
+                if let Expr::Identifier(name) = array.as_ref() {
+                    assigned.insert(name.clone());
+                }
+            }
+            StmtKind::Push { array, .. } => {
+                assigned.insert(array.clone());
+            }
+            StmtKind::If {
+                then_block,
+                else_block,
+                ..
+            } => {
+                collect_bindings(then_block, declarations, assigned);
+                if let Some(else_block) = else_block {
+                    collect_bindings(else_block, declarations, assigned);
+                }
+            }
+            StmtKind::ForEach { var, body, .. } => {
+                assigned.insert(var.clone());
+                collect_bindings(body, declarations, assigned);
+            }
+            StmtKind::For {
+                init,
+                increment,
+                body,
+                ..
+            } => {
+                collect_bindings(std::slice::from_ref(init.as_ref()), declarations, assigned);
+                collect_bindings(
+                    std::slice::from_ref(increment.as_ref()),
+                    declarations,
+                    assigned,
+                );
+                collect_bindings(body, declarations, assigned);
+            }
+            StmtKind::While { body, .. }
+            | StmtKind::Repeat { body, .. }
+            | StmtKind::DoWhile { body, .. }
+            | StmtKind::Block(body) => collect_bindings(body, declarations, assigned),
+            StmtKind::Print(_)
+            | StmtKind::Write(_)
+            | StmtKind::Assert { .. }
+            | StmtKind::Return(_)
+            | StmtKind::Break(_)
+            | StmtKind::Continue(_)
+            | StmtKind::Expression(_) => {}
+        }
+    }
+}
+
+struct Propagator {
+    /// Variables that hold their initial value for their whole lifetime
+    candidates: HashSet<String>,
+    /// Candidates in scope whose initial value is a literal
+    known: Vec<(String, Expr)>,
+}
+
+impl Propagator {
+    /// Rewrite statements that form a scope
+    fn block(&mut self, stmts: &[Stmt]) -> Vec<Stmt> {
+        let scope_start = self.known.len();
+        let stmts = stmts.iter().map(|stmt| self.stmt(stmt)).collect();
+        self.known.truncate(scope_start);
+        stmts
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Stmt {
+        let kind = match &stmt.kind {
+            StmtKind::VarDecl {
+                name,
+                value,
+                is_const,
+            } => {
+                let value = self.expr(value);
+                if self.candidates.contains(name) {
+                    let folded = fold_constants(&value);
+                    if is_literal(&folded) {
+                        self.known.push((name.clone(), folded));
+                    }
+                }
+                StmtKind::VarDecl {
+                    name: name.clone(),
+                    value,
+                    is_const: *is_const,
+                }
+            }
+            StmtKind::Assignment { name, value } => StmtKind::Assignment {
+                name: name.clone(),
+                value: self.expr(value),
+            },
+            StmtKind::IndexAssignment {
+                array,
+                index,
+                value,
+            } => StmtKind::IndexAssignment {
+                array: array.clone(),
+                index: Box::new(self.expr(index)),
+                value: self.expr(value),
+            },
+            StmtKind::Push { array, value } => StmtKind::Push {
+                array: array.clone(),
+                value: self.expr(value),
+            },
+            StmtKind::Print(value) => StmtKind::Print(self.expr(value)),
+            StmtKind::Write(value) => StmtKind::Write(self.expr(value)),
+            StmtKind::Assert { condition, message } => StmtKind::Assert {
+                condition: self.expr(condition),
+                message: message.clone(),
+            },
+            StmtKind::Ask { name, prompt, text } => StmtKind::Ask {
+                name: name.clone(),
+                prompt: prompt.as_ref().map(|prompt| self.expr(prompt)),
+                text: *text,
+            },
+            StmtKind::If {
+                condition,
+                then_block,
+                else_block,
+            } => StmtKind::If {
+                condition: self.expr(condition),
+                then_block: self.block(then_block),
+                else_block: else_block.as_ref().map(|block| self.block(block)),
+            },
+            StmtKind::While {
+                condition,
+                body,
+                label,
+            } => StmtKind::While {
+                condition: self.expr(condition),
+                body: self.block(body),
+                label: label.clone(),
+            },
+            StmtKind::Repeat { count, body } => StmtKind::Repeat {
+                count: self.expr(count),
+                body: self.block(body),
+            },
+            StmtKind::DoWhile { body, condition } => StmtKind::DoWhile {
+                body: self.block(body),
+                condition: self.expr(condition),
+            },
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                label,
+            } => StmtKind::ForEach {
+                var: var.clone(),
+                iterable: self.expr(iterable),
+                body: self.block(body),
+                label: label.clone(),
+            },
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+                label,
+            } => {
+                // The loop variable is scoped to the loop
+                let scope_start = self.known.len();
+                let kind = StmtKind::For {
+                    init: Box::new(self.stmt(init)),
+                    condition: self.expr(condition),
+                    increment: Box::new(self.stmt(increment)),
+                    body: self.block(body),
+                    label: label.clone(),
+                };
+                self.known.truncate(scope_start);
+                kind
+            }
+            StmtKind::Return(value) => {
+                StmtKind::Return(value.as_ref().map(|value| self.expr(value)))
+            }
+            StmtKind::Expression(value) => StmtKind::Expression(self.expr(value)),
+            StmtKind::Block(stmts) => StmtKind::Block(self.block(stmts)),
+            StmtKind::Break(_) | StmtKind::Continue(_) => stmt.kind.clone(),
+        };
+        Stmt::new(kind, stmt.span)
+    }
+
+    fn expr(&self, expr: &Expr) -> Expr {
+        let sub = |e: &Expr| Box::new(self.expr(e));
+        match expr {
+            Expr::Identifier(name) => self
+                .known
+                .iter()
+                .rev()
+                .find(|(known, _)| known == name)
+                .map_or_else(|| expr.clone(), |(_, value)| value.clone()),
+            Expr::Binary { left, op, right } => Expr::Binary {
+                left: sub(left),
+                op: op.clone(),
+                right: sub(right),
+            },
+            Expr::Unary { op, operand } => Expr::Unary {
+                op: op.clone(),
+                operand: sub(operand),
+            },
+            Expr::Call { name, args } => Expr::Call {
+                name: name.clone(),
+                args: args.iter().map(|arg| self.expr(arg)).collect(),
+            },
+            Expr::Array(elements) => Expr::Array(elements.iter().map(|e| self.expr(e)).collect()),
+            Expr::Map(entries) => Expr::Map(
+                entries
+                    .iter()
+                    .map(|(key, value)| (self.expr(key), self.expr(value)))
+                    .collect(),
+            ),
+            Expr::Index { array, index } => Expr::Index {
+                array: sub(array),
+                index: sub(index),
+            },
+            Expr::Substring { string, from, to } => Expr::Substring {
+                string: sub(string),
+                from: sub(from),
+                to: sub(to),
+            },
+            Expr::Slice { array, from, to } => Expr::Slice {
+                array: sub(array),
+                from: sub(from),
+                to: sub(to),
+            },
+            Expr::Replace {
+                string,
+                pattern,
+                replacement,
+            } => Expr::Replace {
+                string: sub(string),
+                pattern: sub(pattern),
+                replacement: sub(replacement),
+            },
+            Expr::Ternary {
+                condition,
+                then_value,
+                else_value,
+            } => Expr::Ternary {
+                condition: sub(condition),
+                then_value: sub(then_value),
+                else_value: sub(else_value),
+            },
+            Expr::Number(_)
+            | Expr::Integer(_)
+            | Expr::String(_)
+            | Expr::Boolean(_)
+            | Expr::Pop(_) => expr.clone(),
+        }
+    }
+}
+
+/// Loop-invariant code motion: arithmetic in a `while` or `for` condition
+/// that only involves locals the loop never writes is computed once, into a
+/// temporary declared just before the loop.
+///
+/// Only conditions are searched. A condition runs at least once, so
+/// computing its invariant parts up front can't raise an error the original
+/// program wouldn't; the body may never run at all.
+fn hoist_loop_invariants(params: &[String], body: &[Stmt]) -> Vec<Stmt> {
+    let mut hoister = Hoister {
+        scopes: vec![params.to_vec()],
+        temporaries: 0,
+    };
+    hoister.block(body)
+}
+
+struct Hoister {
+    /// Locals declared in each enclosing scope; other names are globals,
+    /// which any call could change
+    scopes: Vec<Vec<String>>,
+    temporaries: usize,
+}
+
+impl Hoister {
+    fn block(&mut self, stmts: &[Stmt]) -> Vec<Stmt> {
+        self.scopes.push(Vec::new());
+        let mut out = Vec::with_capacity(stmts.len());
+        for stmt in stmts {
+            self.stmt(stmt, &mut out);
+        }
+        self.scopes.pop();
+        out
+    }
+
+    /// Rewrite `stmt` into `out`, preceded by any temporaries it needs
+    fn stmt(&mut self, stmt: &Stmt, out: &mut Vec<Stmt>) {
+        let span = stmt.span;
+        match &stmt.kind {
+            StmtKind::VarDecl { name, .. } => {
+                self.scopes.last_mut().unwrap().push(name.clone());
+                out.push(stmt.clone());
+            }
+            StmtKind::While {
+                condition,
+                body,
+                label,
+            } => {
+                let mut written = HashSet::new();
+                collect_writes(body, &mut written);
+                let condition = self.hoist(condition, &written, span, out);
+                let kind = StmtKind::While {
+                    condition,
+                    body: self.block(body),
+                    label: label.clone(),
+                };
+                out.push(Stmt::new(kind, span));
+            }
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+                label,
+            } => {
+                let mut written = HashSet::new();
+                collect_writes(std::slice::from_ref(init.as_ref()), &mut written);
+                collect_writes(std::slice::from_ref(increment.as_ref()), &mut written);
+                collect_writes(body, &mut written);
+
+                // The temporaries go between the initializer and the first
+                // test, so everything still runs in the original order
+                self.scopes.push(Vec::new());
+                let mut hoisted = Vec::new();
+                self.stmt(init, &mut hoisted);
+                let condition = self.hoist(condition, &written, span, &mut hoisted);
+                if hoisted.len() == 1 {
+                    let kind = StmtKind::For {
+                        init: init.clone(),
+                        condition,
+                        increment: increment.clone(),
+                        body: self.block(body),
+                        label: label.clone(),
+                    };
+                    out.push(Stmt::new(kind, span));
+                } else {
+                    let last = hoisted.pop().unwrap();
+                    let kind = StmtKind::For {
+                        init: Box::new(last),
+                        condition,
+                        increment: increment.clone(),
+                        body: self.block(body),
+                        label: label.clone(),
+                    };
+                    hoisted.push(Stmt::new(kind, span));
+                    out.push(Stmt::new(StmtKind::Block(hoisted), span));
+                }
+                self.scopes.pop();
+            }
+            StmtKind::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                let kind = StmtKind::If {
+                    condition: condition.clone(),
+                    then_block: self.block(then_block),
+                    else_block: else_block.as_ref().map(|block| self.block(block)),
+                };
+                out.push(Stmt::new(kind, span));
+            }
+            StmtKind::Repeat { count, body } => {
+                let kind = StmtKind::Repeat {
+                    count: count.clone(),
+                    body: self.block(body),
+                };
+                out.push(Stmt::new(kind, span));
+            }
+            // The condition runs after the body, so nothing can move above it
+            StmtKind::DoWhile { body, condition } => {
+                let kind = StmtKind::DoWhile {
+                    body: self.block(body),
+                    condition: condition.clone(),
+                };
+                out.push(Stmt::new(kind, span));
+            }
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                label,
+            } => {
+                let kind = StmtKind::ForEach {
+                    var: var.clone(),
+                    iterable: iterable.clone(),
+                    body: self.block(body),
+                    label: label.clone(),
+                };
+                out.push(Stmt::new(kind, span));
+            }
+            StmtKind::Block(stmts) => {
+                let kind = StmtKind::Block(self.block(stmts));
+                out.push(Stmt::new(kind, span));
+            }
+            _ => out.push(stmt.clone()),
+        }
+    }
+
+    /// Replace the largest invariant parts of `expr` with temporaries,
+    /// declaring them in `out` at the loop's `span`
+    fn hoist(
+        &mut self,
+        expr: &Expr,
+        written: &HashSet<String>,
+        span: Span,
+        out: &mut Vec<Stmt>,
+    ) -> Expr {
+        if self.is_invariant(expr, written)
+            && !is_literal(expr)
+            && !matches!(expr, Expr::Identifier(_))
+        {
+            let name = format!("$invariant{}", self.temporaries);
+            self.temporaries += 1;
+            let kind = StmtKind::VarDecl {
+                name: name.clone(),
+                value: expr.clone(),
+                is_const: true,
+            };
+            out.push(Stmt::new(kind, span));
+            self.scopes.last_mut().unwrap().push(name.clone());
+            return Expr::Identifier(name);
+        }
+
+        let mut sub = |e: &Expr| Box::new(self.hoist(e, written, span, out));
+        match expr {
+            Expr::Binary { left, op, right } => {
+                // The right side of and/or/otherwise may not run at all
+                if matches!(op, BinaryOp::And | BinaryOp::Or | BinaryOp::Otherwise) {
+                    return Expr::Binary {
+                        left: sub(left),
+                        op: op.clone(),
+                        right: right.clone(),
+                    };
+                }
+                Expr::Binary {
+                    left: sub(left),
+                    op: op.clone(),
+                    right: sub(right),
+                }
+            }
+            Expr::Unary { op, operand } => Expr::Unary {
+                op: op.clone(),
+                operand: sub(operand),
+            },
+            _ => expr.clone(),
+        }
+    }
+
+    /// Pure arithmetic (or `length of`) over literals and locals the loop
+    /// doesn't write
+    fn is_invariant(&self, expr: &Expr, written: &HashSet<String>) -> bool {
+        match expr {
+            Expr::Number(_) | Expr::Integer(_) | Expr::String(_) | Expr::Boolean(_) => true,
+            Expr::Identifier(name) => {
+                !written.contains(name) && self.scopes.iter().flatten().any(|local| local == name)
+            }
+            Expr::Binary { left, op, right } => {
+                matches!(
+                    op,
+                    BinaryOp::Add
+                        | BinaryOp::Subtract
+                        | BinaryOp::Multiply
+                        | BinaryOp::Divide
+                        | BinaryOp::IntDivide
+                        | BinaryOp::Modulo
+                        | BinaryOp::Power
+                ) && self.is_invariant(left, written)
+                    && self.is_invariant(right, written)
+            }
+            Expr::Unary {
+                op: UnaryOp::Length | UnaryOp::Negate,
+                operand,
+            } => self.is_invariant(operand, written),
+            _ => false,
+        }
+    }
+}
+
+/// Every variable `stmts` declares or changes, including arrays changed in place
+fn collect_writes(stmts: &[Stmt], written: &mut HashSet<String>) {
+    for stmt in stmts {
+        match &stmt.kind {
+            StmtKind::VarDecl { name, value, .. } | StmtKind::Assignment { name, value } => {
+                written.insert(name.clone());
+                collect_pops(value, written);
+            }
+            StmtKind::Ask { name, .. } => {
+                written.insert(name.clone());
+            }
+            StmtKind::IndexAssignment {
+                array,
+                index,
+                value,
+            } => {
+                if let Expr::Identifier(name) = array.as_ref() {
+                    written.insert(name.clone());
+                }
+                collect_pops(index, written);
+                collect_pops(value, written);
+            }
+            StmtKind::Push { array, value } => {
+                written.insert(array.clone());
+                collect_pops(value, written);
+            }
+            StmtKind::Print(value)
+            | StmtKind::Write(value)
+            | StmtKind::Assert {
+                condition: value, ..
+            }
+            | StmtKind::Expression(value)
+            | StmtKind::Return(Some(value)) => collect_pops(value, written),
+            StmtKind::If {
+                condition,
+                then_block,
+                else_block,
+            } => {
+                collect_pops(condition, written);
+                collect_writes(then_block, written);
+                collect_writes(else_block.as_deref().unwrap_or_default(), written);
+            }
+            StmtKind::While {
+                condition, body, ..
+            } => {
+                collect_pops(condition, written);
+                collect_writes(body, written);
+            }
+            StmtKind::Repeat { count, body } => {
+                collect_pops(count, written);
+                collect_writes(body, written);
+            }
+            StmtKind::DoWhile { body, condition } => {
+                collect_writes(body, written);
+                collect_pops(condition, written);
+            }
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                ..
+            } => {
+                written.insert(var.clone());
+                collect_pops(iterable, written);
+                collect_writes(body, written);
+            }
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                collect_writes(std::slice::from_ref(init.as_ref()), written);
+                collect_pops(condition, written);
+                collect_writes(std::slice::from_ref(increment.as_ref()), written);
+                collect_writes(body, written);
+            }
+            StmtKind::Block(body) => collect_writes(body, written),
+            StmtKind::Return(None) | StmtKind::Break(_) | StmtKind::Continue(_) => {}
+        }
+    }
+}
+
+/// Arrays that `pop from` shrinks somewhere inside `expr`
+fn collect_pops(expr: &Expr, written: &mut HashSet<String>) {
+    match expr {
+        Expr::Pop(name) => {
+            written.insert(name.clone());
+        }
+        Expr::Binary { left, right, .. } => {
+            collect_pops(left, written);
+            collect_pops(right, written);
+        }
+        Expr::Unary { operand, .. } => collect_pops(operand, written),
+        Expr::Call { args, .. } | Expr::Array(args) => {
+            args.iter().for_each(|arg| collect_pops(arg, written))
+        }
+        Expr::Map(entries) => entries.iter().for_each(|(key, value)| {
+            collect_pops(key, written);
+            collect_pops(value, written);
+        }),
+        Expr::Index { array, index } => {
+            collect_pops(array, written);
+            collect_pops(index, written);
+        }
+        Expr::Substring { string, from, to } => {
+            collect_pops(string, written);
+            collect_pops(from, written);
+            collect_pops(to, written);
+        }
+        Expr::Slice { array, from, to } => {
+            collect_pops(array, written);
+            collect_pops(from, written);
+            collect_pops(to, written);
+        }
+        Expr::Replace {
+            string,
+            pattern,
+            replacement,
+        } => {
+            collect_pops(string, written);
+            collect_pops(pattern, written);
+            collect_pops(replacement, written);
+        }
+        Expr::Ternary {
+            condition,
+            then_value,
+            else_value,
+        } => {
+            collect_pops(condition, written);
+            collect_pops(then_value, written);
+            collect_pops(else_value, written);
+        }
+        Expr::Number(_)
+        | Expr::Integer(_)
+        | Expr::String(_)
+        | Expr::Boolean(_)
+        | Expr::Identifier(_) => {}
+    }
+}
+
+/// Largest returned expression, in nodes, that `inline_functions` copies
+/// into its callers
+const MAX_INLINED_NODES: usize = 16;
+
+/// Replace calls to small helper functions with the expression they return,
+/// saving the call overhead.
+///
+/// A function qualifies when its body is a single `return` of an expression,
+/// or just that expression, that only reads its parameters and calls no
+/// user function, so it can never be recursive. Arguments are still evaluated once and in order:
+/// when they are all literals or variables they are substituted directly,
+/// otherwise each non-literal argument is first bound to a temporary
+/// declared just before the statement. That is only done when nothing but
+/// literals is evaluated before the call in that statement, and never in a
+/// loop condition or increment, which run more than once; in those places
+/// such calls are left alone.
+pub fn inline_functions(program: &Program) -> Program {
+    let names: HashSet<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+    let mut inliner = Inliner {
+        inlinable: program
+            .functions
+            .iter()
+            .filter_map(|f| match f.body.as_slice() {
+                [Stmt {
+                    kind: StmtKind::Return(Some(value)) | StmtKind::Expression(value),
+                    ..
+                }] if is_inlinable(value, &f.params, &names, &mut 0) => {
+                    Some((f.name.clone(), (f.params.clone(), value.clone())))
+                }
+                _ => None,
+            })
+            .collect(),
+        temporaries: 0,
+        pending: Vec::new(),
+        span: Span::default(),
+        hoist: false,
+    };
+
+    Program {
+        imports: program.imports.clone(),
+        functions: program
+            .functions
+            .iter()
+            .map(|f| Function {
+                name: f.name.clone(),
+                params: f.params.clone(),
+                body: inliner.block(&f.body),
+            })
+            .collect(),
+    }
+}
+
+/// Whether `expr` is small, only reads `params` and calls only built-ins;
+/// `nodes` counts the nodes seen so far
+fn is_inlinable(
+    expr: &Expr,
+    params: &[String],
+    functions: &HashSet<&str>,
+    nodes: &mut usize,
+) -> bool {
+    *nodes += 1;
+    if *nodes > MAX_INLINED_NODES {
+        return false;
+    }
+    let mut check = |e: &Expr| is_inlinable(e, params, functions, nodes);
+    match expr {
+        Expr::Number(_) | Expr::Integer(_) | Expr::String(_) | Expr::Boolean(_) => true,
+        Expr::Identifier(name) => params.contains(name),
+        Expr::Binary { left, right, .. } => check(left) && check(right),
+        Expr::Unary { operand, .. } => check(operand),
+        Expr::Call { name, args } => {
+            !functions.contains(name.as_str())
+                && Builtin::from_name(name).is_some()
+                && args.iter().all(check)
+        }
+        Expr::Array(elements) => elements.iter().all(check),
+        Expr::Map(pairs) => pairs.iter().all(|(key, value)| check(key) && check(value)),
+        Expr::Index { array, index } => check(array) && check(index),
+        Expr::Substring { string, from, to } => check(string) && check(from) && check(to),
+        Expr::Slice { array, from, to } => check(array) && check(from) && check(to),
+        Expr::Replace {
+            string,
+            pattern,
+            replacement,
+        } => check(string) && check(pattern) && check(replacement),
+        Expr::Ternary {
+            condition,
+            then_value,
+            else_value,
+        } => check(condition) && check(then_value) && check(else_value),
+        Expr::Pop(_) => false,
+    }
+}
+
+struct Inliner {
+    /// Parameters and returned expression of each function that can be inlined
+    inlinable: HashMap<String, (Vec<String>, Expr)>,
+    temporaries: usize,
+    /// Temporaries to declare before the statement being rewritten
+    pending: Vec<Stmt>,
+    /// Where the statement being rewritten starts, given to its temporaries
+    span: Span,
+    /// Whether a temporary declared before the statement would still be
+    /// evaluated in the original order
+    hoist: bool,
+}
+
+impl Inliner {
+    fn block(&mut self, stmts: &[Stmt]) -> Vec<Stmt> {
+        let outer = std::mem::take(&mut self.pending);
+        let mut out = Vec::with_capacity(stmts.len());
+        for stmt in stmts {
+            let stmt = self.stmt(stmt, true);
+            out.append(&mut self.pending);
+            out.push(stmt);
+        }
+        self.pending = outer;
+        out
+    }
+
+    /// Rewrite `stmt`; `once` says whether it runs once each time control
+    /// reaches it, so its arguments may be bound to temporaries
+    fn stmt(&mut self, stmt: &Stmt, once: bool) -> Stmt {
+        self.span = stmt.span;
+        let kind = match &stmt.kind {
+            StmtKind::VarDecl {
+                name,
+                value,
+                is_const,
+            } => StmtKind::VarDecl {
+                name: name.clone(),
+                value: self.start(value, once),
+                is_const: *is_const,
+            },
+            StmtKind::Assignment { name, value } => StmtKind::Assignment {
+                name: name.clone(),
+                value: self.start(value, once),
+            },
+            StmtKind::IndexAssignment {
+                array,
+                index,
+                value,
+            } => StmtKind::IndexAssignment {
+                array: Box::new(self.start(array, false)),
+                index: Box::new(self.start(index, false)),
+                value: self.start(value, false),
+            },
+            StmtKind::Push { array, value } => StmtKind::Push {
+                array: array.clone(),
+                value: self.start(value, once),
+            },
+            StmtKind::Print(expr) => StmtKind::Print(self.start(expr, once)),
+            StmtKind::Write(expr) => StmtKind::Write(self.start(expr, once)),
+            StmtKind::Assert { condition, message } => StmtKind::Assert {
+                condition: self.start(condition, once),
+                message: message.clone(),
+            },
+            StmtKind::Ask { name, prompt, text } => StmtKind::Ask {
+                name: name.clone(),
+                prompt: prompt.as_ref().map(|p| self.start(p, once)),
+                text: *text,
+            },
+            StmtKind::If {
+                condition,
+                then_block,
+                else_block,
+            } => StmtKind::If {
+                condition: self.start(condition, once),
+                then_block: self.block(then_block),
+                else_block: else_block.as_deref().map(|b| self.block(b)),
+            },
+            StmtKind::While {
+                condition,
+                body,
+                label,
+            } => StmtKind::While {
+                condition: self.start(condition, false),
+                body: self.block(body),
+                label: label.clone(),
+            },
+            StmtKind::Repeat { count, body } => StmtKind::Repeat {
+                count: self.start(count, once),
+                body: self.block(body),
+            },
+            StmtKind::DoWhile { body, condition } => StmtKind::DoWhile {
+                body: self.block(body),
+                condition: self.start(condition, false),
+            },
+            StmtKind::ForEach {
+                var,
+                iterable,
+                body,
+                label,
+            } => StmtKind::ForEach {
+                var: var.clone(),
+                iterable: self.start(iterable, once),
+                body: self.block(body),
+                label: label.clone(),
+            },
+            StmtKind::For {
+                init,
+                condition,
+                increment,
+                body,
+                label,
+            } => StmtKind::For {
+                init: Box::new(self.stmt(init, once)),
+                condition: self.start(condition, false),
+                increment: Box::new(self.stmt(increment, false)),
+                body: self.block(body),
+                label: label.clone(),
+            },
+            StmtKind::Return(expr) => StmtKind::Return(expr.as_ref().map(|e| self.start(e, once))),
+            StmtKind::Expression(expr) => StmtKind::Expression(self.start(expr, once)),
+            StmtKind::Block(stmts) => StmtKind::Block(self.block(stmts)),
+            StmtKind::Break(_) | StmtKind::Continue(_) => stmt.kind.clone(),
+        };
+        Stmt::new(kind, stmt.span)
+    }
+
+    /// Rewrite an expression a statement evaluates first
+    fn start(&mut self, expr: &Expr, hoist: bool) -> Expr {
+        self.hoist = hoist;
+        let expr = self.expr(expr);
+        self.hoist = false;
+        expr
+    }
+
+    /// Rewrite `expr`, visiting its parts in evaluation order
+    fn expr(&mut self, expr: &Expr) -> Expr {
+        let rewritten = match expr {
+            Expr::Number(_) | Expr::Integer(_) | Expr::String(_) | Expr::Boolean(_) => {
+                return expr.clone()
+            }
+            Expr::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or | BinaryOp::Otherwise),
+                right,
+            } => {
+                let left = self.expr(left);
+                // The right operand doesn't always run
+                self.hoist = false;
+                Expr::Binary {
+                    left: Box::new(left),
+                    op: op.clone(),
+                    right: Box::new(self.expr(right)),
+                }
+            }
+            Expr::Binary { left, op, right } => Expr::Binary {
+                left: Box::new(self.expr(left)),
+                op: op.clone(),
+                right: Box::new(self.expr(right)),
+            },
+            Expr::Unary { op, operand } => Expr::Unary {
+                op: op.clone(),
+                operand: Box::new(self.expr(operand)),
+            },
+            Expr::Call { name, args } => self.call(name, args),
+            Expr::Array(elements) => Expr::Array(elements.iter().map(|e| self.expr(e)).collect()),
+            Expr::Map(pairs) => Expr::Map(
+                pairs
+                    .iter()
+                    .map(|(key, value)| (self.expr(key), self.expr(value)))
+                    .collect(),
+            ),
+            Expr::Index { array, index } => Expr::Index {
+                array: Box::new(self.expr(array)),
+                index: Box::new(self.expr(index)),
+            },
+            Expr::Substring { string, from, to } => Expr::Substring {
+                string: Box::new(self.expr(string)),
+                from: Box::new(self.expr(from)),
+                to: Box::new(self.expr(to)),
+            },
+            Expr::Slice { array, from, to } => Expr::Slice {
+                array: Box::new(self.expr(array)),
+                from: Box::new(self.expr(from)),
+                to: Box::new(self.expr(to)),
+            },
+            Expr::Replace {
+                string,
+                pattern,
+                replacement,
+            } => Expr::Replace {
+                string: Box::new(self.expr(string)),
+                pattern: Box::new(self.expr(pattern)),
+                replacement: Box::new(self.expr(replacement)),
+            },
+            Expr::Ternary {
+                condition,
+                then_value,
+                else_value,
+            } => {
+                let condition = self.expr(condition);
+                // Only one of the branches runs
+                self.hoist = false;
+                Expr::Ternary {
+                    condition: Box::new(condition),
+                    then_value: Box::new(self.expr(then_value)),
+                    else_value: Box::new(self.expr(else_value)),
+                }
+            }
+            Expr::Identifier(_) | Expr::Pop(_) => expr.clone(),
+        };
+        // A variable read, or anything that may have an effect, now comes
+        // before whatever is evaluated next
+        self.hoist = false;
+        rewritten
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Expr {
+        let hoist = self.hoist;
+        let args: Vec<Expr> = args.iter().map(|arg| self.expr(arg)).collect();
+        let Some((params, body)) = self.inlinable.get(name) else {
+            return Expr::Call {
+                name: name.to_string(),
+                args,
+            };
+        };
+        let simple = args
+            .iter()
+            .all(|arg| is_literal(arg) || matches!(arg, Expr::Identifier(_)));
+        if params.len() != args.len() || !(simple || hoist) {
+            return Expr::Call {
+                name: name.to_string(),
+                args,
+            };
+        }
+
+        let mut bindings = HashMap::new();
+        for (param, arg) in params.iter().zip(args) {
+            let value = if simple || is_literal(&arg) {
+                arg
+            } else {
+                let temporary = format!("$inline{}", self.temporaries);
+                self.temporaries += 1;
+                self.pending.push(Stmt::new(
+                    StmtKind::VarDecl {
+                        name: temporary.clone(),
+                        value: arg,
+                        is_const: true,
+                    },
+                    self.span,
+                ));
+                Expr::Identifier(temporary)
+            };
+            bindings.insert(param.clone(), value);
+        }
+        substitute(body, &bindings)
+    }
+}
+
+/// `expr` with each parameter replaced by its binding
+fn substitute(expr: &Expr, bindings: &HashMap<String, Expr>) -> Expr {
+    let sub = |e: &Expr| Box::new(substitute(e, bindings));
+    match expr {
+        Expr::Identifier(name) => bindings[name].clone(),
+        Expr::Number(_) | Expr::Integer(_) | Expr::String(_) | Expr::Boolean(_) | Expr::Pop(_) => {
+            expr.clone()
+        }
+        Expr::Binary { left, op, right } => Expr::Binary {
+            left: sub(left),
+            op: op.clone(),
+            right: sub(right),
+        },
+        Expr::Unary { op, operand } => Expr::Unary {
+            op: op.clone(),
+            operand: sub(operand),
+        },
+        Expr::Call { name, args } => Expr::Call {
+            name: name.clone(),
+            args: args.iter().map(|a| substitute(a, bindings)).collect(),
+        },
+        Expr::Array(elements) => {
+            Expr::Array(elements.iter().map(|e| substitute(e, bindings)).collect())
+        }
+        Expr::Map(pairs) => Expr::Map(
+            pairs
+                .iter()
+                .map(|(k, v)| (substitute(k, bindings), substitute(v, bindings)))
+                .collect(),
+        ),
+        Expr::Index { array, index } => Expr::Index {
+            array: sub(array),
+            index: sub(index),
+        },
+        Expr::Substring { string, from, to } => Expr::Substring {
+            string: sub(string),
+            from: sub(from),
+            to: sub(to),
+        },
+        Expr::Slice { array, from, to } => Expr::Slice {
+            array: sub(array),
+            from: sub(from),
+            to: sub(to),
+        },
+        Expr::Replace {
+            string,
+            pattern,
+            replacement,
+        } => Expr::Replace {
+            string: sub(string),
+            pattern: sub(pattern),
+            replacement: sub(replacement),
+        },
+        Expr::Ternary {
+            condition,
+            then_value,
+            else_value,
+        } => Expr::Ternary {
+            condition: sub(condition),
+            then_value: sub(then_value),
+            else_value: sub(else_value),
+        },
     }
 }
 
 /// Optimize a program
 pub fn optimize_program(program: &Program) -> Program {
     Program {
+        imports: program.imports.clone(),
         functions: program.functions.iter().map(optimize_function).collect(),
     }
 }
+
+/// Fold constants in every function of a program, and nothing else
+pub fn fold_program(program: &Program) -> Program {
+    Program {
+        imports: program.imports.clone(),
+        functions: program.functions.iter().map(fold_function).collect(),
+    }
+}
@@ -0,0 +1,161 @@
+/// How `print` and `write` show values
+///
+/// Each engine's value type walks itself through `Printable`, handing its
+/// pieces to a `Formatter` that decides how they look. `Plain` is the
+/// everyday output, which `Display` uses too; `Json` prints every value as
+/// JSON, for programs whose output another tool reads.
+use crate::builtins::{format_number, quote_string, Numeric};
+use std::collections::HashMap;
+
+/// Writes the pieces of a value to `out`; arrays and maps are bracketed
+/// and separated the same way whichever formatter is used
+pub trait Formatter {
+    fn null(&self, out: &mut String);
+    fn boolean(&self, out: &mut String, b: bool);
+    fn number(&self, out: &mut String, n: Numeric);
+    /// A string, `nested` inside an array or map rather than printed alone
+    fn string(&self, out: &mut String, s: &str, nested: bool);
+    /// The key of a map entry, with what separates it from the value
+    fn key(&self, out: &mut String, key: &str);
+}
+
+/// A value `print` can show
+pub trait Printable {
+    fn print_to(&self, formatter: &dyn Formatter, out: &mut String, nested: bool);
+}
+
+/// The text `formatter` gives `value`
+pub fn render(value: &impl Printable, formatter: &dyn Formatter) -> String {
+    let mut out = String::new();
+    value.print_to(formatter, &mut out, false);
+    out
+}
+
+/// `[1, 2, 3]`
+pub fn print_array<V: Printable>(items: &[V], formatter: &dyn Formatter, out: &mut String) {
+    out.push('[');
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        item.print_to(formatter, out, true);
+    }
+    out.push(']');
+}
+
+/// `{a: 1, b: 2}`, keys sorted so output is deterministic
+pub fn print_map<V: Printable>(
+    map: &HashMap<String, V>,
+    formatter: &dyn Formatter,
+    out: &mut String,
+) {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    out.push('{');
+    for (i, key) in keys.into_iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        formatter.key(out, key);
+        map[key].print_to(formatter, out, true);
+    }
+    out.push('}');
+}
+
+/// The default output: strings bare unless they're inside an array or map
+pub struct Plain;
+
+impl Formatter for Plain {
+    fn null(&self, out: &mut String) {
+        out.push_str("null");
+    }
+
+    fn boolean(&self, out: &mut String, b: bool) {
+        out.push_str(if b { "true" } else { "false" });
+    }
+
+    fn number(&self, out: &mut String, n: Numeric) {
+        out.push_str(&n.to_string());
+    }
+
+    fn string(&self, out: &mut String, s: &str, nested: bool) {
+        if nested {
+            out.push_str(&quote_string(s));
+        } else {
+            out.push_str(s);
+        }
+    }
+
+    fn key(&self, out: &mut String, key: &str) {
+        out.push_str(key);
+        out.push_str(": ");
+    }
+}
+
+/// `--print-format json`: every value as JSON. JSON has no infinity or
+/// nan, so those print as `null`.
+pub struct Json;
+
+impl Formatter for Json {
+    fn null(&self, out: &mut String) {
+        out.push_str("null");
+    }
+
+    fn boolean(&self, out: &mut String, b: bool) {
+        Plain.boolean(out, b);
+    }
+
+    fn number(&self, out: &mut String, n: Numeric) {
+        match n {
+            Numeric::Integer(n) => out.push_str(&n.to_string()),
+            Numeric::Float(n) if n.is_finite() => out.push_str(&format_number(n)),
+            Numeric::Float(_) => out.push_str("null"),
+        }
+    }
+
+    fn string(&self, out: &mut String, s: &str, _nested: bool) {
+        json_string(out, s);
+    }
+
+    fn key(&self, out: &mut String, key: &str) {
+        json_string(out, key);
+        out.push_str(": ");
+    }
+}
+
+/// `s` as a quoted JSON string
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_strings_are_escaped() {
+        let mut out = String::new();
+        json_string(&mut out, "say \"hi\"\n\\\u{1}");
+        assert_eq!(out, r#""say \"hi\"\n\\\u0001""#);
+    }
+
+    #[test]
+    fn test_json_has_no_infinity() {
+        let mut out = String::new();
+        Json.number(&mut out, Numeric::Float(f64::INFINITY));
+        Json.number(&mut out, Numeric::Float(2.5));
+        assert_eq!(out, "null2.5");
+    }
+}
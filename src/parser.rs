@@ -1,15 +1,58 @@
 use crate::ast::*;
-use crate::token::{Token, TokenType};
+use crate::error::ParseError;
+use crate::lexer::Lexer;
+use crate::token::{StringPart, Token, TokenType};
 use anyhow::{anyhow, Result};
 
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    /// The `{` and `(` tokens consumed but not yet closed, innermost last
+    open: Vec<Token>,
+    /// Labels of the loops around the current statement
+    loop_labels: Vec<String>,
+}
+
+/// `X is even` and the other numeric predicates, desugared to the
+/// comparison each stands for, or `operand` back if `word` isn't one
+fn predicate(word: &str, operand: Expr) -> std::result::Result<Expr, Expr> {
+    let compare = |left: Expr, op: BinaryOp| Expr::Binary {
+        left: Box::new(left),
+        op,
+        right: Box::new(Expr::Integer(0)),
+    };
+    let modulo_two = |left: Expr| Expr::Binary {
+        left: Box::new(left),
+        op: BinaryOp::Modulo,
+        right: Box::new(Expr::Integer(2)),
+    };
+    Ok(match word {
+        "even" => compare(modulo_two(operand), BinaryOp::Equals),
+        // Shifting by one keeps the remainder at zero for negative odd
+        // numbers too, where `modulo by 2` gives -1
+        "odd" => compare(
+            modulo_two(Expr::Binary {
+                left: Box::new(operand),
+                op: BinaryOp::Subtract,
+                right: Box::new(Expr::Integer(1)),
+            }),
+            BinaryOp::Equals,
+        ),
+        "positive" => compare(operand, BinaryOp::Greater),
+        "negative" => compare(operand, BinaryOp::Less),
+        "zero" => compare(operand, BinaryOp::Equals),
+        _ => return Err(operand),
+    })
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, current: 0 }
+        Parser {
+            tokens,
+            current: 0,
+            open: Vec::new(),
+            loop_labels: Vec::new(),
+        }
     }
 
     fn current_token(&self) -> &Token {
@@ -35,26 +78,110 @@ impl Parser {
         if std::mem::discriminant(&self.current_token().token_type)
             == std::mem::discriminant(expected)
         {
+            match expected {
+                TokenType::LeftBrace | TokenType::LeftParen => {
+                    self.open.push(self.current_token().clone())
+                }
+                TokenType::RightBrace | TokenType::RightParen => {
+                    self.open.pop();
+                }
+                _ => {}
+            }
             self.advance();
             Ok(())
+        } else if let Some(err) = self.unclosed() {
+            Err(err)
         } else {
-            Err(anyhow!(
-                "Expected {:?}, found {:?} at line {}",
-                expected,
-                self.current_token().token_type,
-                self.current_token().line
-            ))
+            Err(ParseError::Expected {
+                expected: format!("{:?}", expected),
+                found: format!("{:?}", self.current_token().token_type),
+                line: self.current_token().line,
+            }
+            .into())
+        }
+    }
+
+    /// Running out of tokens inside a `{` or `(` is reported against the
+    /// innermost unclosed one, since the end of the file is no help in
+    /// finding the missing brace
+    fn unclosed(&self) -> Option<anyhow::Error> {
+        if !matches!(self.current_token().token_type, TokenType::Eof) {
+            return None;
         }
+        let open = self.open.last()?;
+        let delimiter = match open.token_type {
+            TokenType::LeftBrace => '{',
+            _ => '(',
+        };
+        Some(
+            ParseError::Unclosed {
+                delimiter,
+                line: open.line,
+                column: open.column,
+            }
+            .into(),
+        )
+    }
+
+    /// Error for finding the current token where `what` should be
+    fn expected(&self, what: &str) -> anyhow::Error {
+        if let Some(err) = self.unclosed() {
+            return err;
+        }
+        ParseError::Expected {
+            expected: what.to_string(),
+            found: self.current_token().token_type.to_string(),
+            line: self.current_token().line,
+        }
+        .into()
+    }
+
+    /// Error for a token that can't start a statement or expression
+    fn unexpected(&self) -> anyhow::Error {
+        if let Some(err) = self.unclosed() {
+            return err;
+        }
+        ParseError::UnexpectedToken {
+            found: format!("{:?}", self.current_token().token_type),
+            line: self.current_token().line,
+        }
+        .into()
     }
 
     pub fn parse(&mut self) -> Result<Program> {
-        let mut functions = Vec::new();
+        let mut imports = Vec::new();
+        while matches!(self.current_token().token_type, TokenType::Import) {
+            self.advance();
+            match &self.current_token().token_type {
+                TokenType::StringLit(path) => {
+                    imports.push(path.clone());
+                    self.advance();
+                }
+                _ => return Err(self.expected("a file path after import")),
+            }
+        }
 
+        let mut functions = Vec::new();
         while !matches!(self.current_token().token_type, TokenType::Eof) {
             functions.push(self.parse_function()?);
         }
 
-        Ok(Program { functions })
+        Ok(Program { imports, functions })
+    }
+
+    /// Parse REPL input: function definitions and bare statements in any order
+    pub fn parse_repl_input(&mut self) -> Result<(Vec<Function>, Vec<Stmt>)> {
+        let mut functions = Vec::new();
+        let mut statements = Vec::new();
+
+        while !matches!(self.current_token().token_type, TokenType::Eof) {
+            match self.current_token().token_type {
+                TokenType::Function => functions.push(self.parse_function()?),
+                _ => statements.push(self.parse_statement()?),
+            }
+        }
+
+        Ok((functions, statements))
     }
 
     fn parse_function(&mut self) -> Result<Function> {
@@ -65,7 +192,7 @@ impl Parser {
             self.advance();
             name
         } else {
-            return Err(anyhow!("Expected function name"));
+            return Err(self.expected("function name"));
         };
 
         self.expect(&TokenType::LeftParen)?;
@@ -80,7 +207,7 @@ impl Parser {
                     self.advance();
                 }
             } else {
-                return Err(anyhow!("Expected parameter name"));
+                return Err(self.expected("parameter name"));
             }
         }
 
@@ -107,34 +234,46 @@ impl Parser {
         Ok(statements)
     }
 
+    /// Where the current token starts
+    fn span(&self) -> Span {
+        let token = self.current_token();
+        Span {
+            line: token.line,
+            column: token.column,
+        }
+    }
+
     fn parse_statement(&mut self) -> Result<Stmt> {
-        match &self.current_token().token_type {
+        let span = self.span();
+        let kind = match &self.current_token().token_type {
             TokenType::Variable => self.parse_var_decl(false),
             TokenType::Constant => self.parse_var_decl(true),
             TokenType::Print => self.parse_print(),
+            TokenType::Write => self.parse_write(),
             TokenType::Ask => self.parse_ask(),
+            TokenType::Assert => self.parse_assert(),
+            TokenType::Push => self.parse_push(),
+            TokenType::Increase | TokenType::Decrease | TokenType::MultiplyVerb => {
+                self.parse_compound_assignment()
+            }
+            TokenType::Pop => Ok(StmtKind::Expression(self.parse_expression()?)),
             TokenType::If => self.parse_if(),
             TokenType::While => self.parse_while(),
+            TokenType::Repeat => self.parse_repeat(),
+            TokenType::Do => self.parse_do_while(),
             TokenType::For => self.parse_for(),
             TokenType::Return => self.parse_return(),
-            TokenType::Break => {
-                self.advance();
-                Ok(Stmt::Break)
-            }
-            TokenType::Continue => {
-                self.advance();
-                Ok(Stmt::Continue)
-            }
+            TokenType::Break => Ok(StmtKind::Break(self.parse_loop_target()?)),
+            TokenType::Continue => Ok(StmtKind::Continue(self.parse_loop_target()?)),
             TokenType::Identifier(_) => self.parse_assignment_or_expr(),
-            _ => Err(anyhow!(
-                "Unexpected token: {:?} at line {}",
-                self.current_token().token_type,
-                self.current_token().line
-            )),
-        }
+            // Any other expression may stand alone, usually as the value a
+            // function body ends with
+            _ => Ok(StmtKind::Expression(self.parse_expression()?)),
+        }?;
+        Ok(Stmt::new(kind, span))
     }
 
-    fn parse_var_decl(&mut self, is_const: bool) -> Result<Stmt> {
+    fn parse_var_decl(&mut self, is_const: bool) -> Result<StmtKind> {
         self.advance(); // Skip 'var' or 'const'
 
         let name = if let TokenType::Identifier(n) = &self.current_token().token_type {
@@ -142,27 +281,27 @@ impl Parser {
             self.advance();
             name
         } else {
-            return Err(anyhow!("Expected variable name"));
+            return Err(self.expected("variable name"));
         };
 
         self.expect(&TokenType::Assign)?;
 
         let value = self.parse_expression()?;
 
-        Ok(Stmt::VarDecl {
+        Ok(StmtKind::VarDecl {
             name,
             value,
             is_const,
         })
     }
 
-    fn parse_assignment_or_expr(&mut self) -> Result<Stmt> {
+    fn parse_assignment_or_expr(&mut self) -> Result<StmtKind> {
         let name = if let TokenType::Identifier(n) = &self.current_token().token_type {
             let name = n.clone();
             self.advance();
             name
         } else {
-            return Err(anyhow!("Expected identifier"));
+            return Err(self.expected("identifier"));
         };
 
         // Check for array index assignment: identifier at index is value
@@ -173,43 +312,112 @@ impl Parser {
             if matches!(self.current_token().token_type, TokenType::Assign) {
                 self.advance();
                 let value = self.parse_expression()?;
-                return Ok(Stmt::IndexAssignment {
+                return Ok(StmtKind::IndexAssignment {
                     array: Box::new(Expr::Identifier(name)),
                     index: Box::new(index),
                     value,
                 });
             } else {
-                return Err(anyhow!("Expected 'is' after array index"));
+                return Err(self.expected("'is' after array index"));
             }
         }
 
         if matches!(self.current_token().token_type, TokenType::Assign) {
             self.advance();
             let value = self.parse_expression()?;
-            Ok(Stmt::Assignment { name, value })
+            Ok(StmtKind::Assignment { name, value })
         } else {
             // It's a function call
             self.current -= 1; // Go back
             let expr = self.parse_expression()?;
-            Ok(Stmt::Expression(expr))
+            Ok(StmtKind::Expression(expr))
         }
     }
 
-    fn parse_print(&mut self) -> Result<Stmt> {
+    /// `increase x by n`, `decrease x by n` and `multiply x by n`, which
+    /// desugar to `x is x plus n` and so on
+    fn parse_compound_assignment(&mut self) -> Result<StmtKind> {
+        let op = match self.current_token().token_type {
+            TokenType::Increase => BinaryOp::Add,
+            TokenType::Decrease => BinaryOp::Subtract,
+            _ => BinaryOp::Multiply,
+        };
+        let verb = self.current_token().token_type.to_string();
+        self.advance();
+
+        let name = if let TokenType::Identifier(n) = &self.current_token().token_type {
+            let name = n.clone();
+            self.advance();
+            name
+        } else {
+            return Err(self.expected(&format!("variable name after '{}'", verb)));
+        };
+
+        if !matches!(&self.current_token().token_type, TokenType::Identifier(s) if s == "by") {
+            return Err(self.expected(&format!("'by' after '{} {}'", verb, name)));
+        }
+        self.advance();
+
+        let amount = self.parse_expression()?;
+        Ok(StmtKind::Assignment {
+            name: name.clone(),
+            value: Expr::Binary {
+                left: Box::new(Expr::Identifier(name)),
+                op,
+                right: Box::new(amount),
+            },
+        })
+    }
+
+    fn parse_print(&mut self) -> Result<StmtKind> {
         self.advance(); // Skip 'print'
         let expr = self.parse_expression()?;
-        Ok(Stmt::Print(expr))
+        Ok(StmtKind::Print(expr))
     }
 
-    fn parse_ask(&mut self) -> Result<Stmt> {
+    fn parse_write(&mut self) -> Result<StmtKind> {
+        self.advance(); // Skip 'write'
+        let expr = self.parse_expression()?;
+        Ok(StmtKind::Write(expr))
+    }
+
+    fn parse_push(&mut self) -> Result<StmtKind> {
+        self.advance(); // Skip 'push'
+
+        let value = self.parse_expression()?;
+        self.expect(&TokenType::Onto)?;
+
+        let array = if let TokenType::Identifier(n) = &self.current_token().token_type {
+            let name = n.clone();
+            self.advance();
+            name
+        } else {
+            return Err(self.expected("array variable after 'onto'"));
+        };
+
+        Ok(StmtKind::Push { array, value })
+    }
+
+    fn parse_ask(&mut self) -> Result<StmtKind> {
         self.advance(); // Skip 'ask'
 
+        // `ask text name` rather than asking for a variable called `text`
+        let line = self.current_token().line;
+        let text = matches!(&self.current_token().token_type, TokenType::Identifier(w) if w == "text")
+            && matches!(
+                self.peek_token(1),
+                Some(Token { token_type: TokenType::Identifier(_), line: next, .. }) if *next == line
+            );
+        if text {
+            self.advance();
+        }
+
         let name = if let TokenType::Identifier(n) = &self.current_token().token_type {
             let name = n.clone();
             self.advance();
             name
         } else {
-            return Err(anyhow!("Expected variable name after 'ask'"));
+            return Err(self.expected("variable name after 'ask'"));
         };
 
         // Optional prompt
@@ -220,9 +428,17 @@ impl Parser {
                 | TokenType::Variable
                 | TokenType::Constant
                 | TokenType::Print
+                | TokenType::Write
                 | TokenType::Ask
+                | TokenType::Assert
+                | TokenType::Push
+                | TokenType::Increase
+                | TokenType::Decrease
+                | TokenType::MultiplyVerb
                 | TokenType::If
                 | TokenType::While
+                | TokenType::Repeat
+                | TokenType::Do
                 | TokenType::For
                 | TokenType::Return
                 | TokenType::Identifier(_)
@@ -232,10 +448,33 @@ impl Parser {
             None
         };
 
-        Ok(Stmt::Ask { name, prompt })
+        Ok(StmtKind::Ask { name, prompt, text })
     }
 
-    fn parse_if(&mut self) -> Result<Stmt> {
+    fn parse_assert(&mut self) -> Result<StmtKind> {
+        self.advance(); // Skip 'assert'
+
+        let condition = self.parse_expression()?;
+
+        let message = if matches!(&self.current_token().token_type, TokenType::Identifier(s) if s == "message")
+        {
+            self.advance();
+            match &self.current_token().token_type {
+                TokenType::StringLit(text) => {
+                    let text = text.clone();
+                    self.advance();
+                    Some(text)
+                }
+                _ => return Err(self.expected("a string after 'message'")),
+            }
+        } else {
+            None
+        };
+
+        Ok(StmtKind::Assert { condition, message })
+    }
+
+    fn parse_if(&mut self) -> Result<StmtKind> {
         self.advance(); // Skip 'if'
 
         let condition = self.parse_expression()?;
@@ -245,35 +484,144 @@ impl Parser {
 
         let else_block = if matches!(self.current_token().token_type, TokenType::Else) {
             self.advance();
-            self.expect(&TokenType::LeftBrace)?;
-            let block = self.parse_block()?;
-            self.expect(&TokenType::RightBrace)?;
-            Some(block)
+            if matches!(self.current_token().token_type, TokenType::If) {
+                // `else if` chains become a nested if as the sole else statement
+                let span = self.span();
+                Some(vec![Stmt::new(self.parse_if()?, span)])
+            } else {
+                self.expect(&TokenType::LeftBrace)?;
+                let block = self.parse_block()?;
+                self.expect(&TokenType::RightBrace)?;
+                Some(block)
+            }
         } else {
             None
         };
 
-        Ok(Stmt::If {
+        Ok(StmtKind::If {
             condition,
             then_block,
             else_block,
         })
     }
 
-    fn parse_while(&mut self) -> Result<Stmt> {
+    fn parse_while(&mut self) -> Result<StmtKind> {
         self.advance(); // Skip 'while'
 
         let condition = self.parse_expression()?;
+        let label = self.parse_loop_label()?;
+        let body = self.parse_loop_body(label.as_ref())?;
+
+        Ok(StmtKind::While {
+            condition,
+            body,
+            label,
+        })
+    }
+
+    /// `as name` after a loop header
+    fn parse_loop_label(&mut self) -> Result<Option<String>> {
+        if !matches!(self.current_token().token_type, TokenType::As) {
+            return Ok(None);
+        }
+        self.advance();
+        match &self.current_token().token_type {
+            TokenType::Identifier(name) => {
+                let name = name.clone();
+                self.advance();
+                Ok(Some(name))
+            }
+            _ => Err(self.expected("loop label after 'as'")),
+        }
+    }
+
+    /// A loop body in braces, in which `break label` and `continue label` can
+    /// name this loop
+    fn parse_loop_body(&mut self, label: Option<&String>) -> Result<Vec<Stmt>> {
+        self.expect(&TokenType::LeftBrace)?;
+        self.loop_labels.extend(label.cloned());
+        let body = self.parse_block();
+        if label.is_some() {
+            self.loop_labels.pop();
+        }
+        let body = body?;
+        self.expect(&TokenType::RightBrace)?;
+        Ok(body)
+    }
+
+    /// The label after `break` or `continue`, which has to be on the same
+    /// line so the next statement isn't mistaken for one
+    fn parse_loop_target(&mut self) -> Result<Option<String>> {
+        let line = self.current_token().line;
+        self.advance(); // Skip 'break' or 'continue'
+
+        let label = match &self.current_token().token_type {
+            TokenType::Identifier(name) if self.current_token().line == line => name.clone(),
+            _ => return Ok(None),
+        };
+        if !self.loop_labels.contains(&label) {
+            return Err(ParseError::UnknownLabel { label, line }.into());
+        }
+        self.advance();
+        Ok(Some(label))
+    }
+
+    fn parse_for_each(&mut self) -> Result<StmtKind> {
+        self.advance(); // Skip 'each'
+
+        let var = if let TokenType::Identifier(n) = &self.current_token().token_type {
+            let name = n.clone();
+            self.advance();
+            name
+        } else {
+            return Err(self.expected("variable name after 'for each'"));
+        };
+
+        self.expect(&TokenType::In)?;
+        let iterable = self.parse_expression()?;
+        let label = self.parse_loop_label()?;
+        let body = self.parse_loop_body(label.as_ref())?;
+
+        Ok(StmtKind::ForEach {
+            var,
+            iterable,
+            body,
+            label,
+        })
+    }
+
+    fn parse_repeat(&mut self) -> Result<StmtKind> {
+        self.advance(); // Skip 'repeat'
+
+        // parse_factor stops at a `times` that is followed by `{`
+        let count = self.parse_expression()?;
+        self.expect(&TokenType::Multiply)?;
         self.expect(&TokenType::LeftBrace)?;
         let body = self.parse_block()?;
         self.expect(&TokenType::RightBrace)?;
 
-        Ok(Stmt::While { condition, body })
+        Ok(StmtKind::Repeat { count, body })
     }
 
-    fn parse_for(&mut self) -> Result<Stmt> {
+    fn parse_do_while(&mut self) -> Result<StmtKind> {
+        self.advance(); // Skip 'do'
+
+        self.expect(&TokenType::LeftBrace)?;
+        let body = self.parse_block()?;
+        self.expect(&TokenType::RightBrace)?;
+        self.expect(&TokenType::While)?;
+        let condition = self.parse_expression()?;
+
+        Ok(StmtKind::DoWhile { body, condition })
+    }
+
+    fn parse_for(&mut self) -> Result<StmtKind> {
         self.advance(); // Skip 'for'
 
+        if matches!(self.current_token().token_type, TokenType::Each) {
+            return self.parse_for_each();
+        }
+
         self.expect(&TokenType::LeftParen)?;
 
         let init = Box::new(self.parse_statement()?);
@@ -283,40 +631,71 @@ impl Parser {
         let increment = Box::new(self.parse_statement()?);
 
         self.expect(&TokenType::RightParen)?;
-        self.expect(&TokenType::LeftBrace)?;
-        let body = self.parse_block()?;
-        self.expect(&TokenType::RightBrace)?;
+        let label = self.parse_loop_label()?;
+        let body = self.parse_loop_body(label.as_ref())?;
 
-        Ok(Stmt::For {
+        Ok(StmtKind::For {
             init,
             condition,
             increment,
             body,
+            label,
         })
     }
 
-    fn parse_return(&mut self) -> Result<Stmt> {
+    fn parse_return(&mut self) -> Result<StmtKind> {
         self.advance(); // Skip 'return'
 
         if matches!(
             self.current_token().token_type,
             TokenType::RightBrace | TokenType::Eof
         ) {
-            Ok(Stmt::Return(None))
+            Ok(StmtKind::Return(None))
         } else {
             let expr = self.parse_expression()?;
-            Ok(Stmt::Return(Some(expr)))
+            Ok(StmtKind::Return(Some(expr)))
         }
     }
 
     fn parse_expression(&mut self) -> Result<Expr> {
-        self.parse_or()
+        self.parse_otherwise()
+    }
+
+    /// `X otherwise Y`, also written `X or else Y`
+    fn parse_otherwise(&mut self) -> Result<Expr> {
+        let mut left = self.parse_or()?;
+
+        loop {
+            match self.current_token().token_type {
+                TokenType::Otherwise => self.advance(),
+                TokenType::Or if self.or_else() => {
+                    self.advance(); // Skip "or"
+                    self.advance(); // Skip "else"
+                }
+                _ => break,
+            }
+            let right = self.parse_or()?;
+            left = Expr::Binary {
+                left: Box::new(left),
+                op: BinaryOp::Otherwise,
+                right: Box::new(right),
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn or_else(&self) -> bool {
+        matches!(
+            self.peek_token(1).map(|t| &t.token_type),
+            Some(TokenType::Else)
+        )
     }
 
     fn parse_or(&mut self) -> Result<Expr> {
         let mut left = self.parse_and()?;
 
-        while matches!(self.current_token().token_type, TokenType::Or) {
+        while matches!(self.current_token().token_type, TokenType::Or) && !self.or_else() {
             self.advance();
             let right = self.parse_and()?;
             left = Expr::Binary {
@@ -330,11 +709,11 @@ impl Parser {
     }
 
     fn parse_and(&mut self) -> Result<Expr> {
-        let mut left = self.parse_equality()?;
+        let mut left = self.parse_not()?;
 
         while matches!(self.current_token().token_type, TokenType::And) {
             self.advance();
-            let right = self.parse_equality()?;
+            let right = self.parse_not()?;
             left = Expr::Binary {
                 left: Box::new(left),
                 op: BinaryOp::And,
@@ -345,10 +724,58 @@ impl Parser {
         Ok(left)
     }
 
+    /// A leading `not` negates the whole comparison after it, so `not a equals b`
+    /// is `not (a equals b)`. A `not` further into an operand, as in
+    /// `a equals not b`, is an ordinary unary operator.
+    fn parse_not(&mut self) -> Result<Expr> {
+        if matches!(self.current_token().token_type, TokenType::Not) {
+            self.advance();
+            let operand = self.parse_not()?;
+            return Ok(Expr::Unary {
+                op: UnaryOp::Not,
+                operand: Box::new(operand),
+            });
+        }
+        self.parse_equality()
+    }
+
     fn parse_equality(&mut self) -> Result<Expr> {
         let mut left = self.parse_comparison()?;
 
         loop {
+            // `X is null`, `X is even` and so on, each also with `is not`
+            if matches!(self.current_token().token_type, TokenType::Assign) {
+                let negated = matches!(
+                    self.peek_token(1).map(|t| &t.token_type),
+                    Some(TokenType::Not)
+                );
+                let word_at = if negated { 2 } else { 1 };
+                left = match self.peek_token(word_at).map(|t| &t.token_type) {
+                    Some(TokenType::Null) => Expr::Unary {
+                        op: UnaryOp::IsNull,
+                        operand: Box::new(left),
+                    },
+                    Some(TokenType::Identifier(word)) => match predicate(word, left) {
+                        Ok(expr) => expr,
+                        Err(operand) => {
+                            left = operand;
+                            break;
+                        }
+                    },
+                    _ => break,
+                };
+                for _ in 0..=word_at {
+                    self.advance();
+                }
+                if negated {
+                    left = Expr::Unary {
+                        op: UnaryOp::Not,
+                        operand: Box::new(left),
+                    };
+                }
+                continue;
+            }
+
             let op = match &self.current_token().token_type {
                 TokenType::Equals => BinaryOp::Equals,
                 TokenType::Not => {
@@ -383,10 +810,14 @@ impl Parser {
     }
 
     fn parse_comparison(&mut self) -> Result<Expr> {
-        let mut left = self.parse_term()?;
+        let mut left = self.parse_bitor()?;
 
         loop {
             let op = match &self.current_token().token_type {
+                TokenType::Contains => {
+                    self.advance();
+                    BinaryOp::Contains
+                }
                 TokenType::Greater => {
                     self.advance();
                     // Check for "greater than"
@@ -450,7 +881,7 @@ impl Parser {
                 _ => break,
             };
 
-            let right = self.parse_term()?;
+            let right = self.parse_bitor()?;
             left = Expr::Binary {
                 left: Box::new(left),
                 op,
@@ -461,6 +892,56 @@ impl Parser {
         Ok(left)
     }
 
+    // The bitwise operators bind tighter than comparisons and looser than
+    // arithmetic: `bitor`, then `xor`, then `bitand`, then the shifts
+
+    fn parse_bitor(&mut self) -> Result<Expr> {
+        self.parse_left_assoc(&[(TokenType::BitOr, BinaryOp::BitOr)], Self::parse_xor)
+    }
+
+    fn parse_xor(&mut self) -> Result<Expr> {
+        self.parse_left_assoc(&[(TokenType::Xor, BinaryOp::Xor)], Self::parse_bitand)
+    }
+
+    fn parse_bitand(&mut self) -> Result<Expr> {
+        self.parse_left_assoc(&[(TokenType::BitAnd, BinaryOp::BitAnd)], Self::parse_shift)
+    }
+
+    fn parse_shift(&mut self) -> Result<Expr> {
+        self.parse_left_assoc(
+            &[
+                (TokenType::ShiftLeft, BinaryOp::ShiftLeft),
+                (TokenType::ShiftRight, BinaryOp::ShiftRight),
+            ],
+            Self::parse_term,
+        )
+    }
+
+    /// Operands parsed by `operand`, joined left to right by the operators
+    /// whose tokens are in `ops`
+    fn parse_left_assoc(
+        &mut self,
+        ops: &[(TokenType, BinaryOp)],
+        operand: fn(&mut Self) -> Result<Expr>,
+    ) -> Result<Expr> {
+        let mut left = operand(self)?;
+
+        while let Some((_, op)) = ops
+            .iter()
+            .find(|(token, _)| *token == self.current_token().token_type)
+        {
+            self.advance();
+            let right = operand(self)?;
+            left = Expr::Binary {
+                left: Box::new(left),
+                op: op.clone(),
+                right: Box::new(right),
+            };
+        }
+
+        Ok(left)
+    }
+
     fn parse_term(&mut self) -> Result<Expr> {
         let mut left = self.parse_factor()?;
 
@@ -484,18 +965,28 @@ impl Parser {
     }
 
     fn parse_factor(&mut self) -> Result<Expr> {
-        let mut left = self.parse_unary()?;
+        let mut left = self.parse_power()?;
 
         loop {
             let op = match &self.current_token().token_type {
+                // `repeat n times {`
+                TokenType::Multiply
+                    if matches!(
+                        self.peek_token(1).map(|t| &t.token_type),
+                        Some(TokenType::LeftBrace)
+                    ) =>
+                {
+                    break
+                }
                 TokenType::Multiply => BinaryOp::Multiply,
                 TokenType::Divide => BinaryOp::Divide,
+                TokenType::IntDivide => BinaryOp::IntDivide,
                 TokenType::Modulo => BinaryOp::Modulo,
                 _ => break,
             };
 
             self.advance();
-            let right = self.parse_unary()?;
+            let right = self.parse_power()?;
             left = Expr::Binary {
                 left: Box::new(left),
                 op,
@@ -506,6 +997,23 @@ impl Parser {
         Ok(left)
     }
 
+    fn parse_power(&mut self) -> Result<Expr> {
+        let base = self.parse_unary()?;
+
+        if matches!(self.current_token().token_type, TokenType::Power) {
+            self.advance();
+            // Right-associative: 2 to the power of 3 to the power of 2 is 2^(3^2)
+            let exponent = self.parse_power()?;
+            return Ok(Expr::Binary {
+                left: Box::new(base),
+                op: BinaryOp::Power,
+                right: Box::new(exponent),
+            });
+        }
+
+        Ok(base)
+    }
+
     fn parse_unary(&mut self) -> Result<Expr> {
         match &self.current_token().token_type {
             TokenType::Not => {
@@ -534,6 +1042,15 @@ impl Parser {
                     operand: Box::new(operand),
                 })
             }
+            TokenType::Type => {
+                self.advance();
+                self.expect(&TokenType::Of)?;
+                let operand = self.parse_unary()?;
+                Ok(Expr::Unary {
+                    op: UnaryOp::TypeOf,
+                    operand: Box::new(operand),
+                })
+            }
             TokenType::Uppercase => {
                 self.advance();
                 let operand = self.parse_unary()?;
@@ -542,6 +1059,102 @@ impl Parser {
                     operand: Box::new(operand),
                 })
             }
+            TokenType::Lowercase => {
+                self.advance();
+                let operand = self.parse_unary()?;
+                Ok(Expr::Unary {
+                    op: UnaryOp::Lowercase,
+                    operand: Box::new(operand),
+                })
+            }
+            TokenType::Trim => {
+                self.advance();
+                let operand = self.parse_unary()?;
+                Ok(Expr::Unary {
+                    op: UnaryOp::Trim,
+                    operand: Box::new(operand),
+                })
+            }
+            TokenType::Reverse => {
+                self.advance();
+                let operand = self.parse_unary()?;
+                Ok(Expr::Unary {
+                    op: UnaryOp::Reverse,
+                    operand: Box::new(operand),
+                })
+            }
+            TokenType::Sort => {
+                self.advance();
+                let operand = self.parse_unary()?;
+                Ok(Expr::Unary {
+                    op: UnaryOp::Sort,
+                    operand: Box::new(operand),
+                })
+            }
+            TokenType::Join => {
+                self.advance();
+                let array = self.parse_primary()?;
+                self.expect(&TokenType::With)?;
+                let separator = self.parse_primary()?;
+                Ok(Expr::Binary {
+                    left: Box::new(array),
+                    op: BinaryOp::Join,
+                    right: Box::new(separator),
+                })
+            }
+            TokenType::IndexOf => {
+                self.advance();
+                let needle = self.parse_primary()?;
+                self.expect(&TokenType::In)?;
+                let string = self.parse_primary()?;
+                Ok(Expr::Binary {
+                    left: Box::new(string),
+                    op: BinaryOp::IndexOf,
+                    right: Box::new(needle),
+                })
+            }
+            TokenType::Replace => {
+                self.advance();
+                let pattern = self.parse_primary()?;
+                self.expect(&TokenType::With)?;
+                let replacement = self.parse_primary()?;
+                self.expect(&TokenType::In)?;
+                let string = self.parse_primary()?;
+                Ok(Expr::Replace {
+                    string: Box::new(string),
+                    pattern: Box::new(pattern),
+                    replacement: Box::new(replacement),
+                })
+            }
+            TokenType::If => {
+                self.advance();
+                let condition = self.parse_expression()?;
+                // `then` is only a keyword here, so it can still name a variable
+                if !matches!(&self.current_token().token_type, TokenType::Identifier(w) if w == "then")
+                {
+                    return Err(self.expected("'then' after the condition"));
+                }
+                self.advance();
+                let then_value = self.parse_expression()?;
+                self.expect(&TokenType::Else)?;
+                let else_value = self.parse_expression()?;
+                Ok(Expr::Ternary {
+                    condition: Box::new(condition),
+                    then_value: Box::new(then_value),
+                    else_value: Box::new(else_value),
+                })
+            }
+            TokenType::Pop => {
+                self.advance();
+                self.expect(&TokenType::From)?;
+                if let TokenType::Identifier(name) = &self.current_token().token_type {
+                    let name = name.clone();
+                    self.advance();
+                    Ok(Expr::Pop(name))
+                } else {
+                    Err(self.expected("array variable after 'pop from'"))
+                }
+            }
             TokenType::Substring => {
                 self.advance();
                 let string = self.parse_primary()?;
@@ -555,10 +1168,60 @@ impl Parser {
                     to: Box::new(to),
                 })
             }
+            TokenType::Slice => {
+                self.advance();
+                let array = self.parse_primary()?;
+                self.expect(&TokenType::From)?;
+                let from = self.parse_primary()?;
+                self.expect(&TokenType::To)?;
+                let to = self.parse_primary()?;
+                Ok(Expr::Slice {
+                    array: Box::new(array),
+                    from: Box::new(from),
+                    to: Box::new(to),
+                })
+            }
             _ => self.parse_primary(),
         }
     }
 
+    /// Desugar `"a {x} b"` into `"a " plus x plus " b"`, starting from a string so
+    /// that numeric holes are formatted rather than summed
+    fn parse_interpolation(&mut self, parts: &[StringPart], line: usize) -> Result<Expr> {
+        let mut result = Expr::String(String::new());
+
+        for (i, part) in parts.iter().enumerate() {
+            let expr = match part {
+                StringPart::Literal(s) => Expr::String(s.clone()),
+                StringPart::Expr(source) => {
+                    let mut parser = Parser::new(Lexer::new(source.clone()).tokenize());
+                    let expr = parser
+                        .parse_expression()
+                        .map_err(|e| anyhow!("{} in string interpolation at line {}", e, line))?;
+                    if !matches!(parser.current_token().token_type, TokenType::Eof) {
+                        return Err(anyhow!(
+                            "Unexpected {} in string interpolation at line {}",
+                            parser.current_token().token_type,
+                            line
+                        ));
+                    }
+                    expr
+                }
+            };
+
+            result = match (i, expr) {
+                (0, Expr::String(s)) => Expr::String(s),
+                (_, expr) => Expr::Binary {
+                    left: Box::new(result),
+                    op: BinaryOp::Add,
+                    right: Box::new(expr),
+                },
+            };
+        }
+
+        Ok(result)
+    }
+
     fn parse_primary(&mut self) -> Result<Expr> {
         let mut expr = match &self.current_token().token_type.clone() {
             TokenType::Number(n) => {
@@ -566,11 +1229,22 @@ impl Parser {
                 self.advance();
                 Expr::Number(num)
             }
+            TokenType::Integer(n) => {
+                let num = *n;
+                self.advance();
+                Expr::Integer(num)
+            }
             TokenType::StringLit(s) => {
                 let string = s.clone();
                 self.advance();
                 Expr::String(string)
             }
+            TokenType::InterpolatedString(parts) => {
+                let parts = parts.clone();
+                let line = self.current_token().line;
+                self.advance();
+                self.parse_interpolation(&parts, line)?
+            }
             TokenType::Boolean(b) => {
                 let val = *b;
                 self.advance();
@@ -580,18 +1254,27 @@ impl Parser {
                 self.advance(); // Skip 'list'
                 let mut elements = Vec::new();
 
-                // Parse list elements until we hit a statement boundary or right brace
+                // Parse list elements until we hit a statement boundary or a brace
                 loop {
                     if matches!(
                         self.current_token().token_type,
                         TokenType::RightBrace
+                            | TokenType::LeftBrace
                             | TokenType::Eof
                             | TokenType::Variable
                             | TokenType::Constant
                             | TokenType::Print
+                            | TokenType::Write
                             | TokenType::Ask
+                            | TokenType::Assert
+                            | TokenType::Push
+                            | TokenType::Increase
+                            | TokenType::Decrease
+                            | TokenType::MultiplyVerb
                             | TokenType::If
                             | TokenType::While
+                            | TokenType::Repeat
+                            | TokenType::Do
                             | TokenType::For
                             | TokenType::Return
                     ) {
@@ -612,13 +1295,57 @@ impl Parser {
 
                 Expr::Array(elements)
             }
+            TokenType::Map => {
+                self.advance(); // Skip 'map'
+                let mut entries = Vec::new();
+
+                // Parse `key to value` pairs until a statement boundary or right brace
+                loop {
+                    if matches!(
+                        self.current_token().token_type,
+                        TokenType::RightBrace
+                            | TokenType::Eof
+                            | TokenType::Variable
+                            | TokenType::Constant
+                            | TokenType::Print
+                            | TokenType::Write
+                            | TokenType::Ask
+                            | TokenType::Assert
+                            | TokenType::Push
+                            | TokenType::Increase
+                            | TokenType::Decrease
+                            | TokenType::MultiplyVerb
+                            | TokenType::If
+                            | TokenType::While
+                            | TokenType::Repeat
+                            | TokenType::Do
+                            | TokenType::For
+                            | TokenType::Return
+                    ) {
+                        break;
+                    }
+
+                    let key = self.parse_term()?;
+                    self.expect(&TokenType::To)?;
+                    let value = self.parse_term()?;
+                    entries.push((key, value));
+
+                    if matches!(self.current_token().token_type, TokenType::Comma) {
+                        self.advance();
+                    } else {
+                        break;
+                    }
+                }
+
+                Expr::Map(entries)
+            }
             TokenType::Identifier(name) => {
                 let name = name.clone();
                 self.advance();
 
                 // Check for function call
                 if matches!(self.current_token().token_type, TokenType::LeftParen) {
-                    self.advance();
+                    self.expect(&TokenType::LeftParen)?;
                     let mut args = Vec::new();
 
                     while !matches!(self.current_token().token_type, TokenType::RightParen) {
@@ -636,18 +1363,12 @@ impl Parser {
                 }
             }
             TokenType::LeftParen => {
-                self.advance();
+                self.expect(&TokenType::LeftParen)?;
                 let e = self.parse_expression()?;
                 self.expect(&TokenType::RightParen)?;
                 e
             }
-            _ => {
-                return Err(anyhow!(
-                    "Unexpected token in expression: {:?} at line {}",
-                    self.current_token().token_type,
-                    self.current_token().line
-                ))
-            }
+            _ => return Err(self.unexpected()),
         };
 
         // Check for array indexing with 'at'
@@ -663,3 +1384,213 @@ impl Parser {
         Ok(expr)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn parse_main_body(body: &str) -> Vec<Stmt> {
+        let source = format!("function main() {{\n{}\n}}", body);
+        let tokens = Lexer::new(source).tokenize();
+        let mut program = Parser::new(tokens).parse().expect("Failed to parse");
+        program.functions.remove(0).body
+    }
+
+    #[test]
+    fn test_else_if_chain() {
+        let body = parse_main_body("if a { print 1 } else if b { print 2 } else { print 3 }");
+
+        let StmtKind::If { else_block, .. } = &body[0].kind else {
+            panic!("expected if statement, got {:?}", body[0]);
+        };
+        let else_block = else_block.as_ref().expect("missing else block");
+        assert_eq!(else_block.len(), 1);
+
+        let StmtKind::If {
+            condition,
+            else_block: inner_else,
+            ..
+        } = &else_block[0].kind
+        else {
+            panic!("expected nested if, got {:?}", else_block[0]);
+        };
+        assert_eq!(condition, &Expr::Identifier("b".to_string()));
+        let inner_else = inner_else.as_ref().expect("missing inner else block");
+        assert_eq!(inner_else[0].kind, StmtKind::Print(Expr::Integer(3)));
+    }
+
+    #[test]
+    fn test_power_is_right_associative() {
+        let body = parse_main_body("print 2 to the power of 3 to the power of 2");
+        let expected = Expr::Binary {
+            left: Box::new(Expr::Integer(2)),
+            op: BinaryOp::Power,
+            right: Box::new(Expr::Binary {
+                left: Box::new(Expr::Integer(3)),
+                op: BinaryOp::Power,
+                right: Box::new(Expr::Integer(2)),
+            }),
+        };
+        assert_eq!(body[0].kind, StmtKind::Print(expected));
+    }
+
+    #[test]
+    fn test_ask_text() {
+        let body = parse_main_body("ask text zip \"Zip: \"\nask text\nzip is 1");
+        assert_eq!(
+            body[0].kind,
+            StmtKind::Ask {
+                name: "zip".to_string(),
+                prompt: Some(Expr::String("Zip: ".to_string())),
+                text: true,
+            }
+        );
+        // A name on the next line starts a new statement, so this asks for `text`
+        assert_eq!(
+            body[1].kind,
+            StmtKind::Ask {
+                name: "text".to_string(),
+                prompt: None,
+                text: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_compound_assignment_desugars() {
+        let body = parse_main_body("increase total by 5\nmultiply total by n plus 1");
+        let update = |op, right| StmtKind::Assignment {
+            name: "total".to_string(),
+            value: Expr::Binary {
+                left: Box::new(Expr::Identifier("total".to_string())),
+                op,
+                right: Box::new(right),
+            },
+        };
+        assert_eq!(body[0].kind, update(BinaryOp::Add, Expr::Integer(5)));
+        assert_eq!(
+            body[1].kind,
+            update(
+                BinaryOp::Multiply,
+                Expr::Binary {
+                    left: Box::new(Expr::Identifier("n".to_string())),
+                    op: BinaryOp::Add,
+                    right: Box::new(Expr::Integer(1)),
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn test_predicates_desugar_to_comparisons() {
+        let body = parse_main_body("print n is zero");
+        assert_eq!(
+            body[0].kind,
+            StmtKind::Print(Expr::Binary {
+                left: Box::new(Expr::Identifier("n".to_string())),
+                op: BinaryOp::Equals,
+                right: Box::new(Expr::Integer(0)),
+            })
+        );
+
+        // Any other word after `is` is left for the caller
+        assert!(parse_error("function main() {\n print n is big\n}").contains("Assign"));
+    }
+
+    #[test]
+    fn test_not_binds_looser_than_comparisons() {
+        let expr = |source: &str| match &parse_main_body(&format!("print {}", source))[0].kind {
+            StmtKind::Print(expr) => expr.clone(),
+            other => panic!("expected print, got {:?}", other),
+        };
+        let name = |n: &str| Box::new(Expr::Identifier(n.to_string()));
+        let not = |operand| Expr::Unary {
+            op: UnaryOp::Not,
+            operand: Box::new(operand),
+        };
+        let a_equals_b = Expr::Binary {
+            left: name("a"),
+            op: BinaryOp::Equals,
+            right: name("b"),
+        };
+
+        assert_eq!(expr("not a equals b"), not(a_equals_b.clone()));
+        assert_eq!(expr("not (a equals b)"), not(a_equals_b));
+        assert_eq!(
+            expr("a and not b"),
+            Expr::Binary {
+                left: name("a"),
+                op: BinaryOp::And,
+                right: Box::new(not(Expr::Identifier("b".to_string()))),
+            }
+        );
+        assert_eq!(
+            expr("a and not b equals c"),
+            expr("a and (not (b equals c))")
+        );
+        assert_eq!(expr("not a or b"), expr("(not a) or b"));
+        // Further into an operand `not` is a plain unary operator
+        assert_eq!(expr("a equals not b"), expr("a equals (not b)"));
+        assert_eq!(
+            expr("a not equals b"),
+            Expr::Binary {
+                left: name("a"),
+                op: BinaryOp::NotEquals,
+                right: name("b"),
+            }
+        );
+    }
+
+    fn parse_error(source: &str) -> String {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        Parser::new(tokens).parse().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_missing_brace_names_the_opening_line() {
+        let err = parse_error(
+            "function main() {\n    if 1 equals 1 {\n        print 1\n    }\n    return 0\n",
+        );
+        assert_eq!(err, "unclosed '{' opened at line 1, column 17");
+
+        // The innermost unclosed brace is the one reported
+        let err = parse_error("function main() {\n    while true {\n        print 1\n");
+        assert_eq!(err, "unclosed '{' opened at line 2, column 16");
+    }
+
+    #[test]
+    fn test_missing_paren_names_the_opening_line() {
+        let err = parse_error("function main() {\n    print (1 plus\n");
+        assert_eq!(err, "unclosed '(' opened at line 2, column 11");
+
+        let err = parse_error("function main() {\n    print max(1, 2\n");
+        assert_eq!(err, "unclosed '(' opened at line 2, column 14");
+    }
+
+    #[test]
+    fn test_loop_labels() {
+        let body = parse_main_body(
+            "while true as outer {\n    while true {\n        break outer\n    }\n    continue\n}",
+        );
+        let StmtKind::While { body, label, .. } = &body[0].kind else {
+            panic!("expected while, got {:?}", body[0]);
+        };
+        assert_eq!(label.as_deref(), Some("outer"));
+        let StmtKind::While {
+            body: inner, label, ..
+        } = &body[0].kind
+        else {
+            panic!("expected while, got {:?}", body[0]);
+        };
+        assert_eq!(*label, None);
+        assert_eq!(inner[0].kind, StmtKind::Break(Some("outer".to_string())));
+        assert_eq!(body[1].kind, StmtKind::Continue(None));
+
+        // A label is only in scope inside its own loop
+        let err = parse_error(
+            "function main() {\n    while true as a {\n    }\n    while true {\n        break a\n    }\n}",
+        );
+        assert_eq!(err, "No enclosing loop is labeled 'a' at line 5");
+    }
+}
@@ -3,63 +3,193 @@
 /// This module performs post-compilation bytecode optimization by examining
 /// small windows of instructions and replacing them with more efficient sequences.
 use crate::bytecode::*;
+use std::collections::HashSet;
 
 /// Optimize a chunk of bytecode with peephole optimizations
 pub fn optimize_chunk(chunk: &mut Chunk) {
-    optimize_instructions(&mut chunk.code);
+    optimize_code(chunk);
 
     // Optimize all function chunks recursively
     for (_name, func_chunk) in chunk.functions.iter_mut() {
-        optimize_instructions(&mut func_chunk.code);
+        optimize_code(func_chunk);
+    }
+}
+
+/// Run every pass over one chunk's own code
+fn optimize_code(chunk: &mut Chunk) {
+    optimize_loops(&mut chunk.code, &chunk.constants, chunk.arity);
+    optimize_instructions(&mut chunk.code, &chunk.constants, chunk.arity);
+    remove_nops(chunk);
+}
+
+/// Every instruction index some jump lands on
+fn jump_targets(code: &[Instruction]) -> HashSet<usize> {
+    code.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Jump(target)
+            | Instruction::JumpIfFalse(target)
+            | Instruction::JumpIfTrue(target) => Some(*target),
+            _ => None,
+        })
+        .collect()
+}
+
+fn jump_target_mut(instruction: &mut Instruction) -> Option<&mut usize> {
+    match instruction {
+        Instruction::Jump(target)
+        | Instruction::JumpIfFalse(target)
+        | Instruction::JumpIfTrue(target) => Some(target),
+        _ => None,
+    }
+}
+
+/// Whether `instruction` is known to push a number: a numeric constant or
+/// one of the `numeric` local slots. Globals may be assigned anything by
+/// code compiled later, so they never qualify.
+fn loads_number(
+    instruction: &Instruction,
+    constants: &[Constant],
+    numeric: &HashSet<usize>,
+) -> bool {
+    match instruction {
+        Instruction::LoadConst(idx) => matches!(
+            constants.get(*idx),
+            Some(Constant::Number(_) | Constant::Integer(_))
+        ),
+        Instruction::LoadVar(slot) => numeric.contains(slot),
+        _ => false,
+    }
+}
+
+/// Local slots that only ever hold numbers, because every `StoreVar` to
+/// them stores one. Starts from every slot stored to and drops those with a
+/// store that might not be a number, until none is dropped, so a counter
+/// like `i is i plus 1` stays numeric as long as `i` starts out a number.
+/// Parameters may be passed anything, so they never qualify.
+fn numeric_locals(
+    code: &[Instruction],
+    constants: &[Constant],
+    arity: usize,
+    targets: &HashSet<usize>,
+) -> HashSet<usize> {
+    let mut numeric: HashSet<usize> = code
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::StoreVar(slot) if *slot >= arity => Some(*slot),
+            _ => None,
+        })
+        .collect();
+
+    loop {
+        let dropped: Vec<usize> = code
+            .iter()
+            .enumerate()
+            .filter_map(|(at, instruction)| match instruction {
+                Instruction::StoreVar(slot)
+                    if numeric.contains(slot)
+                        && !stores_number(code, at, constants, &numeric, targets) =>
+                {
+                    Some(*slot)
+                }
+                _ => None,
+            })
+            .collect();
+        if dropped.is_empty() {
+            return numeric;
+        }
+        for slot in dropped {
+            numeric.remove(&slot);
+        }
+    }
+}
+
+/// Whether the value the `StoreVar` at `store` takes off the stack is known
+/// to be a number, given the `numeric` local slots
+fn stores_number(
+    code: &[Instruction],
+    store: usize,
+    constants: &[Constant],
+    numeric: &HashSet<usize>,
+    targets: &HashSet<usize>,
+) -> bool {
+    // Whatever a jump brings to an instruction isn't visible from here
+    let straight = |from: usize| (from + 1..=store).all(|at| !targets.contains(&at));
+    let Some(producer) = store.checked_sub(1) else {
+        return false;
+    };
+    match &code[producer] {
+        // Each of these either pushes a number or stops with an error
+        Instruction::Subtract
+        | Instruction::Multiply
+        | Instruction::Divide
+        | Instruction::IntDivide
+        | Instruction::Modulo
+        | Instruction::Power
+        | Instruction::Negate
+        | Instruction::Bitwise(_)
+        | Instruction::IncrementInt
+        | Instruction::Length => straight(producer),
+        // `plus` also joins strings, so only two number operands qualify
+        Instruction::Add | Instruction::AddInt => {
+            producer >= 2
+                && straight(producer - 2)
+                && loads_number(&code[producer - 2], constants, numeric)
+                && loads_number(&code[producer - 1], constants, numeric)
+        }
+        load => straight(producer) && loads_number(load, constants, numeric),
     }
 }
 
 /// Perform peephole optimizations on instruction sequence
-fn optimize_instructions(code: &mut Vec<Instruction>) {
+fn optimize_instructions(code: &mut [Instruction], constants: &[Constant], arity: usize) {
+    let targets = jump_targets(code);
+    let numeric = numeric_locals(code, constants, arity, &targets);
     let mut i = 0;
 
     while i < code.len() {
-        // Arithmetic operations - use specialized integer instructions
-        // These patterns work for any Load* + Load* + arithmetic op
-
-        if i + 2 < code.len() {
-            let is_load_1 = matches!(
-                &code[i],
-                Instruction::LoadConst(_) | Instruction::LoadVar(_) | Instruction::LoadGlobal(_)
-            );
-            let is_load_2 = matches!(
-                &code[i + 1],
-                Instruction::LoadConst(_) | Instruction::LoadVar(_) | Instruction::LoadGlobal(_)
-            );
+        // Arithmetic operations and comparisons, such as a loop's
+        // `i less than n` - use specialized integer instructions, but only
+        // when both operands are known numbers (`plus` also joins strings)
 
-            if is_load_1 && is_load_2 {
-                // Replace general arithmetic with fast integer arithmetic
-                match &code[i + 2] {
-                    Instruction::Add => {
-                        code[i + 2] = Instruction::AddInt;
-                    }
-                    Instruction::Subtract => {
-                        code[i + 2] = Instruction::SubInt;
-                    }
-                    Instruction::Multiply => {
-                        code[i + 2] = Instruction::MulInt;
-                    }
-                    Instruction::Less => {
-                        code[i + 2] = Instruction::LessInt;
-                    }
-                    _ => {}
+        if i + 2 < code.len()
+            && loads_number(&code[i], constants, &numeric)
+            && loads_number(&code[i + 1], constants, &numeric)
+        {
+            // Replace general arithmetic with fast integer arithmetic
+            match &code[i + 2] {
+                Instruction::Add => {
+                    code[i + 2] = Instruction::AddInt;
+                }
+                Instruction::Subtract => {
+                    code[i + 2] = Instruction::SubInt;
+                }
+                Instruction::Multiply => {
+                    code[i + 2] = Instruction::MulInt;
                 }
+                Instruction::Less => {
+                    code[i + 2] = Instruction::LessInt;
+                }
+                _ => {}
             }
         }
 
-        // Pattern 7: Remove dead code after Return/Halt
+        // Pattern 7: Remove dead code after Return/Exit/Halt. Nothing falls through
+        // them or an unconditional jump, so what follows is unreachable up to
+        // the next instruction some jump lands on.
         if matches!(
             code[i],
-            Instruction::Return | Instruction::ReturnNull | Instruction::Halt
+            Instruction::Return
+                | Instruction::ReturnNull
+                | Instruction::Exit
+                | Instruction::Halt
+                | Instruction::TailCall(_, _)
+                | Instruction::Jump(_)
         ) {
-            // Check if there are unreachable instructions after this
-            // (except for jump targets - which we can't easily detect here)
-            // This is conservative - we skip this optimization for now
+            let mut j = i + 1;
+            while j < code.len() && !targets.contains(&j) {
+                code[j] = Instruction::Nop;
+                j += 1;
+            }
         }
 
         // Pattern 8: Jump to next instruction → Remove
@@ -85,36 +215,92 @@ fn optimize_instructions(code: &mut Vec<Instruction>) {
             }
         }
 
+        // Pattern 11: Not + conditional jump → the opposite jump, which
+        // `while not done` loops produce. Only when nothing jumps straight to
+        // the conditional jump, skipping the Not.
+        if i + 1 < code.len() && code[i] == Instruction::Not && !targets.contains(&(i + 1)) {
+            let flipped = match code[i + 1] {
+                Instruction::JumpIfFalse(target) => Some(Instruction::JumpIfTrue(target)),
+                Instruction::JumpIfTrue(target) => Some(Instruction::JumpIfFalse(target)),
+                _ => None,
+            };
+            if let Some(flipped) = flipped {
+                code[i] = Instruction::Nop;
+                code[i + 1] = flipped;
+            }
+        }
+
         i += 1;
     }
+}
+
+/// Compact the code by dropping Nop instructions, moving jumps that landed on
+/// a removed instruction to the one that followed it
+fn remove_nops(chunk: &mut Chunk) {
+    let keep: Vec<bool> = chunk
+        .code
+        .iter()
+        .map(|instruction| !matches!(instruction, Instruction::Nop))
+        .collect();
 
-    // Remove Nop instructions (compact the code)
-    code.retain(|inst| !matches!(inst, Instruction::Nop));
+    // new_index[i] is where instruction i, or the next one kept after it, ends up
+    let mut new_index = Vec::with_capacity(keep.len() + 1);
+    let mut kept = 0;
+    for &k in &keep {
+        new_index.push(kept);
+        kept += k as usize;
+    }
+    new_index.push(kept);
+
+    for instruction in chunk.code.iter_mut() {
+        if let Some(target) = jump_target_mut(instruction) {
+            *target = new_index[*target];
+        }
+    }
+
+    let mut flags = keep.iter();
+    chunk.code.retain(|_| *flags.next().unwrap());
+    if chunk.lines.len() == keep.len() {
+        let mut flags = keep.iter();
+        chunk.lines.retain(|_| *flags.next().unwrap());
+    }
 }
 
 /// Optimize arithmetic operations in loops
-/// This looks for common loop patterns and optimizes them
-#[allow(dead_code)]
-pub fn optimize_loops(chunk: &mut Chunk) {
-    let code = &mut chunk.code;
+/// This looks for common loop patterns and optimizes them, leaving Nops
+/// behind for `remove_nops`
+fn optimize_loops(code: &mut [Instruction], constants: &[Constant], arity: usize) {
+    let targets = jump_targets(code);
+    let numeric = numeric_locals(code, constants, arity, &targets);
     let mut i = 0;
 
-    while i < code.len() {
-        // Pattern: Loop counter increment
+    while i + 3 < code.len() {
+        // Pattern: Loop counter increment, for a local known to be a number
+        // since `plus 1` on a string appends to it
         // LoadVar(x) + LoadConst(1) + Add + StoreVar(x) => LoadVar(x) + IncrementInt + StoreVar(x)
-        if i + 3 < code.len() {
-            if let (
-                Instruction::LoadVar(var1),
-                Instruction::LoadConst(_const_idx),
-                Instruction::Add,
-                Instruction::StoreVar(var2),
-            ) = (&code[i], &code[i + 1], &code[i + 2], &code[i + 3])
+        if let (
+            Instruction::LoadVar(var1),
+            Instruction::LoadConst(const_idx),
+            Instruction::Add,
+            Instruction::StoreVar(var2),
+        ) = (&code[i], &code[i + 1], &code[i + 2], &code[i + 3])
+        {
+            let adds_one = matches!(
+                constants.get(*const_idx),
+                Some(Constant::Integer(1)) | Some(Constant::Number(1.0))
+            );
+            if var1 == var2
+                && adds_one
+                && numeric.contains(var1)
+                && (i + 1..=i + 3).all(|at| !targets.contains(&at))
             {
-                if var1 == var2 {
-                    // Check if constant is 1
-                    // For now, just use AddInt as it's faster
-                    code[i + 2] = Instruction::AddInt;
-                }
+                // The Nop goes first, so the store still follows its value
+                let var = *var1;
+                code[i] = Instruction::Nop;
+                code[i + 1] = Instruction::LoadVar(var);
+                code[i + 2] = Instruction::IncrementInt;
+                i += 4;
+                continue;
             }
         }
 
@@ -157,4 +343,158 @@ mod tests {
         assert_eq!(chunk.code.len(), 1);
         assert_eq!(chunk.code[0], Instruction::Halt);
     }
+
+    #[test]
+    fn test_variables_keep_generic_arithmetic() {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Constant::String("x".into()));
+
+        // Either could hold a string, so `plus` might be concatenation
+        chunk.emit(Instruction::LoadVar(0), 1);
+        chunk.emit(Instruction::LoadVar(1), 1);
+        chunk.emit(Instruction::Add, 1);
+        chunk.emit(Instruction::LoadConst(0), 1);
+        chunk.emit(Instruction::LoadVar(0), 1);
+        chunk.emit(Instruction::Add, 1);
+
+        optimize_chunk(&mut chunk);
+
+        assert_eq!(chunk.code[2], Instruction::Add);
+        assert_eq!(chunk.code[5], Instruction::Add);
+    }
+
+    #[test]
+    fn test_locals_only_count_as_numbers_when_every_store_is_one() {
+        let mut chunk = Chunk::new();
+        chunk.arity = 1;
+        chunk.add_constant(Constant::Integer(0));
+        chunk.add_constant(Constant::String("x".into()));
+
+        // Slot 1 is a counter; slot 2 is joined by a jump that may bring a
+        // string; slot 0 is a parameter
+        chunk.emit(Instruction::LoadConst(0), 1);
+        chunk.emit(Instruction::StoreVar(1), 1);
+        chunk.emit(Instruction::LoadVar(1), 2);
+        chunk.emit(Instruction::LoadConst(0), 2);
+        chunk.emit(Instruction::Add, 2);
+        chunk.emit(Instruction::StoreVar(1), 2);
+        chunk.emit(Instruction::LoadConst(1), 3);
+        chunk.emit(Instruction::Jump(9), 3);
+        chunk.emit(Instruction::LoadConst(0), 3);
+        chunk.emit(Instruction::StoreVar(2), 3);
+        chunk.emit(Instruction::LoadVar(1), 4);
+        chunk.emit(Instruction::LoadConst(0), 4);
+        chunk.emit(Instruction::Less, 4);
+        chunk.emit(Instruction::LoadVar(2), 5);
+        chunk.emit(Instruction::LoadConst(0), 5);
+        chunk.emit(Instruction::Less, 5);
+        chunk.emit(Instruction::LoadVar(0), 6);
+        chunk.emit(Instruction::LoadConst(0), 6);
+        chunk.emit(Instruction::Less, 6);
+
+        let targets = HashSet::from([9]);
+        let numeric = numeric_locals(&chunk.code, &chunk.constants, chunk.arity, &targets);
+        assert_eq!(numeric, HashSet::from([1]));
+
+        optimize_chunk(&mut chunk);
+        let comparisons: Vec<_> = chunk
+            .code
+            .iter()
+            .filter(|i| matches!(i, Instruction::Less | Instruction::LessInt))
+            .collect();
+        assert_eq!(
+            comparisons,
+            [
+                &Instruction::LessInt,
+                &Instruction::Less,
+                &Instruction::Less
+            ]
+        );
+    }
+
+    #[test]
+    fn test_counter_increment_becomes_increment_int() {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Constant::Integer(0));
+        chunk.add_constant(Constant::Integer(1));
+        chunk.add_constant(Constant::String("x".into()));
+
+        // Slot 0 counts up from 0; slot 1 holds a string, which `plus 1`
+        // appends to
+        chunk.emit(Instruction::LoadConst(0), 1);
+        chunk.emit(Instruction::StoreVar(0), 1);
+        chunk.emit(Instruction::LoadConst(2), 2);
+        chunk.emit(Instruction::StoreVar(1), 2);
+        for slot in [0, 1] {
+            chunk.emit(Instruction::LoadVar(slot), 3);
+            chunk.emit(Instruction::LoadConst(1), 3);
+            chunk.emit(Instruction::Add, 3);
+            chunk.emit(Instruction::StoreVar(slot), 3);
+        }
+
+        optimize_chunk(&mut chunk);
+
+        assert_eq!(
+            chunk.code[4..],
+            [
+                Instruction::LoadVar(0),
+                Instruction::IncrementInt,
+                Instruction::StoreVar(0),
+                Instruction::LoadVar(1),
+                Instruction::LoadConst(1),
+                Instruction::Add,
+                Instruction::StoreVar(1),
+            ]
+        );
+        assert_eq!(chunk.lines.len(), chunk.code.len());
+    }
+
+    #[test]
+    fn test_dead_code_after_return_keeps_jump_targets() {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Constant::Number(1.0));
+
+        chunk.emit(Instruction::JumpIfFalse(4), 1);
+        chunk.emit(Instruction::LoadConst(0), 2);
+        chunk.emit(Instruction::Return, 2);
+        chunk.emit(Instruction::Print, 3); // unreachable
+        chunk.emit(Instruction::LoadConst(0), 4); // the JumpIfFalse lands here
+        chunk.emit(Instruction::Return, 4);
+
+        optimize_chunk(&mut chunk);
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                Instruction::JumpIfFalse(3),
+                Instruction::LoadConst(0),
+                Instruction::Return,
+                Instruction::LoadConst(0),
+                Instruction::Return,
+            ]
+        );
+        assert_eq!(chunk.lines, vec![1, 2, 2, 4, 4]);
+    }
+
+    #[test]
+    fn test_not_before_conditional_jump_flips_it() {
+        let mut chunk = Chunk::new();
+        chunk.emit(Instruction::LoadVar(0), 1);
+        chunk.emit(Instruction::Not, 1);
+        chunk.emit(Instruction::JumpIfFalse(4), 1);
+        chunk.emit(Instruction::Jump(0), 2);
+        chunk.emit(Instruction::ReturnNull, 3);
+
+        optimize_chunk(&mut chunk);
+
+        assert_eq!(
+            chunk.code,
+            vec![
+                Instruction::LoadVar(0),
+                Instruction::JumpIfTrue(3),
+                Instruction::Jump(0),
+                Instruction::ReturnNull,
+            ]
+        );
+    }
 }
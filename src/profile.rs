@@ -0,0 +1,71 @@
+/// Execution profiling for the stack VMs
+///
+/// With profiling on, a VM records every instruction it executes here along
+/// with the stack depth at that point. With it off the VMs skip the
+/// recording, so the hot loop only pays for one `Option` check.
+use crate::bytecode::Instruction;
+
+pub struct Profile {
+    counts: [u64; Instruction::COUNT],
+    max_stack_depth: usize,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            counts: [0; Instruction::COUNT],
+            max_stack_depth: 0,
+        }
+    }
+
+    /// Count one execution of `instruction`, with `stack_depth` values on the stack
+    #[inline(always)]
+    pub fn record(&mut self, instruction: &Instruction, stack_depth: usize) {
+        self.counts[instruction.opcode() as usize] += 1;
+        self.max_stack_depth = self.max_stack_depth.max(stack_depth);
+    }
+
+    /// One line per instruction kind that ran, most frequent first, then totals
+    pub fn report(&self) -> String {
+        let total: u64 = self.counts.iter().sum();
+        let mut executed: Vec<(&str, u64)> = Instruction::NAMES
+            .iter()
+            .zip(self.counts)
+            .filter(|(_, count)| *count > 0)
+            .map(|(name, count)| (*name, count))
+            .collect();
+        executed.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+        let mut report = String::new();
+        for (name, count) in executed {
+            report.push_str(&format!(
+                "{}: {} ({:.1}%)\n",
+                name,
+                thousands(count),
+                count as f64 * 100.0 / total as f64
+            ));
+        }
+        report.push_str(&format!("Instructions executed: {}\n", thousands(total)));
+        report.push_str(&format!("Max stack depth: {}\n", self.max_stack_depth));
+        report
+    }
+}
+
+/// `1203441` as `1,203,441`
+fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
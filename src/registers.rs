@@ -0,0 +1,646 @@
+/// Translation from stack bytecode to register bytecode
+///
+/// Every stack slot of a call frame maps to a fixed register: locals keep
+/// their slot, and the temporary at stack depth `d` becomes register
+/// `local_count + d`. Stack shuffling then shows up as moves between
+/// registers, which a clean-up pass folds into the instructions around them,
+/// so `sum is sum plus i` becomes a single `AddR(sum, sum, i)`.
+use crate::bytecode::*;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// Translate a chunk and its functions to register bytecode
+pub fn translate(chunk: &Chunk) -> Result<RegisterChunk> {
+    let mut functions = HashMap::with_capacity(chunk.functions.len());
+    for (name, function) in &chunk.functions {
+        let translated =
+            translate(function).map_err(|err| err.context(format!("in function '{}'", name)))?;
+        functions.insert(name.clone(), translated);
+    }
+
+    let depths = stack_depths(chunk)?;
+    let r = |depth: usize| chunk.local_count + depth;
+
+    let mut code = Vec::with_capacity(chunk.code.len());
+    for (instruction, depth) in chunk.code.iter().zip(&depths) {
+        // Unreachable instructions are dropped
+        let d = match depth {
+            Some(d) => *d,
+            None => {
+                code.push(None);
+                continue;
+            }
+        };
+
+        let unary = |op: fn(Reg, Reg) -> RegInstruction| Some(op(r(d - 1), r(d - 1)));
+        let binary =
+            |op: fn(Reg, Reg, Reg) -> RegInstruction| Some(op(r(d - 2), r(d - 2), r(d - 1)));
+        let ternary = |op: fn(Reg, Reg, Reg, Reg) -> RegInstruction| {
+            Some(op(r(d - 3), r(d - 3), r(d - 2), r(d - 1)))
+        };
+
+        use RegInstruction::*;
+        code.push(match instruction {
+            Instruction::LoadConst(idx) => Some(LoadConstR(r(d), *idx)),
+            Instruction::LoadVar(slot) => Some(MoveR(r(d), *slot)),
+            Instruction::StoreVar(slot) => Some(MoveR(*slot, r(d - 1))),
+            Instruction::LoadGlobal(name) => Some(LoadGlobalR(r(d), name.clone())),
+            Instruction::StoreGlobal(name) => Some(StoreGlobalR(name.clone(), r(d - 1))),
+            Instruction::Add | Instruction::AddInt => binary(AddR),
+            Instruction::Subtract | Instruction::SubInt => binary(SubR),
+            Instruction::Multiply | Instruction::MulInt => binary(MulR),
+            Instruction::Divide => binary(DivR),
+            Instruction::IntDivide => binary(IntDivR),
+            Instruction::Modulo => binary(ModR),
+            Instruction::Power => binary(PowR),
+            Instruction::Bitwise(op) => Some(BitwiseR(*op, r(d - 2), r(d - 2), r(d - 1))),
+            Instruction::Negate => unary(NegR),
+            Instruction::IncrementInt => unary(IncR),
+            Instruction::Equal => binary(EqR),
+            Instruction::NotEqual => binary(NeR),
+            Instruction::Greater => binary(GtR),
+            Instruction::GreaterEqual => binary(GeR),
+            Instruction::Less | Instruction::LessInt => binary(LtR),
+            Instruction::LessEqual => binary(LeR),
+            Instruction::And => binary(AndR),
+            Instruction::Or => binary(OrR),
+            Instruction::Not => unary(NotR),
+            Instruction::Jump(target) => Some(JumpR(*target)),
+            Instruction::JumpIfFalse(target) => Some(JumpIfFalseR(r(d - 1), *target)),
+            Instruction::JumpIfTrue(target) => Some(JumpIfTrueR(r(d - 1), *target)),
+            Instruction::Call(name, arity) => Some(CallR(name.clone(), r(d - arity), *arity)),
+            Instruction::CallBuiltin(builtin, arity) => {
+                Some(CallBuiltinR(*builtin, r(d - arity), r(d - arity), *arity))
+            }
+            Instruction::TailCall(name, arity) => {
+                Some(TailCallR(name.clone(), r(d - arity), *arity))
+            }
+            Instruction::CallIndex(id, arity) => Some(CallIndexR(*id, r(d - arity), *arity)),
+            Instruction::TailCallIndex(id, arity) => {
+                Some(TailCallIndexR(*id, r(d - arity), *arity))
+            }
+            Instruction::Return => Some(ReturnR(r(d - 1))),
+            Instruction::ReturnNull => Some(ReturnNullR),
+            Instruction::Exit => Some(ExitR(r(d - 1))),
+            Instruction::Pop | Instruction::Nop => None,
+            Instruction::Dup => Some(MoveR(r(d), r(d - 1))),
+            Instruction::MakeArray(size) => Some(MakeArrayR(r(d - size), r(d - size), *size)),
+            Instruction::MakeMap(size) => Some(MakeMapR(r(d - 2 * size), r(d - 2 * size), *size)),
+            Instruction::GetIndex => binary(GetIndexR),
+            Instruction::SetIndex => ternary(SetIndexR),
+            Instruction::MapGet => binary(MapGetR),
+            Instruction::MapSet => ternary(MapSetR),
+            Instruction::ArrayPush => binary(ArrayPushR),
+            Instruction::ArrayPop => Some(ArrayPopR(r(d - 1), r(d - 1))),
+            Instruction::Length => unary(LengthR),
+            Instruction::Uppercase => unary(UppercaseR),
+            Instruction::Lowercase => unary(LowercaseR),
+            Instruction::Substring => ternary(SubstringR),
+            Instruction::Contains => binary(ContainsR),
+            Instruction::IndexOf => binary(IndexOfR),
+            Instruction::Replace => ternary(ReplaceR),
+            Instruction::Trim => unary(TrimR),
+            Instruction::Slice => ternary(SliceR),
+            Instruction::Reverse => unary(ReverseR),
+            Instruction::Sort => unary(SortR),
+            Instruction::Join => binary(JoinR),
+            Instruction::TypeOf => unary(TypeOfR),
+            Instruction::Print => Some(PrintR(r(d - 1))),
+            Instruction::Write => Some(WriteR(r(d - 1))),
+            Instruction::Input(prompt) => Some(InputR(r(d), prompt.clone())),
+            Instruction::InputText(prompt) => Some(InputTextR(r(d), prompt.clone())),
+            Instruction::Assert(message) => Some(AssertR(r(d - 1), message.clone())),
+            Instruction::ExpectArray => Some(ExpectArrayR(r(d - 1))),
+            // main's return value is left on the stack as the exit code
+            Instruction::Halt => Some(HaltR(d.checked_sub(1).map(r))),
+        });
+    }
+
+    let register_count = chunk.local_count + max_stack_depth(chunk)?;
+    simplify(&mut code, chunk.local_count, register_count);
+
+    // Drop the deleted instructions, pointing jumps at whatever follows them
+    let mut new_index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for instruction in &code {
+        new_index.push(kept);
+        if instruction.is_some() {
+            kept += 1;
+        }
+    }
+
+    let mut register_code = Vec::with_capacity(kept);
+    let mut lines = Vec::with_capacity(kept);
+    for (i, instruction) in code.into_iter().enumerate() {
+        if let Some(mut instruction) = instruction {
+            if let Some(target) = jump_target_mut(&mut instruction) {
+                *target = new_index.get(*target).copied().unwrap_or(kept);
+            }
+            register_code.push(instruction);
+            lines.push(chunk.lines[i]);
+        }
+    }
+
+    Ok(RegisterChunk {
+        code: register_code,
+        constants: chunk.constants.clone(),
+        functions,
+        lines,
+        register_count,
+        arity: chunk.arity,
+    })
+}
+
+/// Stack depth before each instruction, or `None` where it is unreachable
+pub fn stack_depths(chunk: &Chunk) -> Result<Vec<Option<usize>>> {
+    let mut depths = vec![None; chunk.code.len()];
+    let mut pending: Vec<(usize, usize)> = vec![(0, 0)];
+
+    while let Some((ip, depth)) = pending.pop() {
+        // Running off the end is left for the VM to report
+        if ip >= chunk.code.len() {
+            continue;
+        }
+        match depths[ip] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(anyhow!(
+                    "Instruction {} is reached with stack depths {} and {}",
+                    ip,
+                    known,
+                    depth
+                ))
+            }
+            None => depths[ip] = Some(depth),
+        }
+
+        let instruction = &chunk.code[ip];
+        let (pops, pushes) = stack_effect(instruction);
+        let after = depth
+            .checked_sub(pops)
+            .ok_or_else(|| anyhow!("Stack underflow at instruction {}", ip))?
+            + pushes;
+
+        match instruction {
+            Instruction::Jump(target) => pending.push((*target, after)),
+            Instruction::JumpIfFalse(target) | Instruction::JumpIfTrue(target) => {
+                pending.push((*target, after));
+                pending.push((ip + 1, after));
+            }
+            Instruction::Return
+            | Instruction::ReturnNull
+            | Instruction::Exit
+            | Instruction::TailCall(_, _)
+            | Instruction::TailCallIndex(_, _)
+            | Instruction::Halt => {}
+            _ => pending.push((ip + 1, after)),
+        }
+    }
+
+    Ok(depths)
+}
+
+/// Deepest the value stack gets in a call frame, above its locals
+pub fn max_stack_depth(chunk: &Chunk) -> Result<usize> {
+    let depths = stack_depths(chunk)?;
+    Ok(chunk
+        .code
+        .iter()
+        .zip(&depths)
+        .filter_map(|(instruction, depth)| {
+            let depth = (*depth)?;
+            let (pops, pushes) = stack_effect(instruction);
+            Some(depth.max(depth - pops + pushes))
+        })
+        .max()
+        .unwrap_or(0))
+}
+
+/// Number of values an instruction pops and pushes
+fn stack_effect(instruction: &Instruction) -> (usize, usize) {
+    use Instruction::*;
+
+    match instruction {
+        LoadConst(_) | LoadVar(_) | LoadGlobal(_) | Input(_) | InputText(_) => (0, 1),
+        StoreVar(_) | Pop | Print | Write | Assert(_) | JumpIfFalse(_) | JumpIfTrue(_) | Return
+        | Exit => (1, 0),
+        StoreGlobal(_) | Negate | IncrementInt | Not | Length | Uppercase | Lowercase | Trim
+        | Reverse | Sort | TypeOf => (1, 1),
+        Add | Subtract | Multiply | Divide | IntDivide | Modulo | Power | Bitwise(_) | AddInt
+        | SubInt | MulInt | LessInt | Equal | NotEqual | Greater | GreaterEqual | Less
+        | LessEqual | And | Or | GetIndex | MapGet | ArrayPush | Contains | IndexOf | Join => {
+            (2, 1)
+        }
+        SetIndex | MapSet | Substring | Slice | Replace => (3, 1),
+        Dup | ArrayPop => (1, 2),
+        Call(_, arity) | CallIndex(_, arity) | CallBuiltin(_, arity) | MakeArray(arity) => {
+            (*arity, 1)
+        }
+        MakeMap(size) => (2 * size, 1),
+        TailCall(_, arity) | TailCallIndex(_, arity) => (*arity, 0),
+        Jump(_) | ReturnNull | Halt | Nop | ExpectArray => (0, 0),
+    }
+}
+
+/// Fold the moves left over from stack shuffling into the instructions that
+/// produce and consume the temporaries involved. Deleted instructions become
+/// `None`.
+fn simplify(code: &mut [Option<RegInstruction>], first_temp: Reg, register_count: usize) {
+    let targets = jump_targets(code);
+
+    // Read a temporary's source directly: `t = x; y = t + 1` becomes `y = x + 1`
+    let live_out = liveness(code, register_count);
+    for i in 0..code.len() {
+        let (t, x) = match code[i] {
+            Some(RegInstruction::MoveR(t, x)) if t >= first_temp && t != x => (t, x),
+            _ => continue,
+        };
+        if let Some(j) = sole_reader(code, &targets, &live_out, i, t, x) {
+            if let Some(reader) = code[j].as_mut() {
+                for operand in operands_mut(reader) {
+                    if *operand == t {
+                        *operand = x;
+                    }
+                }
+            }
+            code[i] = None;
+        }
+    }
+
+    // Write results straight to their destination: `t = a + b; x = t` becomes `x = a + b`
+    let live_out = liveness(code, register_count);
+    for i in 0..code.len() {
+        let t = match code[i].as_mut().and_then(dst_mut) {
+            Some(t) if *t >= first_temp => *t,
+            _ => continue,
+        };
+        let j = match (i + 1..code.len()).find(|&j| code[j].is_some()) {
+            Some(j) => j,
+            None => continue,
+        };
+        if targets[i + 1..=j].iter().any(|&target| target) {
+            continue;
+        }
+        if let Some(RegInstruction::MoveR(x, source)) = code[j] {
+            if source == t && x != t && !live_out[j][t] {
+                if let Some(dst) = code[i].as_mut().and_then(dst_mut) {
+                    *dst = x;
+                }
+                code[j] = None;
+            }
+        }
+    }
+}
+
+/// The instruction after `i` that consumes temporary `t`, if `t = x` can be
+/// dropped in favour of reading `x` there
+fn sole_reader(
+    code: &[Option<RegInstruction>],
+    targets: &[bool],
+    live_out: &[Vec<bool>],
+    i: usize,
+    t: Reg,
+    x: Reg,
+) -> Option<usize> {
+    for j in i + 1..code.len() {
+        // Another path joins here, where `t` may hold something else
+        if targets[j] {
+            return None;
+        }
+        let instruction = match &code[j] {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+
+        if reads(instruction).contains(&t) {
+            // Argument windows must stay contiguous, so they can't be renamed
+            let renamable = window(instruction).is_none();
+            let dead = !live_out[j][t] || writes(instruction).contains(&t);
+            return (renamable && dead).then_some(j);
+        }
+        if clobbers(instruction, t) || clobbers(instruction, x) || ends_block(instruction) {
+            return None;
+        }
+    }
+    None
+}
+
+/// Registers live after each instruction
+fn liveness(code: &[Option<RegInstruction>], register_count: usize) -> Vec<Vec<bool>> {
+    // First instruction kept at or after each index
+    let mut next_kept = vec![None; code.len() + 1];
+    for i in (0..code.len()).rev() {
+        next_kept[i] = if code[i].is_some() {
+            Some(i)
+        } else {
+            next_kept[i + 1]
+        };
+    }
+    let kept_from = |i: usize| next_kept.get(i).copied().flatten();
+
+    let successors: Vec<Vec<usize>> = code
+        .iter()
+        .enumerate()
+        .map(|(i, instruction)| {
+            use RegInstruction::*;
+            let next = match instruction {
+                Some(JumpR(target)) => vec![kept_from(*target)],
+                Some(JumpIfFalseR(_, target) | JumpIfTrueR(_, target)) => {
+                    vec![kept_from(*target), kept_from(i + 1)]
+                }
+                Some(
+                    ReturnR(_)
+                    | ReturnNullR
+                    | ExitR(_)
+                    | TailCallR(_, _, _)
+                    | TailCallIndexR(_, _, _)
+                    | HaltR(_),
+                )
+                | None => vec![],
+                Some(_) => vec![kept_from(i + 1)],
+            };
+            next.into_iter().flatten().collect()
+        })
+        .collect();
+    let uses: Vec<Vec<Reg>> = code
+        .iter()
+        .map(|i| i.as_ref().map_or(vec![], reads))
+        .collect();
+    let defs: Vec<Vec<Reg>> = code
+        .iter()
+        .map(|i| i.as_ref().map_or(vec![], writes))
+        .collect();
+
+    let mut live_in = vec![vec![false; register_count]; code.len()];
+    let mut live_out = vec![vec![false; register_count]; code.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..code.len()).rev() {
+            let mut out = vec![false; register_count];
+            for &s in &successors[i] {
+                for (reg, live) in live_in[s].iter().enumerate() {
+                    out[reg] |= live;
+                }
+            }
+
+            let mut in_ = out.clone();
+            for &reg in &defs[i] {
+                in_[reg] = false;
+            }
+            for &reg in &uses[i] {
+                in_[reg] = true;
+            }
+
+            if in_ != live_in[i] || out != live_out[i] {
+                live_in[i] = in_;
+                live_out[i] = out;
+                changed = true;
+            }
+        }
+    }
+    live_out
+}
+
+/// Whether each instruction index is the target of a jump
+fn jump_targets(code: &[Option<RegInstruction>]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for instruction in code.iter().flatten() {
+        if let Some(target) = jump_target_mut(&mut instruction.clone()) {
+            if let Some(slot) = targets.get_mut(*target) {
+                *slot = true;
+            }
+        }
+    }
+    targets
+}
+
+fn jump_target_mut(instruction: &mut RegInstruction) -> Option<&mut usize> {
+    match instruction {
+        RegInstruction::JumpR(target)
+        | RegInstruction::JumpIfFalseR(_, target)
+        | RegInstruction::JumpIfTrueR(_, target) => Some(target),
+        _ => None,
+    }
+}
+
+fn ends_block(instruction: &RegInstruction) -> bool {
+    use RegInstruction::*;
+    matches!(
+        instruction,
+        JumpR(_)
+            | JumpIfFalseR(_, _)
+            | JumpIfTrueR(_, _)
+            | ReturnR(_)
+            | ReturnNullR
+            | ExitR(_)
+            | TailCallR(_, _, _)
+            | TailCallIndexR(_, _, _)
+            | HaltR(_)
+    )
+}
+
+/// Single registers an instruction reads, which may be renamed
+fn operands_mut(instruction: &mut RegInstruction) -> Vec<&mut Reg> {
+    use RegInstruction::*;
+
+    match instruction {
+        MoveR(_, a)
+        | StoreGlobalR(_, a)
+        | NegR(_, a)
+        | IncR(_, a)
+        | NotR(_, a)
+        | LengthR(_, a)
+        | UppercaseR(_, a)
+        | LowercaseR(_, a)
+        | TrimR(_, a)
+        | ReverseR(_, a)
+        | SortR(_, a)
+        | TypeOfR(_, a)
+        | ArrayPopR(_, a)
+        | JumpIfFalseR(a, _)
+        | JumpIfTrueR(a, _)
+        | ReturnR(a)
+        | ExitR(a)
+        | PrintR(a)
+        | WriteR(a)
+        | AssertR(a, _)
+        | ExpectArrayR(a)
+        | HaltR(Some(a)) => vec![a],
+        AddR(_, a, b)
+        | SubR(_, a, b)
+        | MulR(_, a, b)
+        | DivR(_, a, b)
+        | IntDivR(_, a, b)
+        | ModR(_, a, b)
+        | PowR(_, a, b)
+        | BitwiseR(_, _, a, b)
+        | EqR(_, a, b)
+        | NeR(_, a, b)
+        | GtR(_, a, b)
+        | GeR(_, a, b)
+        | LtR(_, a, b)
+        | LeR(_, a, b)
+        | AndR(_, a, b)
+        | OrR(_, a, b)
+        | GetIndexR(_, a, b)
+        | MapGetR(_, a, b)
+        | ArrayPushR(_, a, b)
+        | ContainsR(_, a, b)
+        | IndexOfR(_, a, b)
+        | JoinR(_, a, b) => vec![a, b],
+        SetIndexR(_, a, b, c)
+        | MapSetR(_, a, b, c)
+        | SubstringR(_, a, b, c)
+        | SliceR(_, a, b, c)
+        | ReplaceR(_, a, b, c) => vec![a, b, c],
+        LoadConstR(_, _)
+        | LoadGlobalR(_, _)
+        | JumpR(_)
+        | CallR(_, _, _)
+        | CallIndexR(_, _, _)
+        | CallBuiltinR(_, _, _, _)
+        | TailCallR(_, _, _)
+        | TailCallIndexR(_, _, _)
+        | ReturnNullR
+        | MakeArrayR(_, _, _)
+        | MakeMapR(_, _, _)
+        | InputR(_, _)
+        | InputTextR(_, _)
+        | HaltR(None) => vec![],
+    }
+}
+
+/// Contiguous registers an instruction reads as arguments
+fn window(instruction: &RegInstruction) -> Option<(Reg, usize)> {
+    use RegInstruction::*;
+
+    match instruction {
+        CallR(_, first, count)
+        | CallIndexR(_, first, count)
+        | TailCallR(_, first, count)
+        | TailCallIndexR(_, first, count)
+        | CallBuiltinR(_, _, first, count)
+        | MakeArrayR(_, first, count) => Some((*first, *count)),
+        MakeMapR(_, first, size) => Some((*first, 2 * size)),
+        _ => None,
+    }
+}
+
+fn reads(instruction: &RegInstruction) -> Vec<Reg> {
+    let mut registers: Vec<Reg> = operands_mut(&mut instruction.clone())
+        .into_iter()
+        .map(|reg| *reg)
+        .collect();
+    if let Some((first, count)) = window(instruction) {
+        registers.extend(first..first + count);
+    }
+    registers
+}
+
+/// The destination of an instruction that writes exactly one register
+fn dst_mut(instruction: &mut RegInstruction) -> Option<&mut Reg> {
+    use RegInstruction::*;
+
+    match instruction {
+        LoadConstR(dst, _)
+        | MoveR(dst, _)
+        | LoadGlobalR(dst, _)
+        | AddR(dst, _, _)
+        | SubR(dst, _, _)
+        | MulR(dst, _, _)
+        | DivR(dst, _, _)
+        | IntDivR(dst, _, _)
+        | ModR(dst, _, _)
+        | PowR(dst, _, _)
+        | BitwiseR(_, dst, _, _)
+        | NegR(dst, _)
+        | IncR(dst, _)
+        | EqR(dst, _, _)
+        | NeR(dst, _, _)
+        | GtR(dst, _, _)
+        | GeR(dst, _, _)
+        | LtR(dst, _, _)
+        | LeR(dst, _, _)
+        | AndR(dst, _, _)
+        | OrR(dst, _, _)
+        | NotR(dst, _)
+        | CallBuiltinR(_, dst, _, _)
+        | MakeArrayR(dst, _, _)
+        | MakeMapR(dst, _, _)
+        | GetIndexR(dst, _, _)
+        | SetIndexR(dst, _, _, _)
+        | MapGetR(dst, _, _)
+        | MapSetR(dst, _, _, _)
+        | ArrayPushR(dst, _, _)
+        | LengthR(dst, _)
+        | UppercaseR(dst, _)
+        | LowercaseR(dst, _)
+        | SubstringR(dst, _, _, _)
+        | ContainsR(dst, _, _)
+        | IndexOfR(dst, _, _)
+        | ReplaceR(dst, _, _, _)
+        | TrimR(dst, _)
+        | SliceR(dst, _, _, _)
+        | ReverseR(dst, _)
+        | SortR(dst, _)
+        | JoinR(dst, _, _)
+        | TypeOfR(dst, _)
+        | InputR(dst, _)
+        | InputTextR(dst, _) => Some(dst),
+        _ => None,
+    }
+}
+
+fn writes(instruction: &RegInstruction) -> Vec<Reg> {
+    match instruction {
+        RegInstruction::ArrayPopR(dst, _) => vec![*dst, dst + 1],
+        RegInstruction::CallR(_, base, _) | RegInstruction::CallIndexR(_, base, _) => {
+            vec![*base]
+        }
+        _ => dst_mut(&mut instruction.clone()).map_or(vec![], |dst| vec![*dst]),
+    }
+}
+
+/// Whether an instruction may overwrite `reg`. A call's frame starts at its
+/// first argument, so the callee is free to use everything from there up.
+fn clobbers(instruction: &RegInstruction, reg: Reg) -> bool {
+    match instruction {
+        RegInstruction::CallR(_, base, _) | RegInstruction::CallIndexR(_, base, _) => reg >= *base,
+        _ => writes(instruction).contains(&reg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_moves_fold_into_arithmetic() {
+        let source = r#"
+function main() {
+    var sum is 0
+    var i is 0
+    while i less than 10 {
+        sum is sum plus i
+        i is i plus 1
+    }
+    return sum
+}
+"#;
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let chunk = Compiler::new().compile_to_registers(program).unwrap();
+
+        // sum and i are locals 0 and 1
+        let main = &chunk.functions["main"];
+        assert!(main.code.contains(&RegInstruction::AddR(0, 0, 1)));
+        assert!(!main
+            .code
+            .iter()
+            .any(|instruction| matches!(instruction, RegInstruction::MoveR(_, _))));
+    }
+}
@@ -0,0 +1,155 @@
+//! Interactive read-eval-print loop
+//!
+//! Each input is parsed as a mix of function definitions and bare
+//! statements. Statements run at global scope on one long-lived
+//! `Interpreter`, so variables and functions carry over between inputs.
+
+use crate::interpreter::{Exit, Interpreter, Value};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::token::TokenType;
+use anyhow::{anyhow, Result};
+use std::io::Write;
+
+/// Run the loop until the interpreter's input reaches end of file or an
+/// input calls `exit`. Returns the exit code, 0 at end of file.
+pub fn run(interpreter: &mut Interpreter, out: &mut dyn Write) -> Result<i32> {
+    writeln!(out, "TopLang REPL. Press Ctrl-D to exit.")?;
+
+    while let Some(source) = read_input(interpreter, out)? {
+        if source.trim().is_empty() {
+            continue;
+        }
+
+        if let Err(e) = eval(interpreter, &source, out) {
+            if let Some(Exit(code)) = e.downcast_ref::<Exit>() {
+                return Ok(*code);
+            }
+            writeln!(out, "Error: {}", e)?;
+        }
+    }
+
+    writeln!(out)?;
+    Ok(0)
+}
+
+/// Read one complete input, continuing across lines while braces are open.
+/// Returns `None` at end of file.
+fn read_input(interpreter: &mut Interpreter, out: &mut dyn Write) -> Result<Option<String>> {
+    let mut source = String::new();
+
+    loop {
+        write!(out, "{}", if source.is_empty() { "> " } else { "... " })?;
+        out.flush()?;
+
+        let mut line = String::new();
+        if interpreter.read_line(&mut line)? == 0 {
+            return Ok(if source.is_empty() {
+                None
+            } else {
+                Some(source)
+            });
+        }
+        source.push_str(&line);
+
+        if open_braces(&source) <= 0 {
+            return Ok(Some(source));
+        }
+    }
+}
+
+fn open_braces(source: &str) -> i32 {
+    let mut lexer = Lexer::new(source.to_string());
+    let tokens = lexer.tokenize();
+    // End the input at a bad token so it's reported now, not once the
+    // braces happen to close
+    if !lexer.errors().is_empty() {
+        return 0;
+    }
+    tokens
+        .iter()
+        .map(|token| match token.token_type {
+            TokenType::LeftBrace => 1,
+            TokenType::RightBrace => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn eval(interpreter: &mut Interpreter, source: &str, out: &mut dyn Write) -> Result<()> {
+    let mut lexer = Lexer::new(source.to_string());
+    let tokens = lexer.tokenize();
+    if let Some(error) = lexer.errors().first() {
+        return Err(anyhow!("{}", error.render(source)));
+    }
+    let (functions, statements) = Parser::new(tokens).parse_repl_input()?;
+
+    for func in functions {
+        interpreter.define_function(func);
+    }
+
+    for stmt in &statements {
+        match interpreter.run_global(stmt)? {
+            Some(Value::Null) | None => {}
+            Some(value) => writeln!(out, "{}", value)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn run_session(input: &str) -> String {
+        let mut interpreter = Interpreter::with_input(Box::new(Cursor::new(input.to_string())));
+        let mut out = Vec::new();
+        run(&mut interpreter, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_state_survives_between_inputs() {
+        let output = run_session(
+            "var x is 4\nfunction double(n) {\n    return n times 2\n}\ndouble(x) plus 1\n",
+        );
+        assert!(output.contains("... "), "missing continuation prompt");
+        assert!(output.contains("> 9\n"));
+    }
+
+    #[test]
+    fn test_errors_do_not_end_session() {
+        let output = run_session("var is\nmissing\n1 plus 1\n");
+        assert!(output.contains("Error: "));
+        assert!(output.contains("Undefined variable: missing"));
+        assert!(output.contains("> 2\n"));
+    }
+
+    #[test]
+    fn test_bad_characters_are_reported_by_the_lexer() {
+        let output = run_session("var price is $5\nif true {\n    print ~\n1 plus 1\n");
+        assert!(
+            output.contains("Error: Unexpected character '$' at line 1, column 14"),
+            "{}",
+            output
+        );
+        assert!(output.contains("var price is $5\n"), "{}", output);
+        assert!(
+            output.contains("Unexpected character '~' at line 2"),
+            "{}",
+            output
+        );
+        assert!(output.contains("> 2\n"), "{}", output);
+    }
+
+    #[test]
+    fn test_exit_ends_session() {
+        let input = "print 1\nexit(4)\nprint 2\n";
+        let mut interpreter = Interpreter::with_input(Box::new(Cursor::new(input.to_string())));
+        let mut out = Vec::new();
+        assert_eq!(run(&mut interpreter, &mut out).unwrap(), 4);
+        assert!(!String::from_utf8(out).unwrap().contains("2\n"));
+    }
+}
@@ -2,6 +2,9 @@
 ///
 /// This module provides the runtime support functions that compiled
 /// TopLang programs need (print, input, array operations, etc.)
+use crate::builtins::{array_index, Numeric};
+use crate::error::RuntimeError;
+use crate::output::{print_array, render, Formatter, Plain, Printable};
 use std::io::{self, Write};
 
 /// Value type for runtime (NaN-boxed for performance)
@@ -103,54 +106,129 @@ impl Value {
     }
 }
 
+/// What a heap pointer points to. Every heap object starts with one, so
+/// the runtime can tell strings from arrays before reading the rest.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+enum HeapKind {
+    String,
+    Array,
+}
+
 // Runtime heap-allocated string type
 #[repr(C)]
 pub struct RuntimeString {
+    kind: HeapKind,
     data: *mut u8,
     len: usize,
     capacity: usize,
 }
 
+// Runtime array type
+#[repr(C)]
+pub struct RuntimeArray {
+    kind: HeapKind,
+    data: *mut Value,
+    len: usize,
+    capacity: usize,
+}
+
+/// The kind of heap object `val` points to, if it is a pointer
+fn heap_kind(val: Value) -> Option<HeapKind> {
+    // SAFETY: every pointer value comes from `string_value` or
+    // `toplang_array_new`, whose objects start with their kind
+    val.is_ptr()
+        .then(|| unsafe { *(val.as_ptr() as *const HeapKind) })
+}
+
+/// The text of a string value
+fn as_str<'a>(val: Value) -> Option<&'a str> {
+    if heap_kind(val) != Some(HeapKind::String) {
+        return None;
+    }
+    // SAFETY: the kind says this is a `RuntimeString` holding UTF-8
+    unsafe {
+        let s = &*(val.as_ptr() as *const RuntimeString);
+        let slice = std::slice::from_raw_parts(s.data, s.len);
+        Some(std::str::from_utf8_unchecked(slice))
+    }
+}
+
+/// The elements of an array value
+fn as_array<'a>(val: Value) -> Option<&'a mut [Value]> {
+    if heap_kind(val) != Some(HeapKind::Array) {
+        return None;
+    }
+    // SAFETY: the kind says this is a `RuntimeArray`
+    unsafe {
+        let arr = &*(val.as_ptr() as *const RuntimeArray);
+        Some(std::slice::from_raw_parts_mut(arr.data, arr.len))
+    }
+}
+
+/// Move `buffer` onto the heap as a string value
+fn string_value(mut buffer: Vec<u8>) -> Value {
+    let len = buffer.len();
+    let capacity = buffer.capacity();
+    let data = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+
+    let runtime_str = Box::new(RuntimeString {
+        kind: HeapKind::String,
+        data,
+        len,
+        capacity,
+    });
+
+    Value::ptr(Box::into_raw(runtime_str) as *mut u8)
+}
+
+/// Stop the program with a runtime error, as the C backend's runtime does
+fn fail(error: impl std::fmt::Display) -> ! {
+    let _ = io::stdout().flush();
+    eprintln!("Error: {}", error);
+    std::process::exit(1)
+}
+
+/// A value that isn't the `expected` type
+fn type_mismatch(expected: &'static str, got: Value) -> RuntimeError {
+    RuntimeError::TypeMismatch {
+        expected,
+        got: render(&got, &Plain),
+    }
+}
+
+impl Printable for Value {
+    fn print_to(&self, formatter: &dyn Formatter, out: &mut String, nested: bool) {
+        if self.is_number() {
+            formatter.number(out, Numeric::Float(self.as_number()));
+        } else if self.is_boolean() {
+            formatter.boolean(out, self.as_boolean());
+        } else if let Some(s) = as_str(*self) {
+            formatter.string(out, s, nested);
+        } else if let Some(items) = as_array(*self) {
+            print_array(items, formatter, out);
+        } else {
+            formatter.null(out);
+        }
+    }
+}
+
 // Runtime functions exported for native code
 
 /// Print a value to stdout
 #[no_mangle]
 pub extern "C" fn toplang_print(val: Value) {
-    if val.is_number() {
-        let n = val.as_number();
-        if n.fract() == 0.0 {
-            println!("{}", n as i64);
-        } else {
-            println!("{}", n);
-        }
-    } else if val.is_boolean() {
-        println!("{}", val.as_boolean());
-    } else if val.is_null() {
-        println!("null");
-    } else if val.is_ptr() {
-        unsafe {
-            let str_ptr = val.as_ptr() as *const RuntimeString;
-            let s = &*str_ptr;
-            let slice = std::slice::from_raw_parts(s.data, s.len);
-            let string = std::str::from_utf8_unchecked(slice);
-            println!("{}", string);
-        }
-    }
+    println!("{}", render(&val, &Plain));
 }
 
 /// Read input from stdin
 #[no_mangle]
 pub extern "C" fn toplang_input(prompt: Value) -> Value {
     // Print prompt if provided
-    if !prompt.is_null() && prompt.is_ptr() {
-        unsafe {
-            let str_ptr = prompt.as_ptr() as *const RuntimeString;
-            let s = &*str_ptr;
-            let slice = std::slice::from_raw_parts(s.data, s.len);
-            let string = std::str::from_utf8_unchecked(slice);
-            print!("{}", string);
-            io::stdout().flush().unwrap();
-        }
+    if !prompt.is_null() {
+        print!("{}", render(&prompt, &Plain));
+        io::stdout().flush().unwrap();
     }
 
     // Read line
@@ -165,19 +243,7 @@ pub extern "C" fn toplang_input(prompt: Value) -> Value {
         }
     }
 
-    // Allocate runtime string
-    let len = buffer.len();
-    let capacity = buffer.capacity();
-    let data = buffer.as_mut_ptr();
-    std::mem::forget(buffer);
-
-    let runtime_str = Box::new(RuntimeString {
-        data,
-        len,
-        capacity,
-    });
-
-    Value::ptr(Box::into_raw(runtime_str) as *mut u8)
+    string_value(buffer.into_bytes())
 }
 
 /// Create a runtime string from a Rust string (for constants)
@@ -187,56 +253,25 @@ pub extern "C" fn toplang_input(prompt: Value) -> Value {
 /// of length `len` that will remain valid for the duration of this call.
 #[no_mangle]
 pub unsafe extern "C" fn toplang_string_new(data: *const u8, len: usize) -> Value {
-    let slice = std::slice::from_raw_parts(data, len);
-    let mut buffer = slice.to_vec();
-
-    let len = buffer.len();
-    let capacity = buffer.capacity();
-    let data = buffer.as_mut_ptr();
-    std::mem::forget(buffer);
-
-    let runtime_str = Box::new(RuntimeString {
-        data,
-        len,
-        capacity,
-    });
-
-    Value::ptr(Box::into_raw(runtime_str) as *mut u8)
+    string_value(std::slice::from_raw_parts(data, len).to_vec())
 }
 
-/// Add two values
+/// Add two numbers, or join a string with a string or number
 #[no_mangle]
 pub extern "C" fn toplang_add(a: Value, b: Value) -> Value {
     if a.is_number() && b.is_number() {
         Value::number(a.as_number() + b.as_number())
-    } else if a.is_ptr() && b.is_ptr() {
-        // String concatenation
-        unsafe {
-            let str_a = &*(a.as_ptr() as *const RuntimeString);
-            let str_b = &*(b.as_ptr() as *const RuntimeString);
-
-            let slice_a = std::slice::from_raw_parts(str_a.data, str_a.len);
-            let slice_b = std::slice::from_raw_parts(str_b.data, str_b.len);
-
-            let mut result = Vec::with_capacity(str_a.len + str_b.len);
-            result.extend_from_slice(slice_a);
-            result.extend_from_slice(slice_b);
-
-            let len = result.len();
-            let capacity = result.capacity();
-            let data = result.as_mut_ptr();
-            std::mem::forget(result);
-
-            let runtime_str = Box::new(RuntimeString {
-                data,
-                len,
-                capacity,
-            });
-
-            Value::ptr(Box::into_raw(runtime_str) as *mut u8)
-        }
+    } else if (as_str(a).is_some() && (b.is_number() || as_str(b).is_some()))
+        || (a.is_number() && as_str(b).is_some())
+    {
+        let joined = render(&a, &Plain) + &render(&b, &Plain);
+        string_value(joined.into_bytes())
     } else {
-        Value::null()
+        fail(format!(
+            "Cannot add {} and {}",
+            render(&a, &Plain),
+            render(&b, &Plain)
+        ))
     }
 }
 
@@ -273,14 +308,23 @@ pub extern "C" fn toplang_divide(a: Value, b: Value) -> Value {
 /// Compare two values for equality
 #[no_mangle]
 pub extern "C" fn toplang_equal(a: Value, b: Value) -> Value {
+    Value::boolean(values_equal(a, b))
+}
+
+/// Equality as the VMs see it: strings by their text, arrays element by element
+fn values_equal(a: Value, b: Value) -> bool {
     if a.is_number() && b.is_number() {
-        Value::boolean(a.as_number() == b.as_number())
+        a.as_number() == b.as_number()
     } else if a.is_boolean() && b.is_boolean() {
-        Value::boolean(a.as_boolean() == b.as_boolean())
+        a.as_boolean() == b.as_boolean()
     } else if a.is_null() && b.is_null() {
-        Value::boolean(true)
+        true
+    } else if let (Some(x), Some(y)) = (as_str(a), as_str(b)) {
+        x == y
+    } else if let (Some(x), Some(y)) = (as_array(a), as_array(b)) {
+        x.len() == y.len() && x.iter().zip(y.iter()).all(|(a, b)| values_equal(*a, *b))
     } else {
-        Value::boolean(false)
+        false
     }
 }
 
@@ -304,42 +348,53 @@ pub extern "C" fn toplang_greater(a: Value, b: Value) -> Value {
     }
 }
 
+/// Exit code of a program whose `main` returned `val`
+#[no_mangle]
+pub extern "C" fn toplang_exit_code(val: Value) -> i32 {
+    if val.is_number() {
+        val.as_number() as i32
+    } else {
+        0
+    }
+}
+
+/// End the program with the exit code `val` gives, as `exit` does in the VMs
+#[no_mangle]
+pub extern "C" fn toplang_exit(val: Value) -> ! {
+    let _ = io::stdout().flush();
+    std::process::exit(toplang_exit_code(val))
+}
+
 /// Logical NOT
 #[no_mangle]
 pub extern "C" fn toplang_not(a: Value) -> Value {
     Value::boolean(!a.is_truthy())
 }
 
-/// Get array length
+/// Length of a string (in characters) or an array
 #[no_mangle]
-pub extern "C" fn toplang_array_length(arr: Value) -> Value {
-    if arr.is_ptr() {
-        unsafe {
-            let arr_ptr = arr.as_ptr() as *const RuntimeArray;
-            let arr_ref = &*arr_ptr;
-            Value::number(arr_ref.len as f64)
-        }
-    } else {
-        Value::null()
-    }
+pub extern "C" fn toplang_length(val: Value) -> Value {
+    let len = match (as_str(val), as_array(val)) {
+        (Some(s), _) => s.chars().count(),
+        (_, Some(items)) => items.len(),
+        _ => fail("Length can only be applied to strings or arrays"),
+    };
+    Value::number(len as f64)
 }
 
-// Runtime array type
-#[repr(C)]
-pub struct RuntimeArray {
-    data: *mut Value,
-    len: usize,
-    capacity: usize,
+/// `val`, stopping the program unless it's an array, for `for each`
+#[no_mangle]
+pub extern "C" fn toplang_expect_array(val: Value) -> Value {
+    if as_array(val).is_none() {
+        fail(RuntimeError::NotIterable(render(&val, &Plain)));
+    }
+    val
 }
 
 /// Create a new array
 #[no_mangle]
 pub extern "C" fn toplang_array_new(size: i64) -> Value {
-    let size = size as usize;
-    let mut vec = Vec::with_capacity(size);
-    for _ in 0..size {
-        vec.push(Value::null());
-    }
+    let mut vec = vec![Value::null(); size as usize];
 
     let len = vec.len();
     let capacity = vec.capacity();
@@ -347,6 +402,7 @@ pub extern "C" fn toplang_array_new(size: i64) -> Value {
     std::mem::forget(vec);
 
     let runtime_arr = Box::new(RuntimeArray {
+        kind: HeapKind::Array,
         data,
         len,
         capacity,
@@ -355,38 +411,40 @@ pub extern "C" fn toplang_array_new(size: i64) -> Value {
     Value::ptr(Box::into_raw(runtime_arr) as *mut u8)
 }
 
+/// The element of `arr` that `index` names, stopping the program with the
+/// VMs' error if there's no such element
+fn element<'a>(arr: Value, index: Value) -> &'a mut Value {
+    let items = as_array(arr).unwrap_or_else(|| fail(type_mismatch("array", arr)));
+    if !index.is_number() {
+        fail(type_mismatch("number", index));
+    }
+    match array_index(index.as_number(), items.len()) {
+        Ok(position) => &mut items[position],
+        Err(error) => fail(error),
+    }
+}
+
 /// Get array element
 #[no_mangle]
 pub extern "C" fn toplang_array_get(arr: Value, index: Value) -> Value {
-    if arr.is_ptr() && index.is_number() {
-        unsafe {
-            let arr_ptr = arr.as_ptr() as *const RuntimeArray;
-            let arr_ref = &*arr_ptr;
-            let idx = index.as_number() as usize;
-            if idx < arr_ref.len {
-                let values = std::slice::from_raw_parts(arr_ref.data, arr_ref.len);
-                values[idx]
-            } else {
-                Value::null()
-            }
-        }
-    } else {
-        Value::null()
-    }
+    *element(arr, index)
 }
 
-/// Set array element
+/// Set array element, in place, for filling in an array just created
 #[no_mangle]
 pub extern "C" fn toplang_array_set(arr: Value, index: Value, val: Value) {
-    if arr.is_ptr() && index.is_number() {
-        unsafe {
-            let arr_ptr = arr.as_ptr() as *mut RuntimeArray;
-            let arr_ref = &mut *arr_ptr;
-            let idx = index.as_number() as usize;
-            if idx < arr_ref.len {
-                let values = std::slice::from_raw_parts_mut(arr_ref.data, arr_ref.len);
-                values[idx] = val;
-            }
-        }
+    *element(arr, index) = val;
+}
+
+/// A copy of `arr` with one element replaced. Arrays aren't reference
+/// counted, so any other variable may hold `arr` and it's never changed.
+#[no_mangle]
+pub extern "C" fn toplang_array_with(arr: Value, index: Value, val: Value) -> Value {
+    let items = as_array(arr).unwrap_or_else(|| fail(type_mismatch("array", arr)));
+    let copy = toplang_array_new(items.len() as i64);
+    if let Some(copied) = as_array(copy) {
+        copied.copy_from_slice(items);
     }
+    *element(copy, index) = val;
+    copy
 }
@@ -1,9 +1,18 @@
 use std::fmt;
 
+/// A piece of a double-quoted string that contains `{expr}` holes
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringPart {
+    Literal(String),
+    /// Source text of an embedded expression, parsed later
+    Expr(String),
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     // Keywords
     Function,
+    Import,
     Return,
     If,
     Else,
@@ -12,24 +21,64 @@ pub enum TokenType {
     Variable,
     Constant,
     Print,
+    Write,
     Ask,
+    Assert,
     List,
+    Map,
     At,
     Break,
     Continue,
     Length,
     Uppercase,
+    Lowercase,
     Substring,
     From,
     To,
     Of,
+    Contains,
+    #[allow(dead_code)] // Constructed dynamically in lexer for "index of"
+    IndexOf,
+    Replace,
+    With,
+    In,
+    Trim,
+    Slice,
+    Reverse,
+    Sort,
+    Join,
+    Null,
+    Otherwise,
+    Push,
+    Onto,
+    Pop,
+    Increase,
+    Decrease,
+    /// `multiply x by n` (the `times` operator is `Multiply`)
+    MultiplyVerb,
+    Repeat,
+    /// `do { body } while condition`
+    Do,
+    Each,
+    Type,
+    /// `as` before a loop label
+    As,
 
     // Operators
     Plus,
     Minus,
     Multiply,
     Divide,
+    IntDivide,
     Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    Xor,
+    /// `shift left`
+    ShiftLeft,
+    /// `shift right`
+    ShiftRight,
     Assign,
     Equals,
     #[allow(dead_code)] // Constructed dynamically in lexer for "not equals"
@@ -54,7 +103,10 @@ pub enum TokenType {
     // Literals
     Identifier(String),
     Number(f64),
+    /// A number written without a fraction or exponent that fits in an `i64`
+    Integer(i64),
     StringLit(String),
+    InterpolatedString(Vec<StringPart>),
     Boolean(bool),
 
     // Special
@@ -83,6 +135,7 @@ impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             TokenType::Function => write!(f, "function"),
+            TokenType::Import => write!(f, "import"),
             TokenType::Return => write!(f, "return"),
             TokenType::If => write!(f, "if"),
             TokenType::Else => write!(f, "else"),
@@ -91,22 +144,56 @@ impl fmt::Display for TokenType {
             TokenType::Variable => write!(f, "var"),
             TokenType::Constant => write!(f, "const"),
             TokenType::Print => write!(f, "print"),
+            TokenType::Write => write!(f, "write"),
             TokenType::Ask => write!(f, "ask"),
+            TokenType::Assert => write!(f, "assert"),
             TokenType::List => write!(f, "list"),
+            TokenType::Map => write!(f, "map"),
             TokenType::At => write!(f, "at"),
             TokenType::Break => write!(f, "break"),
             TokenType::Continue => write!(f, "continue"),
             TokenType::Length => write!(f, "length"),
             TokenType::Uppercase => write!(f, "uppercase"),
+            TokenType::Lowercase => write!(f, "lowercase"),
             TokenType::Substring => write!(f, "substring"),
             TokenType::From => write!(f, "from"),
             TokenType::To => write!(f, "to"),
             TokenType::Of => write!(f, "of"),
+            TokenType::Contains => write!(f, "contains"),
+            TokenType::IndexOf => write!(f, "index of"),
+            TokenType::Replace => write!(f, "replace"),
+            TokenType::With => write!(f, "with"),
+            TokenType::In => write!(f, "in"),
+            TokenType::Trim => write!(f, "trim"),
+            TokenType::Slice => write!(f, "slice"),
+            TokenType::Reverse => write!(f, "reverse"),
+            TokenType::Sort => write!(f, "sort"),
+            TokenType::Join => write!(f, "join"),
+            TokenType::Null => write!(f, "null"),
+            TokenType::Otherwise => write!(f, "otherwise"),
+            TokenType::Push => write!(f, "push"),
+            TokenType::Onto => write!(f, "onto"),
+            TokenType::Pop => write!(f, "pop"),
+            TokenType::Increase => write!(f, "increase"),
+            TokenType::Decrease => write!(f, "decrease"),
+            TokenType::MultiplyVerb => write!(f, "multiply"),
+            TokenType::Repeat => write!(f, "repeat"),
+            TokenType::Do => write!(f, "do"),
+            TokenType::Each => write!(f, "each"),
+            TokenType::As => write!(f, "as"),
+            TokenType::Type => write!(f, "type"),
             TokenType::Plus => write!(f, "plus"),
             TokenType::Minus => write!(f, "minus"),
             TokenType::Multiply => write!(f, "times"),
             TokenType::Divide => write!(f, "divided"),
+            TokenType::IntDivide => write!(f, "integer divided by"),
             TokenType::Modulo => write!(f, "modulo"),
+            TokenType::Power => write!(f, "to the power of"),
+            TokenType::BitAnd => write!(f, "bitand"),
+            TokenType::BitOr => write!(f, "bitor"),
+            TokenType::Xor => write!(f, "xor"),
+            TokenType::ShiftLeft => write!(f, "shift left"),
+            TokenType::ShiftRight => write!(f, "shift right"),
             TokenType::Assign => write!(f, "is"),
             TokenType::Equals => write!(f, "equals"),
             TokenType::NotEquals => write!(f, "not equals"),
@@ -124,7 +211,9 @@ impl fmt::Display for TokenType {
             TokenType::Comma => write!(f, ","),
             TokenType::Identifier(s) => write!(f, "identifier '{}'", s),
             TokenType::Number(n) => write!(f, "number {}", n),
+            TokenType::Integer(n) => write!(f, "number {}", n),
             TokenType::StringLit(s) => write!(f, "string \"{}\"", s),
+            TokenType::InterpolatedString(_) => write!(f, "interpolated string"),
             TokenType::Boolean(b) => write!(f, "boolean {}", b),
             TokenType::Eof => write!(f, "end of file"),
             TokenType::Unknown => write!(f, "unknown"),
@@ -0,0 +1,56 @@
+/// Line tracing for the stack VMs
+///
+/// With tracing on, a VM reports every instruction's source line here, and
+/// each time execution moves onto a different line that line is printed to
+/// stderr, so it interleaves with the program's own output. With it off the
+/// VMs skip the call, as they do for profiling.
+pub struct Trace {
+    /// The program's source, one entry per line, if it's available
+    source: Vec<String>,
+    last_line: Option<usize>,
+}
+
+impl Trace {
+    /// Trace a program compiled from `source`, or from unknown source when
+    /// running precompiled bytecode, where only line numbers are printed
+    pub fn new(source: Option<&str>) -> Self {
+        Trace {
+            source: source
+                .map(|s| s.lines().map(|line| line.trim().to_string()).collect())
+                .unwrap_or_default(),
+            last_line: None,
+        }
+    }
+
+    /// Note that an instruction from `line` is about to run
+    #[inline(always)]
+    pub fn record(&mut self, line: Option<usize>) {
+        if line.is_some() && line != self.last_line {
+            self.last_line = line;
+            if let Some(line) = line {
+                eprintln!("{}", self.describe(line));
+            }
+        }
+    }
+
+    /// `[line 4] print "big"`, or just the number if the text is unknown
+    fn describe(&self, line: usize) -> String {
+        match line.checked_sub(1).and_then(|i| self.source.get(i)) {
+            Some(text) => format!("[line {}] {}", line, text),
+            None => format!("[line {}]", line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_shows_the_source_text() {
+        let trace = Trace::new(Some("function main() {\n    print 1\n}"));
+        assert_eq!(trace.describe(2), "[line 2] print 1");
+        assert_eq!(trace.describe(9), "[line 9]");
+        assert_eq!(Trace::new(None).describe(2), "[line 2]");
+    }
+}
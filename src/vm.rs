@@ -7,18 +7,28 @@
 //!
 //! NOTE: This is a deprecated implementation. Use vm_optimized.rs or vm_nanbox.rs instead.
 
+use crate::builtins::{
+    array_index, parse_numeric, range_values, slice_range, sort_array, substring, Builtin, Numeric,
+    Rng, SortKey,
+};
 use crate::bytecode::*;
+use crate::error::RuntimeError;
+use crate::output::{print_array, print_map, render, Formatter, Plain, Printable};
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::io::{self, Write};
+use std::rc::Rc;
 
 /// Runtime value types
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
-    String(String),
+    /// A whole number, kept exact until arithmetic needs a float
+    Integer(i64),
+    String(Rc<str>),
     Boolean(bool),
     Array(Vec<Value>),
+    Map(HashMap<String, Value>),
     Null,
 }
 
@@ -27,57 +37,252 @@ impl Value {
         match self {
             Value::Boolean(b) => *b,
             Value::Number(n) => *n != 0.0,
+            Value::Integer(n) => *n != 0,
             Value::String(s) => !s.is_empty(),
             Value::Array(a) => !a.is_empty(),
+            Value::Map(m) => !m.is_empty(),
             Value::Null => false,
         }
     }
 
     pub fn as_number(&self) -> Result<f64> {
+        self.as_numeric().map(Numeric::as_f64)
+    }
+
+    /// The number this value holds, keeping integers exact
+    pub fn as_numeric(&self) -> Result<Numeric> {
+        self.numeric()
+            .ok_or_else(|| RuntimeError::type_mismatch("number", self).into())
+    }
+
+    /// The number this value holds, if it is one
+    pub fn numeric(&self) -> Option<Numeric> {
         match self {
-            Value::Number(n) => Ok(*n),
-            _ => Err(anyhow!("Expected number, got {:?}", self)),
+            Value::Number(n) => Some(Numeric::Float(*n)),
+            Value::Integer(n) => Some(Numeric::Integer(*n)),
+            _ => None,
+        }
+    }
+
+    /// The process exit code for a value returned from `main`: a number's
+    /// whole part, and 0 for anything else
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Value::Number(n) => *n as i32,
+            Value::Integer(n) => *n as i32,
+            _ => 0,
         }
     }
 
     pub fn as_string(&self) -> Result<String> {
         match self {
-            Value::String(s) => Ok(s.clone()),
-            _ => Err(anyhow!("Expected string, got {:?}", self)),
+            Value::String(s) => Ok(s.to_string()),
+            _ => Err(RuntimeError::type_mismatch("string", self).into()),
         }
     }
 
     pub fn as_array(&self) -> Result<Vec<Value>> {
         match self {
             Value::Array(a) => Ok(a.clone()),
-            _ => Err(anyhow!("Expected array, got {:?}", self)),
+            _ => Err(RuntimeError::type_mismatch("array", self).into()),
+        }
+    }
+
+    pub fn as_map(&self) -> Result<HashMap<String, Value>> {
+        match self {
+            Value::Map(m) => Ok(m.clone()),
+            _ => Err(RuntimeError::type_mismatch("map", self).into()),
+        }
+    }
+
+    /// Convert a value used as a map key (keys must be strings)
+    pub fn as_map_key(&self) -> Result<String> {
+        match self {
+            Value::String(s) => Ok(s.to_string()),
+            _ => Err(RuntimeError::type_mismatch("string", self).into()),
+        }
+    }
+
+    /// Read `key` from a map value; missing keys read as null
+    pub fn map_get(&self, key: &Value) -> Result<Value> {
+        match self {
+            Value::Map(m) => Ok(m.get(&key.as_map_key()?).cloned().unwrap_or(Value::Null)),
+            _ => Err(RuntimeError::type_mismatch("map", self).into()),
+        }
+    }
+
+    /// Insert `key` into a map value and return the updated map
+    pub fn map_set(self, key: &Value, value: Value) -> Result<Value> {
+        match self {
+            Value::Map(mut m) => {
+                m.insert(key.as_map_key()?, value);
+                Ok(Value::Map(m))
+            }
+            other => Err(RuntimeError::type_mismatch("map", &other).into()),
+        }
+    }
+
+    /// Name of the value's type, as returned by `type of`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) | Value::Integer(_) => "number",
+            Value::String(_) => "string",
+            Value::Boolean(_) => "boolean",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+            Value::Null => "null",
+        }
+    }
+
+    /// `to_number`: numbers pass through and strings are parsed
+    pub fn to_number(&self) -> Result<Value> {
+        match self {
+            Value::Number(_) | Value::Integer(_) => Ok(self.clone()),
+            Value::String(s) => Ok(parse_numeric(s)?.into()),
+            other => Err(RuntimeError::type_mismatch("number or string", other).into()),
+        }
+    }
+
+    /// `plus`: numeric addition, or concatenation when either side is a string
+    pub fn add(&self, other: &Value) -> Result<Value> {
+        match (self, other) {
+            (Value::Number(_) | Value::Integer(_), Value::Number(_) | Value::Integer(_)) => {
+                Ok(self.as_numeric()?.plus(other.as_numeric()?).into())
+            }
+            (Value::String(_), Value::String(_) | Value::Number(_) | Value::Integer(_))
+            | (Value::Number(_) | Value::Integer(_), Value::String(_)) => {
+                Ok(Value::String(format!("{}{}", self, other).into()))
+            }
+            _ => {
+                let culprit = match self {
+                    Value::String(_) | Value::Number(_) | Value::Integer(_) => other,
+                    _ => self,
+                };
+                Err(RuntimeError::type_mismatch("number or string", culprit).into())
+            }
+        }
+    }
+
+    /// Append to an array value and return the updated array
+    pub fn array_push(self, value: Value) -> Result<Value> {
+        match self {
+            Value::Array(mut a) => {
+                a.push(value);
+                Ok(Value::Array(a))
+            }
+            other => Err(RuntimeError::type_mismatch("array", &other).into()),
+        }
+    }
+
+    /// `slice`: elements `from` up to but excluding `to` of an array value
+    pub fn slice(&self, from: f64, to: f64) -> Result<Value> {
+        match self {
+            Value::Array(a) => Ok(Value::Array(a[slice_range(from, to, a.len())?].to_vec())),
+            other => Err(RuntimeError::type_mismatch("array", other).into()),
+        }
+    }
+
+    /// `reverse`: an array value back to front
+    pub fn reversed(self) -> Result<Value> {
+        match self {
+            Value::Array(mut a) => {
+                a.reverse();
+                Ok(Value::Array(a))
+            }
+            other => Err(RuntimeError::type_mismatch("array", &other).into()),
+        }
+    }
+
+    /// `sort`: an array value in ascending order
+    pub fn sorted(self) -> Result<Value> {
+        match self {
+            Value::Array(a) => Ok(Value::Array(sort_array(a, |value| match value {
+                Value::Number(x) => SortKey::Number(Numeric::Float(*x)),
+                Value::Integer(n) => SortKey::Number(Numeric::Integer(*n)),
+                Value::String(s) => SortKey::String(s.to_string()),
+                other => SortKey::Other(other.type_name()),
+            })?)),
+            other => Err(RuntimeError::type_mismatch("array", &other).into()),
+        }
+    }
+
+    /// `join`: the elements of an array value as text, separated by
+    /// `separator`
+    pub fn join(&self, separator: &Value) -> Result<Value> {
+        let separator = separator.as_string()?;
+        match self {
+            Value::Array(a) => Ok(Value::String(
+                a.iter()
+                    .map(Value::to_string)
+                    .collect::<Vec<_>>()
+                    .join(&separator)
+                    .into(),
+            )),
+            other => Err(RuntimeError::type_mismatch("array", other).into()),
+        }
+    }
+
+    /// Remove the last element of an array value, returning it and the
+    /// updated array
+    pub fn array_pop(self) -> Result<(Value, Value)> {
+        match self {
+            Value::Array(mut a) => {
+                let last = a.pop().ok_or(RuntimeError::EmptyArray)?;
+                Ok((last, Value::Array(a)))
+            }
+            other => Err(RuntimeError::type_mismatch("array", &other).into()),
+        }
+    }
+}
+
+/// Run a built-in on already-popped arguments (shared by the `Value` VMs)
+pub fn call_builtin(builtin: Builtin, args: &[Value], rng: &mut Rng) -> Result<Value> {
+    match (builtin, args) {
+        (Builtin::ToNumber, [value]) => return value.to_number(),
+        (Builtin::ToString, [value]) => return Ok(Value::String(value.to_string().into())),
+        (Builtin::ToBoolean, [value]) => return Ok(Value::Boolean(value.is_truthy())),
+        _ => {}
+    }
+    let numbers = args
+        .iter()
+        .map(Value::as_number)
+        .collect::<Result<Vec<_>>>()?;
+    match builtin {
+        Builtin::Range => Ok(Value::Array(
+            range_values(&numbers)?
+                .into_iter()
+                .map(Value::Number)
+                .collect(),
+        )),
+        _ => Ok(Value::Number(builtin.apply(&numbers, rng)?)),
+    }
+}
+
+impl From<Numeric> for Value {
+    fn from(n: Numeric) -> Value {
+        match n {
+            Numeric::Integer(n) => Value::Integer(n),
+            Numeric::Float(x) => Value::Number(x),
         }
     }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&render(self, &Plain))
+    }
+}
+
+impl Printable for Value {
+    fn print_to(&self, formatter: &dyn Formatter, out: &mut String, nested: bool) {
         match self {
-            Value::Number(n) => {
-                if n.fract() == 0.0 && n.is_finite() {
-                    write!(f, "{}", *n as i64)
-                } else {
-                    write!(f, "{}", n)
-                }
-            }
-            Value::String(s) => write!(f, "{}", s),
-            Value::Boolean(b) => write!(f, "{}", b),
-            Value::Array(arr) => {
-                write!(f, "[")?;
-                for (i, val) in arr.iter().enumerate() {
-                    if i > 0 {
-                        write!(f, ", ")?;
-                    }
-                    write!(f, "{}", val)?;
-                }
-                write!(f, "]")
-            }
-            Value::Null => write!(f, "null"),
+            Value::Number(n) => formatter.number(out, Numeric::Float(*n)),
+            Value::Integer(n) => formatter.number(out, Numeric::Integer(*n)),
+            Value::String(s) => formatter.string(out, s, nested),
+            Value::Boolean(b) => formatter.boolean(out, *b),
+            Value::Array(items) => print_array(items, formatter, out),
+            Value::Map(map) => print_map(map, formatter, out),
+            Value::Null => formatter.null(out),
         }
     }
 }
@@ -85,7 +290,7 @@ impl std::fmt::Display for Value {
 /// Call frame for function calls
 #[derive(Debug, Clone)]
 struct CallFrame {
-    chunk: Chunk,
+    chunk: Rc<Chunk>,
     ip: usize,
     stack_base: usize,
 }
@@ -101,21 +306,47 @@ pub struct VM {
     /// Global variables
     globals: HashMap<String, Value>,
 
+    /// Function table shared by every call frame
+    functions: HashMap<String, Rc<Chunk>>,
+
+    /// The same functions by index, for `CallIndex`
+    function_table: Vec<Rc<Chunk>>,
+
     /// Call frames for function calls
     frames: Vec<CallFrame>,
 
+    /// Most call frames a program may have at once
+    max_depth: usize,
+
+    /// Most values the stack may hold at once
+    max_stack: usize,
+
     /// Debug mode
     debug: bool,
+
+    /// Generator behind `random` and `random_int`
+    rng: Rng,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
         VM {
-            stack: vec![Value::Null; 256], // Pre-allocate stack
+            stack: vec![Value::Null; initial_stack_size(DEFAULT_MAX_STACK)],
             sp: 0,
             globals: HashMap::new(),
+            functions: HashMap::new(),
+            function_table: Vec::new(),
             frames: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_stack: DEFAULT_MAX_STACK,
             debug: false,
+            rng: Rng::from_entropy(),
         }
     }
 
@@ -123,11 +354,45 @@ impl VM {
         self.debug = debug;
     }
 
-    /// Execute a chunk of bytecode
+    /// Stop programs that nest calls more than `depth` deep
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Stop programs that need more than `slots` values on the stack at once
+    pub fn set_max_stack(&mut self, slots: usize) {
+        self.max_stack = slots;
+        self.stack.truncate(slots);
+    }
+
+    /// Make `random` and `random_int` reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Execute a chunk of bytecode, reporting the source line of a failing instruction
     pub fn execute(&mut self, chunk: Chunk) -> Result<i32> {
+        self.run(chunk).map_err(|err| match self.current_line() {
+            Some(line) => err.context(format!("at line {}", line)),
+            None => err,
+        })
+    }
+
+    /// Source line of the instruction being executed
+    fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        // ip has already been advanced past the current instruction
+        frame.chunk.line(frame.ip.checked_sub(1)?)
+    }
+
+    fn run(&mut self, mut chunk: Chunk) -> Result<i32> {
+        // Function chunks are looked up from here regardless of the calling frame
+        (self.functions, self.function_table) =
+            function_tables(std::mem::take(&mut chunk.functions));
+
         // Create initial frame
         let frame = CallFrame {
-            chunk,
+            chunk: Rc::new(chunk),
             ip: 0,
             stack_base: 0,
         };
@@ -169,16 +434,17 @@ impl VM {
                     };
                     let value = match constant {
                         Constant::Number(n) => Value::Number(n),
+                        Constant::Integer(n) => Value::Integer(n),
                         Constant::String(s) => Value::String(s),
                         Constant::Boolean(b) => Value::Boolean(b),
                         Constant::Null => Value::Null,
                     };
-                    self.push(value);
+                    self.push(value)?;
                 }
 
                 Instruction::LoadVar(idx) => {
                     let value = self.stack[stack_base + idx].clone();
-                    self.push(value);
+                    self.push(value)?;
                 }
 
                 Instruction::StoreVar(idx) => {
@@ -190,9 +456,9 @@ impl VM {
                     let value = self
                         .globals
                         .get(&name)
-                        .ok_or_else(|| anyhow!("Undefined variable: {}", name))?
+                        .ok_or_else(|| RuntimeError::UndefinedVariable(name.to_string()))?
                         .clone();
-                    self.push(value);
+                    self.push(value)?;
                 }
 
                 Instruction::StoreGlobal(name) => {
@@ -200,137 +466,126 @@ impl VM {
                     self.globals.insert(name, value);
                 }
 
-                Instruction::Add => {
+                // The integer forms are only emitted for numeric operands,
+                // but a hand-built chunk may disagree, so they share the checked path
+                Instruction::Add | Instruction::AddInt => {
                     let b = self.pop();
                     let a = self.pop();
-                    match (&a, &b) {
-                        (Value::Number(x), Value::Number(y)) => {
-                            self.push(Value::Number(x + y));
-                        }
-                        (Value::String(x), Value::String(y)) => {
-                            self.push(Value::String(format!("{}{}", x, y)));
-                        }
-                        _ => return Err(anyhow!("Cannot add {:?} and {:?}", a, b)),
-                    }
+                    self.push(a.add(&b)?)?;
                 }
 
-                Instruction::Subtract => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    self.push(Value::Number(a - b));
+                Instruction::Subtract | Instruction::SubInt => {
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(a.minus(b).into())?;
                 }
 
-                Instruction::Multiply => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    self.push(Value::Number(a * b));
+                Instruction::Multiply | Instruction::MulInt => {
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(a.times(b).into())?;
                 }
 
                 Instruction::Divide => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    if b == 0.0 {
-                        return Err(anyhow!("Division by zero"));
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
                     }
-                    self.push(Value::Number(a / b));
+                    self.push(a.divide(b).into())?;
                 }
 
-                Instruction::Modulo => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    if b == 0.0 {
-                        return Err(anyhow!("Modulo by zero"));
+                Instruction::IntDivide => {
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
                     }
-                    self.push(Value::Number(a % b));
-                }
-
-                Instruction::Negate => {
-                    let a = self.pop().as_number()?;
-                    self.push(Value::Number(-a));
+                    self.push(a.floor_divide(b).into())?;
                 }
 
-                // Fast integer operations - no type checking
-                Instruction::AddInt => {
-                    if let (Value::Number(b), Value::Number(a)) = (self.pop(), self.pop()) {
-                        self.push(Value::Number(a + b));
+                Instruction::Modulo => {
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::ModuloByZero.into());
                     }
+                    self.push(a.modulo(b).into())?;
                 }
 
-                Instruction::SubInt => {
-                    if let (Value::Number(b), Value::Number(a)) = (self.pop(), self.pop()) {
-                        self.push(Value::Number(a - b));
-                    }
+                Instruction::Power => {
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(a.power(b).into())?;
                 }
 
-                Instruction::MulInt => {
-                    if let (Value::Number(b), Value::Number(a)) = (self.pop(), self.pop()) {
-                        self.push(Value::Number(a * b));
-                    }
+                Instruction::Bitwise(op) => {
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(op.apply(a, b)?.into())?;
                 }
 
-                Instruction::LessInt => {
-                    if let (Value::Number(b), Value::Number(a)) = (self.pop(), self.pop()) {
-                        self.push(Value::Boolean(a < b));
-                    }
+                Instruction::Negate => {
+                    let a = self.pop().as_numeric()?;
+                    self.push(a.negate().into())?;
                 }
 
                 Instruction::IncrementInt => {
-                    if let Value::Number(a) = self.pop() {
-                        self.push(Value::Number(a + 1.0));
-                    }
+                    let a = self.pop().as_numeric()?;
+                    self.push(a.plus(Numeric::Integer(1)).into())?;
                 }
 
                 Instruction::Equal => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(self.values_equal(&a, &b)));
+                    self.push(Value::Boolean(self.values_equal(&a, &b)))?;
                 }
 
                 Instruction::NotEqual => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(!self.values_equal(&a, &b)));
+                    self.push(Value::Boolean(!self.values_equal(&a, &b)))?;
                 }
 
                 Instruction::Greater => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    self.push(Value::Boolean(a > b));
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(Value::Boolean(a > b))?;
                 }
 
                 Instruction::GreaterEqual => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    self.push(Value::Boolean(a >= b));
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(Value::Boolean(a >= b))?;
                 }
 
-                Instruction::Less => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    self.push(Value::Boolean(a < b));
+                Instruction::Less | Instruction::LessInt => {
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(Value::Boolean(a < b))?;
                 }
 
                 Instruction::LessEqual => {
-                    let b = self.pop().as_number()?;
-                    let a = self.pop().as_number()?;
-                    self.push(Value::Boolean(a <= b));
+                    let b = self.pop().as_numeric()?;
+                    let a = self.pop().as_numeric()?;
+                    self.push(Value::Boolean(a <= b))?;
                 }
 
                 Instruction::And => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(a.is_truthy() && b.is_truthy()));
+                    self.push(Value::Boolean(a.is_truthy() && b.is_truthy()))?;
                 }
 
                 Instruction::Or => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(a.is_truthy() || b.is_truthy()));
+                    self.push(Value::Boolean(a.is_truthy() || b.is_truthy()))?;
                 }
 
                 Instruction::Not => {
                     let a = self.pop();
-                    self.push(Value::Boolean(!a.is_truthy()));
+                    self.push(Value::Boolean(!a.is_truthy()))?;
                 }
 
                 Instruction::Jump(target) => {
@@ -354,26 +609,34 @@ impl VM {
                     }
                 }
 
+                Instruction::CallBuiltin(builtin, arity) => {
+                    let mut args = Vec::with_capacity(arity);
+                    for _ in 0..arity {
+                        args.push(self.pop());
+                    }
+                    args.reverse();
+                    let result = call_builtin(builtin, &args, &mut self.rng)?;
+                    self.push(result)?;
+                }
+
                 Instruction::Call(name, arity) => {
-                    // Get the function chunk
-                    let func_chunk = {
-                        let current_frame = self.frames.last().unwrap();
-                        current_frame
-                            .chunk
-                            .functions
-                            .get(&name)
-                            .ok_or_else(|| anyhow!("Undefined function: {}", name))?
-                            .clone()
-                    };
+                    let func_chunk = self.function(&name, arity)?;
+                    self.call(func_chunk, arity)?;
+                }
 
-                    // Create new call frame
-                    let new_frame = CallFrame {
-                        chunk: func_chunk,
-                        ip: 0,
-                        stack_base: self.sp - arity,
-                    };
+                Instruction::CallIndex(id, arity) => {
+                    let func_chunk = self.function_at(id, arity)?;
+                    self.call(func_chunk, arity)?;
+                }
 
-                    self.frames.push(new_frame);
+                Instruction::TailCall(name, arity) => {
+                    let func_chunk = self.function(&name, arity)?;
+                    self.tail_call(func_chunk, arity, stack_base)?;
+                }
+
+                Instruction::TailCallIndex(id, arity) => {
+                    let func_chunk = self.function_at(id, arity)?;
+                    self.tail_call(func_chunk, arity, stack_base)?;
                 }
 
                 Instruction::Return => {
@@ -386,15 +649,11 @@ impl VM {
                     self.sp = old_frame.stack_base;
 
                     // Push return value
-                    self.push(return_value);
+                    self.push(return_value)?;
 
                     // If no more frames, we're done
                     if self.frames.is_empty() {
-                        let exit_code = match self.pop() {
-                            Value::Number(n) => n as i32,
-                            _ => 0,
-                        };
-                        return Ok(exit_code);
+                        return Ok(self.pop().exit_code());
                     }
                 }
 
@@ -406,7 +665,7 @@ impl VM {
                     self.sp = old_frame.stack_base;
 
                     // Push null
-                    self.push(Value::Null);
+                    self.push(Value::Null)?;
 
                     // If no more frames, we're done
                     if self.frames.is_empty() {
@@ -414,13 +673,21 @@ impl VM {
                     }
                 }
 
+                Instruction::Exit => {
+                    let exit_code = self.pop().as_number()? as i32;
+                    // Unwind every frame at once
+                    self.frames.clear();
+                    self.sp = 0;
+                    return Ok(exit_code);
+                }
+
                 Instruction::Pop => {
                     self.pop();
                 }
 
                 Instruction::Dup => {
                     let value = self.peek(0).clone();
-                    self.push(value);
+                    self.push(value)?;
                 }
 
                 Instruction::MakeArray(size) => {
@@ -429,62 +696,178 @@ impl VM {
                         elements.push(self.pop());
                     }
                     elements.reverse(); // We popped in reverse order
-                    self.push(Value::Array(elements));
+                    self.push(Value::Array(elements))?;
                 }
 
                 Instruction::GetIndex => {
-                    let index = self.pop().as_number()? as usize;
-                    let array = self.pop().as_array()?;
+                    let index = self.pop();
+                    let target = self.pop();
 
-                    if index >= array.len() {
-                        return Err(anyhow!("Array index out of bounds: {}", index));
+                    if matches!(target, Value::Map(_)) {
+                        self.push(target.map_get(&index)?)?;
+                    } else {
+                        let array = target.as_array()?;
+                        let index = array_index(index.as_number()?, array.len())?;
+                        self.push(array[index].clone())?;
                     }
-
-                    self.push(array[index].clone());
                 }
 
                 Instruction::SetIndex => {
                     let value = self.pop();
-                    let index = self.pop().as_number()? as usize;
-                    let mut array = self.pop().as_array()?;
+                    let index = self.pop();
+                    let target = self.pop();
 
-                    if index >= array.len() {
-                        return Err(anyhow!("Array index out of bounds: {}", index));
+                    if matches!(target, Value::Map(_)) {
+                        self.push(target.map_set(&index, value)?)?;
+                    } else {
+                        let mut array = target.as_array()?;
+                        let index = array_index(index.as_number()?, array.len())?;
+                        array[index] = value;
+                        self.push(Value::Array(array))?;
                     }
+                }
 
-                    array[index] = value;
-                    self.push(Value::Array(array));
+                Instruction::MakeMap(size) => {
+                    let mut entries = Vec::with_capacity(size);
+                    for _ in 0..size {
+                        let value = self.pop();
+                        let key = self.pop();
+                        entries.push((key, value));
+                    }
+                    entries.reverse();
+
+                    let mut map = HashMap::with_capacity(size);
+                    for (key, value) in entries {
+                        map.insert(key.as_map_key()?, value);
+                    }
+                    self.push(Value::Map(map))?;
+                }
+
+                Instruction::MapGet => {
+                    let key = self.pop();
+                    let map = self.pop();
+                    self.push(map.map_get(&key)?)?;
+                }
+
+                Instruction::MapSet => {
+                    let value = self.pop();
+                    let key = self.pop();
+                    let map = self.pop();
+                    self.push(map.map_set(&key, value)?)?;
+                }
+
+                Instruction::ArrayPush => {
+                    let value = self.pop();
+                    let array = self.pop();
+                    self.push(array.array_push(value)?)?;
+                }
+
+                Instruction::ArrayPop => {
+                    let (last, array) = self.pop().array_pop()?;
+                    self.push(last)?;
+                    self.push(array)?;
                 }
 
                 Instruction::Length => {
                     let value = self.pop();
                     let len = match value {
-                        Value::String(s) => s.len(),
+                        Value::String(s) => s.chars().count(),
                         Value::Array(a) => a.len(),
-                        _ => {
-                            return Err(anyhow!("Length can only be applied to strings or arrays"))
+                        Value::Map(m) => m.len(),
+                        other => {
+                            return Err(
+                                RuntimeError::type_mismatch("string, array or map", &other).into()
+                            )
                         }
                     };
-                    self.push(Value::Number(len as f64));
+                    self.push(Value::Integer(len as i64))?;
                 }
 
                 Instruction::Uppercase => {
                     let s = self.pop().as_string()?;
-                    self.push(Value::String(s.to_uppercase()));
+                    self.push(Value::String(s.to_uppercase().into()))?;
+                }
+
+                Instruction::Lowercase => {
+                    let s = self.pop().as_string()?;
+                    self.push(Value::String(s.to_lowercase().into()))?;
                 }
 
                 Instruction::Substring => {
-                    let to = self.pop().as_number()? as usize;
-                    let from = self.pop().as_number()? as usize;
+                    let to = self.pop().as_number()?;
+                    let from = self.pop().as_number()?;
                     let s = self.pop().as_string()?;
+                    self.push(Value::String(substring(&s, from, to)?.into()))?;
+                }
 
-                    let chars: Vec<char> = s.chars().collect();
-                    if from > to || to > chars.len() {
-                        return Err(anyhow!("Substring indices out of bounds"));
-                    }
+                Instruction::Contains => {
+                    let needle = self.pop();
+                    let target = self.pop();
+                    // Arrays look for an equal element, strings for a substring
+                    let found = if let Value::Array(items) = &target {
+                        items.iter().any(|item| self.values_equal(item, &needle))
+                    } else {
+                        target.as_string()?.contains(needle.as_string()?.as_str())
+                    };
+                    self.push(Value::Boolean(found))?;
+                }
 
-                    let result: String = chars[from..to].iter().collect();
-                    self.push(Value::String(result));
+                Instruction::IndexOf => {
+                    let needle = self.pop().as_string()?;
+                    let s = self.pop().as_string()?;
+                    let index = s
+                        .find(needle.as_str())
+                        .map_or(-1, |i| s[..i].chars().count() as i64);
+                    self.push(Value::Integer(index))?;
+                }
+
+                Instruction::Replace => {
+                    let replacement = self.pop().as_string()?;
+                    let pattern = self.pop().as_string()?;
+                    let s = self.pop().as_string()?;
+                    self.push(Value::String(
+                        s.replace(pattern.as_str(), &replacement).into(),
+                    ))?;
+                }
+
+                Instruction::Trim => {
+                    let s = self.pop().as_string()?;
+                    self.push(Value::String(s.trim().into()))?;
+                }
+
+                Instruction::Slice => {
+                    let to = self.pop().as_number()?;
+                    let from = self.pop().as_number()?;
+                    let array = self.pop();
+                    self.push(array.slice(from, to)?)?;
+                }
+
+                Instruction::Reverse => {
+                    let array = self.pop();
+                    self.push(array.reversed()?)?;
+                }
+
+                Instruction::Sort => {
+                    let array = self.pop();
+                    self.push(array.sorted()?)?;
+                }
+
+                Instruction::Join => {
+                    let separator = self.pop();
+                    let array = self.pop();
+                    self.push(array.join(&separator)?)?;
+                }
+
+                Instruction::TypeOf => {
+                    let value = self.pop();
+                    self.push(Value::String(value.type_name().into()))?;
+                }
+
+                Instruction::ExpectArray => {
+                    let value = self.peek(0);
+                    if !matches!(value, Value::Array(_)) {
+                        return Err(RuntimeError::NotIterable(value.to_string()).into());
+                    }
                 }
 
                 Instruction::Print => {
@@ -492,26 +875,38 @@ impl VM {
                     println!("{}", value);
                 }
 
-                Instruction::Input(prompt) => {
-                    if let Some(p) = prompt {
-                        print!("{}", p);
-                        io::stdout().flush()?;
+                Instruction::Write => {
+                    print!("{}", self.pop());
+                    io::stdout().flush()?;
+                }
+
+                Instruction::Assert(message) => {
+                    if !self.pop().is_truthy() {
+                        return Err(RuntimeError::AssertionFailed(message.clone()).into());
                     }
+                }
 
-                    let mut input = String::new();
-                    io::stdin().read_line(&mut input)?;
-                    let input = input.trim().to_string();
+                Instruction::Input(prompt) => {
+                    let input = read_line(prompt.as_deref())?;
+                    let input = input.trim();
 
                     // Try to parse as number, otherwise keep as string
-                    if let Ok(n) = input.parse::<f64>() {
-                        self.push(Value::Number(n));
+                    if let Ok(n) = parse_numeric(input) {
+                        self.push(n.into())?;
                     } else {
-                        self.push(Value::String(input));
+                        self.push(Value::String(input.into()))?;
                     }
                 }
 
+                Instruction::InputText(prompt) => {
+                    let input = read_line(prompt.as_deref())?;
+                    self.push(Value::String(input.into()))?;
+                }
+
                 Instruction::Halt => {
-                    return Ok(0);
+                    // main's return value is left on the stack as the exit code
+                    let result = self.stack[..self.sp].last();
+                    return Ok(result.map_or(0, Value::exit_code));
                 }
 
                 Instruction::Nop => {
@@ -522,12 +917,14 @@ impl VM {
     }
 
     // Stack operations
-    fn push(&mut self, value: Value) {
+    fn push(&mut self, value: Value) -> Result<()> {
         if self.sp >= self.stack.len() {
-            self.stack.resize(self.stack.len() * 2, Value::Null);
+            let size = grown_stack_size(self.stack.len(), self.max_stack)?;
+            self.stack.resize(size, Value::Null);
         }
         self.stack[self.sp] = value;
         self.sp += 1;
+        Ok(())
     }
 
     fn pop(&mut self) -> Value {
@@ -538,14 +935,87 @@ impl VM {
         self.stack[self.sp].clone()
     }
 
+    /// Look up a function by `CallIndex` number, checking it like a call by
+    /// name since a chunk that skipped `Chunk::validate` may hold any operands
+    fn function_at(&self, id: usize, arity: usize) -> Result<Rc<Chunk>> {
+        let function = self
+            .function_table
+            .get(id)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(format!("#{}", id)))?;
+        if function.arity != arity {
+            check_arity(
+                function_name(&self.functions, function),
+                function.arity,
+                arity,
+            )?;
+        }
+        Ok(function.clone())
+    }
+
+    /// Look up a function by name, checking the call passes as many arguments
+    /// as it takes
+    fn function(&self, name: &str, arity: usize) -> Result<Rc<Chunk>> {
+        let function = self
+            .functions
+            .get(name)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?
+            .clone();
+        check_arity(name, function.arity, arity)?;
+        Ok(function)
+    }
+
+    /// Enter `function`, whose `arity` arguments are on top of the stack
+    fn call(&mut self, function: Rc<Chunk>, arity: usize) -> Result<()> {
+        check_depth(
+            self.frames.len() + 1,
+            self.max_depth,
+            &function,
+            &self.functions,
+        )?;
+
+        // Arguments become the first locals; reserve the remaining slots
+        let callee_base = self.sp - arity;
+        for _ in arity..function.local_count {
+            self.push(Value::Null)?;
+        }
+
+        self.frames.push(CallFrame {
+            chunk: function,
+            ip: 0,
+            stack_base: callee_base,
+        });
+        Ok(())
+    }
+
+    /// Run `function` in place of the current frame, which starts at `stack_base`
+    fn tail_call(&mut self, function: Rc<Chunk>, arity: usize, stack_base: usize) -> Result<()> {
+        // Move the arguments down over the current frame's locals
+        let args_start = self.sp - arity;
+        for i in 0..arity {
+            self.stack.swap(stack_base + i, args_start + i);
+        }
+        self.sp = stack_base + arity;
+        for _ in arity..function.local_count {
+            self.push(Value::Null)?;
+        }
+
+        // Reuse the current frame instead of pushing a new one
+        let frame = self.frames.last_mut().unwrap();
+        frame.chunk = function;
+        frame.ip = 0;
+        Ok(())
+    }
+
     fn peek(&self, distance: usize) -> &Value {
         &self.stack[self.sp - 1 - distance]
     }
 
     #[allow(clippy::only_used_in_recursion)] // False positive - self is needed for method context
     fn values_equal(&self, a: &Value, b: &Value) -> bool {
+        if let (Some(x), Some(y)) = (a.numeric(), b.numeric()) {
+            return x.equals(y);
+        }
         match (a, b) {
-            (Value::Number(x), Value::Number(y)) => (x - y).abs() < f64::EPSILON,
             (Value::String(x), Value::String(y)) => x == y,
             (Value::Boolean(x), Value::Boolean(y)) => x == y,
             (Value::Null, Value::Null) => true,
@@ -560,7 +1030,57 @@ impl VM {
                 }
                 true
             }
+            (Value::Map(x), Value::Map(y)) => {
+                x.len() == y.len()
+                    && x.iter()
+                        .all(|(k, a)| y.get(k).is_some_and(|b| self.values_equal(a, b)))
+            }
             _ => false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `a op b` followed by a Pop, which underflows if `op` pushed nothing
+    fn run_binary(a: Constant, op: Instruction, b: Constant) -> Result<i32> {
+        let mut chunk = Chunk::new();
+        let a = chunk.add_constant(a);
+        let b = chunk.add_constant(b);
+        chunk.emit(Instruction::LoadConst(a), 1);
+        chunk.emit(Instruction::LoadConst(b), 1);
+        chunk.emit(op, 1);
+        chunk.emit(Instruction::Pop, 1);
+        chunk.emit(Instruction::Halt, 1);
+        VM::new().execute(chunk)
+    }
+
+    #[test]
+    fn test_integer_ops_check_their_operands() {
+        let text = || Constant::String("a".into());
+        let one = || Constant::Number(1.0);
+
+        // AddInt falls back to concatenation, the others to a clean error
+        assert!(run_binary(text(), Instruction::AddInt, one()).is_ok());
+        for op in [
+            Instruction::SubInt,
+            Instruction::MulInt,
+            Instruction::LessInt,
+        ] {
+            let err = run_binary(text(), op, one()).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("Expected number"),
+                "{:#}",
+                err
+            );
+        }
+        let err = run_binary(Constant::Boolean(true), Instruction::AddInt, one()).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Expected number or string"),
+            "{:#}",
+            err
+        );
+    }
+}
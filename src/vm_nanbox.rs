@@ -5,16 +5,26 @@
 /// - Inline small functions aggressively
 /// - Specialize common operations
 /// - Cache global lookups
+///
+use crate::builtins::{
+    array_index, parse_numeric, range_values, slice_range, sort_array, substring, Builtin, Numeric,
+    Rng, SortKey,
+};
 use crate::bytecode::*;
+use crate::error::RuntimeError;
 use crate::nanbox_safe::NanValue;
+use crate::output::{render, Formatter, Plain};
+use crate::profile::Profile;
+use crate::trace::Trace;
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::io::{self, Write};
+use std::rc::Rc;
 
 /// Call frame for function calls
 #[derive(Debug, Clone)]
 struct CallFrame {
-    chunk: Chunk,
+    chunk: Rc<Chunk>,
     ip: usize,
     stack_base: usize,
 }
@@ -42,11 +52,45 @@ pub struct NanBoxVM {
     /// Cache generation (incremented on global write)
     cache_generation: usize,
 
+    /// Function table shared by every call frame
+    functions: HashMap<String, Rc<Chunk>>,
+
+    /// The same functions by index, for `CallIndex`
+    function_table: Vec<Rc<Chunk>>,
+
+    /// Boxed string constants, keyed by the address of their text in the
+    /// constant pool, so each is allocated once however often it is loaded
+    strings: HashMap<*const u8, NanValue>,
+
     /// Call frames for function calls
     frames: Vec<CallFrame>,
 
+    /// Most call frames a program may have at once
+    max_depth: usize,
+
+    /// Most values the stack may hold at once
+    max_stack: usize,
+
     /// Debug mode
     debug: bool,
+
+    /// Instruction counts, kept only when profiling is enabled
+    profile: Option<Profile>,
+
+    /// Source lines printed as execution reaches them, when tracing
+    trace: Option<Trace>,
+
+    /// Generator behind `random` and `random_int`
+    rng: Rng,
+
+    /// How `print` and `write` show values
+    formatter: Box<dyn Formatter>,
+}
+
+impl Default for NanBoxVM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NanBoxVM {
@@ -57,8 +101,17 @@ impl NanBoxVM {
             globals: HashMap::with_capacity(64),
             global_cache: HashMap::with_capacity(64),
             cache_generation: 0,
+            functions: HashMap::new(),
+            function_table: Vec::new(),
+            strings: HashMap::new(),
             frames: Vec::with_capacity(32),
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_stack: DEFAULT_MAX_STACK,
             debug: false,
+            profile: None,
+            trace: None,
+            rng: Rng::from_entropy(),
+            formatter: Box::new(Plain),
         }
     }
 
@@ -66,14 +119,71 @@ impl NanBoxVM {
         self.debug = debug;
     }
 
-    /// Execute a chunk of bytecode
+    /// Stop programs that nest calls more than `depth` deep
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Stop programs that need more than `slots` values on the stack at once
+    pub fn set_max_stack(&mut self, slots: usize) {
+        self.max_stack = slots;
+        self.stack.truncate(slots);
+    }
+
+    /// Count the instructions executed from now on and track the stack depth
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    /// What has run since profiling was enabled
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Print each source line as execution reaches it, quoting `source`
+    pub fn enable_tracing(&mut self, source: Option<&str>) {
+        self.trace = Some(Trace::new(source));
+    }
+
+    /// Show printed values with `formatter` instead of the plain output
+    pub fn set_formatter(&mut self, formatter: Box<dyn Formatter>) {
+        self.formatter = formatter;
+    }
+
+    /// Make `random` and `random_int` reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Execute a chunk of bytecode, reporting the source line of a failing instruction
     pub fn execute(&mut self, chunk: Chunk) -> Result<i32> {
+        self.run(chunk).map_err(|err| match self.current_line() {
+            Some(line) => err.context(format!("at line {}", line)),
+            None => err,
+        })
+    }
+
+    /// Source line of the instruction being executed
+    fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        // ip has already been advanced past the current instruction
+        frame.chunk.line(frame.ip.checked_sub(1)?)
+    }
+
+    fn run(&mut self, mut chunk: Chunk) -> Result<i32> {
         // Pre-allocate stack to avoid reallocation
-        self.stack.resize(256, NanValue::null());
+        self.stack
+            .resize(initial_stack_size(self.max_stack), NanValue::null());
+
+        // Function chunks are looked up from here regardless of the calling frame
+        (self.functions, self.function_table) =
+            function_tables(std::mem::take(&mut chunk.functions));
+        // Addresses from an earlier run may have been reused since
+        self.strings.clear();
 
         // Create initial frame
         let frame = CallFrame {
-            chunk,
+            chunk: Rc::new(chunk),
             ip: 0,
             stack_base: 0,
         };
@@ -99,6 +209,13 @@ impl NanBoxVM {
             let instruction = self.frames[frame_idx].chunk.code[ip].clone();
             self.frames[frame_idx].ip += 1;
 
+            if let Some(profile) = &mut self.profile {
+                profile.record(&instruction, self.sp);
+            }
+            if let Some(trace) = &mut self.trace {
+                trace.record(self.frames[frame_idx].chunk.line(ip));
+            }
+
             if self.debug {
                 println!("Stack (sp={}): {:?}", self.sp, &self.stack[0..self.sp]);
                 print!("Execute[{}]: ", ip);
@@ -113,17 +230,22 @@ impl NanBoxVM {
                     let constant = &self.frames[frame_idx].chunk.constants[idx];
                     let value = match constant {
                         Constant::Number(n) => NanValue::number(*n),
-                        Constant::String(s) => NanValue::string(s.clone()),
+                        Constant::Integer(n) => NanValue::integer(*n),
+                        Constant::String(s) => self
+                            .strings
+                            .entry(s.as_ptr())
+                            .or_insert_with(|| NanValue::string(s.to_string()))
+                            .clone(),
                         Constant::Boolean(b) => NanValue::boolean(*b),
                         Constant::Null => NanValue::null(),
                     };
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::LoadVar(idx) => {
                     // Direct copy from stack (no clone needed for simple types)
                     let value = self.stack[stack_base + idx].clone();
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::StoreVar(idx) => {
@@ -135,7 +257,7 @@ impl NanBoxVM {
                     // Inline caching for globals
                     if let Some(cached) = self.global_cache.get(&name) {
                         if cached.generation == self.cache_generation {
-                            self.push_fast(cached.value.clone());
+                            self.push_fast(cached.value.clone())?;
                             continue;
                         }
                     }
@@ -143,7 +265,7 @@ impl NanBoxVM {
                     let value = self
                         .globals
                         .get(&name)
-                        .ok_or_else(|| anyhow!("Undefined variable: {}", name))?
+                        .ok_or_else(|| RuntimeError::UndefinedVariable(name.to_string()))?
                         .clone();
 
                     // Cache for next access
@@ -155,7 +277,7 @@ impl NanBoxVM {
                         },
                     );
 
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::StoreGlobal(name) => {
@@ -169,92 +291,81 @@ impl NanBoxVM {
                 Instruction::Add => {
                     let b = self.pop_fast();
                     let a = self.pop_fast();
-                    if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
-                        self.push_fast(NanValue::number(x + y));
-                    } else if let (Some(x), Some(y)) = (a.as_string(), b.as_string()) {
-                        let result = format!("{}{}", *x, *y);
-                        self.push_fast(NanValue::string(result));
-                    } else {
-                        return Err(anyhow!("Cannot add {:?} and {:?}", a, b));
-                    }
+                    self.push_fast(add_values(&a, &b)?)?;
                 }
 
                 Instruction::Subtract => {
                     let b = self.pop_fast();
                     let a = self.pop_fast();
-                    if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
-                        self.push_fast(NanValue::number(x - y));
-                    } else {
-                        return Err(anyhow!("Cannot subtract non-numbers"));
-                    }
+                    let difference = expect_number(&a)?.minus(expect_number(&b)?);
+                    self.push_fast(NanValue::numeric(difference))?;
                 }
 
                 Instruction::Multiply => {
-                    let b = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::number(a * b));
+                    let b = self.pop_number()?;
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(a.times(b)))?;
                 }
 
                 Instruction::Divide => {
-                    let b = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    if b == 0.0 {
-                        return Err(anyhow!("Division by zero"));
+                    let b = self.pop_number()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
                     }
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::number(a / b));
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(a.divide(b)))?;
+                }
+
+                Instruction::IntDivide => {
+                    let b = self.pop_number()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
+                    }
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(a.floor_divide(b)))?;
                 }
 
                 Instruction::Modulo => {
-                    let b = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    if b == 0.0 {
-                        return Err(anyhow!("Modulo by zero"));
+                    let b = self.pop_number()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::ModuloByZero.into());
                     }
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::number(a % b));
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(a.modulo(b)))?;
+                }
+
+                Instruction::Power => {
+                    let b = self.pop_number()?;
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(a.power(b)))?;
+                }
+
+                Instruction::Bitwise(op) => {
+                    let b = self.pop_number()?;
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(op.apply(a, b)?))?;
                 }
 
                 Instruction::Negate => {
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::number(-a));
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(a.negate()))?;
                 }
 
                 // Super-fast integer operations (no error checking)
                 Instruction::AddInt => {
                     if self.sp >= 2 {
                         if let (Some(b), Some(a)) = (
-                            self.stack[self.sp - 1].as_number(),
-                            self.stack[self.sp - 2].as_number(),
+                            self.stack[self.sp - 1].as_numeric(),
+                            self.stack[self.sp - 2].as_numeric(),
                         ) {
-                            let result = a + b;
+                            let result = a.plus(b);
                             self.sp -= 2;
-                            self.push_fast(NanValue::number(result));
+                            self.push_fast(NanValue::numeric(result))?;
                         } else {
+                            // Not numbers after all (e.g. string concatenation)
                             let b = self.pop_fast();
                             let a = self.pop_fast();
-                            if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
-                                self.push_fast(NanValue::number(x + y));
-                            }
+                            self.push_fast(add_values(&a, &b)?)?;
                         }
                     }
                 }
@@ -262,18 +373,16 @@ impl NanBoxVM {
                 Instruction::SubInt => {
                     if self.sp >= 2 {
                         if let (Some(b), Some(a)) = (
-                            self.stack[self.sp - 1].as_number(),
-                            self.stack[self.sp - 2].as_number(),
+                            self.stack[self.sp - 1].as_numeric(),
+                            self.stack[self.sp - 2].as_numeric(),
                         ) {
-                            let result = a - b;
+                            let result = a.minus(b);
                             self.sp -= 2;
-                            self.push_fast(NanValue::number(result));
+                            self.push_fast(NanValue::numeric(result))?;
                         } else {
-                            let b = self.pop_fast();
-                            let a = self.pop_fast();
-                            if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
-                                self.push_fast(NanValue::number(x - y));
-                            }
+                            let b = self.pop_number()?;
+                            let a = self.pop_number()?;
+                            self.push_fast(NanValue::numeric(a.minus(b)))?;
                         }
                     }
                 }
@@ -281,18 +390,16 @@ impl NanBoxVM {
                 Instruction::MulInt => {
                     if self.sp >= 2 {
                         if let (Some(b), Some(a)) = (
-                            self.stack[self.sp - 1].as_number(),
-                            self.stack[self.sp - 2].as_number(),
+                            self.stack[self.sp - 1].as_numeric(),
+                            self.stack[self.sp - 2].as_numeric(),
                         ) {
-                            let result = a * b;
+                            let result = a.times(b);
                             self.sp -= 2;
-                            self.push_fast(NanValue::number(result));
+                            self.push_fast(NanValue::numeric(result))?;
                         } else {
-                            let b = self.pop_fast();
-                            let a = self.pop_fast();
-                            if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
-                                self.push_fast(NanValue::number(x * y));
-                            }
+                            let b = self.pop_number()?;
+                            let a = self.pop_number()?;
+                            self.push_fast(NanValue::numeric(a.times(b)))?;
                         }
                     }
                 }
@@ -300,103 +407,76 @@ impl NanBoxVM {
                 Instruction::LessInt => {
                     if self.sp >= 2 {
                         if let (Some(b), Some(a)) = (
-                            self.stack[self.sp - 1].as_number(),
-                            self.stack[self.sp - 2].as_number(),
+                            self.stack[self.sp - 1].as_numeric(),
+                            self.stack[self.sp - 2].as_numeric(),
                         ) {
                             let result = a < b;
                             self.sp -= 2;
-                            self.push_fast(NanValue::boolean(result));
+                            self.push_fast(NanValue::boolean(result))?;
                         } else {
-                            let b = self.pop_fast();
-                            let a = self.pop_fast();
-                            if let (Some(x), Some(y)) = (a.as_number(), b.as_number()) {
-                                self.push_fast(NanValue::boolean(x < y));
-                            }
+                            let b = self.pop_number()?;
+                            let a = self.pop_number()?;
+                            self.push_fast(NanValue::boolean(a < b))?;
                         }
                     }
                 }
 
                 Instruction::IncrementInt => {
-                    if let Some(a) = self.pop_fast().as_number() {
-                        self.push_fast(NanValue::number(a + 1.0));
-                    }
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::numeric(a.plus(Numeric::Integer(1))))?;
                 }
 
                 Instruction::Equal => {
                     let b = self.pop_fast();
                     let a = self.pop_fast();
-                    self.push_fast(NanValue::boolean(self.values_equal(&a, &b)));
+                    self.push_fast(NanValue::boolean(self.values_equal(&a, &b)))?;
                 }
 
                 Instruction::NotEqual => {
                     let b = self.pop_fast();
                     let a = self.pop_fast();
-                    self.push_fast(NanValue::boolean(!self.values_equal(&a, &b)));
+                    self.push_fast(NanValue::boolean(!self.values_equal(&a, &b)))?;
                 }
 
                 Instruction::Greater => {
-                    let b = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::boolean(a > b));
+                    let b = self.pop_number()?;
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::boolean(a > b))?;
                 }
 
                 Instruction::GreaterEqual => {
-                    let b = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::boolean(a >= b));
+                    let b = self.pop_number()?;
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::boolean(a >= b))?;
                 }
 
                 Instruction::Less => {
-                    let b = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::boolean(a < b));
+                    let b = self.pop_number()?;
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::boolean(a < b))?;
                 }
 
                 Instruction::LessEqual => {
-                    let b = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    let a = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?;
-                    self.push_fast(NanValue::boolean(a <= b));
+                    let b = self.pop_number()?;
+                    let a = self.pop_number()?;
+                    self.push_fast(NanValue::boolean(a <= b))?;
                 }
 
                 Instruction::And => {
                     let b = self.pop_fast();
                     let a = self.pop_fast();
-                    self.push_fast(NanValue::boolean(a.is_truthy() && b.is_truthy()));
+                    self.push_fast(NanValue::boolean(a.is_truthy() && b.is_truthy()))?;
                 }
 
                 Instruction::Or => {
                     let b = self.pop_fast();
                     let a = self.pop_fast();
-                    self.push_fast(NanValue::boolean(a.is_truthy() || b.is_truthy()));
+                    self.push_fast(NanValue::boolean(a.is_truthy() || b.is_truthy()))?;
                 }
 
                 Instruction::Not => {
                     let a = self.pop_fast();
-                    self.push_fast(NanValue::boolean(!a.is_truthy()));
+                    self.push_fast(NanValue::boolean(!a.is_truthy()))?;
                 }
 
                 Instruction::Jump(target) => {
@@ -417,36 +497,45 @@ impl NanBoxVM {
                     }
                 }
 
+                Instruction::CallBuiltin(builtin, arity) => {
+                    let mut args = Vec::with_capacity(arity);
+                    for _ in 0..arity {
+                        args.push(self.pop_fast());
+                    }
+                    args.reverse();
+                    let result = self.call_builtin(builtin, &args)?;
+                    self.push_fast(result)?;
+                }
+
                 Instruction::Call(name, arity) => {
-                    let func_chunk = self.frames[frame_idx]
-                        .chunk
-                        .functions
-                        .get(&name)
-                        .ok_or_else(|| anyhow!("Undefined function: {}", name))?
-                        .clone();
+                    let func_chunk = self.function(&name, arity)?;
+                    self.call(func_chunk, arity)?;
+                }
 
-                    let new_frame = CallFrame {
-                        chunk: func_chunk,
-                        ip: 0,
-                        stack_base: self.sp - arity,
-                    };
+                Instruction::CallIndex(id, arity) => {
+                    let func_chunk = self.function_at(id, arity)?;
+                    self.call(func_chunk, arity)?;
+                }
+
+                Instruction::TailCall(name, arity) => {
+                    let func_chunk = self.function(&name, arity)?;
+                    self.tail_call(func_chunk, arity, stack_base)?;
+                }
 
-                    self.frames.push(new_frame);
+                Instruction::TailCallIndex(id, arity) => {
+                    let func_chunk = self.function_at(id, arity)?;
+                    self.tail_call(func_chunk, arity, stack_base)?;
                 }
 
                 Instruction::Return => {
                     let return_value = self.pop_fast();
                     let old_frame = self.frames.pop().unwrap();
                     self.sp = old_frame.stack_base;
-                    self.push_fast(return_value);
+                    self.push_fast(return_value)?;
 
                     if self.frames.is_empty() {
                         let val = self.pop_fast();
-                        let exit_code = if let Some(n) = val.as_number() {
-                            n as i32
-                        } else {
-                            0
-                        };
+                        let exit_code = val.as_number().map_or(0, |n| n as i32);
                         return Ok(exit_code);
                     }
                 }
@@ -454,20 +543,32 @@ impl NanBoxVM {
                 Instruction::ReturnNull => {
                     let old_frame = self.frames.pop().unwrap();
                     self.sp = old_frame.stack_base;
-                    self.push_fast(NanValue::null());
+                    self.push_fast(NanValue::null())?;
 
                     if self.frames.is_empty() {
                         return Ok(0);
                     }
                 }
 
+                Instruction::Exit => {
+                    let code = self.pop_fast();
+                    let exit_code = code
+                        .as_number()
+                        .ok_or_else(|| RuntimeError::type_mismatch("number", &code))?
+                        as i32;
+                    // Unwind every frame at once
+                    self.frames.clear();
+                    self.sp = 0;
+                    return Ok(exit_code);
+                }
+
                 Instruction::Pop => {
                     self.pop_fast();
                 }
 
                 Instruction::Dup => {
                     let value = self.peek_fast(0).clone();
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::MakeArray(size) => {
@@ -476,124 +577,244 @@ impl NanBoxVM {
                         elements.push(self.pop_fast());
                     }
                     elements.reverse();
-                    self.push_fast(NanValue::array(elements));
+                    self.push_fast(NanValue::array(elements))?;
                 }
 
                 Instruction::GetIndex => {
-                    let index = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?
-                        as usize;
-                    let array = self
-                        .pop_fast()
-                        .as_array()
-                        .ok_or_else(|| anyhow!("Expected array"))?
-                        .as_ref()
-                        .clone();
+                    let index = self.pop_fast();
+                    let target = self.pop_fast();
 
-                    if index >= array.len() {
-                        return Err(anyhow!("Array index out of bounds: {}", index));
+                    if target.is_map() {
+                        let value = self.map_get(&target, &index)?;
+                        self.push_fast(value)?;
+                    } else {
+                        let index = expect_number(&index)?.as_f64();
+                        let array = expect_array(&target)?.as_ref().clone();
+                        let index = array_index(index, array.len())?;
+                        self.push_fast(array[index].clone())?;
                     }
-
-                    self.push_fast(array[index].clone());
                 }
 
                 Instruction::SetIndex => {
                     let value = self.pop_fast();
-                    let index = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?
-                        as usize;
-                    let mut array = self
-                        .pop_fast()
-                        .as_array()
-                        .ok_or_else(|| anyhow!("Expected array"))?
-                        .as_ref()
-                        .clone();
+                    let index = self.pop_fast();
+                    let target = self.pop_fast();
 
-                    if index >= array.len() {
-                        return Err(anyhow!("Array index out of bounds: {}", index));
+                    if target.is_map() {
+                        let map = self.map_set(&target, &index, value)?;
+                        self.push_fast(map)?;
+                    } else {
+                        let index = expect_number(&index)?.as_f64();
+                        let mut array = expect_array(&target)?.as_ref().clone();
+                        let index = array_index(index, array.len())?;
+                        array[index] = value;
+                        self.push_fast(NanValue::array(array))?;
                     }
+                }
 
-                    array[index] = value;
-                    self.push_fast(NanValue::array(array));
+                Instruction::MakeMap(size) => {
+                    let mut entries = Vec::with_capacity(size);
+                    for _ in 0..size {
+                        let value = self.pop_fast();
+                        let key = self.pop_fast();
+                        entries.push((key, value));
+                    }
+                    entries.reverse();
+
+                    let mut map = HashMap::with_capacity(size);
+                    for (key, value) in entries {
+                        map.insert(self.map_key(&key)?, value);
+                    }
+                    self.push_fast(NanValue::map(map))?;
+                }
+
+                Instruction::MapGet => {
+                    let key = self.pop_fast();
+                    let map = self.pop_fast();
+                    let value = self.map_get(&map, &key)?;
+                    self.push_fast(value)?;
+                }
+
+                Instruction::MapSet => {
+                    let value = self.pop_fast();
+                    let key = self.pop_fast();
+                    let map = self.pop_fast();
+                    let map = self.map_set(&map, &key, value)?;
+                    self.push_fast(map)?;
+                }
+
+                Instruction::ArrayPush => {
+                    let value = self.pop_fast();
+                    let array = self.pop_fast();
+                    let mut array = expect_array(&array)?.as_ref().clone();
+                    array.push(value);
+                    self.push_fast(NanValue::array(array))?;
+                }
+
+                Instruction::ArrayPop => {
+                    let array = self.pop_fast();
+                    let mut array = expect_array(&array)?.as_ref().clone();
+                    let last = array.pop().ok_or(RuntimeError::EmptyArray)?;
+                    self.push_fast(last)?;
+                    self.push_fast(NanValue::array(array))?;
                 }
 
                 Instruction::Length => {
                     let value = self.pop_fast();
                     let len = if let Some(s) = value.as_string() {
-                        s.len()
+                        s.chars().count()
                     } else if let Some(a) = value.as_array() {
                         a.len()
+                    } else if let Some(m) = value.as_map() {
+                        m.len()
                     } else {
-                        return Err(anyhow!("Length can only be applied to strings or arrays"));
+                        return Err(
+                            RuntimeError::type_mismatch("string, array or map", &value).into()
+                        );
                     };
-                    self.push_fast(NanValue::number(len as f64));
+                    self.push_fast(NanValue::integer(len as i64))?;
                 }
 
                 Instruction::Uppercase => {
-                    let s = self
-                        .pop_fast()
-                        .as_string()
-                        .ok_or_else(|| anyhow!("Expected string"))?
-                        .as_ref()
-                        .clone();
-                    self.push_fast(NanValue::string(s.to_uppercase()));
+                    let s = expect_string(&self.pop_fast())?.as_ref().clone();
+                    self.push_fast(NanValue::string(s.to_uppercase()))?;
+                }
+
+                Instruction::Lowercase => {
+                    let s = expect_string(&self.pop_fast())?.as_ref().clone();
+                    self.push_fast(NanValue::string(s.to_lowercase()))?;
                 }
 
                 Instruction::Substring => {
-                    let to = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?
-                        as usize;
-                    let from = self
-                        .pop_fast()
-                        .as_number()
-                        .ok_or_else(|| anyhow!("Expected number"))?
-                        as usize;
-                    let s = self
-                        .pop_fast()
-                        .as_string()
-                        .ok_or_else(|| anyhow!("Expected string"))?
-                        .as_ref()
-                        .clone();
+                    let to = self.pop_number()?;
+                    let from = self.pop_number()?;
+                    let s = expect_string(&self.pop_fast())?.as_ref().clone();
 
-                    let chars: Vec<char> = s.chars().collect();
-                    if from > to || to > chars.len() {
-                        return Err(anyhow!("Substring indices out of bounds"));
-                    }
+                    self.push_fast(NanValue::string(substring(&s, from.as_f64(), to.as_f64())?))?;
+                }
+
+                Instruction::Contains => {
+                    let needle = self.pop_fast();
+                    let target = self.pop_fast();
+                    // Arrays look for an equal element, strings for a substring
+                    let found = if let Some(items) = target.as_array() {
+                        items.iter().any(|item| self.values_equal(item, &needle))
+                    } else {
+                        let s = expect_string(&target)?;
+                        let needle = expect_string(&needle)?;
+                        s.contains(needle.as_str())
+                    };
+                    self.push_fast(NanValue::boolean(found))?;
+                }
+
+                Instruction::IndexOf => {
+                    let needle = self.pop_string()?;
+                    let s = self.pop_string()?;
+                    let index = s
+                        .find(needle.as_str())
+                        .map_or(-1, |i| s[..i].chars().count() as i64);
+                    self.push_fast(NanValue::integer(index))?;
+                }
 
-                    let result: String = chars[from..to].iter().collect();
-                    self.push_fast(NanValue::string(result));
+                Instruction::Replace => {
+                    let replacement = self.pop_string()?;
+                    let pattern = self.pop_string()?;
+                    let s = self.pop_string()?;
+                    self.push_fast(NanValue::string(s.replace(pattern.as_str(), &replacement)))?;
+                }
+
+                Instruction::Trim => {
+                    let s = self.pop_string()?;
+                    self.push_fast(NanValue::string(s.trim().to_string()))?;
+                }
+
+                Instruction::Slice => {
+                    let to = self.pop_number()?;
+                    let from = self.pop_number()?;
+                    let array = expect_array(&self.pop_fast())?;
+                    let range = slice_range(from.as_f64(), to.as_f64(), array.len())?;
+                    self.push_fast(NanValue::array(array[range].to_vec()))?;
+                }
+
+                Instruction::Reverse => {
+                    let mut array = expect_array(&self.pop_fast())?.as_ref().clone();
+                    array.reverse();
+                    self.push_fast(NanValue::array(array))?;
+                }
+
+                Instruction::Sort => {
+                    let array = expect_array(&self.pop_fast())?.as_ref().clone();
+                    let sorted = sort_array(array, |value| {
+                        if let Some(n) = value.as_numeric() {
+                            SortKey::Number(n)
+                        } else if let Some(s) = value.as_string() {
+                            SortKey::String(s.as_ref().clone())
+                        } else {
+                            SortKey::Other(value.type_name())
+                        }
+                    })?;
+                    self.push_fast(NanValue::array(sorted))?;
+                }
+
+                Instruction::Join => {
+                    let separator = self.pop_string()?;
+                    let array = expect_array(&self.pop_fast())?;
+                    let parts: Vec<String> = array.iter().map(NanValue::to_string).collect();
+                    self.push_fast(NanValue::string(parts.join(&separator)))?;
+                }
+
+                Instruction::TypeOf => {
+                    let value = self.pop_fast();
+                    self.push_fast(NanValue::string(value.type_name().to_string()))?;
+                }
+
+                Instruction::ExpectArray => {
+                    let value = self.peek_fast(0);
+                    if value.as_array().is_none() {
+                        return Err(RuntimeError::NotIterable(value.to_string()).into());
+                    }
                 }
 
                 Instruction::Print => {
                     let value = self.pop_fast();
-                    println!("{}", value);
+                    println!("{}", render(&value, &*self.formatter));
                 }
 
-                Instruction::Input(prompt) => {
-                    if let Some(p) = prompt {
-                        print!("{}", p);
-                        io::stdout().flush()?;
+                Instruction::Write => {
+                    let value = self.pop_fast();
+                    print!("{}", render(&value, &*self.formatter));
+                    io::stdout().flush()?;
+                }
+
+                Instruction::Assert(message) => {
+                    if !self.pop_fast().is_truthy() {
+                        return Err(RuntimeError::AssertionFailed(message.clone()).into());
                     }
+                }
 
-                    let mut input = String::new();
-                    io::stdin().read_line(&mut input)?;
+                Instruction::Input(prompt) => {
+                    let input = read_line(prompt.as_deref())?;
                     let input = input.trim().to_string();
 
-                    if let Ok(n) = input.parse::<f64>() {
-                        self.push_fast(NanValue::number(n));
+                    if let Ok(n) = parse_numeric(&input) {
+                        self.push_fast(NanValue::numeric(n))?;
                     } else {
-                        self.push_fast(NanValue::string(input));
+                        self.push_fast(NanValue::string(input))?;
                     }
                 }
 
+                Instruction::InputText(prompt) => {
+                    let input = read_line(prompt.as_deref())?;
+                    self.push_fast(NanValue::string(input))?;
+                }
+
                 Instruction::Halt => {
-                    return Ok(0);
+                    // main's return value is left on the stack as the exit code
+                    let exit_code = self.stack[..self.sp]
+                        .last()
+                        .and_then(|v| v.as_number())
+                        .map_or(0, |n| n as i32);
+                    return Ok(exit_code);
                 }
 
                 Instruction::Nop => {
@@ -603,14 +824,89 @@ impl NanBoxVM {
         }
     }
 
+    /// Look up a function by `CallIndex` number, checking it like a call by
+    /// name since a chunk that skipped `Chunk::validate` may hold any operands
+    fn function_at(&self, id: usize, arity: usize) -> Result<Rc<Chunk>> {
+        let function = self
+            .function_table
+            .get(id)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(format!("#{}", id)))?;
+        if function.arity != arity {
+            check_arity(
+                function_name(&self.functions, function),
+                function.arity,
+                arity,
+            )?;
+        }
+        Ok(function.clone())
+    }
+
+    /// Look up a function by name, checking the call passes as many arguments
+    /// as it takes
+    fn function(&self, name: &str, arity: usize) -> Result<Rc<Chunk>> {
+        let function = self
+            .functions
+            .get(name)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?
+            .clone();
+        check_arity(name, function.arity, arity)?;
+        Ok(function)
+    }
+
+    /// Enter `function`, whose `arity` arguments are on top of the stack
+    #[inline(always)]
+    fn call(&mut self, function: Rc<Chunk>, arity: usize) -> Result<()> {
+        check_depth(
+            self.frames.len() + 1,
+            self.max_depth,
+            &function,
+            &self.functions,
+        )?;
+
+        // Arguments become the first locals; reserve the remaining slots
+        let callee_base = self.sp - arity;
+        for _ in arity..function.local_count {
+            self.push_fast(NanValue::null())?;
+        }
+
+        self.frames.push(CallFrame {
+            chunk: function,
+            ip: 0,
+            stack_base: callee_base,
+        });
+        Ok(())
+    }
+
+    /// Run `function` in place of the current frame, which starts at `stack_base`
+    #[inline(always)]
+    fn tail_call(&mut self, function: Rc<Chunk>, arity: usize, stack_base: usize) -> Result<()> {
+        // Move the arguments down over the current frame's locals
+        let args_start = self.sp - arity;
+        for i in 0..arity {
+            self.stack.swap(stack_base + i, args_start + i);
+        }
+        self.sp = stack_base + arity;
+        for _ in arity..function.local_count {
+            self.push_fast(NanValue::null())?;
+        }
+
+        // Reuse the current frame instead of pushing a new one
+        let frame = self.frames.last_mut().unwrap();
+        frame.chunk = function;
+        frame.ip = 0;
+        Ok(())
+    }
+
     // Ultra-fast stack operations (inlined)
     #[inline(always)]
-    fn push_fast(&mut self, value: NanValue) {
+    fn push_fast(&mut self, value: NanValue) -> Result<()> {
         if self.sp >= self.stack.len() {
-            self.stack.resize(self.stack.len() * 2, NanValue::null());
+            let size = grown_stack_size(self.stack.len(), self.max_stack)?;
+            self.stack.resize(size, NanValue::null());
         }
         self.stack[self.sp] = value;
         self.sp += 1;
+        Ok(())
     }
 
     #[inline(always)]
@@ -624,8 +920,110 @@ impl NanBoxVM {
         &self.stack[self.sp - 1 - distance]
     }
 
+    /// Pop a value that must be a number
+    fn pop_number(&mut self) -> Result<Numeric> {
+        expect_number(&self.pop_fast())
+    }
+
+    /// Pop a value that must be a string
+    fn pop_string(&mut self) -> Result<String> {
+        Ok(expect_string(&self.pop_fast())?.as_ref().clone())
+    }
+
+    fn call_builtin(&mut self, builtin: Builtin, args: &[NanValue]) -> Result<NanValue> {
+        match (builtin, args) {
+            (Builtin::ToNumber, [value]) => {
+                let n = match (value.as_numeric(), value.as_string()) {
+                    (Some(n), _) => n,
+                    (None, Some(s)) => parse_numeric(&s)?,
+                    _ => return Err(RuntimeError::type_mismatch("number or string", value).into()),
+                };
+                return Ok(NanValue::numeric(n));
+            }
+            (Builtin::ToString, [value]) => return Ok(NanValue::string(value.to_string())),
+            (Builtin::ToBoolean, [value]) => return Ok(NanValue::boolean(value.is_truthy())),
+            _ => {}
+        }
+        let numbers = args
+            .iter()
+            .map(|x| {
+                x.as_number()
+                    .ok_or_else(|| RuntimeError::type_mismatch("number", x))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        match builtin {
+            Builtin::Range => Ok(NanValue::array(
+                range_values(&numbers)?
+                    .into_iter()
+                    .map(NanValue::number)
+                    .collect(),
+            )),
+            _ => Ok(NanValue::number(builtin.apply(&numbers, &mut self.rng)?)),
+        }
+    }
+
+    fn map_key(&self, key: &NanValue) -> Result<String> {
+        Ok(expect_string(key)?.as_ref().clone())
+    }
+
+    /// Read `key` from a map value; missing keys read as null
+    fn map_get(&self, map: &NanValue, key: &NanValue) -> Result<NanValue> {
+        let map = expect_map(map)?;
+        Ok(map
+            .get(&self.map_key(key)?)
+            .cloned()
+            .unwrap_or_else(NanValue::null))
+    }
+
+    /// Insert `key` into a map value and return the updated map
+    fn map_set(&self, map: &NanValue, key: &NanValue, value: NanValue) -> Result<NanValue> {
+        let mut map = expect_map(map)?.as_ref().clone();
+        map.insert(self.map_key(key)?, value);
+        Ok(NanValue::map(map))
+    }
+
     #[allow(clippy::only_used_in_recursion)]
     fn values_equal(&self, a: &NanValue, b: &NanValue) -> bool {
         a.equals(b)
     }
 }
+
+/// `plus`: numeric addition, or concatenation when either side is a string
+fn add_values(a: &NanValue, b: &NanValue) -> Result<NanValue> {
+    if let (Some(x), Some(y)) = (a.as_numeric(), b.as_numeric()) {
+        Ok(NanValue::numeric(x.plus(y)))
+    } else if (a.is_string() || a.is_number()) && (b.is_string() || b.is_number()) {
+        Ok(NanValue::string(format!("{}{}", a, b)))
+    } else {
+        let culprit = if a.is_string() || a.is_number() { b } else { a };
+        Err(RuntimeError::type_mismatch("number or string", culprit).into())
+    }
+}
+
+/// `value` as a number, or the type error every engine raises for anything else
+fn expect_number(value: &NanValue) -> Result<Numeric> {
+    value
+        .as_numeric()
+        .ok_or_else(|| RuntimeError::type_mismatch("number", value).into())
+}
+
+/// `value` as a string, or a type error
+fn expect_string(value: &NanValue) -> Result<Rc<String>> {
+    value
+        .as_string()
+        .ok_or_else(|| RuntimeError::type_mismatch("string", value).into())
+}
+
+/// `value` as an array, or a type error
+fn expect_array(value: &NanValue) -> Result<Rc<Vec<NanValue>>> {
+    value
+        .as_array()
+        .ok_or_else(|| RuntimeError::type_mismatch("array", value).into())
+}
+
+/// `value` as a map, or a type error
+fn expect_map(value: &NanValue) -> Result<Rc<HashMap<String, NanValue>>> {
+    value
+        .as_map()
+        .ok_or_else(|| RuntimeError::type_mismatch("map", value).into())
+}
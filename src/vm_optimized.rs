@@ -1,3 +1,4 @@
+use crate::builtins::{array_index, parse_numeric, substring, Numeric, Rng};
 /// Highly optimized VM with reduced cloning and faster arithmetic
 ///
 /// This module implements several micro-optimizations:
@@ -6,17 +7,32 @@
 /// - Specialize common operations
 /// - Cache global lookups
 use crate::bytecode::*;
-use crate::vm::Value;
+use crate::error::RuntimeError;
+use crate::output::{render, Formatter, Plain};
+use crate::profile::Profile;
+use crate::trace::Trace;
+use crate::vm::{call_builtin, Value};
 use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::io::{self, Write};
+use std::rc::Rc;
 
 /// Call frame for function calls
 #[derive(Debug, Clone)]
 struct CallFrame {
-    chunk: Chunk,
+    chunk: Rc<Chunk>,
     ip: usize,
     stack_base: usize,
+    /// Function name, for error messages
+    name: Rc<str>,
+}
+
+/// A Rust function the host made callable from TopLang
+pub type NativeFunction = Box<dyn Fn(&[Value]) -> Result<Value>>;
+
+struct Native {
+    arity: usize,
+    function: NativeFunction,
 }
 
 /// Global variable cache entry
@@ -37,16 +53,57 @@ pub struct OptimizedVM {
     globals: HashMap<String, Value>,
 
     /// Global variable cache (for inline caching)
+    ///
+    /// An entry is only trusted while its generation matches
+    /// `cache_generation`, and every write to `globals` goes through
+    /// `store_global`, which bumps the generation. So a write anywhere, in
+    /// any frame, invalidates every cached value at once and the next read
+    /// goes back to `globals`. Element assignment and `push` build a new
+    /// value and store it with `StoreGlobal`, so they are writes too.
     global_cache: HashMap<String, GlobalCache>,
 
     /// Cache generation (incremented on global write)
     cache_generation: usize,
 
+    /// Function table shared by every call frame
+    functions: HashMap<String, Rc<Chunk>>,
+
+    /// The same functions by index, for `CallIndex`, and their names
+    function_table: Vec<Rc<Chunk>>,
+    function_names: Vec<Rc<str>>,
+
+    /// Host functions, called when no TopLang function has the name
+    natives: HashMap<String, Rc<Native>>,
+
     /// Call frames for function calls
     frames: Vec<CallFrame>,
 
+    /// Most call frames a program may have at once
+    max_depth: usize,
+
+    /// Most values the stack may hold at once
+    max_stack: usize,
+
     /// Debug mode
     debug: bool,
+
+    /// Instruction counts, kept only when profiling is enabled
+    profile: Option<Profile>,
+
+    /// Source lines printed as execution reaches them, when tracing
+    trace: Option<Trace>,
+
+    /// Generator behind `random` and `random_int`
+    rng: Rng,
+
+    /// How `print` and `write` show values
+    formatter: Box<dyn Formatter>,
+}
+
+impl Default for OptimizedVM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl OptimizedVM {
@@ -57,8 +114,18 @@ impl OptimizedVM {
             globals: HashMap::with_capacity(64),
             global_cache: HashMap::with_capacity(64),
             cache_generation: 0,
+            functions: HashMap::new(),
+            function_table: Vec::new(),
+            function_names: Vec::new(),
+            natives: HashMap::new(),
             frames: Vec::with_capacity(32),
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_stack: DEFAULT_MAX_STACK,
             debug: false,
+            profile: None,
+            trace: None,
+            rng: Rng::from_entropy(),
+            formatter: Box::new(Plain),
         }
     }
 
@@ -66,16 +133,103 @@ impl OptimizedVM {
         self.debug = debug;
     }
 
-    /// Execute a chunk of bytecode
+    /// Stop programs that nest calls more than `depth` deep
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Stop programs that need more than `slots` values on the stack at once
+    pub fn set_max_stack(&mut self, slots: usize) {
+        self.max_stack = slots;
+        self.stack.truncate(slots);
+    }
+
+    /// Count the instructions executed from now on and track the stack depth
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+
+    /// What has run since profiling was enabled
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Print each source line as execution reaches it, quoting `source`
+    pub fn enable_tracing(&mut self, source: Option<&str>) {
+        self.trace = Some(Trace::new(source));
+    }
+
+    /// Show printed values with `formatter` instead of the plain output
+    pub fn set_formatter(&mut self, formatter: Box<dyn Formatter>) {
+        self.formatter = formatter;
+    }
+
+    /// Make `random` and `random_int` reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Define a global variable for the program to read
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.store_global(name.to_string(), value);
+    }
+
+    /// The only way globals change, so cached reads never outlive a write
+    fn store_global(&mut self, name: String, value: Value) {
+        self.globals.insert(name, value);
+        self.cache_generation += 1;
+    }
+
+    /// Current value of a global variable
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    /// Make a Rust function callable from TopLang as `name`, taking `arity` arguments
+    pub fn register_builtin(&mut self, name: &str, arity: usize, function: NativeFunction) {
+        self.natives
+            .insert(name.to_string(), Rc::new(Native { arity, function }));
+    }
+
+    /// Execute a chunk of bytecode, reporting the source line of a failing instruction
     pub fn execute(&mut self, chunk: Chunk) -> Result<i32> {
+        self.run(chunk).map_err(|err| match self.current_line() {
+            Some(line) => err.context(format!("at line {}", line)),
+            None => err,
+        })
+    }
+
+    /// Source line of the instruction being executed
+    fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        // ip has already been advanced past the current instruction
+        frame.chunk.line(frame.ip.checked_sub(1)?)
+    }
+
+    fn run(&mut self, mut chunk: Chunk) -> Result<i32> {
+        // A failed earlier run may have left frames behind
+        self.frames.clear();
+        self.sp = 0;
+
         // Pre-allocate stack to avoid reallocation
-        self.stack.resize(256, Value::Null);
+        self.stack
+            .resize(initial_stack_size(self.max_stack), Value::Null);
+
+        // Function chunks are looked up from here regardless of the calling frame
+        self.function_names = chunk
+            .function_names()
+            .into_iter()
+            .map(|name| name.as_str().into())
+            .collect();
+        (self.functions, self.function_table) =
+            function_tables(std::mem::take(&mut chunk.functions));
 
         // Create initial frame
         let frame = CallFrame {
-            chunk,
+            chunk: Rc::new(chunk),
             ip: 0,
             stack_base: 0,
+            name: "<toplevel>".into(),
         };
 
         self.frames.push(frame);
@@ -99,6 +253,13 @@ impl OptimizedVM {
             let instruction = self.frames[frame_idx].chunk.code[ip].clone();
             self.frames[frame_idx].ip += 1;
 
+            if let Some(profile) = &mut self.profile {
+                profile.record(&instruction, self.sp);
+            }
+            if let Some(trace) = &mut self.trace {
+                trace.record(self.frames[frame_idx].chunk.line(ip));
+            }
+
             if self.debug {
                 println!("Stack (sp={}): {:?}", self.sp, &self.stack[0..self.sp]);
                 print!("Execute[{}]: ", ip);
@@ -113,21 +274,22 @@ impl OptimizedVM {
                     let constant = &self.frames[frame_idx].chunk.constants[idx];
                     let value = match constant {
                         Constant::Number(n) => Value::Number(*n),
+                        Constant::Integer(n) => Value::Integer(*n),
                         Constant::String(s) => Value::String(s.clone()),
                         Constant::Boolean(b) => Value::Boolean(*b),
                         Constant::Null => Value::Null,
                     };
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::LoadVar(idx) => {
                     // Direct copy from stack (no clone needed for simple types)
                     let value = self.stack[stack_base + idx].clone();
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::StoreVar(idx) => {
-                    let value = self.pop_fast();
+                    let value = self.pop_fast()?;
                     self.stack[stack_base + idx] = value;
                 }
 
@@ -135,7 +297,7 @@ impl OptimizedVM {
                     // Inline caching for globals
                     if let Some(cached) = self.global_cache.get(&name) {
                         if cached.generation == self.cache_generation {
-                            self.push_fast(cached.value.clone());
+                            self.push_fast(cached.value.clone())?;
                             continue;
                         }
                     }
@@ -143,7 +305,7 @@ impl OptimizedVM {
                     let value = self
                         .globals
                         .get(&name)
-                        .ok_or_else(|| anyhow!("Undefined variable: {}", name))?
+                        .ok_or_else(|| RuntimeError::UndefinedVariable(name.to_string()))?
                         .clone();
 
                     // Cache for next access
@@ -155,64 +317,76 @@ impl OptimizedVM {
                         },
                     );
 
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::StoreGlobal(name) => {
+                    // The value stays on the stack as the assignment's result
                     let value = self.peek_fast(0).clone();
-                    self.globals.insert(name, value);
-                    // Invalidate cache
-                    self.cache_generation += 1;
+                    self.store_global(name, value);
                 }
 
                 // Optimized arithmetic - use raw stack access
                 Instruction::Add => {
-                    let b = self.pop_fast();
-                    let a = self.pop_fast();
-                    match (&a, &b) {
-                        (Value::Number(x), Value::Number(y)) => {
-                            self.push_fast(Value::Number(x + y));
-                        }
-                        (Value::String(x), Value::String(y)) => {
-                            self.push_fast(Value::String(format!("{}{}", x, y)));
-                        }
-                        _ => return Err(anyhow!("Cannot add {:?} and {:?}", a, b)),
-                    }
+                    let b = self.pop_fast()?;
+                    let a = self.pop_fast()?;
+                    self.push_fast(a.add(&b)?)?;
                 }
 
                 Instruction::Subtract => {
-                    let b = self.pop_fast().as_number()?;
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Number(a - b));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.minus(b).into())?;
                 }
 
                 Instruction::Multiply => {
-                    let b = self.pop_fast().as_number()?;
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Number(a * b));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.times(b).into())?;
                 }
 
                 Instruction::Divide => {
-                    let b = self.pop_fast().as_number()?;
-                    if b == 0.0 {
-                        return Err(anyhow!("Division by zero"));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
                     }
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Number(a / b));
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.divide(b).into())?;
+                }
+
+                Instruction::IntDivide => {
+                    let b = self.pop_fast()?.as_numeric()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
+                    }
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.floor_divide(b).into())?;
                 }
 
                 Instruction::Modulo => {
-                    let b = self.pop_fast().as_number()?;
-                    if b == 0.0 {
-                        return Err(anyhow!("Modulo by zero"));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    if b.is_zero() {
+                        return Err(RuntimeError::ModuloByZero.into());
                     }
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Number(a % b));
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.modulo(b).into())?;
+                }
+
+                Instruction::Power => {
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.power(b).into())?;
+                }
+
+                Instruction::Bitwise(op) => {
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(op.apply(a, b)?.into())?;
                 }
 
                 Instruction::Negate => {
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Number(-a));
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.negate().into())?;
                 }
 
                 // Super-fast integer operations (no error checking)
@@ -223,13 +397,12 @@ impl OptimizedVM {
                         {
                             let result = a + b;
                             self.sp -= 2;
-                            self.push_fast(Value::Number(result));
+                            self.push_fast(Value::Number(result))?;
                         } else {
-                            let b = self.pop_fast();
-                            let a = self.pop_fast();
-                            if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                                self.push_fast(Value::Number(x + y));
-                            }
+                            // Not numbers after all (e.g. string concatenation)
+                            let b = self.pop_fast()?;
+                            let a = self.pop_fast()?;
+                            self.push_fast(a.add(&b)?)?;
                         }
                     }
                 }
@@ -241,13 +414,11 @@ impl OptimizedVM {
                         {
                             let result = a - b;
                             self.sp -= 2;
-                            self.push_fast(Value::Number(result));
+                            self.push_fast(Value::Number(result))?;
                         } else {
-                            let b = self.pop_fast();
-                            let a = self.pop_fast();
-                            if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                                self.push_fast(Value::Number(x - y));
-                            }
+                            let b = self.pop_fast()?.as_numeric()?;
+                            let a = self.pop_fast()?.as_numeric()?;
+                            self.push_fast(a.minus(b).into())?;
                         }
                     }
                 }
@@ -259,92 +430,89 @@ impl OptimizedVM {
                         {
                             let result = a * b;
                             self.sp -= 2;
-                            self.push_fast(Value::Number(result));
+                            self.push_fast(Value::Number(result))?;
                         } else {
-                            let b = self.pop_fast();
-                            let a = self.pop_fast();
-                            if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                                self.push_fast(Value::Number(x * y));
-                            }
+                            let b = self.pop_fast()?.as_numeric()?;
+                            let a = self.pop_fast()?.as_numeric()?;
+                            self.push_fast(a.times(b).into())?;
                         }
                     }
                 }
 
                 Instruction::LessInt => {
                     if self.sp >= 2 {
-                        if let (Value::Number(b), Value::Number(a)) =
-                            (&self.stack[self.sp - 1], &self.stack[self.sp - 2])
-                        {
-                            let result = a < b;
+                        let fast = match (&self.stack[self.sp - 2], &self.stack[self.sp - 1]) {
+                            (Value::Number(a), Value::Number(b)) => Some(a < b),
+                            (Value::Integer(a), Value::Integer(b)) => Some(a < b),
+                            _ => None,
+                        };
+                        if let Some(result) = fast {
                             self.sp -= 2;
-                            self.push_fast(Value::Boolean(result));
+                            self.push_fast(Value::Boolean(result))?;
                         } else {
-                            let b = self.pop_fast();
-                            let a = self.pop_fast();
-                            if let (Value::Number(x), Value::Number(y)) = (a, b) {
-                                self.push_fast(Value::Boolean(x < y));
-                            }
+                            let b = self.pop_fast()?.as_numeric()?;
+                            let a = self.pop_fast()?.as_numeric()?;
+                            self.push_fast(Value::Boolean(a < b))?;
                         }
                     }
                 }
 
                 Instruction::IncrementInt => {
-                    if let Value::Number(a) = self.pop_fast() {
-                        self.push_fast(Value::Number(a + 1.0));
-                    }
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(a.plus(Numeric::Integer(1)).into())?;
                 }
 
                 Instruction::Equal => {
-                    let b = self.pop_fast();
-                    let a = self.pop_fast();
-                    self.push_fast(Value::Boolean(self.values_equal(&a, &b)));
+                    let b = self.pop_fast()?;
+                    let a = self.pop_fast()?;
+                    self.push_fast(Value::Boolean(self.values_equal(&a, &b)))?;
                 }
 
                 Instruction::NotEqual => {
-                    let b = self.pop_fast();
-                    let a = self.pop_fast();
-                    self.push_fast(Value::Boolean(!self.values_equal(&a, &b)));
+                    let b = self.pop_fast()?;
+                    let a = self.pop_fast()?;
+                    self.push_fast(Value::Boolean(!self.values_equal(&a, &b)))?;
                 }
 
                 Instruction::Greater => {
-                    let b = self.pop_fast().as_number()?;
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Boolean(a > b));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(Value::Boolean(a > b))?;
                 }
 
                 Instruction::GreaterEqual => {
-                    let b = self.pop_fast().as_number()?;
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Boolean(a >= b));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(Value::Boolean(a >= b))?;
                 }
 
                 Instruction::Less => {
-                    let b = self.pop_fast().as_number()?;
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Boolean(a < b));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(Value::Boolean(a < b))?;
                 }
 
                 Instruction::LessEqual => {
-                    let b = self.pop_fast().as_number()?;
-                    let a = self.pop_fast().as_number()?;
-                    self.push_fast(Value::Boolean(a <= b));
+                    let b = self.pop_fast()?.as_numeric()?;
+                    let a = self.pop_fast()?.as_numeric()?;
+                    self.push_fast(Value::Boolean(a <= b))?;
                 }
 
                 Instruction::And => {
-                    let b = self.pop_fast();
-                    let a = self.pop_fast();
-                    self.push_fast(Value::Boolean(a.is_truthy() && b.is_truthy()));
+                    let b = self.pop_fast()?;
+                    let a = self.pop_fast()?;
+                    self.push_fast(Value::Boolean(a.is_truthy() && b.is_truthy()))?;
                 }
 
                 Instruction::Or => {
-                    let b = self.pop_fast();
-                    let a = self.pop_fast();
-                    self.push_fast(Value::Boolean(a.is_truthy() || b.is_truthy()));
+                    let b = self.pop_fast()?;
+                    let a = self.pop_fast()?;
+                    self.push_fast(Value::Boolean(a.is_truthy() || b.is_truthy()))?;
                 }
 
                 Instruction::Not => {
-                    let a = self.pop_fast();
-                    self.push_fast(Value::Boolean(!a.is_truthy()));
+                    let a = self.pop_fast()?;
+                    self.push_fast(Value::Boolean(!a.is_truthy()))?;
                 }
 
                 Instruction::Jump(target) => {
@@ -352,47 +520,94 @@ impl OptimizedVM {
                 }
 
                 Instruction::JumpIfFalse(target) => {
-                    let condition = self.pop_fast();
+                    let condition = self.pop_fast()?;
                     if !condition.is_truthy() {
                         self.frames[frame_idx].ip = target;
                     }
                 }
 
                 Instruction::JumpIfTrue(target) => {
-                    let condition = self.pop_fast();
+                    let condition = self.pop_fast()?;
                     if condition.is_truthy() {
                         self.frames[frame_idx].ip = target;
                     }
                 }
 
-                Instruction::Call(name, arity) => {
-                    let func_chunk = self.frames[frame_idx]
-                        .chunk
-                        .functions
-                        .get(&name)
-                        .ok_or_else(|| anyhow!("Undefined function: {}", name))?
-                        .clone();
+                Instruction::CallBuiltin(builtin, arity) => {
+                    let mut args = Vec::with_capacity(arity);
+                    for _ in 0..arity {
+                        args.push(self.pop_fast()?);
+                    }
+                    args.reverse();
+                    let result = call_builtin(builtin, &args, &mut self.rng)?;
+                    self.push_fast(result)?;
+                }
 
-                    let new_frame = CallFrame {
-                        chunk: func_chunk,
-                        ip: 0,
-                        stack_base: self.sp - arity,
+                Instruction::Call(name, arity) => {
+                    let func_chunk = match self.functions.get(&name) {
+                        Some(func_chunk) => func_chunk.clone(),
+                        None => {
+                            let native = self
+                                .natives
+                                .get(&name)
+                                .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?
+                                .clone();
+                            check_arity(&name, native.arity, arity)?;
+                            let args_start = self
+                                .sp
+                                .checked_sub(arity)
+                                .ok_or_else(|| self.stack_underflow())?;
+                            let result = (native.function)(&self.stack[args_start..self.sp])?;
+                            self.sp = args_start;
+                            self.push_fast(result)?;
+                            continue;
+                        }
                     };
+                    check_arity(&name, func_chunk.arity, arity)?;
+                    self.call(func_chunk, name.into(), arity)?;
+                }
 
-                    self.frames.push(new_frame);
+                Instruction::CallIndex(id, arity) => {
+                    let (func_chunk, name) = self.function_at(id, arity)?;
+                    self.call(func_chunk, name, arity)?;
+                }
+
+                Instruction::TailCall(name, arity) => {
+                    // Reuse the current frame: a self call keeps its chunk
+                    if *self.frames[frame_idx].name != *name {
+                        let func_chunk = self
+                            .functions
+                            .get(&name)
+                            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?
+                            .clone();
+                        self.frames[frame_idx].chunk = func_chunk;
+                        self.frames[frame_idx].name = name.into();
+                    }
+                    check_arity(
+                        &self.frames[frame_idx].name,
+                        self.frames[frame_idx].chunk.arity,
+                        arity,
+                    )?;
+                    self.tail_call(frame_idx, stack_base, arity)?;
+                }
+
+                Instruction::TailCallIndex(id, arity) => {
+                    let (func_chunk, name) = self.function_at(id, arity)?;
+                    if !Rc::ptr_eq(&self.frames[frame_idx].chunk, &func_chunk) {
+                        self.frames[frame_idx].chunk = func_chunk;
+                        self.frames[frame_idx].name = name;
+                    }
+                    self.tail_call(frame_idx, stack_base, arity)?;
                 }
 
                 Instruction::Return => {
-                    let return_value = self.pop_fast();
+                    let return_value = self.pop_fast()?;
                     let old_frame = self.frames.pop().unwrap();
                     self.sp = old_frame.stack_base;
-                    self.push_fast(return_value);
+                    self.push_fast(return_value)?;
 
                     if self.frames.is_empty() {
-                        let exit_code = match self.pop_fast() {
-                            Value::Number(n) => n as i32,
-                            _ => 0,
-                        };
+                        let exit_code = self.pop_fast()?.exit_code();
                         return Ok(exit_code);
                     }
                 }
@@ -400,110 +615,247 @@ impl OptimizedVM {
                 Instruction::ReturnNull => {
                     let old_frame = self.frames.pop().unwrap();
                     self.sp = old_frame.stack_base;
-                    self.push_fast(Value::Null);
+                    self.push_fast(Value::Null)?;
 
                     if self.frames.is_empty() {
                         return Ok(0);
                     }
                 }
 
+                Instruction::Exit => {
+                    let exit_code = self.pop_fast()?.as_number()? as i32;
+                    // Unwind every frame at once
+                    self.frames.clear();
+                    self.sp = 0;
+                    return Ok(exit_code);
+                }
+
                 Instruction::Pop => {
-                    self.pop_fast();
+                    self.pop_fast()?;
                 }
 
                 Instruction::Dup => {
                     let value = self.peek_fast(0).clone();
-                    self.push_fast(value);
+                    self.push_fast(value)?;
                 }
 
                 Instruction::MakeArray(size) => {
                     let mut elements = Vec::with_capacity(size);
                     for _ in 0..size {
-                        elements.push(self.pop_fast());
+                        elements.push(self.pop_fast()?);
                     }
                     elements.reverse();
-                    self.push_fast(Value::Array(elements));
+                    self.push_fast(Value::Array(elements))?;
                 }
 
                 Instruction::GetIndex => {
-                    let index = self.pop_fast().as_number()? as usize;
-                    let array = self.pop_fast().as_array()?;
+                    let index = self.pop_fast()?;
+                    let target = self.pop_fast()?;
 
-                    if index >= array.len() {
-                        return Err(anyhow!("Array index out of bounds: {}", index));
+                    if matches!(target, Value::Map(_)) {
+                        self.push_fast(target.map_get(&index)?)?;
+                    } else {
+                        let array = target.as_array()?;
+                        let index = array_index(index.as_number()?, array.len())?;
+                        self.push_fast(array[index].clone())?;
                     }
-
-                    self.push_fast(array[index].clone());
                 }
 
                 Instruction::SetIndex => {
-                    let value = self.pop_fast();
-                    let index = self.pop_fast().as_number()? as usize;
-                    let mut array = self.pop_fast().as_array()?;
+                    let value = self.pop_fast()?;
+                    let index = self.pop_fast()?;
+                    let target = self.pop_fast()?;
 
-                    if index >= array.len() {
-                        return Err(anyhow!("Array index out of bounds: {}", index));
+                    if matches!(target, Value::Map(_)) {
+                        self.push_fast(target.map_set(&index, value)?)?;
+                    } else {
+                        let mut array = target.as_array()?;
+                        let index = array_index(index.as_number()?, array.len())?;
+                        array[index] = value;
+                        self.push_fast(Value::Array(array))?;
                     }
+                }
 
-                    array[index] = value;
-                    self.push_fast(Value::Array(array));
+                Instruction::MakeMap(size) => {
+                    let mut entries = Vec::with_capacity(size);
+                    for _ in 0..size {
+                        let value = self.pop_fast()?;
+                        let key = self.pop_fast()?;
+                        entries.push((key, value));
+                    }
+                    entries.reverse();
+
+                    let mut map = HashMap::with_capacity(size);
+                    for (key, value) in entries {
+                        map.insert(key.as_map_key()?, value);
+                    }
+                    self.push_fast(Value::Map(map))?;
+                }
+
+                Instruction::MapGet => {
+                    let key = self.pop_fast()?;
+                    let map = self.pop_fast()?;
+                    self.push_fast(map.map_get(&key)?)?;
+                }
+
+                Instruction::MapSet => {
+                    let value = self.pop_fast()?;
+                    let key = self.pop_fast()?;
+                    let map = self.pop_fast()?;
+                    self.push_fast(map.map_set(&key, value)?)?;
+                }
+
+                Instruction::ArrayPush => {
+                    let value = self.pop_fast()?;
+                    let array = self.pop_fast()?;
+                    self.push_fast(array.array_push(value)?)?;
+                }
+
+                Instruction::ArrayPop => {
+                    let (last, array) = self.pop_fast()?.array_pop()?;
+                    self.push_fast(last)?;
+                    self.push_fast(array)?;
                 }
 
                 Instruction::Length => {
-                    let value = self.pop_fast();
+                    let value = self.pop_fast()?;
                     let len = match value {
-                        Value::String(s) => s.len(),
+                        Value::String(s) => s.chars().count(),
                         Value::Array(a) => a.len(),
-                        _ => {
-                            return Err(anyhow!("Length can only be applied to strings or arrays"))
+                        Value::Map(m) => m.len(),
+                        other => {
+                            return Err(
+                                RuntimeError::type_mismatch("string, array or map", &other).into()
+                            )
                         }
                     };
-                    self.push_fast(Value::Number(len as f64));
+                    self.push_fast(Value::Integer(len as i64))?;
                 }
 
                 Instruction::Uppercase => {
-                    let s = self.pop_fast().as_string()?;
-                    self.push_fast(Value::String(s.to_uppercase()));
+                    let s = self.pop_fast()?.as_string()?;
+                    self.push_fast(Value::String(s.to_uppercase().into()))?;
+                }
+
+                Instruction::Lowercase => {
+                    let s = self.pop_fast()?.as_string()?;
+                    self.push_fast(Value::String(s.to_lowercase().into()))?;
                 }
 
                 Instruction::Substring => {
-                    let to = self.pop_fast().as_number()? as usize;
-                    let from = self.pop_fast().as_number()? as usize;
-                    let s = self.pop_fast().as_string()?;
+                    let to = self.pop_fast()?.as_number()?;
+                    let from = self.pop_fast()?.as_number()?;
+                    let s = self.pop_fast()?.as_string()?;
+                    self.push_fast(Value::String(substring(&s, from, to)?.into()))?;
+                }
 
-                    let chars: Vec<char> = s.chars().collect();
-                    if from > to || to > chars.len() {
-                        return Err(anyhow!("Substring indices out of bounds"));
-                    }
+                Instruction::Contains => {
+                    let needle = self.pop_fast()?;
+                    let target = self.pop_fast()?;
+                    // Arrays look for an equal element, strings for a substring
+                    let found = if let Value::Array(items) = &target {
+                        items.iter().any(|item| self.values_equal(item, &needle))
+                    } else {
+                        target.as_string()?.contains(needle.as_string()?.as_str())
+                    };
+                    self.push_fast(Value::Boolean(found))?;
+                }
+
+                Instruction::IndexOf => {
+                    let needle = self.pop_fast()?.as_string()?;
+                    let s = self.pop_fast()?.as_string()?;
+                    let index = s
+                        .find(needle.as_str())
+                        .map_or(-1, |i| s[..i].chars().count() as i64);
+                    self.push_fast(Value::Integer(index))?;
+                }
+
+                Instruction::Replace => {
+                    let replacement = self.pop_fast()?.as_string()?;
+                    let pattern = self.pop_fast()?.as_string()?;
+                    let s = self.pop_fast()?.as_string()?;
+                    self.push_fast(Value::String(
+                        s.replace(pattern.as_str(), &replacement).into(),
+                    ))?;
+                }
+
+                Instruction::Trim => {
+                    let s = self.pop_fast()?.as_string()?;
+                    self.push_fast(Value::String(s.trim().into()))?;
+                }
 
-                    let result: String = chars[from..to].iter().collect();
-                    self.push_fast(Value::String(result));
+                Instruction::Slice => {
+                    let to = self.pop_fast()?.as_number()?;
+                    let from = self.pop_fast()?.as_number()?;
+                    let array = self.pop_fast()?;
+                    self.push_fast(array.slice(from, to)?)?;
+                }
+
+                Instruction::Reverse => {
+                    let array = self.pop_fast()?;
+                    self.push_fast(array.reversed()?)?;
+                }
+
+                Instruction::Sort => {
+                    let array = self.pop_fast()?;
+                    self.push_fast(array.sorted()?)?;
+                }
+
+                Instruction::Join => {
+                    let separator = self.pop_fast()?;
+                    let array = self.pop_fast()?;
+                    self.push_fast(array.join(&separator)?)?;
+                }
+
+                Instruction::TypeOf => {
+                    let value = self.pop_fast()?;
+                    self.push_fast(Value::String(value.type_name().into()))?;
+                }
+
+                Instruction::ExpectArray => {
+                    let value = self.peek_fast(0);
+                    if !matches!(value, Value::Array(_)) {
+                        return Err(RuntimeError::NotIterable(value.to_string()).into());
+                    }
                 }
 
                 Instruction::Print => {
-                    let value = self.pop_fast();
-                    println!("{}", value);
+                    let value = self.pop_fast()?;
+                    println!("{}", render(&value, &*self.formatter));
                 }
 
-                Instruction::Input(prompt) => {
-                    if let Some(p) = prompt {
-                        print!("{}", p);
-                        io::stdout().flush()?;
+                Instruction::Write => {
+                    let value = self.pop_fast()?;
+                    print!("{}", render(&value, &*self.formatter));
+                    io::stdout().flush()?;
+                }
+
+                Instruction::Assert(message) => {
+                    if !self.pop_fast()?.is_truthy() {
+                        return Err(RuntimeError::AssertionFailed(message.clone()).into());
                     }
+                }
 
-                    let mut input = String::new();
-                    io::stdin().read_line(&mut input)?;
-                    let input = input.trim().to_string();
+                Instruction::Input(prompt) => {
+                    let input = read_line(prompt.as_deref())?;
+                    let input = input.trim();
 
-                    if let Ok(n) = input.parse::<f64>() {
-                        self.push_fast(Value::Number(n));
+                    if let Ok(n) = parse_numeric(input) {
+                        self.push_fast(n.into())?;
                     } else {
-                        self.push_fast(Value::String(input));
+                        self.push_fast(Value::String(input.into()))?;
                     }
                 }
 
+                Instruction::InputText(prompt) => {
+                    let input = read_line(prompt.as_deref())?;
+                    self.push_fast(Value::String(input.into()))?;
+                }
+
                 Instruction::Halt => {
-                    return Ok(0);
+                    // main's return value is left on the stack as the exit code
+                    let exit_code = self.stack[..self.sp].last().map_or(0, Value::exit_code);
+                    return Ok(exit_code);
                 }
 
                 Instruction::Nop => {
@@ -513,20 +865,97 @@ impl OptimizedVM {
         }
     }
 
+    /// Look up a function by `CallIndex` number, checking it like a call by
+    /// name since a chunk that skipped `Chunk::validate` may hold any operands
+    fn function_at(&self, id: usize, arity: usize) -> Result<(Rc<Chunk>, Rc<str>)> {
+        let function = self
+            .function_table
+            .get(id)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(format!("#{}", id)))?;
+        let name = &self.function_names[id];
+        check_arity(name, function.arity, arity)?;
+        Ok((function.clone(), name.clone()))
+    }
+
+    /// Enter `function`, whose `arity` arguments are on top of the stack
+    #[inline(always)]
+    fn call(&mut self, function: Rc<Chunk>, name: Rc<str>, arity: usize) -> Result<()> {
+        if self.frames.len() >= self.max_depth {
+            return Err(RuntimeError::RecursionLimit(name.to_string()).into());
+        }
+
+        // Arguments become the first locals; reserve the remaining slots
+        let callee_base = self
+            .sp
+            .checked_sub(arity)
+            .ok_or_else(|| self.stack_underflow())?;
+        for _ in arity..function.local_count {
+            self.push_fast(Value::Null)?;
+        }
+
+        self.frames.push(CallFrame {
+            chunk: function,
+            ip: 0,
+            stack_base: callee_base,
+            name,
+        });
+        Ok(())
+    }
+
+    /// Restart frame `frame_idx`, which starts at `stack_base`, with the
+    /// function it now holds and the `arity` arguments on top of the stack
+    #[inline(always)]
+    fn tail_call(&mut self, frame_idx: usize, stack_base: usize, arity: usize) -> Result<()> {
+        // Move the arguments down over the old locals and reserve the rest
+        let args_start = self
+            .sp
+            .checked_sub(arity)
+            .ok_or_else(|| self.stack_underflow())?;
+        for i in 0..arity {
+            self.stack.swap(stack_base + i, args_start + i);
+        }
+        self.sp = stack_base + arity;
+        for _ in arity..self.frames[frame_idx].chunk.local_count {
+            self.push_fast(Value::Null)?;
+        }
+
+        self.frames[frame_idx].ip = 0;
+        Ok(())
+    }
+
     // Ultra-fast stack operations (inlined)
     #[inline(always)]
-    fn push_fast(&mut self, value: Value) {
+    fn push_fast(&mut self, value: Value) -> Result<()> {
         if self.sp >= self.stack.len() {
-            self.stack.resize(self.stack.len() * 2, Value::Null);
+            let size = grown_stack_size(self.stack.len(), self.max_stack)?;
+            self.stack.resize(size, Value::Null);
         }
         self.stack[self.sp] = value;
         self.sp += 1;
+        Ok(())
     }
 
     #[inline(always)]
-    fn pop_fast(&mut self) -> Value {
+    fn pop_fast(&mut self) -> Result<Value> {
+        if self.sp == 0 {
+            return Err(self.stack_underflow());
+        }
         self.sp -= 1;
-        std::mem::replace(&mut self.stack[self.sp], Value::Null)
+        Ok(std::mem::replace(&mut self.stack[self.sp], Value::Null))
+    }
+
+    /// Error for popping an empty stack, pointing at the offending instruction
+    #[cold]
+    fn stack_underflow(&self) -> anyhow::Error {
+        match self.frames.last() {
+            // ip has already been advanced past the current instruction
+            Some(frame) => anyhow!(
+                "Stack underflow at ip {} in function '{}'",
+                frame.ip.saturating_sub(1),
+                frame.name
+            ),
+            None => anyhow!("Stack underflow"),
+        }
     }
 
     #[inline(always)]
@@ -536,8 +965,10 @@ impl OptimizedVM {
 
     #[allow(clippy::only_used_in_recursion)]
     fn values_equal(&self, a: &Value, b: &Value) -> bool {
+        if let (Some(x), Some(y)) = (a.numeric(), b.numeric()) {
+            return x.equals(y);
+        }
         match (a, b) {
-            (Value::Number(x), Value::Number(y)) => (x - y).abs() < f64::EPSILON,
             (Value::String(x), Value::String(y)) => x == y,
             (Value::Boolean(x), Value::Boolean(y)) => x == y,
             (Value::Null, Value::Null) => true,
@@ -552,7 +983,241 @@ impl OptimizedVM {
                 }
                 true
             }
+            (Value::Map(x), Value::Map(y)) => {
+                x.len() == y.len()
+                    && x.iter()
+                        .all(|(k, a)| y.get(k).is_some_and(|b| self.values_equal(a, b)))
+            }
             _ => false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn run(source: &str) -> Result<i32> {
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse()?;
+        let chunk = Compiler::new().compile(program)?;
+        OptimizedVM::new().execute(chunk)
+    }
+
+    #[test]
+    fn test_map_operations() {
+        let source = r#"
+function main() {
+    var m is map "a" to 1, "b" to 2
+    m at "c" is 3
+    var key is "b"
+    var b is m at key
+    if m at "missing" equals m at "also missing" {
+        if m equals map "a" to 1, "b" to 2, "c" to 3 {
+            return b plus length of m
+        }
+    }
+    return 0
+}
+"#;
+        assert_eq!(run(source).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_tail_calls_reuse_the_frame() {
+        let source = r#"
+function sum(n, total) {
+    if n equals 0 {
+        return total
+    }
+    return sum(n minus 1, total plus n)
+}
+
+function main() {
+    if sum(1000000, 0) equals 500000500000 {
+        return 1
+    }
+    return 0
+}
+"#;
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let chunk = Compiler::new().compile(program).unwrap();
+        let mut vm = OptimizedVM::new();
+        assert_eq!(vm.execute(chunk).unwrap(), 1);
+        // The frame stack never outgrew its initial allocation
+        assert!(vm.frames.capacity() <= 32);
+    }
+
+    #[test]
+    fn test_calls_share_function_chunks() {
+        let source = r#"
+function countdown(n) {
+    if n equals 0 {
+        return 1 divided by n
+    }
+    return 1 plus countdown(n minus 1)
+}
+
+function main() {
+    return countdown(100)
+}
+"#;
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let chunk = Compiler::new().compile(program).unwrap();
+        let mut vm = OptimizedVM::new();
+        // The division by zero leaves all 101 countdown frames in place
+        assert!(vm.execute(chunk).is_err());
+
+        let countdown = &vm.functions["countdown"];
+        let frames: Vec<_> = vm
+            .frames
+            .iter()
+            .filter(|f| &*f.name == "countdown")
+            .collect();
+        assert_eq!(frames.len(), 101);
+        assert!(frames.iter().all(|f| Rc::ptr_eq(&f.chunk, countdown)));
+        // One reference per frame, plus the tables by name and by index
+        assert_eq!(Rc::strong_count(countdown), 103);
+    }
+
+    #[test]
+    fn test_string_constants_are_shared() {
+        let source = r#"
+function main() {
+    words is list
+    var i is 0
+    while i less than 1000 {
+        push "hello" onto words
+        i is i plus 1
+    }
+    return 0
+}
+"#;
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let chunk = Compiler::new().compile(program).unwrap();
+        let mut vm = OptimizedVM::new();
+        vm.execute(chunk).unwrap();
+
+        // Every load reused the constant's text instead of allocating a copy
+        let words = match &vm.globals["words"] {
+            Value::Array(words) => words,
+            other => panic!("expected an array, got {:?}", other),
+        };
+        assert_eq!(words.len(), 1000);
+        let first = match &words[0] {
+            Value::String(s) => s.clone(),
+            other => panic!("expected a string, got {:?}", other),
+        };
+        assert!(words
+            .iter()
+            .all(|word| matches!(word, Value::String(s) if Rc::ptr_eq(s, &first))));
+    }
+
+    #[test]
+    fn test_native_functions() {
+        let source = r#"
+function main() {
+    var x is double(20)
+    return x plus 2
+}
+"#;
+        let tokens = Lexer::new(source.to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let chunk = Compiler::new().compile(program).unwrap();
+
+        let mut vm = OptimizedVM::new();
+        vm.register_builtin(
+            "double",
+            1,
+            Box::new(|args| Ok(Value::Number(args[0].as_number()? * 2.0))),
+        );
+        assert_eq!(vm.execute(chunk.clone()).unwrap(), 42);
+
+        // Natives are held to their declared arity like any other function
+        let mut vm = OptimizedVM::new();
+        vm.register_builtin("double", 2, Box::new(|_| Ok(Value::Null)));
+        let err = vm.execute(chunk).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("Function 'double' expects 2 arguments, got 1"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_stack_underflow_is_an_error() {
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Constant::Number(1.0));
+        chunk.emit(Instruction::LoadConst(one), 1);
+        chunk.emit(Instruction::Pop, 1);
+        chunk.emit(Instruction::Pop, 1);
+        chunk.emit(Instruction::Halt, 1);
+
+        let err = OptimizedVM::new().execute(chunk).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "at line 1: Stack underflow at ip 2 in function '<toplevel>'"
+        );
+    }
+
+    #[test]
+    fn test_runtime_errors_report_the_line() {
+        let mut chunk = Chunk::new();
+        let one = chunk.add_constant(Constant::Number(1.0));
+        let zero = chunk.add_constant(Constant::Number(0.0));
+        chunk.emit(Instruction::LoadConst(one), 2);
+        chunk.emit(Instruction::Print, 2);
+        chunk.emit(Instruction::LoadConst(one), 3);
+        chunk.emit(Instruction::LoadConst(zero), 3);
+        chunk.emit(Instruction::Divide, 3);
+        chunk.emit(Instruction::Halt, 4);
+
+        let err = OptimizedVM::new().execute(chunk).unwrap_err();
+        assert_eq!(format!("{:#}", err), "at line 3: Division by zero");
+    }
+
+    #[test]
+    fn test_cached_globals_see_writes_in_callees() {
+        let source = r#"
+function bump() {
+    count is count plus 1
+    return 0
+}
+
+function set_first() {
+    items at 0 is 9
+    return 0
+}
+
+function main() {
+    count is 1
+    items is list 1, 2
+    var before is count plus items at 0
+    bump()
+    set_first()
+    return before times 100 plus count times 10 plus items at 0
+}
+"#;
+        // `count` and `items` were cached by the first read in main
+        assert_eq!(run(source).unwrap(), 200 + 20 + 9);
+    }
+
+    #[test]
+    fn test_host_writes_invalidate_cached_globals() {
+        let tokens = Lexer::new("function main() { return limit }".to_string()).tokenize();
+        let program = Parser::new(tokens).parse().unwrap();
+        let chunk = Compiler::new().compile(program).unwrap();
+
+        let mut vm = OptimizedVM::new();
+        vm.set_global("limit", Value::Number(3.0));
+        assert_eq!(vm.execute(chunk.clone()).unwrap(), 3);
+        vm.set_global("limit", Value::Number(4.0));
+        assert_eq!(vm.execute(chunk).unwrap(), 4);
+    }
+}
@@ -0,0 +1,686 @@
+/// Register-based VM
+///
+/// Runs the register bytecode produced by `registers::translate`. Instead of
+/// pushing and popping, each instruction names the registers it reads and
+/// writes, so arithmetic on locals needs no stack traffic at all:
+/// - Locals and temporaries share one register file, one window per frame
+/// - A call's frame starts at its first argument, so arguments are never copied
+/// - Arrays and maps updated into their own register are changed in place
+use crate::builtins::{array_index, parse_numeric, substring, Numeric, Rng};
+use crate::bytecode::*;
+use crate::error::RuntimeError;
+use crate::output::{render, Formatter, Plain};
+use crate::vm::{call_builtin, Value};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Call frame for function calls
+#[derive(Debug, Clone)]
+struct CallFrame {
+    chunk: Rc<RegisterChunk>,
+    ip: usize,
+    /// Index of the frame's first register in the register file
+    base: usize,
+}
+
+/// Register-based Virtual Machine
+pub struct RegisterVM {
+    /// Register file shared by every call frame
+    registers: Vec<Value>,
+
+    /// Global variables
+    globals: HashMap<String, Value>,
+
+    /// Function table shared by every call frame
+    functions: HashMap<String, Rc<RegisterChunk>>,
+
+    /// The same functions by index, for `CallIndexR`
+    function_table: Vec<Rc<RegisterChunk>>,
+
+    /// Call frames for function calls
+    frames: Vec<CallFrame>,
+
+    /// Most call frames a program may have at once
+    max_depth: usize,
+
+    /// Debug mode
+    debug: bool,
+
+    /// Generator behind `random` and `random_int`
+    rng: Rng,
+
+    /// How `print` and `write` show values
+    formatter: Box<dyn Formatter>,
+}
+
+impl Default for RegisterVM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegisterVM {
+    pub fn new() -> Self {
+        RegisterVM {
+            registers: Vec::with_capacity(256),
+            globals: HashMap::new(),
+            functions: HashMap::new(),
+            function_table: Vec::new(),
+            frames: Vec::with_capacity(32),
+            max_depth: DEFAULT_MAX_DEPTH,
+            debug: false,
+            rng: Rng::from_entropy(),
+            formatter: Box::new(Plain),
+        }
+    }
+
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Stop programs that nest calls more than `depth` deep
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.max_depth = depth;
+    }
+
+    /// Show printed values with `formatter` instead of the plain output
+    pub fn set_formatter(&mut self, formatter: Box<dyn Formatter>) {
+        self.formatter = formatter;
+    }
+
+    /// Make `random` and `random_int` reproducible
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Execute a chunk of register bytecode, reporting the source line of a
+    /// failing instruction
+    pub fn execute(&mut self, chunk: RegisterChunk) -> Result<i32> {
+        self.run(chunk).map_err(|err| match self.current_line() {
+            Some(line) => err.context(format!("at line {}", line)),
+            None => err,
+        })
+    }
+
+    /// Source line of the instruction being executed
+    fn current_line(&self) -> Option<usize> {
+        let frame = self.frames.last()?;
+        // ip has already been advanced past the current instruction
+        frame.chunk.line(frame.ip.checked_sub(1)?)
+    }
+
+    fn run(&mut self, mut chunk: RegisterChunk) -> Result<i32> {
+        // Function chunks are looked up from here regardless of the calling frame
+        (self.functions, self.function_table) =
+            function_tables(std::mem::take(&mut chunk.functions));
+
+        let chunk = Rc::new(chunk);
+        self.enter(0, 0, &chunk);
+        self.frames.push(CallFrame {
+            chunk,
+            ip: 0,
+            base: 0,
+        });
+
+        loop {
+            let frame_idx = self.frames.len() - 1;
+            let chunk = Rc::clone(&self.frames[frame_idx].chunk);
+            let ip = self.frames[frame_idx].ip;
+            let base = self.frames[frame_idx].base;
+
+            let instruction = chunk
+                .code
+                .get(ip)
+                .ok_or_else(|| anyhow!("Instruction pointer out of bounds"))?;
+            self.frames[frame_idx].ip += 1;
+
+            if self.debug {
+                println!(
+                    "Registers (base={}): {:?}",
+                    base,
+                    &self.registers[base..base + chunk.register_count]
+                );
+                println!("Execute[{}]: {:?}", ip, instruction);
+            }
+
+            match instruction {
+                RegInstruction::LoadConstR(dst, idx) => {
+                    let value = match &chunk.constants[*idx] {
+                        Constant::Number(n) => Value::Number(*n),
+                        Constant::Integer(n) => Value::Integer(*n),
+                        Constant::String(s) => Value::String(s.clone()),
+                        Constant::Boolean(b) => Value::Boolean(*b),
+                        Constant::Null => Value::Null,
+                    };
+                    self.registers[base + dst] = value;
+                }
+
+                RegInstruction::MoveR(dst, src) => {
+                    self.registers[base + dst] = self.registers[base + src].clone();
+                }
+
+                RegInstruction::LoadGlobalR(dst, name) => {
+                    let value = self
+                        .globals
+                        .get(name)
+                        .ok_or_else(|| RuntimeError::UndefinedVariable(name.to_string()))?
+                        .clone();
+                    self.registers[base + dst] = value;
+                }
+
+                RegInstruction::StoreGlobalR(name, src) => {
+                    let value = self.registers[base + src].clone();
+                    self.globals.insert(name.clone(), value);
+                }
+
+                RegInstruction::AddR(dst, a, b) => {
+                    let result = match (&self.registers[base + a], &self.registers[base + b]) {
+                        (Value::Number(x), Value::Number(y)) => Value::Number(x + y),
+                        (x, y) => x.add(y)?,
+                    };
+                    self.registers[base + dst] = result;
+                }
+
+                RegInstruction::SubR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = x.minus(y).into();
+                }
+
+                RegInstruction::MulR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = x.times(y).into();
+                }
+
+                RegInstruction::DivR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    if y.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
+                    }
+                    self.registers[base + dst] = x.divide(y).into();
+                }
+
+                RegInstruction::IntDivR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    if y.is_zero() {
+                        return Err(RuntimeError::DivisionByZero.into());
+                    }
+                    self.registers[base + dst] = x.floor_divide(y).into();
+                }
+
+                RegInstruction::ModR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    if y.is_zero() {
+                        return Err(RuntimeError::ModuloByZero.into());
+                    }
+                    self.registers[base + dst] = x.modulo(y).into();
+                }
+
+                RegInstruction::PowR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = x.power(y).into();
+                }
+
+                RegInstruction::BitwiseR(op, dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = op.apply(x, y)?.into();
+                }
+
+                RegInstruction::NegR(dst, a) => {
+                    let x = self.registers[base + a].as_numeric()?;
+                    self.registers[base + dst] = x.negate().into();
+                }
+
+                RegInstruction::IncR(dst, a) => {
+                    let x = self.registers[base + a].as_numeric()?;
+                    self.registers[base + dst] = x.plus(Numeric::Integer(1)).into();
+                }
+
+                RegInstruction::EqR(dst, a, b) => {
+                    let equal = values_equal(&self.registers[base + a], &self.registers[base + b]);
+                    self.registers[base + dst] = Value::Boolean(equal);
+                }
+
+                RegInstruction::NeR(dst, a, b) => {
+                    let equal = values_equal(&self.registers[base + a], &self.registers[base + b]);
+                    self.registers[base + dst] = Value::Boolean(!equal);
+                }
+
+                RegInstruction::GtR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = Value::Boolean(x > y);
+                }
+
+                RegInstruction::GeR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = Value::Boolean(x >= y);
+                }
+
+                RegInstruction::LtR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = Value::Boolean(x < y);
+                }
+
+                RegInstruction::LeR(dst, a, b) => {
+                    let (x, y) = self.numbers(base + a, base + b)?;
+                    self.registers[base + dst] = Value::Boolean(x <= y);
+                }
+
+                RegInstruction::AndR(dst, a, b) => {
+                    let result = self.registers[base + a].is_truthy()
+                        && self.registers[base + b].is_truthy();
+                    self.registers[base + dst] = Value::Boolean(result);
+                }
+
+                RegInstruction::OrR(dst, a, b) => {
+                    let result = self.registers[base + a].is_truthy()
+                        || self.registers[base + b].is_truthy();
+                    self.registers[base + dst] = Value::Boolean(result);
+                }
+
+                RegInstruction::NotR(dst, a) => {
+                    let result = !self.registers[base + a].is_truthy();
+                    self.registers[base + dst] = Value::Boolean(result);
+                }
+
+                RegInstruction::JumpR(target) => {
+                    self.frames[frame_idx].ip = *target;
+                }
+
+                RegInstruction::JumpIfFalseR(condition, target) => {
+                    if !self.registers[base + condition].is_truthy() {
+                        self.frames[frame_idx].ip = *target;
+                    }
+                }
+
+                RegInstruction::JumpIfTrueR(condition, target) => {
+                    if self.registers[base + condition].is_truthy() {
+                        self.frames[frame_idx].ip = *target;
+                    }
+                }
+
+                RegInstruction::CallR(name, first, arity) => {
+                    let callee = self.function(name, *arity)?;
+                    self.call(base + first, *arity, callee)?;
+                }
+
+                RegInstruction::CallIndexR(id, first, arity) => {
+                    let callee = self.function_at(*id, *arity)?;
+                    self.call(base + first, *arity, callee)?;
+                }
+
+                RegInstruction::CallBuiltinR(builtin, dst, first, arity) => {
+                    let args = &self.registers[base + first..base + first + arity];
+                    let result = call_builtin(*builtin, args, &mut self.rng)?;
+                    self.registers[base + dst] = result;
+                }
+
+                RegInstruction::TailCallR(name, first, arity) => {
+                    let callee = self.function(name, *arity)?;
+                    self.tail_call(frame_idx, base, *first, *arity, callee);
+                }
+
+                RegInstruction::TailCallIndexR(id, first, arity) => {
+                    let callee = self.function_at(*id, *arity)?;
+                    self.tail_call(frame_idx, base, *first, *arity, callee);
+                }
+
+                RegInstruction::ReturnR(src) => {
+                    let value = std::mem::replace(&mut self.registers[base + src], Value::Null);
+                    self.frames.pop();
+                    if self.frames.is_empty() {
+                        return Ok(value.exit_code());
+                    }
+                    self.registers[base] = value;
+                }
+
+                RegInstruction::ReturnNullR => {
+                    self.frames.pop();
+                    if self.frames.is_empty() {
+                        return Ok(0);
+                    }
+                    self.registers[base] = Value::Null;
+                }
+
+                RegInstruction::ExitR(code) => {
+                    let exit_code = self.registers[base + code].as_number()? as i32;
+                    // Unwind every frame at once
+                    self.frames.clear();
+                    return Ok(exit_code);
+                }
+
+                RegInstruction::MakeArrayR(dst, first, size) => {
+                    let elements = self.registers[base + first..base + first + size]
+                        .iter_mut()
+                        .map(|value| std::mem::replace(value, Value::Null))
+                        .collect();
+                    self.registers[base + dst] = Value::Array(elements);
+                }
+
+                RegInstruction::MakeMapR(dst, first, size) => {
+                    let mut map = HashMap::with_capacity(*size);
+                    for entry in 0..*size {
+                        let key = base + first + 2 * entry;
+                        let value = std::mem::replace(&mut self.registers[key + 1], Value::Null);
+                        map.insert(self.registers[key].as_map_key()?, value);
+                    }
+                    self.registers[base + dst] = Value::Map(map);
+                }
+
+                RegInstruction::GetIndexR(dst, target, index) => {
+                    let target = &self.registers[base + target];
+                    let index = &self.registers[base + index];
+                    let value = match target {
+                        Value::Map(_) => target.map_get(index)?,
+                        _ => {
+                            let index = index.as_number()?;
+                            match target {
+                                Value::Array(array) => {
+                                    array[array_index(index, array.len())?].clone()
+                                }
+                                other => {
+                                    return Err(RuntimeError::type_mismatch("array", &other).into())
+                                }
+                            }
+                        }
+                    };
+                    self.registers[base + dst] = value;
+                }
+
+                RegInstruction::SetIndexR(dst, target, index, value) => {
+                    let value = self.registers[base + value].clone();
+                    let index = self.registers[base + index].clone();
+                    let target = self.take_or_clone(base, *dst, *target);
+
+                    let updated = match target {
+                        Value::Map(_) => target.map_set(&index, value)?,
+                        target => {
+                            let index = index.as_number()?;
+                            match target {
+                                Value::Array(mut array) => {
+                                    let index = array_index(index, array.len())?;
+                                    array[index] = value;
+                                    Value::Array(array)
+                                }
+                                other => {
+                                    return Err(RuntimeError::type_mismatch("array", &other).into())
+                                }
+                            }
+                        }
+                    };
+                    self.registers[base + dst] = updated;
+                }
+
+                RegInstruction::MapGetR(dst, map, key) => {
+                    let value = self.registers[base + map].map_get(&self.registers[base + key])?;
+                    self.registers[base + dst] = value;
+                }
+
+                RegInstruction::MapSetR(dst, map, key, value) => {
+                    let value = self.registers[base + value].clone();
+                    let key = self.registers[base + key].clone();
+                    let map = self.take_or_clone(base, *dst, *map);
+                    self.registers[base + dst] = map.map_set(&key, value)?;
+                }
+
+                RegInstruction::ArrayPushR(dst, array, value) => {
+                    let value = self.registers[base + value].clone();
+                    let array = self.take_or_clone(base, *dst, *array);
+                    self.registers[base + dst] = array.array_push(value)?;
+                }
+
+                RegInstruction::ArrayPopR(dst, src) => {
+                    let array = self.take_or_clone(base, *dst, *src);
+                    let (last, array) = array.array_pop()?;
+                    self.registers[base + dst] = last;
+                    self.registers[base + dst + 1] = array;
+                }
+
+                RegInstruction::LengthR(dst, src) => {
+                    let len = match &self.registers[base + src] {
+                        Value::String(s) => s.chars().count(),
+                        Value::Array(a) => a.len(),
+                        Value::Map(m) => m.len(),
+                        other => {
+                            return Err(
+                                RuntimeError::type_mismatch("string, array or map", &other).into()
+                            )
+                        }
+                    };
+                    self.registers[base + dst] = Value::Integer(len as i64);
+                }
+
+                RegInstruction::UppercaseR(dst, src) => {
+                    let s = self.registers[base + src].as_string()?;
+                    self.registers[base + dst] = Value::String(s.to_uppercase().into());
+                }
+
+                RegInstruction::LowercaseR(dst, src) => {
+                    let s = self.registers[base + src].as_string()?;
+                    self.registers[base + dst] = Value::String(s.to_lowercase().into());
+                }
+
+                RegInstruction::SubstringR(dst, src, from, to) => {
+                    let to = self.registers[base + to].as_number()?;
+                    let from = self.registers[base + from].as_number()?;
+                    let s = self.registers[base + src].as_string()?;
+                    self.registers[base + dst] = Value::String(substring(&s, from, to)?.into());
+                }
+
+                RegInstruction::ContainsR(dst, src, needle) => {
+                    let needle = &self.registers[base + needle];
+                    // Arrays look for an equal element, strings for a substring
+                    let found = match &self.registers[base + src] {
+                        Value::Array(items) => items.iter().any(|item| values_equal(item, needle)),
+                        s => s.as_string()?.contains(needle.as_string()?.as_str()),
+                    };
+                    self.registers[base + dst] = Value::Boolean(found);
+                }
+
+                RegInstruction::IndexOfR(dst, src, needle) => {
+                    let needle = self.registers[base + needle].as_string()?;
+                    let s = self.registers[base + src].as_string()?;
+                    let index = s
+                        .find(needle.as_str())
+                        .map_or(-1, |i| s[..i].chars().count() as i64);
+                    self.registers[base + dst] = Value::Integer(index);
+                }
+
+                RegInstruction::ReplaceR(dst, src, pattern, replacement) => {
+                    let replacement = self.registers[base + replacement].as_string()?;
+                    let pattern = self.registers[base + pattern].as_string()?;
+                    let s = self.registers[base + src].as_string()?;
+                    self.registers[base + dst] =
+                        Value::String(s.replace(pattern.as_str(), &replacement).into());
+                }
+
+                RegInstruction::TrimR(dst, src) => {
+                    let s = self.registers[base + src].as_string()?;
+                    self.registers[base + dst] = Value::String(s.trim().into());
+                }
+
+                RegInstruction::SliceR(dst, array, from, to) => {
+                    let to = self.registers[base + to].as_number()?;
+                    let from = self.registers[base + from].as_number()?;
+                    self.registers[base + dst] = self.registers[base + array].slice(from, to)?;
+                }
+
+                RegInstruction::ReverseR(dst, src) => {
+                    let array = self.take_or_clone(base, *dst, *src);
+                    self.registers[base + dst] = array.reversed()?;
+                }
+
+                RegInstruction::SortR(dst, src) => {
+                    let array = self.take_or_clone(base, *dst, *src);
+                    self.registers[base + dst] = array.sorted()?;
+                }
+
+                RegInstruction::JoinR(dst, array, separator) => {
+                    let joined =
+                        self.registers[base + array].join(&self.registers[base + separator])?;
+                    self.registers[base + dst] = joined;
+                }
+
+                RegInstruction::TypeOfR(dst, src) => {
+                    let name = self.registers[base + src].type_name();
+                    self.registers[base + dst] = Value::String(name.into());
+                }
+
+                RegInstruction::PrintR(src) => {
+                    println!("{}", render(&self.registers[base + src], &*self.formatter));
+                }
+
+                RegInstruction::WriteR(src) => {
+                    print!("{}", render(&self.registers[base + src], &*self.formatter));
+                    io::stdout().flush()?;
+                }
+
+                RegInstruction::AssertR(src, message) => {
+                    if !self.registers[base + src].is_truthy() {
+                        return Err(RuntimeError::AssertionFailed(message.clone()).into());
+                    }
+                }
+
+                RegInstruction::ExpectArrayR(src) => {
+                    let value = &self.registers[base + src];
+                    if !matches!(value, Value::Array(_)) {
+                        return Err(RuntimeError::NotIterable(value.to_string()).into());
+                    }
+                }
+
+                RegInstruction::InputR(dst, prompt) => {
+                    let input = read_line(prompt.as_deref())?;
+                    let input = input.trim();
+
+                    self.registers[base + dst] = match parse_numeric(input) {
+                        Ok(n) => n.into(),
+                        Err(_) => Value::String(input.into()),
+                    };
+                }
+
+                RegInstruction::InputTextR(dst, prompt) => {
+                    let input = read_line(prompt.as_deref())?;
+                    self.registers[base + dst] = Value::String(input.into());
+                }
+
+                RegInstruction::HaltR(src) => {
+                    return Ok(src.map_or(0, |src| self.registers[base + src].exit_code()));
+                }
+            }
+        }
+    }
+
+    /// Push a frame for `callee` starting at `base`, where its arguments are
+    fn call(&mut self, base: usize, arity: usize, callee: Rc<RegisterChunk>) -> Result<()> {
+        check_depth(
+            self.frames.len() + 1,
+            self.max_depth,
+            &callee,
+            &self.functions,
+        )?;
+        self.enter(base, arity, &callee);
+        self.frames.push(CallFrame {
+            chunk: callee,
+            ip: 0,
+            base,
+        });
+        Ok(())
+    }
+
+    /// Run `callee` in place of frame `frame_idx`, which starts at `base`, with
+    /// the arguments in `first..first + arity`
+    fn tail_call(
+        &mut self,
+        frame_idx: usize,
+        base: usize,
+        first: Reg,
+        arity: usize,
+        callee: Rc<RegisterChunk>,
+    ) {
+        // Move the arguments down over the current frame's locals
+        for i in 0..arity {
+            self.registers.swap(base + i, base + first + i);
+        }
+        self.enter(base, arity, &callee);
+        self.frames[frame_idx].chunk = callee;
+        self.frames[frame_idx].ip = 0;
+    }
+
+    /// Look up a function by `CallIndex` number, checking it like a call by
+    /// name since a chunk that skipped `Chunk::validate` may hold any operands
+    fn function_at(&self, id: usize, arity: usize) -> Result<Rc<RegisterChunk>> {
+        let function = self
+            .function_table
+            .get(id)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(format!("#{}", id)))?;
+        if function.arity != arity {
+            check_arity(
+                function_name(&self.functions, function),
+                function.arity,
+                arity,
+            )?;
+        }
+        Ok(function.clone())
+    }
+
+    /// Look up a function, checking the call passes as many arguments as it takes
+    fn function(&self, name: &str, arity: usize) -> Result<Rc<RegisterChunk>> {
+        let function = self
+            .functions
+            .get(name)
+            .cloned()
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?;
+        check_arity(name, function.arity, arity)?;
+        Ok(function)
+    }
+
+    /// Prepare the registers of a frame starting at `base`: the first `arity`
+    /// hold the arguments and the rest start out null
+    fn enter(&mut self, base: usize, arity: usize, chunk: &RegisterChunk) {
+        let end = base + chunk.register_count.max(arity);
+        if self.registers.len() < end {
+            self.registers
+                .resize(end.max(self.registers.len() * 2), Value::Null);
+        }
+        for register in &mut self.registers[base + arity..end] {
+            *register = Value::Null;
+        }
+    }
+
+    /// The value of register `src`, moved out when the result replaces it anyway
+    fn take_or_clone(&mut self, base: usize, dst: Reg, src: Reg) -> Value {
+        if dst == src {
+            std::mem::replace(&mut self.registers[base + src], Value::Null)
+        } else {
+            self.registers[base + src].clone()
+        }
+    }
+
+    #[inline(always)]
+    fn numbers(&self, a: usize, b: usize) -> Result<(Numeric, Numeric)> {
+        // The right operand is checked first, like the stack VMs pop it first
+        let y = self.registers[b].as_numeric()?;
+        Ok((self.registers[a].as_numeric()?, y))
+    }
+}
+
+fn values_equal(a: &Value, b: &Value) -> bool {
+    if let (Some(x), Some(y)) = (a.numeric(), b.numeric()) {
+        return x.equals(y);
+    }
+    match (a, b) {
+        (Value::String(x), Value::String(y)) => x == y,
+        (Value::Boolean(x), Value::Boolean(y)) => x == y,
+        (Value::Null, Value::Null) => true,
+        (Value::Array(x), Value::Array(y)) => {
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|(a, b)| values_equal(a, b))
+        }
+        (Value::Map(x), Value::Map(y)) => {
+            x.len() == y.len()
+                && x.iter()
+                    .all(|(k, a)| y.get(k).is_some_and(|b| values_equal(a, b)))
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,173 @@
+/// Re-running a program whenever its source changes
+///
+/// Files are polled for their modification time and size rather than
+/// watched through filesystem events, which needs no platform-specific
+/// dependencies and is cheap for the handful of files a program has. A
+/// change is acted on once the files have stopped changing for one poll
+/// interval, so an editor that saves in several writes causes a single run.
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often the files are checked
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// What a file looked like when last checked, `None` while it doesn't exist
+type Stamp = Option<(SystemTime, u64)>;
+
+pub struct Watcher {
+    paths: Vec<PathBuf>,
+    stamps: Vec<Stamp>,
+    interval: Duration,
+}
+
+impl Watcher {
+    /// Start watching `paths` in their current state
+    pub fn new(paths: Vec<PathBuf>, interval: Duration) -> Self {
+        let stamps = paths.iter().map(|path| stamp(path)).collect();
+        Watcher {
+            paths,
+            stamps,
+            interval,
+        }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Block until a file has changed and the files have settled again
+    pub fn wait_for_change(&mut self) {
+        loop {
+            thread::sleep(self.interval);
+            if self.refresh() {
+                break;
+            }
+        }
+        while self.refresh() {
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Take new stamps, reporting whether any differ from the last ones
+    fn refresh(&mut self) -> bool {
+        let stamps: Vec<Stamp> = self.paths.iter().map(|path| stamp(path)).collect();
+        let changed = stamps != self.stamps;
+        self.stamps = stamps;
+        changed
+    }
+}
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// `file` and every file it imports, directly or not. Files that can't be
+/// read or parsed are still included, so fixing them triggers a run.
+pub fn sources(file: &Path) -> Vec<PathBuf> {
+    let mut files = vec![file.to_path_buf()];
+    let mut next = 0;
+    while next < files.len() {
+        let path = files[next].clone();
+        next += 1;
+        for import in imports_of(&path) {
+            let import = path.parent().unwrap_or(Path::new("")).join(import);
+            let seen = files.iter().any(|file| same_file(file, &import));
+            if !seen {
+                files.push(import);
+            }
+        }
+    }
+    files
+}
+
+fn imports_of(path: &Path) -> Vec<String> {
+    let Ok(source) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let tokens = Lexer::new(source).tokenize();
+    match Parser::new(tokens).parse() {
+        Ok(program) => program.imports,
+        Err(_) => Vec::new(),
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Call `run`, then call it again each time `file` or one of its imports
+/// changes. Never returns; the user stops it with Ctrl+C.
+pub fn watch(file: &Path, mut run: impl FnMut(&[PathBuf])) -> ! {
+    loop {
+        // Stamp the files before running so edits made during the run count
+        let mut watcher = Watcher::new(sources(file), POLL_INTERVAL);
+        run(watcher.paths());
+        watcher.wait_for_change();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("toplang_watch_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_modifying_a_file_ends_the_wait() {
+        let dir = scratch_dir("modify");
+        let file = dir.join("program.top");
+        fs::write(&file, "function main() { return 0 }").unwrap();
+
+        let (done, finished) = mpsc::channel();
+        let mut watcher = Watcher::new(vec![file.clone()], Duration::from_millis(10));
+        thread::spawn(move || {
+            watcher.wait_for_change();
+            done.send(()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(finished.try_recv().is_err(), "fired without a change");
+        fs::write(&file, "function main() { return 1 }").unwrap();
+        finished
+            .recv_timeout(Duration::from_secs(5))
+            .expect("no run after the file changed");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sources_follow_imports() {
+        let dir = scratch_dir("sources");
+        let main = dir.join("main.top");
+        fs::write(&main, "import \"lib/a.top\"\nfunction main() { return 0 }").unwrap();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        // Imports loop back to the main file, and b.top doesn't parse yet
+        fs::write(
+            dir.join("lib/a.top"),
+            "import \"b.top\"\nimport \"../main.top\"\nfunction a() { return 1 }",
+        )
+        .unwrap();
+        fs::write(dir.join("lib/b.top"), "function b( {").unwrap();
+
+        assert_eq!(
+            sources(&main),
+            vec![main.clone(), dir.join("lib/a.top"), dir.join("lib/b.top")]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
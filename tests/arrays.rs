@@ -0,0 +1,150 @@
+//! Growing and shrinking arrays behaves the same in the interpreter and
+//! the VMs
+
+mod common;
+
+use common::{run, run_interpreter, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_push_and_pop() {
+    let dir = ScratchDir::new("push_pop");
+    let file = dir.write_program(
+        r#"
+function squares(n) {
+    var result is list
+    var i is 0
+    while i less than n {
+        push i times i onto result
+        i is i plus 1
+    }
+    return result
+}
+
+function main() {
+    var items is squares(5)
+    print length of items
+    print items
+    var last is pop from items
+    print last
+    print items
+    pop from items
+    push "end" onto items
+    print items
+    return 0
+}
+"#,
+    );
+
+    let expected = "5\n[0, 1, 4, 9, 16]\n16\n[0, 1, 4, 9]\n[0, 1, 4, \"end\"]\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
+
+#[test]
+fn test_slice_reverse_sort_and_join() {
+    let dir = ScratchDir::new("array_operations");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var numbers is list 3, 1, 2
+    print sort numbers
+    print reverse numbers
+    print numbers
+    print sort list "pear", "apple", "fig"
+    print slice numbers from 1 to 3
+    print slice numbers from 2 to 2
+    print join numbers with ", "
+    print join list "a", true, list 1, 2 with "-"
+    print join (sort reverse numbers) with ""
+    print sort list
+    return 0
+}
+"#,
+    );
+
+    let expected = "[1, 2, 3]\n[2, 1, 3]\n[3, 1, 2]\n[\"apple\", \"fig\", \"pear\"]\n[1, 2]\n[]\n\
+                    3, 1, 2\na-true-[1, 2]\n123\n[]\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
+
+#[test]
+fn test_strings_inside_arrays_are_quoted() {
+    let dir = ScratchDir::new("array_printing");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var mixed is list "1", 1, true, "two words"
+    print mixed
+    var row is list 1, 2
+    var grid is list row, list "a", list "b"
+    print grid
+    var ages is map "bob" to 27
+    ages at "names" is list "bob"
+    ages at "note" is "says \"hi\""
+    print ages
+    print "plain"
+    print mixed at 0
+    return 0
+}
+"#,
+    );
+
+    let expected = "[\"1\", 1, true, \"two words\"]\n[[1, 2], [\"a\", [\"b\"]]]\n\
+                    {bob: 27, names: [\"bob\"], note: \"says \\\"hi\\\"\"}\nplain\n1\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
+
+#[test]
+fn test_contains_checks_membership() {
+    let dir = ScratchDir::new("array_contains");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var numbers is list 1, 2, 3
+    var names is list "ann", "ben"
+    print numbers contains 2
+    print numbers contains 5
+    print names contains "ben"
+    print names contains "be"
+    print list 1, 2, 3 contains 2
+    var empty is list
+    print empty contains 1
+    return 0
+}
+"#,
+    );
+
+    // Elements have to be equal; a substring of one isn't enough
+    let expected = "true\nfalse\ntrue\nfalse\ntrue\nfalse\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+        ["-b", "-O0"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
@@ -0,0 +1,74 @@
+//! `bitand`, `bitor`, `xor`, `shift left` and `shift right`
+
+mod common;
+
+use common::{run, run_compiled, run_failing, ScratchDir, TOPC};
+use std::process::Command;
+
+const MODES: [&[&str]; 5] = [
+    &[],
+    &["-b"],
+    &["-b", "--nanbox"],
+    &["-b", "--threaded"],
+    &["-b", "--registers"],
+];
+
+#[test]
+fn test_bitwise_operators() {
+    let dir = ScratchDir::new("bitwise");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var six is 6
+    var one is 1
+    print six bitand 3
+    print six bitor 3
+    print six xor 3
+    print one shift left 4
+    print minus 16 shift right 2
+    print 1 plus 1 shift left 2 bitor 1
+    return 0
+}
+"#,
+    );
+
+    // Arithmetic binds tighter than shifts, which bind tighter than bitor
+    let expected = "2\n7\n5\n16\n-4\n9\n";
+    for mode in MODES {
+        assert_eq!(
+            run(Command::new(TOPC).args(mode).arg(&file)),
+            expected,
+            "{:?}",
+            mode
+        );
+    }
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
+
+#[test]
+fn test_fractional_operand_is_an_error() {
+    let dir = ScratchDir::new("bitwise_fraction");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var half is 2.5
+    print half bitand 1
+    return 0
+}
+"#,
+    );
+
+    for mode in MODES {
+        let stderr = run_failing(Command::new(TOPC).args(mode).arg(&file));
+        assert!(
+            stderr.contains("'bitand' needs whole numbers, got 2.5"),
+            "{:?}: {}",
+            mode,
+            stderr
+        );
+    }
+
+    let shift = dir.write_program("function main() {\n print 1 shift left 64\n return 0\n}\n");
+    let stderr = run_failing(Command::new(TOPC).arg("-b").arg(&shift));
+    assert!(stderr.contains("can't shift by 64 places"), "{}", stderr);
+}
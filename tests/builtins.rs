@@ -0,0 +1,264 @@
+//! Built-in functions behave the same in every execution mode
+
+mod common;
+
+use common::{run, run_compiled, run_failing, run_interpreter, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_math_builtins_in_every_mode() {
+    let dir = ScratchDir::new("math_builtins");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var x is 16
+    print sqrt(x)
+    print floor(2.7)
+    print ceil(2.1)
+    print round(minus 2.5)
+    print abs(minus 3)
+    return 0
+}
+"#,
+    );
+
+    let expected = "4\n2\n3\n-3\n3\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
+
+#[test]
+fn test_seeded_random_is_reproducible() {
+    let dir = ScratchDir::new("seeded_random");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var i is 0
+    while i less than 5 {
+        print random_int(1, 6)
+        i is i plus 1
+    }
+    var x is random()
+    print x greater than or equals 0 and x less than 1
+    return 0
+}
+"#,
+    );
+
+    let seeded = |mode: &[&str]| {
+        let mut cmd = Command::new(TOPC);
+        cmd.args(mode).arg("--seed").arg("42").arg(&file);
+        run(&mut cmd)
+    };
+
+    let expected = "2\n2\n1\n1\n5\ntrue\n";
+    assert_eq!(seeded(&[]), expected);
+    assert_eq!(seeded(&["-b"]), expected);
+    assert_eq!(seeded(&["-b", "--nanbox"]), expected);
+}
+
+#[test]
+fn test_range() {
+    let dir = ScratchDir::new("range");
+    // Constant arguments are folded at compile time; `n` is not
+    let file = dir.write_program(
+        r#"
+function main() {
+    print range(0, 5)
+    print range(3, 3)
+    var n is 4
+    print range(0, n)
+    print range(n, 0, minus 2)
+    var total is 0
+    var values is range(1, n plus 1)
+    var i is 0
+    while i less than length of values {
+        total is total plus values at i
+        i is i plus 1
+    }
+    print total
+    return 0
+}
+"#,
+    );
+
+    let expected = "[0, 1, 2, 3, 4]\n[]\n[0, 1, 2, 3]\n[4, 2]\n10\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
+
+#[test]
+fn test_conversions() {
+    let dir = ScratchDir::new("conversions");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var answer is "42"
+    print to_number(answer) plus 1
+    print to_number(2.5)
+    print to_string(7)
+    print to_string(1 less than 2)
+    print to_boolean(0)
+    print to_boolean("no")
+    return 0
+}
+"#,
+    );
+
+    let expected = "43\n2.5\n7\ntrue\nfalse\ntrue\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
+
+#[test]
+fn test_to_number_rejects_text() {
+    let dir = ScratchDir::new("to_number_error");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var word is "abc"
+    print to_number(word)
+    return 0
+}
+"#,
+    );
+
+    for mode in [&[][..], &["-b"], &["-b", "--nanbox"]] {
+        let stderr = run_failing(Command::new(TOPC).args(mode).arg(&file));
+        assert!(
+            stderr.to_lowercase().contains("runtime error"),
+            "{}",
+            stderr
+        );
+    }
+
+    let exe = dir.path().join("program");
+    run(Command::new(TOPC).arg("-c").arg(&file).arg("-o").arg(&exe));
+    let stderr = run_failing(&mut Command::new(&exe));
+    assert!(
+        stderr.contains("Cannot convert \"abc\" to a number"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_min_max_clamp() {
+    let dir = ScratchDir::new("min_max_clamp");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var a is 3
+    var b is -7
+    print min(a, b)
+    print max(a, b)
+    print min(a, a)
+    print max(-1, -2.5)
+    print clamp(b, 0, 10)
+    print clamp(25, 0, 10)
+    print clamp(a, 0, 10)
+    print clamp(a, a, a)
+    return 0
+}
+"#,
+    );
+
+    let expected = "-7\n3\n3\n-1\n0\n10\n3\n3\n";
+    assert_eq!(run_interpreter(&file), expected);
+    for flags in [
+        &["-b"][..],
+        &["-b", "--nanbox"],
+        &["-b", "--threaded"],
+        &["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
+
+#[test]
+fn test_min_rejects_non_numbers() {
+    let dir = ScratchDir::new("min_error");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print min("a", 1)
+    return 0
+}
+"#,
+    );
+
+    for mode in [&[][..], &["-b"], &["-b", "--nanbox"]] {
+        let stderr = run_failing(Command::new(TOPC).args(mode).arg(&file));
+        assert!(
+            stderr.to_lowercase().contains("runtime error"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_exit_stops_from_inside_a_call() {
+    let dir = ScratchDir::new("exit");
+    let file = dir.write_program(
+        r#"
+function check(n) {
+    if n greater than 2 {
+        exit(3)
+    }
+    return n
+}
+
+function main() {
+    var i is 0
+    while true {
+        print check(i)
+        i is i plus 1
+    }
+    return 0
+}
+"#,
+    );
+
+    let modes: [&[&str]; 6] = [
+        &[],
+        &["-b"],
+        &["-b", "--nanbox"],
+        &["-b", "--threaded"],
+        &["-b", "--registers"],
+        &["-b", "--inline"],
+    ];
+    for mode in modes {
+        let output = Command::new(TOPC)
+            .args(mode)
+            .arg(&file)
+            .output()
+            .expect("Failed to spawn process");
+        assert_eq!(output.status.code(), Some(3), "{:?}", mode);
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "0\n1\n2\n");
+    }
+
+    let exe = dir.path().join("program");
+    run(Command::new(TOPC)
+        .arg("-c")
+        .arg(&file)
+        .arg("-o")
+        .arg(&exe)
+        .current_dir(dir.path()));
+    let output = Command::new(&exe)
+        .output()
+        .expect("Failed to spawn process");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "0\n1\n2\n");
+}
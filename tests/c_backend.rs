@@ -0,0 +1,178 @@
+//! Compiles TopLang programs with `topc -c` and checks that the native
+//! binary prints exactly what the bytecode VM prints.
+
+mod common;
+
+use common::{run, run_compiled, run_failing, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+fn assert_c_matches_vm(name: &str, source: &str) {
+    let dir = ScratchDir::new(name);
+    let file = dir.write_program(source);
+    assert_eq!(run_compiled(&dir, &file), run_vm(&file));
+}
+
+#[test]
+fn test_operators_match_vm() {
+    let source = r#"
+function check(a, b) {
+    print a modulo by b
+    print a greater than or equals b
+    print a less than or equals b
+    print a not equals b
+    print a greater than b and b greater than 0
+    print a less than b or b equals 0
+    print not a
+    print minus a
+}
+
+function main() {
+    check(7, 3)
+    check(3, 3)
+    check(minus 4, 5)
+    check(0, 2)
+    var i is 0
+    while i less than 3 {
+        i is i plus 1
+    }
+    print i
+    return 0
+}
+"#;
+    assert_c_matches_vm("c_operators", source);
+}
+
+#[test]
+fn test_emit_c_writes_source_without_compiling() {
+    let dir = ScratchDir::new("emit_c");
+    let file = dir.write_program(
+        r#"
+function double(n) {
+    return n times 2
+}
+
+function main() {
+    print double(21)
+    return 0
+}
+"#,
+    );
+    let c_file = dir.path().join("program.c");
+
+    run(Command::new(TOPC)
+        .arg("--emit-c")
+        .arg(&c_file)
+        .arg(&file)
+        .current_dir(dir.path()));
+
+    let c_code = std::fs::read_to_string(&c_file).unwrap();
+    assert!(c_code.contains("int main"), "{}", c_code);
+    assert!(c_code.contains("Value func_double("), "{}", c_code);
+    // Only the C source is written, no executable
+    assert!(!dir.path().join("program").exists());
+}
+
+#[test]
+fn test_many_globals_match_vm() {
+    // More globals than the old 256-entry table held, written in one
+    // function and read back in another
+    let count = 300;
+    let mut source = String::from("function fill() {\n");
+    for i in 0..count {
+        source.push_str(&format!("    g{} is {}\n", i, i));
+    }
+    source.push_str("    return 0\n}\n\nfunction main() {\n    fill()\n    total is 0\n");
+    for i in 0..count {
+        source.push_str(&format!("    total is total plus g{}\n", i));
+    }
+    source.push_str("    g7 is g7 times 2\n    print g7\n    print total\n    return 0\n}\n");
+
+    assert_c_matches_vm("c_globals", &source);
+}
+
+#[test]
+fn test_for_loops_in_functions_with_parameters_match_vm() {
+    // Loop counters and block-scoped locals share the frame with the
+    // parameters, which come first
+    let source = r#"
+function weighted(scale, offset) {
+    var total is 0
+    for (var i is 1 i less than 5 i is i plus 1) {
+        var step is i times scale
+        if step greater than 5 {
+            var bonus is offset
+            total is total plus bonus
+        }
+        total is total plus step
+    }
+    for (var j is 0 j less than 2 j is j plus 1) {
+        var k is j plus offset
+        total is total plus k
+    }
+    return total
+}
+
+function main() {
+    print weighted(2, 10)
+    print weighted(1, 0)
+    var sum is 0
+    for (var n is 0 n less than 3 n is n plus 1) {
+        sum is sum plus weighted(n, n)
+    }
+    print sum
+    return 0
+}
+"#;
+    assert_c_matches_vm("c_for_loops", source);
+}
+
+#[test]
+fn test_division_by_zero_is_an_error() {
+    for (name, operator, quotient) in [
+        ("c_int_div_zero", "integer divided by", "3\n"),
+        ("c_div_zero", "divided by", "3.5\n"),
+    ] {
+        let dir = ScratchDir::new(name);
+        let file = dir.write_program(&format!(
+            "function split(a, b) {{\n    return a {} b\n}}\n\n\
+             function main() {{\n    print split(7, 2)\n    print split(7, 0)\n    return 0\n}}\n",
+            operator
+        ));
+        let exe = dir.path().join("program");
+        run(Command::new(TOPC)
+            .arg("-c")
+            .arg(&file)
+            .arg("-o")
+            .arg(&exe)
+            .current_dir(dir.path()));
+
+        let output = Command::new(&exe).output().unwrap();
+        assert_eq!(output.status.code(), Some(1), "{}", operator);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Error: Division by zero"), "{}", stderr);
+        assert!(run_failing(Command::new(TOPC).arg("-b").arg(&file)).contains("Division by zero"));
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), quotient);
+    }
+}
+
+#[test]
+fn test_lists_are_rejected_rather_than_miscompiled() {
+    let dir = ScratchDir::new("c_lists");
+    let file = dir.write_program(
+        "function main() {\n    for each x in list 4, 5, 6 {\n        print x\n    }\n    return 0\n}\n",
+    );
+    let stderr = run_failing(
+        Command::new(TOPC)
+            .arg("-c")
+            .arg(&file)
+            .arg("-o")
+            .arg(dir.path().join("program"))
+            .current_dir(dir.path()),
+    );
+    assert!(
+        stderr.contains("MakeArray(3) is not supported by the C backend"),
+        "{}",
+        stderr
+    );
+    assert!(!dir.path().join("program").exists());
+}
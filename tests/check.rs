@@ -0,0 +1,55 @@
+//! `topc --check`
+
+mod common;
+
+use common::{run_failing, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_unknown_function_fails_the_check() {
+    let dir = ScratchDir::new("check_unknown_function");
+    let file = dir.write_program(
+        "function main() {\n    var total is 0\n    print totl(total)\n    return 0\n}\n",
+    );
+    let stderr = run_failing(Command::new(TOPC).arg("--check").arg(&file));
+    assert!(
+        stderr.contains("Undefined function: totl at line 3"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("Found 1 error"), "{}", stderr);
+}
+
+#[test]
+fn test_adding_a_boolean_to_a_number_fails_the_check() {
+    let dir = ScratchDir::new("check_number_plus_boolean");
+    let file = dir
+        .write_program("function main() {\n    print 1\n    print 2 plus true\n    return 0\n}\n");
+    let stderr = run_failing(Command::new(TOPC).arg("--check").arg(&file));
+    assert!(
+        stderr.contains("Cannot add number and boolean at line 3"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_check_passes_with_warnings_and_does_not_run() {
+    let dir = ScratchDir::new("check_warnings");
+    let file = dir.write_program("function main() {\n    print limit\n    return 3\n}\n");
+    let output = Command::new(TOPC)
+        .arg("--check")
+        .arg(&file)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("warning: Undefined variable: limit at line 2"),
+        "{}",
+        stderr
+    );
+    // The program itself never ran
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("No errors in"), "{}", stdout);
+}
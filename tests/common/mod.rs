@@ -0,0 +1,105 @@
+//! Helpers shared by the integration tests, which drive the `topc` binary
+
+#![allow(dead_code)] // Not every test file uses every helper
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub const TOPC: &str = env!("CARGO_BIN_EXE_topc");
+
+/// A fresh scratch directory, removed when dropped
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("toplang_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        ScratchDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Write `source` to `program.top` and return its path
+    pub fn write_program(&self, source: &str) -> PathBuf {
+        let file = self.0.join("program.top");
+        std::fs::write(&file, source).unwrap();
+        file
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run a command, assert it succeeded and return its stdout
+pub fn run(cmd: &mut Command) -> String {
+    let output = cmd.output().expect("Failed to spawn process");
+    assert!(
+        output.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Run a command with `input` on its stdin, assert it succeeded and return
+/// its stdout
+pub fn run_with_input(cmd: &mut Command, input: &str) -> String {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn process");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "command failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Output of the program under the interpreter
+pub fn run_interpreter(file: &Path) -> String {
+    run(Command::new(TOPC).arg(file))
+}
+
+/// Output of the program on the bytecode VM
+pub fn run_vm(file: &Path) -> String {
+    run(Command::new(TOPC).arg("-b").arg(file))
+}
+
+/// Output of the program compiled to a native executable with `topc -c`
+pub fn run_compiled(dir: &ScratchDir, file: &Path) -> String {
+    let exe = dir.path().join("program");
+    run(Command::new(TOPC)
+        .arg("-c")
+        .arg(file)
+        .arg("-o")
+        .arg(&exe)
+        .current_dir(dir.path()));
+    run(&mut Command::new(&exe))
+}
+
+/// Run a command, assert it failed and return its stderr
+pub fn run_failing(cmd: &mut Command) -> String {
+    let output = cmd.output().expect("Failed to spawn process");
+    assert!(
+        !output.status.success(),
+        "command unexpectedly succeeded: {}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    String::from_utf8(output.stderr).unwrap()
+}
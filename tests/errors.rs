@@ -0,0 +1,289 @@
+//! Bad programs are rejected with messages that point at the problem
+
+mod common;
+
+use common::{run, run_failing, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_illegal_character_is_reported_by_the_lexer() {
+    let dir = ScratchDir::new("illegal_character");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var price is $5
+    return 0
+}
+"#,
+    );
+
+    let stderr = run_failing(Command::new(TOPC).arg(&file));
+    assert!(
+        stderr.contains("Unexpected character '$' at line 3, column 18"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("var price is $5"), "{}", stderr);
+    assert!(!stderr.contains("Failed to parse"), "{}", stderr);
+}
+
+/// Stderr of `source` on each bytecode VM, all of which must reject it
+fn vm_errors(name: &str, source: &str) -> Vec<String> {
+    let dir = ScratchDir::new(name);
+    let file = dir.write_program(source);
+    [
+        &["-b"][..],
+        &["-b", "--nanbox"],
+        &["-b", "--threaded"],
+        &["-b", "--registers"],
+    ]
+    .iter()
+    .map(|flags| run_failing(Command::new(TOPC).args(*flags).arg(&file)))
+    .collect()
+}
+
+#[test]
+fn test_call_with_too_few_arguments_is_an_error() {
+    let source = r#"
+function add(a, b) {
+    return a plus b
+}
+
+function main() {
+    var x is 1
+    print add(x)
+    return 0
+}
+"#;
+    for stderr in vm_errors("too_few_arguments", source) {
+        assert!(
+            stderr.contains("Function 'add' expects 2 arguments, got 1"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_call_with_too_many_arguments_is_an_error() {
+    let source = r#"
+function add(a, b) {
+    return a plus b
+}
+
+function main() {
+    print add(1, 2, 3)
+    return 0
+}
+"#;
+    for stderr in vm_errors("too_many_arguments", source) {
+        assert!(
+            stderr.contains("Function 'add' expects 2 arguments, got 3"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_invalid_substring_reports_indices_and_length() {
+    let cases = [
+        ("5", "2", "substring 5..2 of string length 4 is invalid"),
+        ("1", "9", "substring 1..9 of string length 4 is invalid"),
+        (
+            "(0 minus 1)",
+            "2",
+            "substring -1..2 of string length 4 is invalid: indices can't be negative",
+        ),
+    ];
+    for (from, to, message) in cases {
+        // Constant indices go through the optimizer, variables don't
+        for indices in [
+            format!("var s is substring \"abcd\" from {} to {}", from, to),
+            format!(
+                "var a is {}\n    var b is {}\n    var s is substring \"abcd\" from a to b",
+                from, to
+            ),
+        ] {
+            let source = format!(
+                "function main() {{\n    {}\n    print s\n    return 0\n}}\n",
+                indices
+            );
+            let dir = ScratchDir::new("substring_interpreter");
+            let file = dir.write_program(&source);
+            let mut errors = vm_errors("substring", &source);
+            errors.push(run_failing(Command::new(TOPC).arg(&file)));
+            for stderr in errors {
+                assert!(stderr.contains(message), "{}: {}", source, stderr);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_invalid_slice_and_sort_are_errors() {
+    let cases = [
+        (
+            "slice list 1, 2 from 1 to 5",
+            "slice 1..5 of array length 2 is invalid",
+        ),
+        (
+            "slice list 1, 2 from (0 minus 1) to 1",
+            "slice -1..1 of array length 2 is invalid: indices can't be negative",
+        ),
+        (
+            "sort list 2, \"b\", 1",
+            "Cannot sort an array of both numbers and strings",
+        ),
+        (
+            "sort list 1, true",
+            "Cannot sort an array containing a boolean",
+        ),
+    ];
+    for (expr, message) in cases {
+        let source = format!("function main() {{\n    print {}\n    return 0\n}}\n", expr);
+        let dir = ScratchDir::new("array_operation_interpreter");
+        let file = dir.write_program(&source);
+        let mut errors = vm_errors("array_operation", &source);
+        errors.push(run_failing(Command::new(TOPC).arg(&file)));
+        for stderr in errors {
+            assert!(stderr.contains(message), "{}: {}", source, stderr);
+        }
+    }
+}
+
+#[test]
+fn test_array_index_errors_report_length() {
+    let cases = [
+        ("7", "index 7 out of bounds for array of length 3"),
+        (
+            "(0 minus 1)",
+            "index -1 out of bounds for array of length 3: indices can't be negative",
+        ),
+    ];
+    for (index, message) in cases {
+        for access in [
+            format!("print items at {}", index),
+            format!("items at {} is 0", index),
+        ] {
+            let source = format!(
+                "function main() {{\n    var items is list 1, 2, 3\n    {}\n    return 0\n}}\n",
+                access
+            );
+            let dir = ScratchDir::new("array_index_interpreter");
+            let file = dir.write_program(&source);
+            let mut errors = vm_errors("array_index", &source);
+            errors.push(run_failing(Command::new(TOPC).arg(&file)));
+            for stderr in errors {
+                assert!(stderr.contains(message), "{}: {}", source, stderr);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_runaway_recursion_is_stopped() {
+    let source = r#"
+function count_up(n) {
+    return 1 plus count_up(n plus 1)
+}
+
+function main() {
+    print count_up(0)
+    return 0
+}
+"#;
+    for stderr in vm_errors("runaway_recursion", source) {
+        assert!(
+            stderr.contains(
+                "maximum recursion depth exceeded, possible infinite recursion in 'count_up'"
+            ),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_max_depth_sets_the_limit() {
+    let dir = ScratchDir::new("max_depth");
+    let file = dir.write_program(
+        r#"
+function depth(n) {
+    if n equals 0 {
+        return 0
+    }
+    return 1 plus depth(n minus 1)
+}
+
+function main() {
+    print depth(50)
+    return 0
+}
+"#,
+    );
+
+    let output = run(Command::new(TOPC)
+        .args(["-b", "--max-depth", "60"])
+        .arg(&file));
+    assert_eq!(output, "50\n");
+    let stderr = run_failing(
+        Command::new(TOPC)
+            .args(["-b", "--max-depth", "40"])
+            .arg(&file),
+    );
+    assert!(
+        stderr.contains("infinite recursion in 'depth'"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_max_stack_stops_programs_that_need_more_values() {
+    let dir = ScratchDir::new("max_stack");
+    let elements = vec!["n"; 100].join(", ");
+    let file = dir.write_program(&format!(
+        "function fill(n) {{\n    var items is list {}\n    return length of items\n}}\n\n\
+         function main() {{\n    print fill(1)\n    return 0\n}}\n",
+        elements
+    ));
+
+    for mode in [&["-b"][..], &["-b", "--nanbox"], &["-b", "--threaded"]] {
+        assert_eq!(run(Command::new(TOPC).args(mode).arg(&file)), "100\n");
+        let stderr = run_failing(
+            Command::new(TOPC)
+                .args(mode)
+                .args(["--max-stack", "64"])
+                .arg(&file),
+        );
+        assert!(
+            stderr.contains("value stack overflow: more than 64 values in use at once"),
+            "{:?}: {}",
+            mode,
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_truncated_bytecode_file_is_rejected() {
+    let dir = ScratchDir::new("truncated_bytecode");
+    let file = dir.write_program("function main() {\n    print \"hi\"\n    return 0\n}\n");
+    let compiled = dir.path().join("program.topc");
+    run(Command::new(TOPC)
+        .arg("--emit-bytecode")
+        .arg(&compiled)
+        .arg(&file));
+    assert_eq!(run(Command::new(TOPC).arg(&compiled)), "hi\n");
+
+    let bytes = std::fs::read(&compiled).unwrap();
+    std::fs::write(&compiled, &bytes[..bytes.len() - 3]).unwrap();
+    let stderr = run_failing(Command::new(TOPC).arg(&compiled));
+    assert!(
+        stderr.contains("Invalid bytecode: Unexpected end of bytecode"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
@@ -0,0 +1,32 @@
+//! `topc --format`
+
+mod common;
+
+use common::{run, ScratchDir, TOPC};
+use std::path::Path;
+use std::process::Command;
+
+fn format(file: &Path) -> String {
+    run(Command::new(TOPC).arg("--format").arg(file))
+}
+
+fn fixture(name: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/format")
+        .join(name);
+    std::fs::read_to_string(path).unwrap()
+}
+
+#[test]
+fn test_format_matches_golden_file() {
+    let dir = ScratchDir::new("format_golden");
+    let file = dir.write_program(&fixture("messy.top"));
+    assert_eq!(format(&file), fixture("formatted.top"));
+}
+
+#[test]
+fn test_formatting_formatted_source_is_a_no_op() {
+    let dir = ScratchDir::new("format_idempotent");
+    let file = dir.write_program(&fixture("formatted.top"));
+    assert_eq!(format(&file), fixture("formatted.top"));
+}
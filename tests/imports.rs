@@ -0,0 +1,124 @@
+//! Programs split over several files with `import`
+
+mod common;
+
+use common::{run_failing, run_interpreter, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_imported_functions_are_callable() {
+    let dir = ScratchDir::new("imports");
+    std::fs::write(
+        dir.path().join("math.top"),
+        r#"
+function square(n) {
+    return n times n
+}
+"#,
+    )
+    .unwrap();
+    let file = dir.write_program(
+        r#"
+import "math.top"
+
+function main() {
+    print square(4)
+    return 0
+}
+"#,
+    );
+
+    assert_eq!(run_interpreter(&file), "16\n");
+    assert_eq!(run_vm(&file), "16\n");
+}
+
+#[test]
+fn test_imports_are_relative_to_the_importing_file() {
+    let dir = ScratchDir::new("nested_imports");
+    std::fs::create_dir_all(dir.path().join("lib")).unwrap();
+    std::fs::write(
+        dir.path().join("lib/shapes.top"),
+        r#"
+import "math.top"
+
+function area(side) {
+    return square(side)
+}
+"#,
+    )
+    .unwrap();
+    // Imports the file importing it, which must not loop
+    std::fs::write(
+        dir.path().join("lib/math.top"),
+        r#"
+import "shapes.top"
+
+function square(n) {
+    return n times n
+}
+"#,
+    )
+    .unwrap();
+    let file = dir.write_program(
+        r#"
+import "lib/shapes.top"
+import "lib/math.top"
+
+function main() {
+    print area(3)
+    return 0
+}
+"#,
+    );
+
+    assert_eq!(run_interpreter(&file), "9\n");
+    assert_eq!(run_vm(&file), "9\n");
+}
+
+#[test]
+fn test_function_defined_in_two_files_is_an_error() {
+    let dir = ScratchDir::new("duplicate_imports");
+    std::fs::write(
+        dir.path().join("math.top"),
+        r#"
+function main() {
+    return 1
+}
+"#,
+    )
+    .unwrap();
+    let file = dir.write_program(
+        r#"
+import "math.top"
+
+function main() {
+    return 0
+}
+"#,
+    );
+
+    let stderr = run_failing(Command::new(TOPC).arg(&file));
+    assert!(
+        stderr.contains("Function 'main' is defined in both"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("math.top"), "{}", stderr);
+}
+
+#[test]
+fn test_missing_import_is_an_error() {
+    let dir = ScratchDir::new("missing_import");
+    let file = dir.write_program(
+        r#"
+import "nowhere.top"
+
+function main() {
+    return 0
+}
+"#,
+    );
+
+    let stderr = run_failing(Command::new(TOPC).arg(&file));
+    assert!(stderr.contains("nowhere.top"), "{}", stderr);
+}
@@ -0,0 +1,57 @@
+//! `topc --inline`
+
+mod common;
+
+use common::{run, run_interpreter, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_inlined_output_matches() {
+    let dir = ScratchDir::new("inline");
+    let file = dir.write_program(
+        r#"
+function square(x) {
+    return x times x
+}
+
+function difference(a, b) {
+    return a minus b
+}
+
+function noisy(value) {
+    print "evaluating " plus value
+    return value
+}
+
+function main() {
+    var n is 7
+    print square(n)
+    print square(noisy(3))
+    print difference(noisy(10), noisy(4))
+    var total is 0
+    for (var i is 0 i less than 4 i is i plus 1) {
+        total is total plus square(i)
+    }
+    print total
+    if n greater than 5 and square(noisy(2)) equals 4 {
+        print "short-circuit kept"
+    }
+    return 0
+}
+"#,
+    );
+
+    let expected = run_interpreter(&file);
+    assert_eq!(
+        expected,
+        "49\nevaluating 3\n9\nevaluating 10\nevaluating 4\n6\n14\nevaluating 2\nshort-circuit kept\n"
+    );
+    for flags in [
+        &["-b"][..],
+        &["-b", "--inline"],
+        &["-b", "--inline", "--nanbox"],
+    ] {
+        let output = run(Command::new(TOPC).args(flags).arg(&file));
+        assert_eq!(output, expected, "{:?}", flags);
+    }
+}
@@ -0,0 +1,53 @@
+//! Reading lines with `ask` and `ask text`
+
+mod common;
+
+use common::{run, run_with_input, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_ask_text_keeps_the_line_as_typed() {
+    let dir = ScratchDir::new("ask_text");
+    let file = dir.write_program(
+        r#"
+function main() {
+    ask code "Code: "
+    ask text zip "Zip: "
+    ask text name
+    print code
+    print type of code
+    print zip
+    print type of zip
+    print name
+    return 0
+}
+"#,
+    );
+
+    let input = "007\n007\n  Ada Lovelace  \n";
+    let expected = "Code: Zip: 7\nnumber\n007\nstring\n  Ada Lovelace  \n";
+    let modes: [&[&str]; 5] = [
+        &[],
+        &["-b"],
+        &["-b", "--nanbox"],
+        &["-b", "--threaded"],
+        &["-b", "--registers"],
+    ];
+    for mode in modes {
+        assert_eq!(
+            run_with_input(Command::new(TOPC).args(mode).arg(&file), input),
+            expected,
+            "{:?}",
+            mode
+        );
+    }
+
+    let exe = dir.path().join("program");
+    run(Command::new(TOPC)
+        .arg("-c")
+        .arg(&file)
+        .arg("-o")
+        .arg(&exe)
+        .current_dir(dir.path()));
+    assert_eq!(run_with_input(&mut Command::new(&exe), input), expected);
+}
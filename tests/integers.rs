@@ -0,0 +1,74 @@
+//! Whole numbers stay exact 64-bit integers until an operation needs a fraction
+
+mod common;
+
+use common::{run, ScratchDir, TOPC};
+use std::process::Command;
+
+// The native backends hold every number as a double
+const MODES: [&[&str]; 5] = [
+    &[],
+    &["-b"],
+    &["-b", "--threaded"],
+    &["-b", "--registers"],
+    &["-b", "--nanbox"],
+];
+
+#[test]
+fn test_large_integer_arithmetic_is_exact() {
+    let dir = ScratchDir::new("integers_exact");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var big is 9007199254740993
+    print big plus 2
+    print big times 1000 minus 1
+    print 9223372036854775807 minus 1
+    print 2 to the power of 62
+    print big modulo by 10
+    return 0
+}
+"#,
+    );
+
+    let expected = "9007199254740995\n9007199254740992999\n9223372036854775806\n\
+                    4611686018427387904\n3\n";
+    for mode in MODES {
+        assert_eq!(
+            run(Command::new(TOPC).args(mode).arg(&file)),
+            expected,
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn test_mixed_integer_and_float_arithmetic() {
+    let dir = ScratchDir::new("integers_mixed");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print 1 plus 0.5
+    print 7 divided by 2
+    print 6 divided by 2
+    print 2.5 times 2
+    print 3 equals 3.0
+    print 2 less than 2.5
+    print 9223372036854775807 plus 1
+    return 0
+}
+"#,
+    );
+
+    // Overflow falls back to a float rather than wrapping
+    let expected = "1.5\n3.5\n3\n5\ntrue\ntrue\n9223372036854776000\n";
+    for mode in MODES {
+        assert_eq!(
+            run(Command::new(TOPC).args(mode).arg(&file)),
+            expected,
+            "{:?}",
+            mode
+        );
+    }
+}
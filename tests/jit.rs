@@ -0,0 +1,338 @@
+//! `topc --jit`, which compiles to native code in memory with Cranelift
+
+mod common;
+
+use common::{run, run_failing, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_dump_ir_prints_each_function() {
+    let dir = ScratchDir::new("dump_ir");
+    let file = dir.write_program(
+        r#"
+function add(a, b) {
+    return a plus b
+}
+
+function main() {
+    print add(2, 3)
+    return 0
+}
+"#,
+    );
+
+    let output = run(Command::new(TOPC).args(["--jit", "--dump-ir"]).arg(&file));
+    let add = output
+        .split("== add ==")
+        .nth(1)
+        .expect("no IR for add")
+        .split("== ")
+        .next()
+        .unwrap();
+    // Values are NaN-boxed, so `plus` is a call into the runtime
+    assert!(add.contains("function "), "{}", add);
+    assert!(add.contains("= call fn0(v0, v1)"), "{}", add);
+    assert!(output.contains("== main =="), "{}", output);
+    assert!(output.contains("== entry point =="), "{}", output);
+    assert!(output.ends_with("}\n\n5\n"), "{}", output);
+
+    // Without the flag only the program's output is printed
+    let output = run(Command::new(TOPC).arg("--jit").arg(&file));
+    assert_eq!(output, "5\n");
+}
+
+#[test]
+fn test_locals_assigned_in_loops_match_vm() {
+    let dir = ScratchDir::new("jit_for_loops");
+    let file = dir.write_program(
+        r#"
+function weighted(scale, offset) {
+    var total is 0
+    for (var i is 1 i less than 5 i is i plus 1) {
+        var step is i times scale
+        if step greater than 5 {
+            var bonus is offset
+            total is total plus bonus
+        }
+        total is total plus step
+    }
+    for (var j is 0 j less than 2 j is j plus 1) {
+        var k is j plus offset
+        total is total plus k
+    }
+    return total
+}
+
+function main() {
+    print weighted(2, 10)
+    print weighted(1, 0)
+    var sum is 0
+    for (var n is 0 n less than 3 n is n plus 1) {
+        sum is sum plus weighted(n, n)
+    }
+    print sum
+    return 0
+}
+"#,
+    );
+
+    // Each assignment inside the loop body reaches the next iteration
+    assert_eq!(
+        run(Command::new(TOPC).arg("--jit").arg(&file)),
+        run_vm(&file)
+    );
+    assert_eq!(run_vm(&file), "61\n11\n43\n");
+}
+
+#[test]
+fn test_strings_and_arrays_match_vm() {
+    let dir = ScratchDir::new("jit_strings_arrays");
+    let file = dir.write_program(
+        r#"
+function size(value) {
+    return length of value
+}
+
+function main() {
+    var xs is list 1, 2.5, "a"
+    print size("héllo")
+    print size(xs)
+    print xs
+    print "n=" plus length of xs
+    return 0
+}
+"#,
+    );
+
+    // Strings count characters, not bytes, and arrays print like the VM's
+    assert_eq!(
+        run(Command::new(TOPC).arg("--jit").arg(&file)),
+        run_vm(&file)
+    );
+    assert_eq!(run_vm(&file), "5\n3\n[1, 2.5, \"a\"]\nn=3\n");
+}
+
+#[test]
+fn test_index_out_of_bounds_is_an_error() {
+    let dir = ScratchDir::new("jit_out_of_bounds");
+    for (name, statement) in [("get", "print xs at 3"), ("set", "xs at 3 is 0")] {
+        let file = dir.write_program(&format!(
+            "function main() {{\n    var xs is list 1, 2, 3\n    {}\n    return 0\n}}\n",
+            statement
+        ));
+        let stderr = run_failing(Command::new(TOPC).arg("--jit").arg(&file));
+        assert!(
+            stderr.contains("index 3 out of bounds for array of length 3"),
+            "{}: {}",
+            name,
+            stderr
+        );
+    }
+}
+
+/// Output of `source` under `--jit`, checked against the bytecode VM
+fn assert_jit_matches_vm(name: &str, source: &str, expected: &str) {
+    let dir = ScratchDir::new(name);
+    let file = dir.write_program(source);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(run(Command::new(TOPC).arg("--jit").arg(&file)), expected);
+}
+
+#[test]
+fn test_while_not_matches_vm() {
+    // `not` and the loop's exit test fold into one JumpIfTrue
+    assert_jit_matches_vm(
+        "jit_while_not",
+        r#"
+function count_up(limit) {
+    var i is 0
+    while not (i equals limit) {
+        i is i plus 1
+    }
+    return i
+}
+
+function main() {
+    print count_up(3)
+    return 0
+}
+"#,
+        "3\n",
+    );
+}
+
+#[test]
+fn test_short_circuit_or_matches_vm() {
+    assert_jit_matches_vm(
+        "jit_or",
+        r#"
+function either(a, b) {
+    return a or b
+}
+
+function main() {
+    print either(false, true)
+    print either(false, false)
+    print either(true, false)
+    return 0
+}
+"#,
+        "true\nfalse\ntrue\n",
+    );
+}
+
+#[test]
+fn test_do_while_matches_vm() {
+    assert_jit_matches_vm(
+        "jit_do_while",
+        r#"
+function repeat_until(limit) {
+    var n is 0
+    do {
+        n is n plus 1
+    } while n less than limit
+    return n
+}
+
+function main() {
+    print repeat_until(4)
+    print repeat_until(0)
+    return 0
+}
+"#,
+        "4\n1\n",
+    );
+}
+
+#[test]
+fn test_otherwise_matches_vm() {
+    assert_jit_matches_vm(
+        "jit_otherwise",
+        r#"
+function nothing() {
+    var unused is 0
+}
+
+function fallback(a, b) {
+    return a otherwise b
+}
+
+function main() {
+    print fallback(7, 8)
+    print fallback(nothing(), 8)
+    return 0
+}
+"#,
+        "7\n8\n",
+    );
+}
+
+#[test]
+fn test_tail_calls_match_vm() {
+    assert_jit_matches_vm(
+        "jit_tail_calls",
+        r#"
+function sum(n, acc) {
+    if n equals 0 {
+        return acc
+    }
+    return sum(n minus 1, acc plus n)
+}
+
+function main() {
+    print sum(100, 0)
+    return 0
+}
+"#,
+        "5050\n",
+    );
+}
+
+#[test]
+fn test_empty_string_matches_vm() {
+    assert_jit_matches_vm(
+        "jit_empty_string",
+        r#"
+function size(value) {
+    return length of value
+}
+
+function main() {
+    print ""
+    print size("")
+    print "a" plus "" plus "b"
+    return 0
+}
+"#,
+        "\n0\nab\n",
+    );
+}
+
+#[test]
+fn test_setting_an_element_leaves_aliases_alone() {
+    assert_jit_matches_vm(
+        "jit_array_alias",
+        r#"
+function main() {
+    var a is list 1, 2
+    var b is a
+    b at 0 is 9
+    print a
+    print b
+    return 0
+}
+"#,
+        "[1, 2]\n[9, 2]\n",
+    );
+}
+
+#[test]
+fn test_exit_stops_the_program() {
+    let dir = ScratchDir::new("jit_exit");
+    let file = dir.write_program(
+        "function main() {\n    print 1\n    exit(3)\n    print 2\n    return 0\n}\n",
+    );
+    let output = Command::new(TOPC).arg("--jit").arg(&file).output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+}
+
+#[test]
+fn test_unsupported_instructions_fail_to_compile() {
+    let dir = ScratchDir::new("jit_unsupported");
+    let file = dir
+        .write_program("function main() {\n    print 1\n    assert 1 equals 2\n    return 0\n}\n");
+    let stderr = run_failing(Command::new(TOPC).arg("--jit").arg(&file));
+    assert!(
+        stderr.contains("Assert(None) is not supported by the native backend"),
+        "{}",
+        stderr
+    );
+    // Nothing runs when any function can't be compiled
+    assert!(!stderr.contains("1\n"), "{}", stderr);
+}
+
+#[test]
+fn test_equality_compares_contents() {
+    assert_jit_matches_vm(
+        "jit_equality",
+        r#"
+function same(a, b) {
+    return a equals b
+}
+
+function main() {
+    var xs is list 1, "a"
+    var ys is list 1, "a"
+    var zs is list 1, "b"
+    print same("wor" plus "ld", "world")
+    print same("world", "word")
+    print same(xs, ys)
+    print same(xs, zs)
+    print same("1", 1)
+    return 0
+}
+"#,
+        "true\nfalse\ntrue\nfalse\nfalse\n",
+    );
+}
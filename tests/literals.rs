@@ -0,0 +1,48 @@
+//! Number literal forms print the same in every execution mode
+
+mod common;
+
+use common::{run_compiled, run_interpreter, run_vm, ScratchDir};
+
+#[test]
+fn test_radix_literals_in_every_mode() {
+    let dir = ScratchDir::new("radix_literals");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print 0xFF
+    print 0b1010 plus 0o17
+    print 1_000_000
+    print 1.5e3
+    return 0
+}
+"#,
+    );
+
+    let expected = "255\n25\n1000000\n1500\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
+
+#[test]
+fn test_numbers_outside_the_integer_range_print_in_every_mode() {
+    let dir = ScratchDir::new("large_numbers");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print 1e20
+    var huge is 1e308
+    print huge divided by 0.1
+    print 0 minus huge times 10
+    print 42
+    return 0
+}
+"#,
+    );
+
+    let expected = "100000000000000000000\ninfinity\n-infinity\n42\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
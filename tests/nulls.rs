@@ -0,0 +1,76 @@
+//! Defaulting nulls with `otherwise` and testing for them with `is null`
+
+mod common;
+
+use common::{run, run_interpreter, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_otherwise_defaults_missing_lookups() {
+    let dir = ScratchDir::new("otherwise");
+    let file = dir.write_program(
+        r#"
+function fallback(name) {
+    print "no age for " plus name
+    return 0
+}
+
+function main() {
+    var ages is map "alice" to 31, "bob" to 27
+    print ages at "alice" otherwise fallback("alice")
+    print ages at "carol" otherwise fallback("carol")
+    print ages at "dave" or else 18
+    print ages at "x" otherwise ages at "y" otherwise "nobody"
+    var names is list "ann", "ben"
+    print names at 1 otherwise fallback("index 1")
+    return 0
+}
+"#,
+    );
+
+    // fallback only runs for carol
+    let expected = "31\nno age for carol\n0\n18\nnobody\nben\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
+
+#[test]
+fn test_is_null_and_is_not_null() {
+    let dir = ScratchDir::new("is_null");
+    let file = dir.write_program(
+        r#"
+function nothing() {
+    return
+}
+
+function main() {
+    var ages is map "bob" to 27
+    if ages at "carol" is null {
+        print "no carol"
+    }
+    if ages at "bob" is not null and ages at "bob" greater than 20 {
+        print "bob is grown"
+    }
+    print nothing() is null
+    print 0 is null
+    print not nothing() is null
+    return 0
+}
+"#,
+    );
+
+    let expected = "no carol\nbob is grown\ntrue\nfalse\nfalse\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
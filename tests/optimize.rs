@@ -0,0 +1,54 @@
+//! `topc -O0`, `-O1` and `-O2`
+
+mod common;
+
+use common::{run, ScratchDir, TOPC};
+use std::process::Command;
+
+/// Instructions of `main` as `--show-bytecode` lists them, without offsets
+fn main_instructions(file: &std::path::Path, level: &str) -> Vec<String> {
+    let output = run(Command::new(TOPC)
+        .args(["-b", "--show-bytecode", level])
+        .arg(file));
+    let listing = output.rsplit("== main ==").next().unwrap();
+    listing
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(2))
+        .map(str::to_string)
+        .collect()
+}
+
+#[test]
+fn test_levels_choose_the_passes() {
+    let dir = ScratchDir::new("optimize_level");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print 2 plus 3
+    const x is 4
+    print x times 10
+    return 0
+}
+"#,
+    );
+
+    let unoptimized = main_instructions(&file, "-O0");
+    assert_eq!(
+        &unoptimized[..4],
+        ["LoadConst", "LoadConst", "Add", "Print"]
+    );
+
+    // -O1 folds `2 plus 3` but doesn't propagate `x`
+    let folded = main_instructions(&file, "-O1");
+    assert_eq!(&folded[..2], ["LoadConst", "Print"]);
+    assert!(folded.iter().any(|i| i == "Multiply"));
+
+    let optimized = main_instructions(&file, "-O2");
+    assert_eq!(&optimized[..2], ["LoadConst", "Print"]);
+    assert!(!optimized.iter().any(|i| i == "Add" || i == "Multiply"));
+
+    for level in ["-O0", "-O1", "-O2"] {
+        let output = run(Command::new(TOPC).args(["-b", level]).arg(&file));
+        assert_eq!(output, "5\n40\n", "{}", level);
+    }
+}
@@ -0,0 +1,94 @@
+//! `topc --print-format json`
+
+mod common;
+
+use common::{run, run_failing, ScratchDir, TOPC};
+use std::process::Command;
+
+const MODES: [&[&str]; 5] = [
+    &[],
+    &["-b"],
+    &["-b", "--nanbox"],
+    &["-b", "--threaded"],
+    &["-b", "--registers"],
+];
+
+#[test]
+fn test_json_output_of_each_value_type() {
+    let dir = ScratchDir::new("output_json");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var ages is map "bob" to 25, "alice" to list 30, "x"
+    print 42
+    print 2.5
+    print "say \"hi\""
+    print true
+    print false
+    print ages at "dave"
+    print list 1, list "a", list true, ages at "dave"
+    print list
+    print ages
+    return 0
+}
+"#,
+    );
+
+    let expected = "42\n2.5\n\"say \\\"hi\\\"\"\ntrue\nfalse\nnull\n\
+                    [1, [\"a\", [true, null]]]\n[]\n\
+                    {\"alice\": [30, \"x\"], \"bob\": 25}\n";
+    for mode in MODES {
+        assert_eq!(
+            run(Command::new(TOPC)
+                .args(mode)
+                .args(["--print-format", "json"])
+                .arg(&file)),
+            expected,
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn test_plain_output_is_the_default() {
+    let dir = ScratchDir::new("output_plain");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print "hi"
+    print list "a", 1
+    print map "k" to "v"
+    return 0
+}
+"#,
+    );
+
+    let expected = "hi\n[\"a\", 1]\n{k: \"v\"}\n";
+    for mode in MODES {
+        assert_eq!(run(Command::new(TOPC).args(mode).arg(&file)), expected);
+        assert_eq!(
+            run(Command::new(TOPC)
+                .args(mode)
+                .args(["--print-format", "plain"])
+                .arg(&file)),
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_json_output_needs_a_vm_or_the_interpreter() {
+    let dir = ScratchDir::new("output_native");
+    let file = dir.write_program("function main() {\n    return 0\n}\n");
+    let stderr = run_failing(
+        Command::new(TOPC)
+            .args(["--jit", "--print-format", "json"])
+            .arg(&file),
+    );
+    assert!(
+        stderr.contains("--print-format json is not supported"),
+        "{}",
+        stderr
+    );
+}
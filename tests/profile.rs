@@ -0,0 +1,95 @@
+//! `topc --bytecode --profile`
+
+mod common;
+
+use common::{run, run_failing, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_profile_counts_loop_jumps() {
+    let dir = ScratchDir::new("profile");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var i is 0
+    while i less than 7 {
+        i is i plus 1
+    }
+    return 0
+}
+"#,
+    );
+
+    for flags in [&["-b"][..], &["-b", "--nanbox"], &["-b", "--threaded"]] {
+        let output = run(Command::new(TOPC).args(flags).arg("--profile").arg(&file));
+        // One backward jump per iteration
+        assert!(
+            output.lines().any(|line| line.starts_with("Jump: 7 (")),
+            "{:?}: {}",
+            flags,
+            output
+        );
+        assert!(
+            output.contains("Max stack depth: "),
+            "{:?}: {}",
+            flags,
+            output
+        );
+    }
+}
+
+#[test]
+fn test_profile_is_rejected_on_register_vm() {
+    let dir = ScratchDir::new("profile_registers");
+    let file = dir.write_program("function main() { return 0 }");
+    let stderr = run_failing(
+        Command::new(TOPC)
+            .args(["-b", "--registers", "--profile"])
+            .arg(&file),
+    );
+    assert!(
+        stderr.contains("not supported on the register VM"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_recursive_calls_are_resolved_to_indices() {
+    let dir = ScratchDir::new("profile_calls");
+    let file = dir.write_program(
+        r#"
+function fib(n) {
+    if n less than 2 {
+        return n
+    }
+    return fib(n minus 1) plus fib(n minus 2)
+}
+
+function main() {
+    print fib(15)
+    return 0
+}
+"#,
+    );
+
+    for flags in [&["-b"][..], &["-b", "--nanbox"], &["-b", "--threaded"]] {
+        let output = run(Command::new(TOPC).args(flags).arg("--profile").arg(&file));
+        assert!(output.starts_with("610\n"), "{:?}: {}", flags, output);
+        // Every call, main's included, goes by index rather than by name
+        assert!(
+            output
+                .lines()
+                .any(|line| line.starts_with("CallIndex: 1,974 (")),
+            "{:?}: {}",
+            flags,
+            output
+        );
+        assert!(
+            !output.lines().any(|line| line.starts_with("Call: ")),
+            "{:?}: {}",
+            flags,
+            output
+        );
+    }
+}
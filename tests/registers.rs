@@ -0,0 +1,47 @@
+//! Runs the sample programs on the register VM and checks that they behave
+//! exactly as they do on the stack-based bytecode VM.
+
+mod common;
+
+use common::TOPC;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+/// Run `file` with the given flags and no input
+fn run_with(file: &Path, flags: &[&str]) -> Output {
+    Command::new(TOPC)
+        .args(flags)
+        .arg(file)
+        .stdin(Stdio::null())
+        .output()
+        .expect("Failed to spawn process")
+}
+
+#[test]
+fn test_examples_match_stack_vm() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut files: Vec<_> = std::fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "top"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+
+    for file in files {
+        let stack = run_with(&file, &["-b"]);
+        let registers = run_with(&file, &["-b", "--registers"]);
+        assert_eq!(
+            String::from_utf8_lossy(&registers.stdout),
+            String::from_utf8_lossy(&stack.stdout),
+            "{}",
+            file.display()
+        );
+        assert_eq!(
+            registers.status.code(),
+            stack.status.code(),
+            "{}",
+            file.display()
+        );
+    }
+}
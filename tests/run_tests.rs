@@ -0,0 +1,95 @@
+//! `topc --run-tests`
+
+mod common;
+
+use common::{run, run_failing, ScratchDir, TOPC};
+use std::path::PathBuf;
+use std::process::Command;
+
+const MODES: [&[&str]; 5] = [
+    &[],
+    &["-b"],
+    &["-b", "--nanbox"],
+    &["-b", "--threaded"],
+    &["-b", "--registers"],
+];
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/run_tests")
+        .join(name)
+}
+
+#[test]
+fn test_run_tests_reports_each_test_and_a_summary() {
+    for mode in MODES {
+        let output = Command::new(TOPC)
+            .args(mode)
+            .arg("--run-tests")
+            .arg(fixture("math.top"))
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1), "{:?}", mode);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines[0], "test test_square ... ok", "{:?}", mode);
+        assert_eq!(
+            lines[1], "test test_square_of_negative ... ok",
+            "{:?}",
+            mode
+        );
+        // A test's own output comes before its result
+        assert_eq!(lines[2], "checking 2 plus 2", "{:?}", mode);
+        assert!(
+            lines[3].starts_with("test test_wrong_answer ... FAILED")
+                && lines[3].ends_with("at line 16: Assertion failed: arithmetic is broken"),
+            "{:?}: {}",
+            mode,
+            lines[3]
+        );
+        assert_eq!(
+            lines[4], "test test_returns_failure ... FAILED (returned 3)",
+            "{:?}",
+            mode
+        );
+        assert_eq!(lines[5], "test test_zero ... ok", "{:?}", mode);
+        assert_eq!(lines.last(), Some(&"3 passed, 2 failed"), "{:?}", mode);
+        assert!(!stdout.contains("main is not run"), "{:?}", mode);
+    }
+}
+
+#[test]
+fn test_run_tests_succeeds_when_every_test_passes() {
+    let dir = ScratchDir::new("run_tests_pass");
+    let file = dir.write_program(
+        r#"
+function test_one() {
+    assert 1 plus 1 equals 2
+}
+
+function test_two() {
+    return 0
+}
+
+function helper() {
+    return 1
+}
+"#,
+    );
+    for mode in MODES {
+        let stdout = run(Command::new(TOPC).args(mode).arg("--run-tests").arg(&file));
+        assert_eq!(
+            stdout, "test test_one ... ok\ntest test_two ... ok\n\n2 passed, 0 failed\n",
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[test]
+fn test_run_tests_needs_a_test() {
+    let dir = ScratchDir::new("run_tests_none");
+    let file = dir.write_program("function main() {\n    return 0\n}\n");
+    let stderr = run_failing(Command::new(TOPC).arg("--run-tests").arg(&file));
+    assert!(stderr.contains("No test_ functions found"), "{}", stderr);
+}
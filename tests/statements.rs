@@ -0,0 +1,554 @@
+//! Statement forms behave the same in the interpreter and the VM
+
+mod common;
+
+use common::{run, run_compiled, run_failing, run_interpreter, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_compound_assignment() {
+    let dir = ScratchDir::new("compound_assign");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var total is 10
+    increase total by 5
+    print total
+    decrease total by 3
+    print total
+    multiply total by 2 plus 1
+    print total
+    return 0
+}
+"#,
+    );
+
+    let expected = "15\n12\n36\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+}
+
+#[test]
+fn test_compound_assignment_needs_a_defined_variable() {
+    let dir = ScratchDir::new("compound_undefined");
+    let file = dir.write_program(
+        r#"
+function main() {
+    increase total by 5
+    return 0
+}
+"#,
+    );
+
+    for mode in [&[][..], &["-b"][..]] {
+        let stderr = run_failing(Command::new(TOPC).args(mode).arg(&file));
+        assert!(
+            stderr.to_lowercase().contains("runtime error"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_repeat() {
+    let dir = ScratchDir::new("repeat");
+    let file = dir.write_program(
+        r#"
+function main() {
+    repeat 3 times {
+        print "hi"
+    }
+    repeat 0 times {
+        print "never"
+    }
+    var n is 0
+    repeat 2 plus 3 times {
+        increase n by 1
+        if n equals 2 {
+            continue
+        }
+        if n equals 4 {
+            break
+        }
+        print n
+    }
+    print n
+    return 0
+}
+"#,
+    );
+
+    let expected = "hi\nhi\nhi\n1\n3\n4\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+}
+
+#[test]
+fn test_for_each() {
+    let dir = ScratchDir::new("for_each");
+    let file = dir.write_program(
+        r#"
+function sum(values) {
+    var total is 0
+    for each value in values {
+        increase total by value
+    }
+    return total
+}
+
+function main() {
+    var numbers is list 1, 2, 3, 4, 5
+    print sum(numbers)
+    for each n in numbers {
+        if n equals 2 {
+            continue
+        }
+        if n equals 4 {
+            break
+        }
+        print n
+    }
+    for each tens in list 10, 20 {
+        for each n in range(0, 2) {
+            print tens plus n
+        }
+    }
+    for each nothing in list {
+        print "never"
+    }
+    return 0
+}
+"#,
+    );
+
+    let expected = "15\n1\n3\n10\n11\n20\n21\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+}
+
+#[test]
+fn test_for_each_variable_shadows_until_the_loop_ends() {
+    let dir = ScratchDir::new("for_each_shadow");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var item is "before"
+    for each item in list 1, 2 {
+        print item
+    }
+    print item
+    return 0
+}
+"#,
+    );
+
+    let expected = "1\n2\nbefore\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).args(["-b", "--registers"]).arg(&file)),
+        expected
+    );
+
+    // A constant can't be the loop variable
+    let file = dir.write_program(
+        "function main() {\n    const x is 1\n    for each x in list 1, 2 {\n    }\n    return 0\n}\n",
+    );
+    for flags in [&[][..], &["-b"]] {
+        let stderr = run_failing(Command::new(TOPC).args(flags).arg(&file));
+        assert!(
+            stderr.contains("Cannot assign to constant 'x'"),
+            "{:?}: {}",
+            flags,
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_for_each_over_a_non_array_is_an_error() {
+    let dir = ScratchDir::new("for_each_non_array");
+    let file = dir.write_program(
+        "function main() {\n    for each k in map \"a\" to 1 {\n        print k\n    }\n    return 0\n}\n",
+    );
+    for flags in [
+        &[][..],
+        &["-b"],
+        &["-b", "--nanbox"],
+        &["-b", "--threaded"],
+        &["-b", "--registers"],
+    ] {
+        let stderr = run_failing(Command::new(TOPC).args(flags).arg(&file));
+        assert!(
+            stderr.contains("for each expects an array, got {a: 1}"),
+            "{:?}: {}",
+            flags,
+            stderr
+        );
+    }
+}
+
+#[test]
+fn test_do_while() {
+    let dir = ScratchDir::new("do_while");
+    let file = dir.write_program(
+        r#"
+function main() {
+    do {
+        print "once"
+    } while false
+    var n is 0
+    do {
+        increase n by 1
+        if n equals 2 {
+            continue
+        }
+        if n equals 5 {
+            break
+        }
+        print n
+    } while n less than 10
+    print n
+    # continue still checks the condition
+    do {
+        increase n by 1
+        continue
+    } while n less than 8
+    print n
+    return 0
+}
+"#,
+    );
+
+    let expected = "once\n1\n3\n4\n5\n8\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
+
+#[test]
+fn test_labeled_break_and_continue() {
+    let dir = ScratchDir::new("labeled_loops");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var row is 0
+    while row less than 5 as rows {
+        increase row by 1
+        for (var col is 1 col less than 4 col is col plus 1) as cols {
+            if col equals 2 {
+                continue
+            }
+            if row equals 2 {
+                continue rows
+            }
+            if row equals 4 {
+                break rows
+            }
+            while true {
+                if col equals 3 {
+                    continue cols
+                }
+                break
+            }
+            print row times 10 plus col
+        }
+        print "end of row"
+    }
+    print row
+    return 0
+}
+"#,
+    );
+
+    let expected = "11\nend of row\n31\nend of row\n4\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
+
+#[test]
+fn test_and_or_short_circuit() {
+    let dir = ScratchDir::new("short_circuit");
+    let file = dir.write_program(
+        r#"
+function loud(value) {
+    print "evaluated"
+    return value
+}
+
+function main() {
+    print false and loud(true)
+    print true or loud(false)
+    print true and loud(false)
+    print false or loud(true)
+    var x is 0
+    if x equals 0 or 10 divided by x greater than 1 {
+        print "no division"
+    }
+    if x not equals 0 and 10 divided by x greater than 1 {
+        print "never"
+    }
+    return 0
+}
+"#,
+    );
+
+    let expected = "false\ntrue\nevaluated\nfalse\nevaluated\ntrue\nno division\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
+
+#[test]
+fn test_if_expression() {
+    let dir = ScratchDir::new("if_expression");
+    let file = dir.write_program(
+        r#"
+function loud(value) {
+    print "evaluated"
+    return value
+}
+
+function larger(a, b) {
+    var m is 0
+    if a greater than b {
+        m is a
+    } else {
+        m is b
+    }
+    return m
+}
+
+function main() {
+    var a is 3
+    var b is 7
+    var m is if a greater than b then a else b
+    print m equals larger(a, b)
+    print if b greater than a then b else a
+    print if a equals 3 then "three" else loud("other")
+    print (if false then 1 else 2) plus 10
+    return 0
+}
+"#,
+    );
+
+    // Only the branch the condition picks is evaluated
+    let expected = "true\n7\nthree\n12\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
+
+#[test]
+fn test_numeric_predicates() {
+    let dir = ScratchDir::new("numeric_predicates");
+    let file = dir.write_program(
+        r#"
+function main() {
+    for each n in list 4, 7, minus 3, minus 6, 0, 2.5, minus 0.5 {
+        var flags is ""
+        if n is even {
+            flags is flags plus " even"
+        }
+        if n is odd {
+            flags is flags plus " odd"
+        }
+        if n is positive {
+            flags is flags plus " positive"
+        }
+        if n is negative {
+            flags is flags plus " negative"
+        }
+        if n is zero {
+            flags is flags plus " zero"
+        }
+        print to_string(n) plus ":" plus flags
+    }
+    print 3 is not even and 10 is not odd
+    return 0
+}
+"#,
+    );
+
+    // Fractions are neither even nor odd
+    let expected = "4: even positive\n7: odd positive\n-3: odd negative\n\
+                    -6: even negative\n0: even zero\n2.5: positive\n-0.5: negative\ntrue\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
+
+#[test]
+fn test_trailing_expression_is_returned() {
+    let dir = ScratchDir::new("implicit_return");
+    let file = dir.write_program(
+        r#"
+function two() { 1 plus 1 }
+
+function sum_to(n) {
+    if n equals 0 {
+        return 0
+    }
+    n plus sum_to(n minus 1)
+}
+
+function greet(name) {
+    print "hi " plus name
+}
+
+function assign() {
+    var x is 5
+    x is x plus 1
+}
+
+function noop() {}
+
+function main() {
+    print two()
+    print sum_to(4)
+    print greet("ada")
+    print assign()
+    print noop()
+    return 0
+}
+"#,
+    );
+
+    // Only an expression at the end is returned; any other statement there
+    // leaves the result null
+    let expected = "2\n10\nhi ada\nnull\nnull\nnull\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        &["-b", "--nanbox"][..],
+        &["-b", "--threaded"],
+        &["-b", "--registers"],
+        &["-b", "--inline"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+}
+
+#[test]
+fn test_assert() {
+    let dir = ScratchDir::new("assert");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var total is 2 plus 2
+    assert total equals 4
+    print "checked"
+    assert total greater than 10 message "total is too small"
+    print "never"
+    return 0
+}
+"#,
+    );
+
+    let modes: [&[&str]; 6] = [
+        &[],
+        &["-b"],
+        &["-b", "--nanbox"],
+        &["-b", "--threaded"],
+        &["-b", "--registers"],
+        &["-b", "--inline"],
+    ];
+    for mode in modes {
+        let output = Command::new(TOPC)
+            .args(mode)
+            .arg(&file)
+            .output()
+            .expect("Failed to spawn process");
+        assert!(!output.status.success(), "{:?} didn't fail", mode);
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "checked\n");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(
+            stderr.contains("at line 6: Assertion failed: total is too small"),
+            "{:?}: {}",
+            mode,
+            stderr
+        );
+    }
+
+    let exe = dir.path().join("program");
+    run(Command::new(TOPC)
+        .arg("-c")
+        .arg(&file)
+        .arg("-o")
+        .arg(&exe)
+        .current_dir(dir.path()));
+    let stderr = run_failing(&mut Command::new(&exe));
+    assert!(
+        stderr.contains("at line 6: Assertion failed: total is too small"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_write_leaves_off_the_newline() {
+    let dir = ScratchDir::new("write");
+    let file = dir.write_program(
+        r#"
+function main() {
+    write "a"
+    write "b"
+    print ""
+    var n is 1
+    while n less than 4 {
+        write n
+        write " "
+        n is n plus 1
+    }
+    print "done"
+    return 0
+}
+"#,
+    );
+
+    let expected = "ab\n1 2 3 done\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    for flags in [
+        ["-b", "--nanbox"],
+        ["-b", "--threaded"],
+        ["-b", "--registers"],
+    ] {
+        assert_eq!(run(Command::new(TOPC).args(flags).arg(&file)), expected);
+    }
+    assert_eq!(run_compiled(&dir, &file), expected);
+}
@@ -0,0 +1,122 @@
+//! String operations agree across the interpreter and the VMs, and index
+//! by character rather than byte
+
+mod common;
+
+use common::{run, run_interpreter, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_string_search_and_edit_with_unicode() {
+    let dir = ScratchDir::new("string_ops");
+    // Literal operands are folded at compile time; variables are not
+    let file = dir.write_program(
+        r#"
+function main() {
+    var s is "  café crème  "
+    var word is "crème"
+    print s contains word
+    print s contains "tea"
+    print index of word in s
+    print index of "è" in "café crème"
+    print index of "tea" in s
+    print replace "é" with "e" in s
+    print trim s
+    print trim "  ünï  " plus "!"
+    var t is trim s
+    var start is index of word in t
+    print substring t from start to 10
+    return 0
+}
+"#,
+    );
+
+    let expected = "true\nfalse\n7\n7\n-1\n  cafe crème  \ncafé crème\nünï!\ncrème\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
+
+#[test]
+fn test_length_counts_characters() {
+    let dir = ScratchDir::new("string_length");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print length of "café"
+    var s is "naïve 日本"
+    var n is length of s
+    print n
+    print substring s from 0 to n
+    print substring s from 6 to n
+    var last is n minus 1
+    print substring s from last to n
+    return 0
+}
+"#,
+    );
+
+    let expected = "4\n8\nnaïve 日本\n日本\n本\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
+
+#[test]
+fn test_string_interpolation() {
+    let dir = ScratchDir::new("string_interpolation");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var name is "Ada"
+    var age is 36
+    print "Hello {name}, you are {age} years old"
+    print "{age}{age}"
+    print "next year: {age plus 1}, half: {age divided by 8}"
+    print "{name plus "!"} {length of name}"
+    print "literal \{braces\} stay"
+    return 0
+}
+"#,
+    );
+
+    let expected = "Hello Ada, you are 36 years old\n3636\nnext year: 37, half: 4.5\nAda! 3\nliteral {braces} stay\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
+
+#[test]
+fn test_concatenating_string_variables() {
+    let dir = ScratchDir::new("string_concat");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var a is "x"
+    var b is "y"
+    print a plus b
+    var n is 2
+    print a plus n
+    print n plus n
+    return 0
+}
+"#,
+    );
+
+    let expected = "xy\nx2\n4\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
@@ -0,0 +1,38 @@
+//! Runs programs on the direct-threaded VM selected with `--threaded`
+
+mod common;
+
+use common::{ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_threaded_vm_runs_program() {
+    let dir = ScratchDir::new("threaded_vm");
+    let file = dir.write_program(
+        r#"
+function square(n) {
+    return n times n
+}
+
+function main() {
+    var total is 0
+    var i is 1
+    while i less than 4 {
+        total is total plus square(i)
+        i is i plus 1
+    }
+    print total
+    return total
+}
+"#,
+    );
+
+    let output = Command::new(TOPC)
+        .args(["-b", "--threaded"])
+        .arg(&file)
+        .output()
+        .expect("Failed to spawn process");
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "14\n");
+    assert_eq!(output.status.code(), Some(14));
+}
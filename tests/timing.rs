@@ -0,0 +1,32 @@
+//! `topc --time`
+
+mod common;
+
+use common::{ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_time_reports_every_phase_on_stderr() {
+    let dir = ScratchDir::new("timing");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print "hello"
+    return 0
+}
+"#,
+    );
+
+    let output = Command::new(TOPC)
+        .args(["-b", "--time"])
+        .arg(&file)
+        .output()
+        .expect("Failed to spawn process");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello\n");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    for phase in ["lex: ", "parse: ", "optimize: ", "compile: ", "run: "] {
+        assert!(stderr.contains(phase), "missing {:?} in {}", phase, stderr);
+    }
+}
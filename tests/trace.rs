@@ -0,0 +1,69 @@
+//! `topc --bytecode --trace`
+
+mod common;
+
+use common::{run_failing, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_trace_shows_only_the_branch_taken() {
+    let dir = ScratchDir::new("trace");
+    let file = dir.write_program(
+        r#"
+function classify(n) {
+    if n greater than 10 {
+        print "big"
+    } else {
+        print "small"
+    }
+    return 0
+}
+
+function main() {
+    classify(42)
+    return 0
+}
+"#,
+    );
+
+    for flags in [&["-b"][..], &["-b", "--nanbox"], &["-b", "--threaded"]] {
+        let output = Command::new(TOPC)
+            .args(flags)
+            .arg("--trace")
+            .arg(&file)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", flags);
+        // The trace goes to stderr, leaving the program's output alone
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "big\n");
+        let trace = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(
+            trace,
+            "[line 12] classify(42)\n\
+             [line 3] if n greater than 10 {\n\
+             [line 4] print \"big\"\n\
+             [line 8] return 0\n\
+             [line 12] classify(42)\n\
+             [line 13] return 0\n",
+            "{:?}",
+            flags
+        );
+        assert!(!trace.contains("small"), "{:?}: {}", flags, trace);
+    }
+}
+
+#[test]
+fn test_trace_needs_a_stack_vm() {
+    let dir = ScratchDir::new("trace_registers");
+    let file = dir.write_program("function main() {\n    return 0\n}\n");
+    let stderr = run_failing(
+        Command::new(TOPC)
+            .args(["-b", "--registers", "--trace"])
+            .arg(&file),
+    );
+    assert!(
+        stderr.contains("--trace is not supported on the register VM"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,50 @@
+//! `type of` names a value's type the same way in every execution mode
+
+mod common;
+
+use common::{run, run_interpreter, run_vm, ScratchDir, TOPC};
+use std::process::Command;
+
+#[test]
+fn test_type_of_each_variant() {
+    let dir = ScratchDir::new("type_of");
+    // The literal operands are folded at compile time; the variables are not
+    let file = dir.write_program(
+        r#"
+function nothing() {
+    return
+}
+
+function main() {
+    print type of 1
+    print type of "one"
+    print type of true
+    print type of list 1, 2
+    var n is 1.5
+    var s is "text"
+    var b is n greater than 1
+    var a is list n, s
+    var m is map "k" to 1
+    print type of n
+    print type of s
+    print type of b
+    print type of a
+    print type of m
+    print type of nothing()
+    if type of n equals "number" {
+        print "numeric"
+    }
+    return 0
+}
+"#,
+    );
+
+    let expected =
+        "number\nstring\nboolean\narray\nnumber\nstring\nboolean\narray\nmap\nnull\nnumeric\n";
+    assert_eq!(run_interpreter(&file), expected);
+    assert_eq!(run_vm(&file), expected);
+    assert_eq!(
+        run(Command::new(TOPC).arg("-b").arg("--nanbox").arg(&file)),
+        expected
+    );
+}
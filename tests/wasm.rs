@@ -0,0 +1,55 @@
+//! `topc --target wasm`
+
+mod common;
+
+use common::{run, run_failing, ScratchDir, TOPC};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn test_target_wasm_writes_a_module() {
+    let dir = ScratchDir::new("wasm");
+    let file = dir.write_program(
+        r#"
+function main() {
+    print "hello"
+    return 0
+}
+"#,
+    );
+    let output = dir.path().join("hello.wasm");
+
+    run(Command::new(TOPC)
+        .args(["--target", "wasm"])
+        .arg(&file)
+        .arg("-o")
+        .arg(&output));
+    let bytes = fs::read(&output).unwrap();
+    assert_eq!(&bytes[..8], b"\0asm\x01\0\0\0");
+}
+
+#[test]
+fn test_target_wasm_rejects_unsupported_programs() {
+    let dir = ScratchDir::new("wasm_unsupported");
+    let file = dir.write_program(
+        r#"
+function main() {
+    var items is list 1, 2, 3
+    print items
+    return 0
+}
+"#,
+    );
+
+    let stderr = run_failing(
+        Command::new(TOPC)
+            .args(["--target", "wasm"])
+            .arg(&file)
+            .current_dir(dir.path()),
+    );
+    assert!(
+        stderr.contains("MakeArray is not supported by the wasm backend"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,67 @@
+//! `topc --watch`
+
+mod common;
+
+use common::{ScratchDir, TOPC};
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+fn program(message: &str) -> String {
+    format!(
+        "function main() {{\n    print \"{}\"\n    return 0\n}}\n",
+        message
+    )
+}
+
+#[test]
+fn test_watch_reruns_on_change_and_survives_errors() {
+    let dir = ScratchDir::new("watch");
+    let file = dir.write_program(&program("first"));
+
+    let mut child = Command::new(TOPC)
+        .arg("--watch")
+        .arg(&file)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to spawn process");
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let (send, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in stdout.lines() {
+            if send.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Everything printed up to and including the next "Watching" line
+    let next_run = || {
+        let mut output = Vec::new();
+        loop {
+            let line = lines
+                .recv_timeout(Duration::from_secs(10))
+                .expect("topc --watch stopped running the program");
+            let done = line.contains("Watching");
+            output.push(line);
+            if done {
+                return output;
+            }
+        }
+    };
+
+    assert_eq!(next_run()[0], "first");
+
+    std::fs::write(&file, "function main() {\n    print \"broken\"\n").unwrap();
+    let output = next_run();
+    assert_eq!(output[0], "Exited with code 1");
+
+    std::fs::write(&file, program("second")).unwrap();
+    assert_eq!(next_run()[0], "second");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}